@@ -0,0 +1,375 @@
+//! Detection of external GPU allocations that scope which GPUs we may use.
+//!
+//! Inside a Slurm job, the scheduler has already decided which GPUs belong to
+//! us. `with-gpu` then only selects among those GPUs and skips its own lock
+//! files, since Slurm owns allocation.
+//!
+//! Inside a Kubernetes pod using the NVIDIA device plugin, the container only
+//! sees its allocated GPUs and `/tmp` is private to the container, so we
+//! select among the visible GPUs and skip lock files as well.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+use with_gpu::{format_index_list, GpuInfo};
+
+/// Tri-state for environment detection flags like `--slurm`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Detection {
+    /// Enable when the environment is detected
+    Auto,
+    /// Always enable
+    On,
+    /// Never enable
+    Off,
+}
+
+/// GPUs granted by an external scheduler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Allocation {
+    /// Human-readable name of the scheduler, for messages
+    pub scheduler: &'static str,
+    /// What gave the environment away, for messages
+    pub detected_by: &'static str,
+    /// The environment variable the GPU list came from
+    pub source_var: Option<&'static str>,
+    /// Allocated GPU indices, or `None` if the scheduler didn't say
+    pub gpu_indices: Option<Vec<usize>>,
+}
+
+impl Allocation {
+    /// One-line description for stderr
+    pub fn describe(&self) -> String {
+        let gpus = match (&self.gpu_indices, self.source_var) {
+            (Some(indices), Some(var)) => {
+                format!("using GPU(s) {} from {}", format_index_list(indices), var)
+            }
+            _ => "using all visible GPUs".to_string(),
+        };
+        format!(
+            "{} environment detected ({}): {}, lock files disabled",
+            self.scheduler, self.detected_by, gpus
+        )
+    }
+}
+
+/// Variables Slurm uses to publish a job's GPUs, in order of preference
+const SLURM_GPU_VARS: [&str; 3] = ["SLURM_JOB_GPUS", "SLURM_STEP_GPUS", "CUDA_VISIBLE_DEVICES"];
+
+/// Detect a Slurm allocation. `var` looks up environment variables (injected
+/// for testing).
+pub fn detect_slurm(
+    mode: Detection,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<Option<Allocation>> {
+    let detected_by = match mode {
+        Detection::Off => return Ok(None),
+        Detection::On => "--slurm on",
+        Detection::Auto if var("SLURM_JOB_ID").is_some() => "SLURM_JOB_ID",
+        Detection::Auto => return Ok(None),
+    };
+
+    for name in SLURM_GPU_VARS {
+        if let Some(value) = var(name).filter(|v| !v.trim().is_empty()) {
+            let gpu_indices = parse_index_list(&value)
+                .context(format!("Invalid {} from Slurm: '{}'", name, value))?;
+            return Ok(Some(Allocation {
+                scheduler: "Slurm",
+                detected_by,
+                source_var: Some(name),
+                gpu_indices: Some(gpu_indices),
+            }));
+        }
+    }
+
+    Ok(Some(Allocation {
+        scheduler: "Slurm",
+        detected_by,
+        source_var: None,
+        gpu_indices: None,
+    }))
+}
+
+/// Detect a Kubernetes pod whose visible GPUs were allocated by the device
+/// plugin. The kubelet sets `KUBERNETES_SERVICE_HOST` in every container.
+/// `NVIDIA_VISIBLE_DEVICES` alone isn't enough: a plain `docker --gpus`
+/// container sets it too, and may share `/tmp` with the host, so its claims
+/// still matter. `var` looks up environment variables (injected for testing).
+pub fn detect_kubernetes(
+    mode: Detection,
+    var: impl Fn(&str) -> Option<String>,
+) -> Option<Allocation> {
+    let detected_by = match mode {
+        Detection::Off => return None,
+        Detection::On => "--k8s on",
+        Detection::Auto if var("KUBERNETES_SERVICE_HOST").is_some() => "KUBERNETES_SERVICE_HOST",
+        Detection::Auto => return None,
+    };
+
+    // The container only sees its own GPUs, so there's nothing to restrict
+    Some(Allocation {
+        scheduler: "Kubernetes",
+        detected_by,
+        source_var: None,
+        gpu_indices: None,
+    })
+}
+
+/// What the container runtime's `NVIDIA_VISIBLE_DEVICES` asks for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VisibleDevices {
+    /// `none` or `void`: the container gets no GPUs
+    NoGpus,
+    /// `all`: every GPU on the host
+    All,
+    /// Specific GPUs, by index or UUID
+    List(Vec<String>),
+}
+
+/// Read `NVIDIA_VISIBLE_DEVICES`, or `None` if it's unset or empty. `var`
+/// looks up environment variables (injected for testing).
+pub fn nvidia_visible_devices(var: impl Fn(&str) -> Option<String>) -> Option<VisibleDevices> {
+    let value = var("NVIDIA_VISIBLE_DEVICES")?;
+    let value = value.trim();
+    match value.to_ascii_lowercase().as_str() {
+        "" => None,
+        "none" | "void" => Some(VisibleDevices::NoGpus),
+        "all" => Some(VisibleDevices::All),
+        _ => Some(VisibleDevices::List(
+            value
+                .split(',')
+                .map(|entry| entry.trim().to_string())
+                .filter(|entry| !entry.is_empty())
+                .collect(),
+        )),
+    }
+}
+
+impl VisibleDevices {
+    /// The indices of `gpus` this setting allows, or `None` if it doesn't
+    /// restrict them. GPUs are matched by UUID, which survives the
+    /// renumbering from 0 that the container runtime applies. A list with
+    /// host indices can't be matched inside the container, where the runtime
+    /// has already applied it, so it doesn't restrict anything further.
+    pub fn allowed_indices(&self, gpus: &[GpuInfo]) -> Option<Vec<usize>> {
+        let entries = match self {
+            VisibleDevices::NoGpus => return Some(Vec::new()),
+            VisibleDevices::All => return None,
+            VisibleDevices::List(entries) => entries,
+        };
+        let is_uuid = |entry: &String| entry.starts_with("GPU-") || entry.starts_with("MIG-");
+        if !entries.iter().all(is_uuid) {
+            return None;
+        }
+        Some(
+            gpus.iter()
+                .filter(|gpu| entries.contains(&gpu.uuid))
+                .map(|gpu| gpu.index)
+                .collect(),
+        )
+    }
+}
+
+/// Warn about an inherited `CUDA_VISIBLE_DEVICES` that CUDA would cut short.
+/// CUDA reads the list up to its first invalid entry (a duplicate, an
+/// out-of-range index, or neither an index nor a UUID) and ignores the rest,
+/// so the CUDA memory query would see fewer GPUs than the value names. A
+/// negative index is CUDA's deliberate terminator (`-1` hides every GPU), so it
+/// isn't warned about.
+pub fn inherited_cuda_visible_devices_warning(value: &str, device_count: usize) -> Option<String> {
+    let mut seen = Vec::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let problem = if entry.starts_with("GPU-") || entry.starts_with("MIG-") {
+            // UUIDs can't be range-checked without a device query
+            None
+        } else {
+            match entry.parse::<i64>() {
+                Ok(index) if index < 0 => return None,
+                Ok(index) if index as u64 >= device_count as u64 => Some(format!(
+                    "GPU {} is out of range, this host has {} GPU(s)",
+                    index, device_count
+                )),
+                Ok(_) => None,
+                Err(_) => Some(format!("'{}' is not a GPU index or UUID", entry)),
+            }
+        };
+        let problem = problem.or_else(|| {
+            seen.contains(&entry)
+                .then(|| format!("'{}' is listed more than once", entry))
+        });
+        if let Some(problem) = problem {
+            return Some(format!(
+                "Inherited CUDA_VISIBLE_DEVICES='{}': {}, so CUDA ignores it and every later entry \
+                 (with-gpu sets CUDA_VISIBLE_DEVICES for the command)",
+                value, problem
+            ));
+        }
+        seen.push(entry);
+    }
+    None
+}
+
+fn parse_index_list(value: &str) -> Result<Vec<usize>> {
+    value
+        .split(',')
+        .map(|s| {
+            s.trim()
+                .parse::<usize>()
+                .context(format!("'{}' is not a GPU index", s.trim()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_slurm_auto_requires_job_id() {
+        let var = env(&[("SLURM_JOB_GPUS", "0,1")]);
+        assert_eq!(detect_slurm(Detection::Auto, var).unwrap(), None);
+    }
+
+    #[test]
+    fn test_slurm_auto_uses_job_gpus() {
+        let var = env(&[
+            ("SLURM_JOB_ID", "42"),
+            ("SLURM_JOB_GPUS", "2,3"),
+            ("CUDA_VISIBLE_DEVICES", "0,1"),
+        ]);
+        let allocation = detect_slurm(Detection::Auto, var).unwrap().unwrap();
+        assert_eq!(allocation.gpu_indices, Some(vec![2, 3]));
+        assert_eq!(allocation.source_var, Some("SLURM_JOB_GPUS"));
+    }
+
+    #[test]
+    fn test_slurm_falls_back_to_cuda_visible_devices() {
+        let var = env(&[("SLURM_JOB_ID", "42"), ("CUDA_VISIBLE_DEVICES", "1")]);
+        let allocation = detect_slurm(Detection::Auto, var).unwrap().unwrap();
+        assert_eq!(allocation.gpu_indices, Some(vec![1]));
+    }
+
+    #[test]
+    fn test_slurm_off_ignores_environment() {
+        let var = env(&[("SLURM_JOB_ID", "42"), ("SLURM_JOB_GPUS", "0")]);
+        assert_eq!(detect_slurm(Detection::Off, var).unwrap(), None);
+    }
+
+    #[test]
+    fn test_slurm_on_without_gpu_list() {
+        let allocation = detect_slurm(Detection::On, env(&[])).unwrap().unwrap();
+        assert_eq!(allocation.gpu_indices, None);
+    }
+
+    #[test]
+    fn test_kubernetes_detected_by_service_host() {
+        let var = env(&[
+            ("KUBERNETES_SERVICE_HOST", "10.0.0.1"),
+            ("NVIDIA_VISIBLE_DEVICES", "GPU-aaaa"),
+        ]);
+        let allocation = detect_kubernetes(Detection::Auto, var).unwrap();
+        assert_eq!(allocation.detected_by, "KUBERNETES_SERVICE_HOST");
+        assert_eq!(allocation.gpu_indices, None);
+    }
+
+    #[test]
+    fn test_kubernetes_not_detected() {
+        assert_eq!(detect_kubernetes(Detection::Auto, env(&[])), None);
+        // A docker --gpus container, which may share /tmp with the host
+        let var = env(&[("NVIDIA_VISIBLE_DEVICES", "all")]);
+        assert_eq!(detect_kubernetes(Detection::Auto, var), None);
+        let var = env(&[("KUBERNETES_SERVICE_HOST", "10.0.0.1")]);
+        assert_eq!(detect_kubernetes(Detection::Off, var), None);
+    }
+
+    #[test]
+    fn test_nvidia_visible_devices_values() {
+        let parse = |value: &str| nvidia_visible_devices(env(&[("NVIDIA_VISIBLE_DEVICES", value)]));
+        assert_eq!(parse("void"), Some(VisibleDevices::NoGpus));
+        assert_eq!(parse("none"), Some(VisibleDevices::NoGpus));
+        assert_eq!(parse("all"), Some(VisibleDevices::All));
+        assert_eq!(
+            parse("0, GPU-aaaa"),
+            Some(VisibleDevices::List(vec!["0".into(), "GPU-aaaa".into()]))
+        );
+        assert_eq!(parse(""), None);
+        assert_eq!(nvidia_visible_devices(env(&[])), None);
+    }
+
+    #[test]
+    fn test_visible_devices_allowed_indices() {
+        let gpus: Vec<GpuInfo> = (0..4)
+            .map(|index| GpuInfo {
+                index,
+                uuid: format!("GPU-{}", index),
+                ..Default::default()
+            })
+            .collect();
+        let list = |entries: &[&str]| {
+            VisibleDevices::List(entries.iter().map(|e| e.to_string()).collect())
+        };
+        assert_eq!(VisibleDevices::All.allowed_indices(&gpus), None);
+        assert_eq!(VisibleDevices::NoGpus.allowed_indices(&gpus), Some(vec![]));
+        assert_eq!(
+            list(&["GPU-1", "GPU-3"]).allowed_indices(&gpus),
+            Some(vec![1, 3])
+        );
+        // Host indices, already applied by the runtime, which renumbered the
+        // GPUs; a length match doesn't matter
+        assert_eq!(list(&["1", "GPU-3"]).allowed_indices(&gpus), None);
+        assert_eq!(list(&["4", "5"]).allowed_indices(&gpus[..2]), None);
+    }
+
+    #[test]
+    fn test_inherited_cuda_visible_devices_valid() {
+        assert_eq!(inherited_cuda_visible_devices_warning("0,1", 2), None);
+        assert_eq!(
+            inherited_cuda_visible_devices_warning("GPU-aaaa,1", 2),
+            None
+        );
+        assert_eq!(inherited_cuda_visible_devices_warning("", 2), None);
+    }
+
+    #[test]
+    fn test_inherited_cuda_visible_devices_negative_hides_gpus() {
+        assert_eq!(inherited_cuda_visible_devices_warning("-1", 4), None);
+        assert_eq!(inherited_cuda_visible_devices_warning("0,-1,gpu9", 4), None);
+    }
+
+    #[test]
+    fn test_inherited_cuda_visible_devices_duplicate() {
+        let warning = inherited_cuda_visible_devices_warning("2,2", 4).unwrap();
+        assert!(
+            warning.contains("'2' is listed more than once"),
+            "{}",
+            warning
+        );
+    }
+
+    #[test]
+    fn test_inherited_cuda_visible_devices_out_of_range() {
+        let warning = inherited_cuda_visible_devices_warning("0,5", 4).unwrap();
+        assert!(warning.contains("GPU 5 is out of range"), "{}", warning);
+    }
+
+    #[test]
+    fn test_inherited_cuda_visible_devices_non_integer() {
+        let warning = inherited_cuda_visible_devices_warning("gpu0", 4).unwrap();
+        assert!(warning.contains("'gpu0' is not a GPU index"), "{}", warning);
+    }
+
+    #[test]
+    fn test_slurm_invalid_gpu_list() {
+        let var = env(&[("SLURM_JOB_ID", "42"), ("SLURM_JOB_GPUS", "gpu0")]);
+        assert!(detect_slurm(Detection::Auto, var).is_err());
+    }
+}
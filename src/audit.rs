@@ -0,0 +1,262 @@
+//! Append-only audit log of selections, for working out after the fact which
+//! job had which GPUs.
+//!
+//! Enabled by setting `WITH_GPU_AUDIT_LOG` to a file path. Each launch appends
+//! one JSON object per line. When the file grows past `MAX_LOG_BYTES`, it is
+//! renamed to `<path>.1` (replacing any previous one) and a new file is
+//! started. Logging is best-effort: failures are ignored, since a missing
+//! audit line shouldn't stop a job from starting.
+//!
+//! `with-gpu history` prints the most recent entries.
+
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use with_gpu::format_index_list;
+
+/// Size at which the log is rotated
+const MAX_LOG_BYTES: u64 = 10 * 1024 * 1024;
+
+/// One line of the log
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct LoggedLaunch {
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub pid: u32,
+    pub user: String,
+    pub gpus: Vec<usize>,
+    pub command: Vec<String>,
+}
+
+/// The audit log path, if `WITH_GPU_AUDIT_LOG` is set
+pub fn log_path(var: impl Fn(&str) -> Option<String>) -> Option<PathBuf> {
+    var("WITH_GPU_AUDIT_LOG")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Append a line recording that this process is launching `command` on
+/// `gpu_indices`
+pub fn record_launch(path: &Path, gpu_indices: &[usize], command: &[String]) {
+    let entry = LoggedLaunch {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        pid: std::process::id(),
+        user: current_user(),
+        gpus: gpu_indices.to_vec(),
+        command: command.to_vec(),
+    };
+    let _ = append(path, &entry);
+}
+
+fn append(path: &Path, entry: &LoggedLaunch) -> std::io::Result<()> {
+    rotate_if_large(path, MAX_LOG_BYTES);
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    // A single write of a short line keeps concurrent appends from interleaving
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())
+}
+
+fn rotate_if_large(path: &Path, max_bytes: u64) {
+    if fs::metadata(path).is_ok_and(|m| m.len() >= max_bytes) {
+        let _ = fs::rename(path, rotated_path(path));
+    }
+}
+
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
+
+/// The last `count` entries, oldest first, reaching back into the rotated
+/// file when the current one is short. Missing files count as empty, and
+/// lines that don't parse (e.g. a write cut short) are skipped.
+pub fn read_recent(path: &Path, count: usize) -> Result<Vec<LoggedLaunch>> {
+    let mut entries = Vec::new();
+    for file in [rotated_path(path), path.to_path_buf()] {
+        let contents = match fs::read_to_string(&file) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", file.display())),
+        };
+        entries.extend(
+            contents
+                .lines()
+                .filter_map(|line| serde_json::from_str::<LoggedLaunch>(line).ok()),
+        );
+    }
+    let skip = entries.len().saturating_sub(count);
+    Ok(entries.split_off(skip))
+}
+
+/// `with-gpu history`: print the last `count` launches
+pub fn print_history(path: &Path, count: usize, json: bool) -> Result<()> {
+    let entries = read_recent(path, count)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+    if entries.is_empty() {
+        println!("No launches recorded in {}", path.display());
+        return Ok(());
+    }
+    for entry in &entries {
+        println!(
+            "{}  {}  GPU(s) {}  pid {}  {}",
+            format_timestamp(entry.timestamp),
+            entry.user,
+            format_index_list(&entry.gpus),
+            entry.pid,
+            entry.command.join(" ")
+        );
+    }
+    Ok(())
+}
+
+/// Seconds since the Unix epoch as `YYYY-MM-DD HH:MM:SS UTC`
+fn format_timestamp(timestamp: u64) -> String {
+    let (days, secs) = (timestamp / 86400, timestamp % 86400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+fn current_user() -> String {
+    if let Some(user) = ["USER", "LOGNAME"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok().filter(|u| !u.is_empty()))
+    {
+        return user;
+    }
+    #[cfg(unix)]
+    {
+        format!("uid {}", unsafe { libc::getuid() })
+    }
+    #[cfg(not(unix))]
+    {
+        "unknown".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_log(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "with-gpu-audit-{}-{}.log",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_record_launch_appends_json_lines() {
+        let path = temp_log("append");
+        let command = vec!["python".to_string(), "train.py".to_string()];
+        record_launch(&path, &[1, 3], &command);
+        record_launch(&path, &[0], &command);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["gpus"], serde_json::json!([1, 3]));
+        assert_eq!(
+            lines[0]["command"],
+            serde_json::json!(["python", "train.py"])
+        );
+        assert_eq!(lines[1]["pid"], std::process::id());
+    }
+
+    #[test]
+    fn test_rotate_if_large() {
+        let path = temp_log("rotate");
+        let rotated = PathBuf::from(format!("{}.1", path.display()));
+        fs::write(&path, "0123456789").unwrap();
+
+        rotate_if_large(&path, 100);
+        assert!(path.exists());
+
+        rotate_if_large(&path, 10);
+        assert!(!path.exists());
+        assert_eq!(fs::read_to_string(&rotated).unwrap(), "0123456789");
+        fs::remove_file(&rotated).unwrap();
+    }
+
+    #[test]
+    fn test_read_recent_spans_rotation() {
+        let path = temp_log("recent");
+        let rotated = rotated_path(&path);
+        assert_eq!(read_recent(&path, 5).unwrap(), vec![]);
+
+        let command = vec!["true".to_string()];
+        record_launch(&path, &[0], &command);
+        record_launch(&path, &[1], &command);
+        fs::rename(&path, &rotated).unwrap();
+        record_launch(&path, &[2], &command);
+        // A torn line is skipped
+        fs::write(
+            &path,
+            fs::read_to_string(&path).unwrap() + "{\"timestamp\": 17\n",
+        )
+        .unwrap();
+
+        let gpus = |entries: Vec<LoggedLaunch>| -> Vec<Vec<usize>> {
+            entries.into_iter().map(|e| e.gpus).collect()
+        };
+        let recent = read_recent(&path, 2);
+        let all = read_recent(&path, 10);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+        assert_eq!(gpus(recent.unwrap()), vec![vec![1], vec![2]]);
+        assert_eq!(gpus(all.unwrap()), vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_timestamp(1_760_614_323), "2025-10-16 11:32:03 UTC");
+    }
+
+    #[test]
+    fn test_log_path() {
+        let var = |value: &'static str| move |_: &str| Some(value.to_string());
+        assert_eq!(
+            log_path(var("/var/log/gpu.log")),
+            Some(PathBuf::from("/var/log/gpu.log"))
+        );
+        assert_eq!(log_path(var("")), None);
+        assert_eq!(log_path(|_: &str| None), None);
+    }
+}
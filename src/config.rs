@@ -0,0 +1,421 @@
+//! Layered defaults for the selection criteria.
+//!
+//! Criteria can come from five places. From highest to lowest precedence:
+//!
+//! 1. Command-line flags
+//! 2. The config file profile `--profile` names (a `[profile.NAME]` table)
+//! 3. `WITH_GPU_*` environment variables (convenient in containers)
+//! 4. The config file's top level (`$WITH_GPU_CONFIG`, else
+//!    `$XDG_CONFIG_HOME/with-gpu/config.toml`, else
+//!    `~/.config/with-gpu/config.toml`)
+//! 5. Built-in defaults
+//!
+//! Each layer is a `PartialCriteria`; `PartialCriteria::or` stacks them.
+//!
+//! This module also reads `--allow-file` GPU allowlists.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use with_gpu::selector::GpuId;
+use with_gpu::{
+    NoiseModel, DEFAULT_HIDDEN_USAGE_THRESHOLD_MB, DEFAULT_IDLE_MAX_UTIL_PERCENT,
+    DEFAULT_IDLE_MEMORY_THRESHOLD_MB,
+};
+
+/// The criteria a single layer sets; `None` defers to the layers below.
+/// Field names double as the config file keys.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartialCriteria {
+    pub min_memory_mb: Option<u64>,
+    pub max_utilization: Option<u8>,
+    pub min_gpus: Option<usize>,
+    pub max_gpus: Option<usize>,
+    pub require_idle: Option<bool>,
+    pub include_used: Option<bool>,
+    pub idle_memory_threshold_mb: Option<u64>,
+    pub hidden_usage_threshold_mb: Option<u64>,
+    pub idle_max_utilization: Option<u8>,
+}
+
+impl PartialCriteria {
+    /// Fill in the fields this layer leaves unset from `lower`
+    pub fn or(self, lower: PartialCriteria) -> PartialCriteria {
+        PartialCriteria {
+            min_memory_mb: self.min_memory_mb.or(lower.min_memory_mb),
+            max_utilization: self.max_utilization.or(lower.max_utilization),
+            min_gpus: self.min_gpus.or(lower.min_gpus),
+            max_gpus: self.max_gpus.or(lower.max_gpus),
+            require_idle: self.require_idle.or(lower.require_idle),
+            include_used: self.include_used.or(lower.include_used),
+            idle_memory_threshold_mb: self
+                .idle_memory_threshold_mb
+                .or(lower.idle_memory_threshold_mb),
+            hidden_usage_threshold_mb: self
+                .hidden_usage_threshold_mb
+                .or(lower.hidden_usage_threshold_mb),
+            idle_max_utilization: self.idle_max_utilization.or(lower.idle_max_utilization),
+        }
+    }
+
+    /// The noise thresholds, with the built-in defaults for unset ones
+    pub fn noise(&self) -> NoiseModel {
+        NoiseModel {
+            idle_memory_mb: self
+                .idle_memory_threshold_mb
+                .unwrap_or(DEFAULT_IDLE_MEMORY_THRESHOLD_MB),
+            hidden_usage_mb: self
+                .hidden_usage_threshold_mb
+                .unwrap_or(DEFAULT_HIDDEN_USAGE_THRESHOLD_MB),
+            idle_max_util_percent: self
+                .idle_max_utilization
+                .unwrap_or(DEFAULT_IDLE_MAX_UTIL_PERCENT),
+        }
+    }
+}
+
+/// The config file: criteria at the top level, plus named profiles of
+/// criteria in `[profile.NAME]` tables
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    pub criteria: PartialCriteria,
+    pub profiles: BTreeMap<String, PartialCriteria>,
+}
+
+impl Config {
+    /// The criteria of profile `name`, or an error listing the profiles
+    /// there are
+    pub fn profile(&self, name: &str) -> Result<PartialCriteria> {
+        if let Some(criteria) = self.profiles.get(name) {
+            return Ok(criteria.clone());
+        }
+        let available = if self.profiles.is_empty() {
+            "no profiles are defined".to_string()
+        } else {
+            let names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            format!("available profiles: {}", names.join(", "))
+        };
+        anyhow::bail!("Unknown profile '{}' ({})", name, available)
+    }
+}
+
+/// Read criteria from `WITH_GPU_*` environment variables. `var` looks up
+/// environment variables (injected for testing). Empty values count as unset.
+pub fn from_env(var: impl Fn(&str) -> Option<String>) -> Result<PartialCriteria> {
+    Ok(PartialCriteria {
+        min_memory_mb: parse_env(&var, "WITH_GPU_MIN_MEMORY")?,
+        max_utilization: parse_env(&var, "WITH_GPU_MAX_UTIL")?,
+        min_gpus: parse_env(&var, "WITH_GPU_MIN_GPUS")?,
+        max_gpus: parse_env(&var, "WITH_GPU_MAX_GPUS")?,
+        require_idle: parse_env_bool(&var, "WITH_GPU_REQUIRE_IDLE")?,
+        include_used: parse_env_bool(&var, "WITH_GPU_INCLUDE_USED")?,
+        idle_memory_threshold_mb: parse_env(&var, "WITH_GPU_IDLE_MEMORY_THRESHOLD")?,
+        hidden_usage_threshold_mb: parse_env(&var, "WITH_GPU_HIDDEN_USAGE_THRESHOLD")?,
+        idle_max_utilization: parse_env(&var, "WITH_GPU_IDLE_MAX_UTIL")?,
+    })
+}
+
+fn non_empty(var: &impl Fn(&str) -> Option<String>, name: &str) -> Option<String> {
+    var(name)
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn parse_env<T: std::str::FromStr>(
+    var: &impl Fn(&str) -> Option<String>,
+    name: &str,
+) -> Result<Option<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match non_empty(var, name) {
+        Some(value) => {
+            Ok(Some(value.parse().with_context(|| {
+                format!("Invalid {} value '{}'", name, value)
+            })?))
+        }
+        None => Ok(None),
+    }
+}
+
+fn parse_env_bool(var: &impl Fn(&str) -> Option<String>, name: &str) -> Result<Option<bool>> {
+    match non_empty(var, name) {
+        Some(value) => {
+            Ok(Some(parse_bool(&value).with_context(|| {
+                format!("Invalid {} value '{}'", name, value)
+            })?))
+        }
+        None => Ok(None),
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => anyhow::bail!("expected 1/0, true/false, yes/no or on/off"),
+    }
+}
+
+/// Where the config file lives, or `None` if no location can be determined
+pub fn config_path(var: impl Fn(&str) -> Option<String>) -> Option<PathBuf> {
+    if let Some(path) = var("WITH_GPU_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let config_dir = match var("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(var("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("with-gpu").join("config.toml"))
+}
+
+/// Read the config file. A missing file is the same as an empty one.
+pub fn load_config(path: &Path) -> Result<Config> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+        }
+    };
+    parse_config(&contents).with_context(|| format!("Invalid config file {}", path.display()))
+}
+
+fn parse_config(contents: &str) -> Result<Config> {
+    let mut table: toml::Table = toml::from_str(contents)?;
+    let profiles = match table.remove("profile") {
+        Some(profiles) => profiles
+            .try_into()
+            .context("Invalid [profile] table (expected [profile.NAME] tables of criteria)")?,
+        None => BTreeMap::new(),
+    };
+    Ok(Config {
+        criteria: toml::Value::Table(table).try_into()?,
+        profiles,
+    })
+}
+
+/// Read an `--allow-file` allowlist: one GPU index or UUID per line, with
+/// blank lines and `#` comments ignored. Unlike the config file, a missing
+/// allowlist is an error, since naming one implies intent.
+pub fn load_allowlist(path: &Path) -> Result<Vec<GpuId>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read --allow-file {}", path.display()))?;
+    parse_allowlist(&contents).with_context(|| format!("Invalid --allow-file {}", path.display()))
+}
+
+fn parse_allowlist(contents: &str) -> Result<Vec<GpuId>> {
+    let mut ids = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let entry = match line.split_once('#') {
+            Some((entry, _comment)) => entry.trim(),
+            None => line.trim(),
+        };
+        if entry.is_empty() {
+            continue;
+        }
+        let id = if entry.starts_with("GPU-") || entry.starts_with("MIG-") {
+            GpuId::Uuid(entry.to_string())
+        } else {
+            entry.parse().map(GpuId::Index).with_context(|| {
+                format!(
+                    "line {}: expected a GPU index or UUID, got '{}'",
+                    number + 1,
+                    entry
+                )
+            })?
+        };
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: Vec<(String, String)> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone())
+    }
+
+    #[test]
+    fn test_from_env() {
+        let criteria = from_env(env(&[
+            ("WITH_GPU_MIN_MEMORY", "8000"),
+            ("WITH_GPU_MAX_UTIL", "50"),
+            ("WITH_GPU_MIN_GPUS", "2"),
+            ("WITH_GPU_MAX_GPUS", ""),
+            ("WITH_GPU_REQUIRE_IDLE", "yes"),
+            ("WITH_GPU_INCLUDE_USED", "off"),
+            ("WITH_GPU_IDLE_MEMORY_THRESHOLD", "1024"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            criteria,
+            PartialCriteria {
+                min_memory_mb: Some(8000),
+                max_utilization: Some(50),
+                min_gpus: Some(2),
+                max_gpus: None,
+                require_idle: Some(true),
+                include_used: Some(false),
+                idle_memory_threshold_mb: Some(1024),
+                hidden_usage_threshold_mb: None,
+                idle_max_utilization: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_from_env_rejects_malformed_values() {
+        let err = from_env(env(&[("WITH_GPU_MIN_GPUS", "two")])).unwrap_err();
+        assert!(format!("{:#}", err).contains("WITH_GPU_MIN_GPUS"));
+        assert!(from_env(env(&[("WITH_GPU_REQUIRE_IDLE", "maybe")])).is_err());
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config = parse_config("min_memory_mb = 40000\nrequire_idle = true\n").unwrap();
+        assert_eq!(config.criteria.min_memory_mb, Some(40000));
+        assert_eq!(config.criteria.require_idle, Some(true));
+        assert!(config.profiles.is_empty());
+        assert!(parse_config("min_memory = 40000\n").is_err());
+    }
+
+    #[test]
+    fn test_profiles() {
+        let config = parse_config(
+            "max_utilization = 50\n\
+             \n\
+             [profile.big]\n\
+             min_memory_mb = 40000\n\
+             \n\
+             [profile.quick]\n\
+             max_utilization = 30\n",
+        )
+        .unwrap();
+        assert_eq!(config.criteria.max_utilization, Some(50));
+        assert_eq!(config.profile("big").unwrap().min_memory_mb, Some(40000));
+        assert_eq!(config.profile("quick").unwrap().max_utilization, Some(30));
+
+        let err = config.profile("huge").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown profile 'huge' (available profiles: big, quick)"
+        );
+        let err = Config::default().profile("big").unwrap_err();
+        assert!(
+            err.to_string().contains("no profiles are defined"),
+            "{}",
+            err
+        );
+
+        assert!(parse_config("[profile.big]\nmin_memory = 1\n").is_err());
+    }
+
+    #[test]
+    fn test_noise() {
+        assert_eq!(PartialCriteria::default().noise(), NoiseModel::default());
+        let criteria =
+            parse_config("idle_memory_threshold_mb = 1024\nhidden_usage_threshold_mb = 1024\n")
+                .unwrap()
+                .criteria;
+        assert_eq!(
+            criteria.noise(),
+            NoiseModel {
+                idle_memory_mb: 1024,
+                hidden_usage_mb: 1024,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_allowlist() {
+        let ids = parse_allowlist(
+            "# GPUs this host may hand out\n\
+             \n\
+             0\n\
+             \x20 GPU-8a1c-0002  # the spare A100\n\
+             # 1 is reserved\n\
+             3\n\
+             MIG-4f2e-0001\n\
+             0\n",
+        )
+        .unwrap();
+        assert_eq!(
+            ids,
+            vec![
+                GpuId::Index(0),
+                GpuId::Uuid("GPU-8a1c-0002".to_string()),
+                GpuId::Index(3),
+                GpuId::Uuid("MIG-4f2e-0001".to_string()),
+            ]
+        );
+        assert_eq!(parse_allowlist("# nothing allowed\n\n").unwrap(), vec![]);
+
+        let err = parse_allowlist("0\ngpu1\n").unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn test_load_allowlist_requires_file() {
+        let path =
+            std::env::temp_dir().join(format!("with-gpu-no-allowlist-{}", std::process::id()));
+        let err = load_allowlist(&path).unwrap_err();
+        assert!(format!("{:#}", err).contains("Failed to read --allow-file"));
+    }
+
+    #[test]
+    fn test_config_path() {
+        assert_eq!(
+            config_path(env(&[("WITH_GPU_CONFIG", "/etc/gpu.toml"), ("HOME", "/h")])),
+            Some(PathBuf::from("/etc/gpu.toml"))
+        );
+        assert_eq!(
+            config_path(env(&[("XDG_CONFIG_HOME", "/x"), ("HOME", "/h")])),
+            Some(PathBuf::from("/x/with-gpu/config.toml"))
+        );
+        assert_eq!(
+            config_path(env(&[("HOME", "/h")])),
+            Some(PathBuf::from("/h/.config/with-gpu/config.toml"))
+        );
+        assert_eq!(config_path(env(&[])), None);
+    }
+
+    #[test]
+    fn test_precedence() {
+        // (flag, environment, config file, expected); None as the expected
+        // value means the built-in default applies
+        let cases = [
+            (Some(1), Some(2), Some(3), Some(1)),
+            (Some(1), None, Some(3), Some(1)),
+            (None, Some(2), Some(3), Some(2)),
+            (None, Some(2), None, Some(2)),
+            (None, None, Some(3), Some(3)),
+            (None, None, None, None),
+        ];
+        for (cli, environment, config, expected) in cases {
+            let layer = |value: Option<usize>| PartialCriteria {
+                min_gpus: value,
+                ..Default::default()
+            };
+            let resolved = layer(cli).or(layer(environment)).or(layer(config));
+            assert_eq!(
+                resolved.min_gpus, expected,
+                "cli={:?} env={:?} config={:?}",
+                cli, environment, config
+            );
+        }
+    }
+}
@@ -3,12 +3,22 @@
 //! This module provides accurate GPU memory information by querying CUDA directly,
 //! bypassing NVML which can return stale data in some scenarios.
 
+use std::fmt;
+use std::sync::Mutex;
+
 use anyhow::{anyhow, Result};
 
+/// A device's PCI location: domain, bus, and device number. Unlike CUDA's
+/// device ordinals, this identifies the same card to CUDA and to NVML.
+pub type PciAddress = (u32, u32, u32);
+
 /// Memory information for a single GPU.
 #[derive(Debug, Clone)]
 pub struct CudaMemoryInfo {
+    /// CUDA's ordinal, which counts only the devices `CUDA_VISIBLE_DEVICES` exposes
     pub device_index: usize,
+    /// `None` if the driver didn't report it
+    pub pci_address: Option<PciAddress>,
     pub free_bytes: u64,
     pub total_bytes: u64,
 }
@@ -22,15 +32,78 @@ impl CudaMemoryInfo {
         self.used_bytes() / (1024 * 1024)
     }
 
-    pub fn free_mb(&self) -> u64 {
-        self.free_bytes / (1024 * 1024)
-    }
-
     pub fn total_mb(&self) -> u64 {
         self.total_bytes / (1024 * 1024)
     }
 }
 
+/// Memory info for the GPUs CUDA sees. Devices it couldn't be read for are
+/// missing from `devices`, so `device_count` can be larger.
+#[derive(Debug, Clone, Default)]
+pub struct CudaMemory {
+    pub device_count: usize,
+    pub devices: Vec<CudaMemoryInfo>,
+}
+
+/// The device refused a context, most likely because it's in
+/// EXCLUSIVE_PROCESS compute mode and another process already holds one.
+/// That's expected on exactly the GPUs we'd rather not disturb, so it isn't
+/// worth a warning; their memory comes from NVML instead.
+#[derive(Debug)]
+pub struct DeviceUnavailable {
+    pub device_index: usize,
+}
+
+impl fmt::Display for DeviceUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "CUDA device {} is unavailable (exclusive compute mode?)",
+            self.device_index
+        )
+    }
+}
+
+impl std::error::Error for DeviceUnavailable {}
+
+/// A device that refused a context. It isn't retried until NVML shows nothing
+/// running on it (see `retry_unavailable_device`), so polling doesn't keep
+/// knocking on it.
+#[derive(Debug, PartialEq)]
+struct UnavailableDevice {
+    device_index: usize,
+    pci_address: Option<PciAddress>,
+}
+
+static UNAVAILABLE_DEVICES: Mutex<Vec<UnavailableDevice>> = Mutex::new(Vec::new());
+
+/// Let the next query try CUDA again on NVML device `index` if it was found
+/// unavailable. Call it once NVML shows no processes on the device, since the
+/// context that kept it exclusive is then gone.
+pub fn retry_unavailable_device(index: usize, pci_address: Option<PciAddress>) {
+    forget_device(&mut UNAVAILABLE_DEVICES.lock().unwrap(), index, pci_address);
+}
+
+/// Drop NVML device `index` from `devices`, matching on PCI location. A device
+/// that didn't report one is matched by ordinal, which may pick the wrong
+/// device; that only costs one more refused context.
+fn forget_device(
+    devices: &mut Vec<UnavailableDevice>,
+    index: usize,
+    pci_address: Option<PciAddress>,
+) {
+    devices.retain(|device| match (device.pci_address, pci_address) {
+        (Some(unavailable), Some(address)) => unavailable != address,
+        _ => device.device_index != index,
+    });
+}
+
+/// Whether a failed `primary_ctx::retain` means the device is unavailable
+/// (exclusive-process or prohibited compute mode) rather than broken
+fn is_device_unavailable(result: cudarc::driver::sys::CUresult) -> bool {
+    result == cudarc::driver::sys::CUresult::CUDA_ERROR_DEVICE_UNAVAILABLE
+}
+
 /// Query memory info for a specific GPU using CUDA Driver API.
 ///
 /// This creates a CUDA context on the device, queries memory, then releases the context.
@@ -48,8 +121,17 @@ pub fn query_device_memory(device_index: usize) -> Result<CudaMemoryInfo> {
     // Create/retain a primary context for this device
     // SAFETY: device is a valid device handle obtained from device::get
     let ctx = unsafe {
-        result::primary_ctx::retain(device)
-            .map_err(|e| anyhow!("Failed to create CUDA context for device {}: {:?}", device_index, e))?
+        result::primary_ctx::retain(device).map_err(|e| {
+            if is_device_unavailable(e.0) {
+                anyhow::Error::new(DeviceUnavailable { device_index })
+            } else {
+                anyhow!(
+                    "Failed to create CUDA context for device {}: {:?}",
+                    device_index,
+                    e
+                )
+            }
+        })?
     };
 
     // Push context to make it current
@@ -60,8 +142,13 @@ pub fn query_device_memory(device_index: usize) -> Result<CudaMemoryInfo> {
     }
 
     // Query memory info using the result module's wrapper
-    let (free, total) = result::mem_get_info()
-        .map_err(|e| anyhow!("Failed to get memory info for device {}: {:?}", device_index, e))?;
+    let (free, total) = result::mem_get_info().map_err(|e| {
+        anyhow!(
+            "Failed to get memory info for device {}: {:?}",
+            device_index,
+            e
+        )
+    })?;
 
     // Release the primary context (decrements refcount, doesn't destroy)
     // SAFETY: device is a valid device handle
@@ -72,13 +159,53 @@ pub fn query_device_memory(device_index: usize) -> Result<CudaMemoryInfo> {
 
     Ok(CudaMemoryInfo {
         device_index,
+        pci_address: pci_address(device),
         free_bytes: free as u64,
         total_bytes: total as u64,
     })
 }
 
-/// Query memory info for all GPUs.
-pub fn query_all_device_memory() -> Result<Vec<CudaMemoryInfo>> {
+/// The PCI location of a CUDA device
+fn pci_address(device: cudarc::driver::sys::CUdevice) -> Option<PciAddress> {
+    use cudarc::driver::result::device::get_attribute;
+    use cudarc::driver::sys::CUdevice_attribute::{
+        CU_DEVICE_ATTRIBUTE_PCI_BUS_ID, CU_DEVICE_ATTRIBUTE_PCI_DEVICE_ID,
+        CU_DEVICE_ATTRIBUTE_PCI_DOMAIN_ID,
+    };
+
+    // SAFETY: device is a valid device handle obtained from device::get
+    let attribute = |attribute| {
+        unsafe { get_attribute(device, attribute) }
+            .ok()
+            .map(|value| value as u32)
+    };
+    Some((
+        attribute(CU_DEVICE_ATTRIBUTE_PCI_DOMAIN_ID)?,
+        attribute(CU_DEVICE_ATTRIBUTE_PCI_BUS_ID)?,
+        attribute(CU_DEVICE_ATTRIBUTE_PCI_DEVICE_ID)?,
+    ))
+}
+
+/// Initialize the CUDA driver and count the devices it sees (for `with-gpu probe`).
+pub fn device_count() -> Result<i32> {
+    use cudarc::driver::result;
+
+    // cudarc panics, rather than returning an error, when libcuda can't be
+    // loaded; keep the panic message quiet and report it as a failed check
+    let previous_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let count = std::panic::catch_unwind(|| {
+        result::init().map_err(|e| anyhow!("Failed to initialize CUDA driver: {:?}", e))?;
+        result::device::get_count().map_err(|e| anyhow!("Failed to get CUDA device count: {:?}", e))
+    });
+    std::panic::set_hook(previous_hook);
+
+    count.map_err(|_| anyhow!("Failed to load the CUDA driver library (libcuda)"))?
+}
+
+/// Query memory info for all GPUs, skipping those at the PCI locations in
+/// `skip` (`--cuda-skip-exclusive`) and any found unavailable earlier.
+pub fn query_all_device_memory(skip: &[PciAddress]) -> Result<CudaMemory> {
     use cudarc::driver::result;
 
     // Initialize CUDA driver API
@@ -89,14 +216,73 @@ pub fn query_all_device_memory() -> Result<Vec<CudaMemoryInfo>> {
 
     let mut results = Vec::with_capacity(device_count as usize);
     for i in 0..device_count {
-        match query_device_memory(i as usize) {
+        let i = i as usize;
+        let unavailable = UNAVAILABLE_DEVICES.lock().unwrap();
+        if unavailable.iter().any(|device| device.device_index == i) {
+            continue;
+        }
+        drop(unavailable);
+        let address = result::device::get(i as i32).ok().and_then(pci_address);
+        if address.is_some_and(|address| skip.contains(&address)) {
+            continue;
+        }
+        match query_device_memory(i) {
             Ok(info) => results.push(info),
+            Err(e) if e.is::<DeviceUnavailable>() => {
+                UNAVAILABLE_DEVICES.lock().unwrap().push(UnavailableDevice {
+                    device_index: i,
+                    pci_address: address,
+                })
+            }
             Err(e) => {
                 // Log warning but continue with other devices
-                eprintln!("Warning: Failed to query CUDA memory for device {}: {}", i, e);
+                eprintln!(
+                    "Warning: Failed to query CUDA memory for device {}: {}",
+                    i, e
+                );
             }
         }
     }
 
-    Ok(results)
+    Ok(CudaMemory {
+        device_count: device_count as usize,
+        devices: results,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cudarc::driver::sys::CUresult;
+
+    #[test]
+    fn test_is_device_unavailable() {
+        assert!(is_device_unavailable(
+            CUresult::CUDA_ERROR_DEVICE_UNAVAILABLE
+        ));
+        assert!(!is_device_unavailable(CUresult::CUDA_ERROR_OUT_OF_MEMORY));
+        assert!(!is_device_unavailable(CUresult::CUDA_ERROR_INVALID_DEVICE));
+
+        let error = anyhow::Error::new(DeviceUnavailable { device_index: 3 });
+        assert!(error.is::<DeviceUnavailable>());
+        assert!(!anyhow!("Failed to create CUDA context").is::<DeviceUnavailable>());
+    }
+
+    #[test]
+    fn test_forget_device() {
+        let device = |device_index, pci_address| UnavailableDevice {
+            device_index,
+            pci_address,
+        };
+        let mut devices = vec![device(0, Some((0, 0x10, 0))), device(1, None)];
+
+        // CUDA's ordinals needn't be NVML's indices; the PCI location decides
+        forget_device(&mut devices, 0, Some((0, 0x11, 0)));
+        assert_eq!(devices.len(), 2);
+        forget_device(&mut devices, 2, Some((0, 0x10, 0)));
+        assert_eq!(devices, vec![device(1, None)]);
+
+        forget_device(&mut devices, 1, Some((0, 0x12, 0)));
+        assert!(devices.is_empty());
+    }
 }
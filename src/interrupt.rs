@@ -0,0 +1,87 @@
+//! Ctrl-C while `--wait` polls. Rather than letting the default handler kill
+//! with-gpu mid-sleep, `catch` records the signal and the wait loop notices it
+//! between short naps, then returns normally so anything it holds is released
+//! on the way out.
+
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often `sleep` checks for an interrupt
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Set by the SIGINT/SIGTERM handler `catch` installs
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The signal that last set `INTERRUPTED` (0 for none)
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// The signal `catch` last recorded, if any
+pub fn signal() -> Option<i32> {
+    match SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// Record SIGINT and SIGTERM in `INTERRUPTED` until the guard is dropped,
+/// which restores the default handlers
+pub fn catch() -> Catch {
+    #[cfg(unix)]
+    set_handlers(record as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    Catch
+}
+
+pub struct Catch;
+
+impl Drop for Catch {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        set_handlers(libc::SIG_DFL);
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn record(signal: libc::c_int) {
+    SIGNAL.store(signal, Ordering::SeqCst);
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+fn set_handlers(handler: libc::sighandler_t) {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        unsafe { libc::signal(signal, handler) };
+    }
+}
+
+/// Sleep for `duration`, waking early once `flag` is set. Returns whether it
+/// was set.
+pub fn sleep(duration: Duration, flag: &AtomicBool) -> bool {
+    let deadline = Instant::now() + duration;
+    loop {
+        if flag.load(Ordering::SeqCst) {
+            return true;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+        thread::sleep(CHECK_INTERVAL.min(deadline - now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sleep_wakes_when_flag_is_set() {
+        let flag = AtomicBool::new(false);
+        assert!(!sleep(Duration::from_millis(10), &flag));
+
+        flag.store(true, Ordering::SeqCst);
+        let start = Instant::now();
+        assert!(sleep(Duration::from_secs(30), &flag));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
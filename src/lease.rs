@@ -0,0 +1,175 @@
+//! Select-and-claim in one step, for library users.
+//!
+//! `acquire` queries a `GpuSource`, selects GPUs, and claims them; the
+//! returned `GpuLease` holds the claims until it is dropped.
+//!
+//! ```no_run
+//! # fn run(source: &mut dyn with_gpu::GpuSource) -> anyhow::Result<()> {
+//! use with_gpu::lease;
+//! use with_gpu::selector::{MostFreeMemoryPolicy, SelectionCriteria};
+//!
+//! let lease = lease::acquire(source, &SelectionCriteria::default(), &MostFreeMemoryPolicy)?;
+//! std::env::set_var("CUDA_VISIBLE_DEVICES", lease.cuda_visible_devices());
+//! // ... use the GPUs; the claims are released when `lease` is dropped
+//! # Ok(())
+//! # }
+//! ```
+
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::lockfile;
+use crate::selector::{self, SelectionCriteria, SelectionPolicy};
+use crate::{GpuInfo, GpuSelection, GpuSource};
+
+/// Selected GPUs, claimed through lock files until dropped
+#[derive(Debug)]
+pub struct GpuLease {
+    selection: GpuSelection,
+    /// The GPUs as queried at selection time
+    gpus: Vec<GpuInfo>,
+    /// What we actually claimed (empty when `use_locks` is off)
+    claimed: Vec<usize>,
+}
+
+impl GpuLease {
+    /// Claim the GPUs in `selection` atomically (all or none). With
+    /// `use_locks` off, nothing is claimed and the lease only carries the
+    /// selection. `hold` keeps the claims valid after this process exits,
+    /// and `reserved_mb` is recorded in them for other selections to discount
+    /// (see `lockfile::claim_gpu_with_hold`).
+    pub fn claim(
+        selection: GpuSelection,
+        gpus: Vec<GpuInfo>,
+        use_locks: bool,
+        hold: Duration,
+        reserved_mb: Option<u64>,
+    ) -> Result<Self, lockfile::ClaimError> {
+        let claimed = if use_locks {
+            lockfile::claim_gpus(&selection.gpu_indices, hold, reserved_mb)?;
+            selection.gpu_indices.clone()
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            selection,
+            gpus,
+            claimed,
+        })
+    }
+
+    /// Like `claim`, but the claims are shared with other sharers (see
+    /// `lockfile::claim_gpu_shared`)
+    pub fn claim_shared(
+        selection: GpuSelection,
+        gpus: Vec<GpuInfo>,
+        use_locks: bool,
+        reserved_mb: Option<u64>,
+    ) -> Result<Self, lockfile::ClaimError> {
+        let claimed = if use_locks {
+            lockfile::claim_gpus_shared(&selection.gpu_indices, reserved_mb)?;
+            selection.gpu_indices.clone()
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            selection,
+            gpus,
+            claimed,
+        })
+    }
+
+    pub fn selection(&self) -> &GpuSelection {
+        &self.selection
+    }
+
+    pub fn gpus(&self) -> &[GpuInfo] {
+        &self.gpus
+    }
+
+    pub fn indices(&self) -> &[usize] {
+        &self.selection.gpu_indices
+    }
+
+    pub fn cuda_visible_devices(&self) -> String {
+        self.selection.to_cuda_visible_devices()
+    }
+}
+
+impl Drop for GpuLease {
+    fn drop(&mut self) {
+        for &gpu_index in &self.claimed {
+            lockfile::release_gpu(gpu_index);
+        }
+    }
+}
+
+/// Select GPUs from `source` and claim them, returning a lease that releases
+/// the claims when dropped
+pub fn acquire(
+    source: &mut dyn GpuSource,
+    criteria: &SelectionCriteria,
+    policy: &dyn SelectionPolicy,
+) -> Result<GpuLease> {
+    let gpus = source.query_gpus()?;
+    let mut selection = selector::select_gpus(&gpus, criteria, policy)?;
+    selection.sort_indices();
+    let reserved_mb = criteria.reservation_mb();
+    let lease = if criteria.share {
+        GpuLease::claim_shared(selection, gpus, criteria.use_locks, reserved_mb)?
+    } else {
+        GpuLease::claim(
+            selection,
+            gpus,
+            criteria.use_locks,
+            Duration::ZERO,
+            reserved_mb,
+        )?
+    };
+    Ok(lease)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selector::MostFreeMemoryPolicy;
+    use crate::test_support::{make_gpu, MockSource, TempLockDir};
+
+    #[test]
+    fn test_lease_releases_claims_on_drop() {
+        let _lock_dir = TempLockDir::new();
+        let indices = [9_000_301, 9_000_302];
+        let mut source = MockSource::new(vec![indices.iter().map(|&i| make_gpu(i)).collect()]);
+        let criteria = SelectionCriteria {
+            min_gpus: 2,
+            max_gpus: 2,
+            ..Default::default()
+        };
+
+        let lease = acquire(&mut source, &criteria, &MostFreeMemoryPolicy).unwrap();
+        assert_eq!(lease.indices(), indices);
+        assert_eq!(lease.cuda_visible_devices(), "9000301,9000302");
+        let pid = Some(std::process::id());
+        assert!(indices.iter().all(|&i| lockfile::get_gpu_claim(i) == pid));
+
+        drop(lease);
+        assert!(indices
+            .iter()
+            .all(|&i| lockfile::get_gpu_claim(i).is_none()));
+    }
+
+    #[test]
+    fn test_lease_without_locks_claims_nothing() {
+        let _lock_dir = TempLockDir::new();
+        let mut source = MockSource::new(vec![vec![make_gpu(9_000_303)]]);
+        let criteria = SelectionCriteria {
+            use_locks: false,
+            ..Default::default()
+        };
+
+        let lease = acquire(&mut source, &criteria, &MostFreeMemoryPolicy).unwrap();
+        assert_eq!(lease.indices(), [9_000_303]);
+        assert_eq!(lockfile::get_gpu_claim(9_000_303), None);
+    }
+}
@@ -1,40 +1,262 @@
+use std::cell::RefCell;
 use std::fmt;
 
-#[derive(Debug, Clone)]
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+pub mod lease;
+pub mod lockfile;
+pub mod selector;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod usage;
+
+/// One GPU's state. Serializes as it appears in `--status --json`, which
+/// `--simulate` reads back (missing fields take their defaults).
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
 pub struct GpuInfo {
     pub index: usize,
+    /// Stable device identifier (e.g. "GPU-8a1c..."), unlike indices which can shift
+    pub uuid: String,
     pub memory_used_mb: u64,
     pub memory_total_mb: u64,
+    /// SM (compute) utilization
     pub utilization_percent: u8,
+    /// Memory-controller utilization; high when a neighbor is bandwidth-bound
+    /// even if SM utilization looks idle
+    pub memory_utilization_percent: u8,
+    /// Core temperature in °C, if the board reports it
+    pub temperature_c: Option<u32>,
+    /// The number of running compute and graphics processes
+    /// (`processes.len()` when queried through NVML)
     pub process_count: usize,
+    /// PIDs of the running processes, as NVML reports them (host PID
+    /// namespace)
+    pub pids: Vec<u32>,
+    /// The running processes in detail
+    pub processes: Vec<GpuProcess>,
+    /// Memory NVML attributes to running compute processes
+    pub attributed_memory_mb: u64,
+    /// How long the GPU has been idle, as observed across `with-gpu` runs
+    /// (`None` when busy or not tracked)
+    pub idle_secs: Option<u64>,
     /// Memory used but not attributed to visible processes (indicates hidden/stale process data)
     pub hidden_usage_mb: u64,
+    /// Where `memory_used_mb` (and so `hidden_usage_mb`) came from
+    pub memory_source: MemorySource,
+    /// NVML clock throttle reasons (`nvmlClocksThrottleReason*` bits); 0 when
+    /// unthrottled or when the board doesn't support the query
+    pub throttle_reasons: u64,
+    /// A vGPU (GRID) slice in a virtual machine: the total memory is the
+    /// profile's share, and unattributed memory isn't treated as hidden usage
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub vgpu: bool,
+    /// Indices of the GPUs this one can read from directly over NVLink or
+    /// PCIe peer-to-peer; only queried for `--require-p2p`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub p2p_peers: Vec<usize>,
+    /// Why the device couldn't be queried (e.g. it fell off the bus); the
+    /// other fields are then unknown. Such a GPU is never idle or selected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Which API reported a GPU's used memory. Process memory is always NVML's,
+/// so when used memory comes from CUDA, a disagreement between the two shows
+/// up as hidden usage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MemorySource {
+    /// Not recorded (e.g. a snapshot from an older version)
+    #[default]
+    Unknown,
+    Nvml,
+    Cuda,
+}
+
+impl MemorySource {
+    fn label(self) -> Option<&'static str> {
+        match self {
+            MemorySource::Unknown => None,
+            MemorySource::Nvml => Some("NVML"),
+            MemorySource::Cuda => Some("CUDA"),
+        }
+    }
+}
+
+/// A process running on a GPU
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct GpuProcess {
+    /// A graphics (e.g. display server) process with no compute context
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub graphics: bool,
+    /// Host PID namespace, as NVML reports it
+    pub pid: u32,
+    /// Memory NVML attributes to the process; `None` when it can't tell
+    /// (e.g. without permission)
+    pub used_memory_mb: Option<u64>,
+    /// Executable name, when it can be looked up
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Owning user, when it can be looked up
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+impl fmt::Display for GpuProcess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pid {}", self.pid)?;
+        let details: Vec<&str> = [
+            self.name.as_deref(),
+            self.user.as_deref(),
+            self.graphics.then_some("graphics"),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if !details.is_empty() {
+            write!(f, " ({})", details.join(", "))?;
+        }
+        match self.used_memory_mb {
+            Some(mb) => write!(f, ": {} MB", mb),
+            None => write!(f, ": memory unknown"),
+        }
+    }
 }
 
-/// Threshold for detecting hidden memory usage (driver jitter tolerance)
-pub const HIDDEN_USAGE_THRESHOLD_MB: u64 = 512;
+/// Default threshold above which unattributed memory counts as hidden usage
+/// (`NoiseModel::hidden_usage_mb`)
+pub const DEFAULT_HIDDEN_USAGE_THRESHOLD_MB: u64 = 512;
+
+/// NVML throttle reason bits that mean a slowdown state, with their labels.
+/// Software power capping is left out: a busy GPU at its power limit reports
+/// it routinely.
+pub const SLOWDOWN_THROTTLE_REASONS: [(u64, &str); 4] = [
+    (0x08, "hw slowdown"),
+    (0x20, "sw thermal slowdown"),
+    (0x40, "hw thermal slowdown"),
+    (0x80, "hw power brake"),
+];
+
+/// Default memory usage below which a GPU with no processes counts as idle
+/// (`NoiseModel::idle_memory_mb`)
+pub const DEFAULT_IDLE_MEMORY_THRESHOLD_MB: u64 = 500;
+
+/// Default SM utilization above which a GPU isn't idle, whatever its memory
+/// and processes (`NoiseModel::idle_max_util_percent`)
+pub const DEFAULT_IDLE_MAX_UTIL_PERCENT: u8 = 5;
+
+/// How much memory the driver uses on its own, as opposed to a job. Both
+/// thresholds measure the same baseline, so a site whose idle GPUs hold more
+/// context memory (e.g. in persistence mode) should raise them together
+/// (`--idle-memory-threshold`, `--hidden-usage-threshold`, or their config
+/// keys).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoiseModel {
+    /// A GPU with no processes counts as idle while it uses less than this
+    pub idle_memory_mb: u64,
+    /// Memory not attributed to any process counts as hidden usage above this
+    pub hidden_usage_mb: u64,
+    /// A GPU busier than this (SM utilization) isn't idle, e.g. one running
+    /// kernels for an MPS client NVML doesn't list
+    pub idle_max_util_percent: u8,
+}
+
+impl Default for NoiseModel {
+    fn default() -> Self {
+        NoiseModel {
+            idle_memory_mb: DEFAULT_IDLE_MEMORY_THRESHOLD_MB,
+            hidden_usage_mb: DEFAULT_HIDDEN_USAGE_THRESHOLD_MB,
+            idle_max_util_percent: DEFAULT_IDLE_MAX_UTIL_PERCENT,
+        }
+    }
+}
 
 impl GpuInfo {
-    /// Returns true if unattributed memory usage exceeds the given threshold.
-    /// This indicates processes using GPU memory that aren't visible to NVML.
-    pub fn has_hidden_usage(&self, threshold_mb: u64) -> bool {
-        self.hidden_usage_mb > threshold_mb
+    /// Returns true if unattributed memory usage exceeds the hidden-usage
+    /// threshold. This indicates processes using GPU memory that aren't
+    /// visible to NVML. Never true for a vGPU slice, whose profile reserves
+    /// memory no guest process owns.
+    pub fn has_hidden_usage(&self, noise: &NoiseModel) -> bool {
+        !self.vgpu && self.hidden_usage_mb > noise.hidden_usage_mb
+    }
+
+    pub fn is_idle(&self, noise: &NoiseModel) -> bool {
+        // A GPU is idle if it has no processes AND minimal memory usage and
+        // utilization. We check memory usage and utilization because NVML
+        // process detection can miss processes in some cases (e.g.,
+        // persistence mode, MPS, certain driver states)
+        self.reports_memory()
+            && self.process_count == 0
+            && self.memory_used_mb < noise.idle_memory_mb
+            && self.utilization_percent <= noise.idle_max_util_percent
+            && !self.has_hidden_usage(noise)
+    }
+
+    /// Like `is_idle`, but unattributed memory (e.g. a driver reservation)
+    /// doesn't count as usage. Used with `--allow-hidden-usage`.
+    pub fn is_idle_ignoring_hidden_usage(&self, noise: &NoiseModel) -> bool {
+        self.reports_memory()
+            && self.process_count == 0
+            && self.memory_used_mb.saturating_sub(self.hidden_usage_mb) < noise.idle_memory_mb
+            && self.utilization_percent <= noise.idle_max_util_percent
     }
 
-    pub fn is_idle(&self) -> bool {
-        // A GPU is idle if it has no processes AND minimal memory usage
-        // We check memory usage because NVML process detection can miss processes
-        // in some cases (e.g., persistence mode, MPS, certain driver states)
-        const IDLE_MEMORY_THRESHOLD_MB: u64 = 500;
-        self.process_count == 0
-            && self.memory_used_mb < IDLE_MEMORY_THRESHOLD_MB
-            && !self.has_hidden_usage(HIDDEN_USAGE_THRESHOLD_MB)
+    /// False when the device reports 0 MB total memory, as it can briefly
+    /// during a driver reset. Such a device is neither idle nor selectable.
+    pub fn reports_memory(&self) -> bool {
+        self.memory_total_mb > 0
+    }
+
+    /// A placeholder for a device that enumerated but failed to answer queries
+    pub fn unavailable(index: usize, error: String) -> Self {
+        Self {
+            index,
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+
+    /// The slowdown states the GPU's clocks are in, e.g. "hw thermal
+    /// slowdown", or `None` when it isn't slowed down
+    pub fn throttle_summary(&self) -> Option<String> {
+        let reasons: Vec<&str> = SLOWDOWN_THROTTLE_REASONS
+            .iter()
+            .filter(|(bit, _)| self.throttle_reasons & bit != 0)
+            .map(|(_, label)| *label)
+            .collect();
+        (!reasons.is_empty()).then(|| reasons.join(", "))
     }
 
     pub fn memory_free_mb(&self) -> u64 {
         self.memory_total_mb.saturating_sub(self.memory_used_mb)
     }
 
+    /// Decomposition of used memory for verbose status, e.g.
+    /// "1200 MB used (CUDA) = 1000 MB attributed to processes (NVML) + 200 MB
+    /// unattributed"
+    pub fn memory_breakdown(&self, noise: &NoiseModel) -> String {
+        let mut breakdown = match self.memory_source.label() {
+            Some(source) => format!(
+                "{} MB used ({}) = {} MB attributed to processes (NVML) + {} MB unattributed",
+                self.memory_used_mb, source, self.attributed_memory_mb, self.hidden_usage_mb
+            ),
+            None => format!(
+                "{} MB used = {} MB attributed to processes + {} MB unattributed",
+                self.memory_used_mb, self.attributed_memory_mb, self.hidden_usage_mb
+            ),
+        };
+        if self.has_hidden_usage(noise) {
+            breakdown.push_str(&format!(
+                " (exceeds {} MB hidden-usage threshold; excluded from selection)",
+                noise.hidden_usage_mb
+            ));
+        }
+        breakdown
+    }
+
     pub fn memory_usage_percent(&self) -> f64 {
         if self.memory_total_mb == 0 {
             0.0
@@ -44,35 +266,275 @@ impl GpuInfo {
     }
 }
 
-impl fmt::Display for GpuInfo {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let status = if self.is_idle() { "IDLE" } else { "USED" };
+impl GpuInfo {
+    /// The `Display` line, with IDLE/USED and hidden usage judged by `noise`
+    pub fn status_line(&self, noise: &NoiseModel) -> String {
+        let mut line = String::new();
+        let _ = self.write_status(&mut line, noise);
+        line
+    }
+
+    fn write_status(&self, f: &mut impl fmt::Write, noise: &NoiseModel) -> fmt::Result {
+        if let Some(error) = &self.error {
+            return write!(f, "GPU {}: ERROR (unavailable): {}", self.index, error);
+        }
+        let idle = self.is_idle(noise);
+        let status = if idle { "IDLE" } else { "USED" };
         write!(
             f,
-            "GPU {}: {} - {}/{} MB ({:.1}%), {} util, {} processes",
+            "GPU {}: {} - {}/{} MB ({:.1}%), {} util, {} mem util, {} processes",
             self.index,
             status,
             self.memory_used_mb,
             self.memory_total_mb,
             self.memory_usage_percent(),
             self.utilization_percent,
+            self.memory_utilization_percent,
             self.process_count
         )?;
-        if self.has_hidden_usage(HIDDEN_USAGE_THRESHOLD_MB) {
+        if let Some(temperature) = self.temperature_c {
+            write!(f, ", {}°C", temperature)?;
+        }
+        if self.vgpu {
+            write!(f, ", vGPU")?;
+        }
+        if let Some(idle_secs) = self.idle_secs.filter(|_| idle) {
+            write!(f, ", idle for {}s", idle_secs)?;
+        }
+        if let Some(throttle) = self.throttle_summary() {
+            write!(f, ", throttled ({})", throttle)?;
+        }
+        if self.has_hidden_usage(noise) {
             write!(f, " (suspected hidden usage: {} MB)", self.hidden_usage_mb)?;
         }
         Ok(())
     }
 }
 
-#[derive(Debug, Clone)]
+impl fmt::Display for GpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_status(f, &NoiseModel::default())
+    }
+}
+
+/// Percentage of memory free across all of the given GPUs combined
+/// (0.0 when no memory is reported at all)
+pub fn system_free_percent(gpus: &[GpuInfo]) -> f64 {
+    let total: u64 = gpus.iter().map(|g| g.memory_total_mb).sum();
+    let free: u64 = gpus.iter().map(|g| g.memory_free_mb()).sum();
+    if total == 0 {
+        0.0
+    } else {
+        (free as f64 / total as f64) * 100.0
+    }
+}
+
+/// Host-wide rollup shown after the `--status` listing. Memory and
+/// utilization cover the GPUs that answered queries.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct StatusSummary {
+    pub gpu_count: usize,
+    pub idle_count: usize,
+    pub memory_free_mb: u64,
+    pub memory_total_mb: u64,
+    /// Mean SM utilization
+    pub average_utilization_percent: f64,
+    /// GPUs held by `with-gpu` claims, exclusive or shared
+    pub claimed_count: usize,
+}
+
+impl StatusSummary {
+    /// Summarize `gpus`, with idleness judged by `noise`. `claimed` lists the
+    /// indices of GPUs with `with-gpu` claims.
+    pub fn new(gpus: &[GpuInfo], noise: &NoiseModel, claimed: &[usize]) -> Self {
+        let answered: Vec<&GpuInfo> = gpus.iter().filter(|g| g.error.is_none()).collect();
+        let average_utilization_percent = if answered.is_empty() {
+            0.0
+        } else {
+            answered
+                .iter()
+                .map(|g| g.utilization_percent as f64)
+                .sum::<f64>()
+                / answered.len() as f64
+        };
+        StatusSummary {
+            gpu_count: gpus.len(),
+            idle_count: gpus.iter().filter(|g| g.is_idle(noise)).count(),
+            memory_free_mb: answered.iter().map(|g| g.memory_free_mb()).sum(),
+            memory_total_mb: answered.iter().map(|g| g.memory_total_mb).sum(),
+            average_utilization_percent,
+            claimed_count: gpus.iter().filter(|g| claimed.contains(&g.index)).count(),
+        }
+    }
+}
+
+impl fmt::Display for StatusSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let free_percent = if self.memory_total_mb == 0 {
+            0.0
+        } else {
+            self.memory_free_mb as f64 / self.memory_total_mb as f64 * 100.0
+        };
+        write!(
+            f,
+            "{} GPU(s), {} idle, {}/{} MB free ({:.1}%), {:.0}% average util, {} claimed by with-gpu",
+            self.gpu_count,
+            self.idle_count,
+            self.memory_free_mb,
+            self.memory_total_mb,
+            free_percent,
+            self.average_utilization_percent,
+            self.claimed_count
+        )
+    }
+}
+
+/// Most runs of indices `format_index_list` shows before summarizing the rest
+const INDEX_LIST_MAX_RUNS: usize = 8;
+
+/// Format GPU indices for humans, collapsing ascending runs (`0-15, 20, 30-31`)
+/// and summarizing past `INDEX_LIST_MAX_RUNS` runs as "(and N more)", so a
+/// 64-GPU host doesn't flood the terminal. Order is kept as given.
+pub fn format_index_list(indices: &[usize]) -> String {
+    if indices.is_empty() {
+        return "none".to_string();
+    }
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for &index in indices {
+        match runs.last_mut() {
+            Some((_, end)) if index == *end + 1 => *end = index,
+            _ => runs.push((index, index)),
+        }
+    }
+    let shown: Vec<String> = runs
+        .iter()
+        .take(INDEX_LIST_MAX_RUNS)
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        })
+        .collect();
+    let hidden: usize = runs
+        .iter()
+        .skip(INDEX_LIST_MAX_RUNS)
+        .map(|(start, end)| end - start + 1)
+        .sum();
+    let mut list = shown.join(", ");
+    if hidden > 0 {
+        list.push_str(&format!(" (and {} more)", hidden));
+    }
+    list
+}
+
+/// A source of GPU state. The real implementation queries NVML/CUDA; tests can
+/// substitute canned snapshots.
+pub trait GpuSource {
+    fn query_gpus(&mut self) -> anyhow::Result<Vec<GpuInfo>>;
+}
+
+impl<S: GpuSource + ?Sized> GpuSource for Box<S> {
+    fn query_gpus(&mut self) -> anyhow::Result<Vec<GpuInfo>> {
+        (**self).query_gpus()
+    }
+}
+
+/// A fixed snapshot, e.g. one captured with `--status --json`, returned on
+/// every query
+pub struct SnapshotSource {
+    pub gpus: Vec<GpuInfo>,
+}
+
+impl GpuSource for SnapshotSource {
+    fn query_gpus(&mut self) -> anyhow::Result<Vec<GpuInfo>> {
+        Ok(self.gpus.clone())
+    }
+}
+
+/// Owns a source for repeated polling (a dashboard or a wait loop), so one
+/// handle is kept across refreshes instead of being set up for every query.
+/// `refresh` takes `&self`, so the monitor can be shared by whatever polls it.
+pub struct GpuMonitor<S> {
+    source: RefCell<S>,
+}
+
+impl<S: GpuSource> GpuMonitor<S> {
+    pub fn new(source: S) -> Self {
+        GpuMonitor {
+            source: RefCell::new(source),
+        }
+    }
+
+    /// Query the GPUs' current state
+    pub fn refresh(&self) -> anyhow::Result<Vec<GpuInfo>> {
+        self.source.borrow_mut().query_gpus()
+    }
+
+    pub fn into_inner(self) -> S {
+        self.source.into_inner()
+    }
+}
+
+/// Wraps a source, hiding GPUs whose index isn't in `allowed` (e.g. GPUs
+/// outside a scheduler's allocation). `None` allows every GPU.
+pub struct RestrictedSource<S> {
+    pub inner: S,
+    pub allowed: Option<Vec<usize>>,
+}
+
+impl<S: GpuSource> GpuSource for RestrictedSource<S> {
+    fn query_gpus(&mut self) -> anyhow::Result<Vec<GpuInfo>> {
+        let mut gpus = self.inner.query_gpus()?;
+        if let Some(allowed) = &self.allowed {
+            gpus.retain(|g| allowed.contains(&g.index));
+        }
+        Ok(gpus)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
 pub struct GpuSelection {
     pub gpu_indices: Vec<usize>,
+    /// UUIDs of the selected GPUs, parallel to `gpu_indices`
+    pub gpu_uuids: Vec<String>,
     pub all_idle: bool,
     pub warning: Option<String>,
 }
 
 impl GpuSelection {
+    /// Put the selected indices in ascending order, so the lowest physical
+    /// index becomes CUDA device 0 regardless of how the GPUs were ranked.
+    /// `gpu_uuids` follows along when it lines up with `gpu_indices`.
+    pub fn sort_indices(&mut self) {
+        if self.gpu_uuids.len() == self.gpu_indices.len() {
+            let mut pairs: Vec<(usize, String)> = self
+                .gpu_indices
+                .drain(..)
+                .zip(self.gpu_uuids.drain(..))
+                .collect();
+            pairs.sort_unstable();
+            (self.gpu_indices, self.gpu_uuids) = pairs.into_iter().unzip();
+        } else {
+            self.gpu_indices.sort_unstable();
+        }
+    }
+
+    /// Move GPU `index` to the front, so it becomes CUDA device 0, keeping
+    /// the others in order. Returns false, changing nothing, if `index`
+    /// isn't selected.
+    pub fn set_primary(&mut self, index: usize) -> bool {
+        let Some(position) = self.gpu_indices.iter().position(|&i| i == index) else {
+            return false;
+        };
+        self.gpu_indices[..=position].rotate_right(1);
+        if position < self.gpu_uuids.len() {
+            self.gpu_uuids[..=position].rotate_right(1);
+        }
+        true
+    }
+
     pub fn to_cuda_visible_devices(&self) -> String {
         self.gpu_indices
             .iter()
@@ -80,70 +542,370 @@ impl GpuSelection {
             .collect::<Vec<_>>()
             .join(",")
     }
+
+    /// Like `to_cuda_visible_devices`, but lists the selected GPUs by UUID, which
+    /// stays correct even if device enumeration order changes.
+    pub fn to_cuda_visible_devices_by_uuid(&self, gpus: &[GpuInfo]) -> String {
+        self.gpu_indices
+            .iter()
+            .filter_map(|i| gpus.iter().find(|g| g.index == *i))
+            .map(|g| g.uuid.as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    fn make_gpu(
+    #[test]
+    fn test_monitor_refreshes_from_one_source() {
+        let snapshot = |memory_used_mb| {
+            vec![GpuInfo {
+                memory_used_mb,
+                ..test_support::make_gpu(0)
+            }]
+        };
+        let monitor = GpuMonitor::new(test_support::MockSource::new(vec![
+            snapshot(100),
+            snapshot(200),
+        ]));
+        assert_eq!(monitor.refresh().unwrap()[0].memory_used_mb, 100);
+        assert_eq!(monitor.refresh().unwrap()[0].memory_used_mb, 200);
+        assert_eq!(monitor.into_inner().queries, 2);
+    }
+
+    const NOISE: NoiseModel = NoiseModel {
+        idle_memory_mb: DEFAULT_IDLE_MEMORY_THRESHOLD_MB,
+        hidden_usage_mb: DEFAULT_HIDDEN_USAGE_THRESHOLD_MB,
+        idle_max_util_percent: DEFAULT_IDLE_MAX_UTIL_PERCENT,
+    };
+
+    fn gpu_with_usage(
         index: usize,
         memory_used_mb: u64,
         process_count: usize,
         hidden_usage_mb: u64,
     ) -> GpuInfo {
         GpuInfo {
-            index,
             memory_used_mb,
-            memory_total_mb: 24000,
-            utilization_percent: 0,
             process_count,
+            attributed_memory_mb: memory_used_mb.saturating_sub(hidden_usage_mb),
             hidden_usage_mb,
+            ..test_support::make_gpu(index)
         }
     }
 
+    #[test]
+    fn test_memory_breakdown() {
+        let gpu = gpu_with_usage(0, 1200, 1, 200);
+        assert_eq!(
+            gpu.memory_breakdown(&NOISE),
+            "1200 MB used = 1000 MB attributed to processes + 200 MB unattributed"
+        );
+
+        let gpu = gpu_with_usage(0, 5000, 1, 4000);
+        assert!(gpu
+            .memory_breakdown(&NOISE)
+            .contains("excluded from selection"));
+
+        let gpu = GpuInfo {
+            memory_source: MemorySource::Cuda,
+            ..gpu_with_usage(0, 1200, 1, 200)
+        };
+        assert_eq!(
+            gpu.memory_breakdown(&NOISE),
+            "1200 MB used (CUDA) = 1000 MB attributed to processes (NVML) + 200 MB unattributed"
+        );
+    }
+
     #[test]
     fn test_has_hidden_usage_ignores_small_noise() {
-        let gpu = make_gpu(0, 600, 1, 100);
-        assert!(!gpu.has_hidden_usage(512));
+        let gpu = gpu_with_usage(0, 600, 1, 100);
+        assert!(!gpu.has_hidden_usage(&NOISE));
     }
 
     #[test]
     fn test_has_hidden_usage_detects_large_discrepancy() {
-        let gpu = make_gpu(0, 12000, 0, 11500);
-        assert!(gpu.has_hidden_usage(512));
-        assert!(!gpu.is_idle());
+        let gpu = gpu_with_usage(0, 12000, 0, 11500);
+        assert!(gpu.has_hidden_usage(&NOISE));
+        assert!(!gpu.is_idle(&NOISE));
     }
 
     #[test]
     fn test_is_idle_with_no_hidden_usage() {
-        let gpu = make_gpu(0, 300, 0, 0);
-        assert!(gpu.is_idle());
+        let gpu = gpu_with_usage(0, 300, 0, 0);
+        assert!(gpu.is_idle(&NOISE));
     }
 
     #[test]
     fn test_is_idle_false_when_has_processes() {
-        let gpu = make_gpu(0, 300, 1, 0);
-        assert!(!gpu.is_idle());
+        let gpu = gpu_with_usage(0, 300, 1, 0);
+        assert!(!gpu.is_idle(&NOISE));
     }
 
     #[test]
     fn test_is_idle_false_when_memory_above_threshold() {
-        let gpu = make_gpu(0, 600, 0, 0);
-        assert!(!gpu.is_idle());
+        let gpu = gpu_with_usage(0, 600, 0, 0);
+        assert!(!gpu.is_idle(&NOISE));
+    }
+
+    #[test]
+    fn test_is_idle_with_threshold() {
+        let raised = NoiseModel {
+            idle_memory_mb: 1024,
+            ..NOISE
+        };
+        let gpu = gpu_with_usage(0, 700, 0, 0);
+        assert!(!gpu.is_idle(&NOISE));
+        assert!(gpu.is_idle(&raised));
+        assert!(!gpu_with_usage(0, 1100, 0, 0).is_idle(&raised));
+        assert!(gpu.status_line(&raised).contains("IDLE"));
+        assert!(format!("{}", gpu).contains("USED"));
+    }
+
+    #[test]
+    fn test_hidden_usage_threshold() {
+        let raised = NoiseModel {
+            hidden_usage_mb: 1024,
+            ..NOISE
+        };
+        let gpu = gpu_with_usage(0, 800, 0, 800);
+        assert!(gpu.has_hidden_usage(&NOISE));
+        assert!(!gpu.has_hidden_usage(&raised));
+        assert!(gpu.memory_breakdown(&NOISE).contains("exceeds 512 MB"));
+        assert!(!gpu.status_line(&raised).contains("hidden usage"));
+        assert_eq!(NoiseModel::default(), NOISE);
+    }
+
+    #[test]
+    fn test_busy_gpu_without_processes_is_not_idle() {
+        // e.g. kernels from an MPS client, whose process NVML doesn't list
+        let gpu = GpuInfo {
+            utilization_percent: 50,
+            ..gpu_with_usage(0, 300, 0, 0)
+        };
+        assert!(!gpu.is_idle(&NOISE));
+        assert!(!gpu.is_idle_ignoring_hidden_usage(&NOISE));
+
+        let lenient = NoiseModel {
+            idle_max_util_percent: 60,
+            ..NOISE
+        };
+        assert!(gpu.is_idle(&lenient));
+        assert!(gpu_with_usage(0, 300, 0, 0).is_idle(&NOISE));
+    }
+
+    #[test]
+    fn test_is_idle_false_when_total_memory_is_zero() {
+        let gpu = GpuInfo {
+            memory_total_mb: 0,
+            ..gpu_with_usage(0, 0, 0, 0)
+        };
+        assert!(!gpu.is_idle(&NOISE));
+        assert!(!gpu.is_idle_ignoring_hidden_usage(&NOISE));
+    }
+
+    #[test]
+    fn test_unavailable_gpu() {
+        let gpu = GpuInfo::unavailable(2, "GPU is lost".to_string());
+        assert!(!gpu.is_idle(&NOISE));
+        assert_eq!(
+            format!("{}", gpu),
+            "GPU 2: ERROR (unavailable): GPU is lost"
+        );
+    }
+
+    #[test]
+    fn test_gpu_info_json_round_trip() {
+        let gpus = vec![
+            gpu_with_usage(0, 1200, 1, 200),
+            GpuInfo::unavailable(1, "lost".into()),
+        ];
+        let json = serde_json::to_string(&gpus).unwrap();
+        let parsed: Vec<GpuInfo> = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+
+        // Hand-written snapshots can leave fields out
+        let parsed: Vec<GpuInfo> =
+            serde_json::from_str(r#"[{"index": 3, "memory_total_mb": 24000}]"#).unwrap();
+        assert_eq!(parsed[0].index, 3);
+        assert!(parsed[0].is_idle(&NOISE));
     }
 
     #[test]
     fn test_display_shows_hidden_usage() {
-        let gpu = make_gpu(0, 12000, 0, 11500);
+        let gpu = gpu_with_usage(0, 12000, 0, 11500);
         let display = format!("{}", gpu);
         assert!(display.contains("suspected hidden usage: 11500 MB"));
     }
 
+    #[test]
+    fn test_vgpu_has_no_hidden_usage() {
+        let gpu = GpuInfo {
+            vgpu: true,
+            ..gpu_with_usage(0, 12000, 0, 11500)
+        };
+        assert!(!gpu.has_hidden_usage(&NOISE));
+        let display = format!("{}", gpu);
+        assert!(display.contains(", vGPU"), "{}", display);
+        assert!(!display.contains("hidden usage"), "{}", display);
+    }
+
     #[test]
     fn test_display_hides_small_hidden_usage() {
-        let gpu = make_gpu(0, 600, 1, 100);
+        let gpu = gpu_with_usage(0, 600, 1, 100);
         let display = format!("{}", gpu);
         assert!(!display.contains("hidden usage"));
     }
+
+    #[test]
+    fn test_throttle_summary() {
+        let mut gpu = gpu_with_usage(0, 0, 0, 0);
+        assert_eq!(gpu.throttle_summary(), None);
+
+        // GPU idle and software power cap aren't slowdowns
+        gpu.throttle_reasons = 0x01 | 0x04;
+        assert_eq!(gpu.throttle_summary(), None);
+
+        gpu.throttle_reasons = 0x04 | 0x40 | 0x80;
+        assert_eq!(
+            gpu.throttle_summary().as_deref(),
+            Some("hw thermal slowdown, hw power brake")
+        );
+        assert!(format!("{}", gpu).contains(", throttled (hw thermal slowdown, hw power brake)"));
+    }
+
+    #[test]
+    fn test_gpu_process_display() {
+        let mut process = GpuProcess {
+            pid: 4242,
+            used_memory_mb: Some(8000),
+            name: Some("python".to_string()),
+            user: Some("alice".to_string()),
+            graphics: false,
+        };
+        assert_eq!(process.to_string(), "pid 4242 (python, alice): 8000 MB");
+
+        let xorg = GpuProcess {
+            pid: 1200,
+            used_memory_mb: Some(40),
+            name: Some("Xorg".to_string()),
+            user: Some("root".to_string()),
+            graphics: true,
+        };
+        assert_eq!(xorg.to_string(), "pid 1200 (Xorg, root, graphics): 40 MB");
+
+        process.name = None;
+        process.used_memory_mb = None;
+        assert_eq!(process.to_string(), "pid 4242 (alice): memory unknown");
+    }
+
+    #[test]
+    fn test_format_index_list() {
+        assert_eq!(format_index_list(&[]), "none");
+        assert_eq!(format_index_list(&[3]), "3");
+        let contiguous: Vec<usize> = (0..64).collect();
+        assert_eq!(format_index_list(&contiguous), "0-63");
+        assert_eq!(
+            format_index_list(&[0, 1, 2, 3, 20, 30, 31]),
+            "0-3, 20, 30-31"
+        );
+        // Manual selections keep their order
+        assert_eq!(format_index_list(&[3, 1, 2]), "3, 1-2");
+
+        let sparse: Vec<usize> = (0..64).step_by(2).collect();
+        assert_eq!(
+            format_index_list(&sparse),
+            "0, 2, 4, 6, 8, 10, 12, 14 (and 24 more)"
+        );
+        let oversized: Vec<usize> = (0..10).flat_map(|i| [i * 10, i * 10 + 1]).collect();
+        assert_eq!(
+            format_index_list(&oversized),
+            "0-1, 10-11, 20-21, 30-31, 40-41, 50-51, 60-61, 70-71 (and 4 more)"
+        );
+    }
+
+    #[test]
+    fn test_system_free_percent() {
+        let gpus = vec![
+            gpu_with_usage(0, 18000, 1, 0),
+            gpu_with_usage(1, 6000, 1, 0),
+        ];
+        assert_eq!(system_free_percent(&gpus), 50.0);
+        assert_eq!(system_free_percent(&[]), 0.0);
+    }
+
+    #[test]
+    fn test_status_summary() {
+        let mut busy = gpu_with_usage(1, 6000, 1, 0);
+        busy.utilization_percent = 80;
+        let gpus = vec![
+            gpu_with_usage(0, 0, 0, 0),
+            busy,
+            GpuInfo::unavailable(2, "fell off the bus".to_string()),
+        ];
+        let summary = StatusSummary::new(&gpus, &NoiseModel::default(), &[1, 7]);
+        assert_eq!(
+            summary.to_string(),
+            "3 GPU(s), 1 idle, 42000/48000 MB free (87.5%), 40% average util, 1 claimed by with-gpu"
+        );
+    }
+
+    #[test]
+    fn test_set_primary_reorders_cuda_visible_devices() {
+        let mut selection = GpuSelection {
+            gpu_indices: vec![0, 2, 3],
+            gpu_uuids: vec!["GPU-0".into(), "GPU-2".into(), "GPU-3".into()],
+            all_idle: true,
+            warning: None,
+        };
+        assert!(selection.set_primary(3));
+        assert_eq!(selection.to_cuda_visible_devices(), "3,0,2");
+        assert_eq!(selection.gpu_uuids, vec!["GPU-3", "GPU-0", "GPU-2"]);
+
+        assert!(!selection.set_primary(1));
+        assert_eq!(selection.to_cuda_visible_devices(), "3,0,2");
+    }
+
+    #[test]
+    fn test_sort_indices() {
+        let mut selection = GpuSelection {
+            gpu_indices: vec![3, 0, 2],
+            gpu_uuids: vec!["GPU-3".into(), "GPU-0".into(), "GPU-2".into()],
+            all_idle: true,
+            warning: None,
+        };
+        selection.sort_indices();
+        assert_eq!(selection.to_cuda_visible_devices(), "0,2,3");
+        assert_eq!(selection.gpu_uuids, vec!["GPU-0", "GPU-2", "GPU-3"]);
+
+        // Without a UUID per index, the indices are still all kept
+        let mut selection = GpuSelection {
+            gpu_indices: vec![3, 0, 2],
+            gpu_uuids: Vec::new(),
+            all_idle: true,
+            warning: None,
+        };
+        selection.sort_indices();
+        assert_eq!(selection.to_cuda_visible_devices(), "0,2,3");
+        assert!(selection.gpu_uuids.is_empty());
+    }
+
+    #[test]
+    fn test_to_cuda_visible_devices_by_uuid() {
+        let gpus = vec![gpu_with_usage(0, 0, 0, 0), gpu_with_usage(1, 0, 0, 0)];
+        let selection = GpuSelection {
+            gpu_indices: vec![1, 0],
+            gpu_uuids: vec!["GPU-0001".into(), "GPU-0000".into()],
+            all_idle: true,
+            warning: None,
+        };
+        assert_eq!(selection.to_cuda_visible_devices(), "1,0");
+        assert_eq!(
+            selection.to_cuda_visible_devices_by_uuid(&gpus),
+            "GPU-0001,GPU-0000"
+        );
+    }
 }
@@ -2,14 +2,41 @@
 //!
 //! Prevents race conditions when multiple `with-gpu` processes start simultaneously
 //! by creating per-GPU lock files that track which process has claimed each GPU.
+//!
+//! A lock file holds the claiming PID, optionally followed by a Unix timestamp
+//! until which the claim holds even if that process has exited (`--warmup-hold`,
+//! 0 for none) and by the memory in MB the claimant reserved (`--min-memory`).
+//!
+//! Under MPS, several jobs can share a GPU (`--share`). Their claims go in a
+//! separate `gpu-N.shared` file listing one claim per line: a PID, optionally
+//! followed by its reservation. A shared GPU can take more sharers but can't
+//! be claimed exclusively, and vice versa.
+//!
+//! Each claiming process also lists the lock files it holds in an
+//! `owned-<pid>.manifest` file. `with-gpu` usually execs the command, so no
+//! process is left to release the claims; the manifest lets external tooling
+//! tie the claims to the command's PID and clean up after it dies.
 
 use std::fs::{self, File, OpenOptions};
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-/// Directory for lock files
-fn lock_dir() -> PathBuf {
-    PathBuf::from("/tmp/with-gpu")
+/// Directory for lock files: `$WITH_GPU_LOCK_DIR`, or else `/tmp/with-gpu`
+pub fn lock_dir() -> PathBuf {
+    #[cfg(any(test, feature = "test-support"))]
+    if let Some(dir) = crate::test_support::lock_dir_override() {
+        return dir;
+    }
+    lock_dir_from(|name| std::env::var(name).ok())
+}
+
+fn lock_dir_from(var: impl Fn(&str) -> Option<String>) -> PathBuf {
+    match var("WITH_GPU_LOCK_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from("/tmp/with-gpu"),
+    }
 }
 
 /// Path to lock file for a specific GPU
@@ -17,13 +44,33 @@ fn lock_path(gpu_index: usize) -> PathBuf {
     lock_dir().join(format!("gpu-{}.lock", gpu_index))
 }
 
+/// Path to the shared-claim file for a specific GPU
+fn shared_path(gpu_index: usize) -> PathBuf {
+    lock_dir().join(format!("gpu-{}.shared", gpu_index))
+}
+
+/// Path to the manifest of lock files held by a process
+fn manifest_path(pid: u32) -> PathBuf {
+    lock_dir().join(format!("owned-{}.manifest", pid))
+}
+
 /// Ensure the lock directory exists
-fn ensure_lock_dir() -> std::io::Result<()> {
+pub(crate) fn ensure_lock_dir() -> std::io::Result<()> {
     fs::create_dir_all(lock_dir())
 }
 
+/// Check that lock files can be created, by creating the lock directory and
+/// a probe file in it. The directory can exist yet be unwritable, e.g. when
+/// another user created it.
+pub fn check_lock_dir_writable() -> std::io::Result<()> {
+    ensure_lock_dir()?;
+    let probe = lock_dir().join(format!(".probe-{}", std::process::id()));
+    File::create(&probe)?;
+    fs::remove_file(&probe)
+}
+
 /// Check if a process with the given PID is still alive
-fn is_pid_alive(pid: u32) -> bool {
+pub fn is_pid_alive(pid: u32) -> bool {
     // On Unix, sending signal 0 checks if process exists without actually signaling
     #[cfg(unix)]
     {
@@ -45,9 +92,23 @@ fn is_pid_alive(pid: u32) -> bool {
     }
 }
 
+/// A live claim on a GPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Claim {
+    pub pid: u32,
+    /// Memory the claimant expects to allocate (its `--min-memory`), if its
+    /// lock file records one. Older lock files don't.
+    pub reserved_mb: Option<u64>,
+}
+
 /// Check if a GPU is currently claimed by another process.
 /// Returns Some(pid) if claimed, None if available.
 pub fn get_gpu_claim(gpu_index: usize) -> Option<u32> {
+    read_gpu_claim(gpu_index).map(|claim| claim.pid)
+}
+
+/// Like `get_gpu_claim`, with the claim's reservation
+fn read_gpu_claim(gpu_index: usize) -> Option<Claim> {
     let path = lock_path(gpu_index);
 
     let mut file = match File::open(&path) {
@@ -60,31 +121,218 @@ pub fn get_gpu_claim(gpu_index: usize) -> Option<u32> {
         return None;
     }
 
-    let pid: u32 = match contents.trim().parse() {
-        Ok(p) => p,
-        Err(_) => {
+    let (pid, hold_until, reserved_mb) = match parse_lock_contents(&contents) {
+        Some(claim) => claim,
+        None => {
             // Invalid lock file, remove it
             let _ = fs::remove_file(&path);
             return None;
         }
     };
 
-    if is_pid_alive(pid) {
-        Some(pid)
-    } else {
-        // Stale lock file (process died), clean it up
-        let _ = fs::remove_file(&path);
-        None
+    // A warmup hold keeps the claim alive past its process, e.g. for a launcher
+    // that exits before its workload has allocated GPU memory
+    let held = hold_until.is_some_and(|until| now_secs() < until);
+    let claim = Claim { pid, reserved_mb };
+    if is_pid_alive(pid) || held {
+        return Some(claim);
     }
+
+    // Stale lock file (process died), clean it up
+    match fs::remove_file(&path) {
+        Ok(()) => None,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(_) => {
+            // e.g. another user's file in the sticky /tmp/with-gpu: it still
+            // blocks our claims, so keep reporting it
+            if file_owner(&path).is_some_and(|uid| Some(uid) != current_uid()) {
+                warn_foreign_stale_lock(&WARNED_FOREIGN_STALE_LOCK, &path);
+            }
+            Some(claim)
+        }
+    }
+}
+
+static WARNED_FOREIGN_STALE_LOCK: AtomicBool = AtomicBool::new(false);
+
+/// Print (once per `warned` flag) that a stale lock file belongs to another
+/// user and can't be removed. Returns whether it printed.
+fn warn_foreign_stale_lock(warned: &AtomicBool, path: &Path) -> bool {
+    if warned.swap(true, Ordering::Relaxed) {
+        return false;
+    }
+    eprintln!(
+        "Warning: {} is a stale claim owned by another user and can't be removed, so that GPU stays claimed; ask its owner to delete it, or set WITH_GPU_LOCK_DIR to a lock directory of your own",
+        path.display()
+    );
+    true
+}
+
+#[cfg(unix)]
+fn file_owner(path: &Path) -> Option<u32> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|m| m.uid())
+}
+
+#[cfg(not(unix))]
+fn file_owner(_path: &Path) -> Option<u32> {
+    None
 }
 
-/// Check if a GPU is available (not claimed by another process)
+#[cfg(unix)]
+fn current_uid() -> Option<u32> {
+    Some(unsafe { libc::geteuid() })
+}
+
+#[cfg(not(unix))]
+fn current_uid() -> Option<u32> {
+    None
+}
+
+/// Parse "PID", "PID HOLD_UNTIL" or "PID HOLD_UNTIL RESERVED_MB" lock file
+/// contents
+fn parse_lock_contents(contents: &str) -> Option<(u32, Option<u64>, Option<u64>)> {
+    let mut fields = contents.split_whitespace();
+    let pid = fields.next()?.parse().ok()?;
+    let hold_until = match fields.next() {
+        Some(field) => Some(field.parse().ok()?),
+        None => None,
+    };
+    let reserved_mb = match fields.next() {
+        Some(field) => Some(field.parse().ok()?),
+        None => None,
+    };
+    Some((pid, hold_until, reserved_mb))
+}
+
+/// Lock file contents for a claim by `pid`, the inverse of
+/// `parse_lock_contents`
+fn format_lock_contents(pid: u32, hold_until: Option<u64>, reserved_mb: Option<u64>) -> String {
+    match (hold_until, reserved_mb) {
+        (None, None) => pid.to_string(),
+        (Some(until), None) => format!("{} {}", pid, until),
+        (until, Some(reserved)) => format!("{} {} {}", pid, until.unwrap_or(0), reserved),
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Check if a GPU is available (not claimed by another process, exclusively
+/// or shared)
 pub fn is_gpu_available(gpu_index: usize) -> bool {
+    get_gpu_claim(gpu_index).is_none() && get_shared_claims(gpu_index).is_empty()
+}
+
+/// Check if a GPU can take another sharer: it may be shared, but nobody
+/// holds it exclusively. Whether it has memory to spare is up to selection,
+/// which discounts the sharers' reservations.
+pub fn is_gpu_available_shared(gpu_index: usize) -> bool {
     get_gpu_claim(gpu_index).is_none()
 }
 
+/// The live PIDs sharing a GPU (empty if it isn't shared)
+pub fn get_shared_claims(gpu_index: usize) -> Vec<u32> {
+    read_shared_claims(gpu_index)
+        .into_iter()
+        .map(|claim| claim.pid)
+        .collect()
+}
+
+/// Like `get_shared_claims`, with each sharer's reservation
+fn read_shared_claims(gpu_index: usize) -> Vec<Claim> {
+    fs::read_to_string(shared_path(gpu_index))
+        .map(|contents| parse_shared_contents(&contents))
+        .unwrap_or_default()
+}
+
+/// Parse a shared-claim file ("PID" or "PID RESERVED_MB" per line), keeping
+/// the claims whose PIDs are still alive. PIDs from different users share
+/// the file, so unlike exclusive claims there's no owner check for PID reuse.
+fn parse_shared_contents(contents: &str) -> Vec<Claim> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let pid = fields.next()?.parse().ok()?;
+            let reserved_mb = fields.next().and_then(|field| field.parse().ok());
+            Some(Claim { pid, reserved_mb })
+        })
+        .filter(|claim| is_pid_alive(claim.pid))
+        .collect()
+}
+
+/// Rewrite a GPU's shared-claim file with `update` applied to its live
+/// claims. The file stays locked throughout, so concurrent sharers don't
+/// drop each other's entries.
+fn update_shared_claims(
+    gpu_index: usize,
+    update: impl FnOnce(&mut Vec<Claim>),
+) -> std::io::Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(shared_path(gpu_index))?;
+    lock_exclusive(&file)?;
+    // Sharers may be other users; only the file's creator can do this
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = file.set_permissions(fs::Permissions::from_mode(0o666));
+    }
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let mut claims = parse_shared_contents(&contents);
+    update(&mut claims);
+
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    for claim in claims {
+        match claim.reserved_mb {
+            Some(reserved) => writeln!(file, "{} {}", claim.pid, reserved)?,
+            None => writeln!(file, "{}", claim.pid)?,
+        }
+    }
+    Ok(())
+}
+
+/// Take an exclusive advisory lock on `file`, released when it's closed
+#[cfg(unix)]
+fn lock_exclusive(file: &File) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_exclusive(_file: &File) -> std::io::Result<()> {
+    Ok(())
+}
+
 /// Attempt to claim a GPU. Returns Ok(()) if successful, Err if already claimed.
 pub fn claim_gpu(gpu_index: usize) -> Result<(), ClaimError> {
+    claim_gpu_with_hold(gpu_index, Duration::ZERO, None)
+}
+
+/// Like `claim_gpu`, but the claim also holds for `hold` after claiming even
+/// if this process exits, covering the gap before a detached workload
+/// allocates memory. `reserved_mb` records how much memory the workload
+/// expects to allocate, for other selections to discount.
+pub fn claim_gpu_with_hold(
+    gpu_index: usize,
+    hold: Duration,
+    reserved_mb: Option<u64>,
+) -> Result<(), ClaimError> {
     ensure_lock_dir().map_err(|e| ClaimError::IoError(e.to_string()))?;
 
     let path = lock_path(gpu_index);
@@ -93,6 +341,9 @@ pub fn claim_gpu(gpu_index: usize) -> Result<(), ClaimError> {
     if let Some(pid) = get_gpu_claim(gpu_index) {
         return Err(ClaimError::AlreadyClaimed { gpu_index, pid });
     }
+    if let Some(&pid) = get_shared_claims(gpu_index).first() {
+        return Err(ClaimError::AlreadyClaimed { gpu_index, pid });
+    }
 
     // Try to create lock file atomically
     let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
@@ -113,15 +364,154 @@ pub fn claim_gpu(gpu_index: usize) -> Result<(), ClaimError> {
         Err(e) => return Err(ClaimError::IoError(e.to_string())),
     };
 
-    // Write our PID to the lock file
+    // Write our PID (and hold deadline and reservation, if any) to the lock file
+    let hold_until = (!hold.is_zero()).then(|| now_secs() + hold.as_secs());
+    write!(
+        file,
+        "{}",
+        format_lock_contents(std::process::id(), hold_until, reserved_mb)
+    )
+    .map_err(|e| ClaimError::IoError(e.to_string()))?;
+
+    // A sharer may have checked for our claim just before we wrote it
+    if let Some(&sharer) = get_shared_claims(gpu_index).first() {
+        let _ = fs::remove_file(&path);
+        return Err(ClaimError::AlreadyClaimed {
+            gpu_index,
+            pid: sharer,
+        });
+    }
+
+    Ok(())
+}
+
+/// Claim a GPU alongside any other sharers (`--share`, for MPS). Fails only
+/// if another process holds it exclusively. `reserved_mb` is as for
+/// `claim_gpu_with_hold`.
+pub fn claim_gpu_shared(gpu_index: usize, reserved_mb: Option<u64>) -> Result<(), ClaimError> {
+    ensure_lock_dir().map_err(|e| ClaimError::IoError(e.to_string()))?;
+
+    if let Some(pid) = get_gpu_claim(gpu_index) {
+        return Err(ClaimError::AlreadyClaimed { gpu_index, pid });
+    }
     let pid = std::process::id();
-    write!(file, "{}", pid).map_err(|e| ClaimError::IoError(e.to_string()))?;
+    update_shared_claims(gpu_index, |claims| {
+        claims.retain(|claim| claim.pid != pid);
+        claims.push(Claim { pid, reserved_mb });
+    })
+    .map_err(|e| ClaimError::IoError(e.to_string()))?;
+
+    // An exclusive claimer may have checked for sharers just before we joined
+    if let Some(holder) = get_gpu_claim(gpu_index) {
+        release_shared(gpu_index, pid);
+        return Err(ClaimError::AlreadyClaimed {
+            gpu_index,
+            pid: holder,
+        });
+    }
+    Ok(())
+}
+
+/// Claim all of the given GPUs or none: if any claim fails, the GPUs claimed
+/// by this call are released before the error is returned.
+pub fn claim_gpus(
+    gpu_indices: &[usize],
+    hold: Duration,
+    reserved_mb: Option<u64>,
+) -> Result<(), ClaimError> {
+    claim_all(
+        gpu_indices,
+        |gpu_index| claim_gpu_with_hold(gpu_index, hold, reserved_mb),
+        lock_path,
+    )
+}
+
+/// `claim_gpus` for shared claims
+pub fn claim_gpus_shared(
+    gpu_indices: &[usize],
+    reserved_mb: Option<u64>,
+) -> Result<(), ClaimError> {
+    claim_all(
+        gpu_indices,
+        |gpu_index| claim_gpu_shared(gpu_index, reserved_mb),
+        shared_path,
+    )
+}
 
+fn claim_all(
+    gpu_indices: &[usize],
+    claim: impl Fn(usize) -> Result<(), ClaimError>,
+    path: fn(usize) -> PathBuf,
+) -> Result<(), ClaimError> {
+    remove_stale_manifests();
+    for (i, &gpu_index) in gpu_indices.iter().enumerate() {
+        if let Err(e) = claim(gpu_index) {
+            for &claimed in &gpu_indices[..i] {
+                release_gpu(claimed);
+            }
+            return Err(e);
+        }
+    }
+    update_manifest(|paths| {
+        for &gpu_index in gpu_indices {
+            let path = path(gpu_index);
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+    });
     Ok(())
 }
 
-/// Get list of GPUs that are currently claimed (for status display)
-pub fn get_claimed_gpus() -> Vec<(usize, u32)> {
+/// The lock files listed in a process's manifest
+pub fn read_manifest(pid: u32) -> Vec<PathBuf> {
+    fs::read_to_string(manifest_path(pid))
+        .map(|contents| contents.lines().map(PathBuf::from).collect())
+        .unwrap_or_default()
+}
+
+/// Rewrite this process's manifest with `update` applied, removing it once
+/// it lists nothing. The new contents are written to a temporary file and
+/// renamed into place, so tooling never reads a partial manifest. Failures
+/// are ignored: the manifest is informational.
+fn update_manifest(update: impl FnOnce(&mut Vec<PathBuf>)) {
+    let pid = std::process::id();
+    let mut paths = read_manifest(pid);
+    update(&mut paths);
+    let path = manifest_path(pid);
+    if paths.is_empty() {
+        let _ = fs::remove_file(path);
+    } else {
+        let contents: String = paths.iter().map(|p| format!("{}\n", p.display())).collect();
+        let tmp_path = path.with_extension(format!("tmp-{}", pid));
+        if fs::write(&tmp_path, contents).is_ok() {
+            let _ = fs::rename(&tmp_path, path);
+        }
+    }
+}
+
+/// Remove the manifests of processes that have exited. Their lock files are
+/// cleaned up separately, as stale claims, once nothing (such as a warmup
+/// hold) keeps them valid.
+fn remove_stale_manifests() {
+    let Ok(entries) = fs::read_dir(lock_dir()) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let filename = entry.file_name();
+        let pid = filename
+            .to_str()
+            .and_then(|name| name.strip_prefix("owned-"))
+            .and_then(|name| name.strip_suffix(".manifest"))
+            .and_then(|pid| pid.parse().ok());
+        if pid.is_some_and(|pid| !is_pid_alive(pid)) {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// Get list of GPUs that are currently claimed
+pub fn get_claimed_gpus() -> Vec<(usize, Claim)> {
     let mut claimed = Vec::new();
 
     // Dynamically enumerate lock files to support any number of GPUs
@@ -140,8 +530,8 @@ pub fn get_claimed_gpus() -> Vec<(usize, u32)> {
                 .and_then(|s| s.strip_suffix(".lock"))
             {
                 if let Ok(gpu_index) = idx_str.parse::<usize>() {
-                    if let Some(pid) = get_gpu_claim(gpu_index) {
-                        claimed.push((gpu_index, pid));
+                    if let Some(claim) = read_gpu_claim(gpu_index) {
+                        claimed.push((gpu_index, claim));
                     }
                 }
             }
@@ -152,6 +542,63 @@ pub fn get_claimed_gpus() -> Vec<(usize, u32)> {
     claimed
 }
 
+/// GPUs with shared claims, and the claims of the processes sharing each
+pub fn get_shared_gpus() -> Vec<(usize, Vec<Claim>)> {
+    let Ok(entries) = fs::read_dir(lock_dir()) else {
+        return Vec::new();
+    };
+    let mut shared: Vec<(usize, Vec<Claim>)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let filename = entry.file_name();
+            let gpu_index = filename
+                .to_str()?
+                .strip_prefix("gpu-")?
+                .strip_suffix(".shared")?
+                .parse()
+                .ok()?;
+            let claims = read_shared_claims(gpu_index);
+            (!claims.is_empty()).then_some((gpu_index, claims))
+        })
+        .collect();
+    shared.sort_by_key(|(idx, _)| *idx);
+    shared
+}
+
+/// Release a GPU if this process holds its claim, exclusive or shared
+pub fn release_gpu(gpu_index: usize) {
+    let pid = std::process::id();
+    if get_gpu_claim(gpu_index) == Some(pid) {
+        let _ = fs::remove_file(lock_path(gpu_index));
+    }
+    if get_shared_claims(gpu_index).contains(&pid) {
+        release_shared(gpu_index, pid);
+    }
+    let released = [lock_path(gpu_index), shared_path(gpu_index)];
+    update_manifest(|paths| paths.retain(|p| !released.contains(p)));
+}
+
+fn release_shared(gpu_index: usize, pid: u32) {
+    let _ = update_shared_claims(gpu_index, |claims| claims.retain(|c| c.pid != pid));
+}
+
+/// Release every GPU claimed by this process. Used when the command fails to
+/// start, so the claims don't linger until our PID is reaped or reused.
+pub fn release_all_owned() {
+    let pid = std::process::id();
+    for (gpu_index, claim) in get_claimed_gpus() {
+        if claim.pid == pid {
+            let _ = fs::remove_file(lock_path(gpu_index));
+        }
+    }
+    for (gpu_index, claims) in get_shared_gpus() {
+        if claims.iter().any(|claim| claim.pid == pid) {
+            release_shared(gpu_index, pid);
+        }
+    }
+    let _ = fs::remove_file(manifest_path(pid));
+}
+
 #[derive(Debug)]
 pub enum ClaimError {
     AlreadyClaimed { gpu_index: usize, pid: u32 },
@@ -170,3 +617,193 @@ impl std::fmt::Display for ClaimError {
 }
 
 impl std::error::Error for ClaimError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempLockDir;
+
+    #[test]
+    fn test_parse_lock_contents() {
+        assert_eq!(parse_lock_contents("1234"), Some((1234, None, None)));
+        assert_eq!(
+            parse_lock_contents("1234 1700000000\n"),
+            Some((1234, Some(1700000000), None))
+        );
+        assert_eq!(
+            parse_lock_contents("1234 0 4096"),
+            Some((1234, Some(0), Some(4096)))
+        );
+        assert_eq!(parse_lock_contents("garbage"), None);
+        assert_eq!(parse_lock_contents("1234 soon"), None);
+    }
+
+    #[test]
+    fn test_format_lock_contents_round_trips() {
+        for (hold_until, reserved_mb) in [
+            (None, None),
+            (Some(1700000000), None),
+            (None, Some(4096)),
+            (Some(1700000000), Some(4096)),
+        ] {
+            let contents = format_lock_contents(1234, hold_until, reserved_mb);
+            let (pid, parsed_hold, parsed_reserved) = parse_lock_contents(&contents).unwrap();
+            assert_eq!(pid, 1234);
+            assert_eq!(parsed_hold.filter(|&until| until > 0), hold_until);
+            assert_eq!(parsed_reserved, reserved_mb);
+        }
+    }
+
+    #[test]
+    fn test_lock_dir_from_env() {
+        let dir = |value: Option<&str>| lock_dir_from(|_| value.map(String::from));
+        assert_eq!(dir(None), PathBuf::from("/tmp/with-gpu"));
+        assert_eq!(dir(Some("")), PathBuf::from("/tmp/with-gpu"));
+        assert_eq!(
+            dir(Some("/run/user/1000/with-gpu")),
+            PathBuf::from("/run/user/1000/with-gpu")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_owner_is_current_user_for_our_files() {
+        let path = std::env::temp_dir().join(format!("with-gpu-owner-{}", std::process::id()));
+        fs::write(&path, "").unwrap();
+        let owner = file_owner(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(owner, current_uid());
+    }
+
+    #[test]
+    fn test_foreign_stale_lock_warns_once() {
+        let warned = AtomicBool::new(false);
+        let path = Path::new("/tmp/with-gpu/gpu-0.lock");
+        assert!(warn_foreign_stale_lock(&warned, path));
+        assert!(!warn_foreign_stale_lock(&warned, path));
+        assert!(!warn_foreign_stale_lock(
+            &warned,
+            Path::new("/tmp/with-gpu/gpu-1.lock")
+        ));
+    }
+
+    #[test]
+    fn test_warmup_hold_outlives_dead_process() {
+        let _lock_dir = TempLockDir::new();
+        let gpu_index = 9_000_201;
+        ensure_lock_dir().unwrap();
+
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+
+        let path = lock_path(gpu_index);
+        fs::write(&path, format!("{} {}", dead_pid, now_secs() + 3600)).unwrap();
+        assert_eq!(get_gpu_claim(gpu_index), Some(dead_pid));
+
+        // Once the hold has expired, the claim is stale and gets cleaned up
+        fs::write(&path, format!("{} {}", dead_pid, now_secs() - 1)).unwrap();
+        assert_eq!(get_gpu_claim(gpu_index), None);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_claim_gpus_is_all_or_nothing() {
+        let _lock_dir = TempLockDir::new();
+        let (free, taken) = (9_000_211, 9_000_212);
+        claim_gpu(taken).unwrap();
+
+        let result = claim_gpus(&[free, taken], Duration::ZERO, None);
+        let free_claim = get_gpu_claim(free);
+        release_gpu(taken);
+
+        assert!(matches!(
+            result,
+            Err(ClaimError::AlreadyClaimed { gpu_index, .. }) if gpu_index == taken
+        ));
+        assert_eq!(free_claim, None);
+    }
+
+    #[test]
+    fn test_shared_claims_exclude_exclusive_ones() {
+        let _lock_dir = TempLockDir::new();
+        let (shared, exclusive) = (9_000_221, 9_000_222);
+        let pid = std::process::id();
+
+        claim_gpu_shared(shared, None).unwrap();
+        // Sharing again replaces our entry rather than adding a second one
+        claim_gpu_shared(shared, Some(4096)).unwrap();
+        let sharers = get_shared_gpus()
+            .into_iter()
+            .find(|(idx, _)| *idx == shared)
+            .map(|(_, claims)| claims);
+        let shareable = is_gpu_available_shared(shared);
+        let exclusive_result = claim_gpu(shared);
+
+        claim_gpu(exclusive).unwrap();
+        let shared_result = claim_gpu_shared(exclusive, None);
+        release_gpu(exclusive);
+        release_gpu(shared);
+
+        assert_eq!(
+            sharers,
+            Some(vec![Claim {
+                pid,
+                reserved_mb: Some(4096)
+            }])
+        );
+        assert!(shareable);
+        assert!(matches!(
+            exclusive_result,
+            Err(ClaimError::AlreadyClaimed { pid: holder, .. }) if holder == pid
+        ));
+        assert!(shared_result.is_err());
+        assert!(get_shared_claims(exclusive).is_empty());
+        assert!(get_shared_claims(shared).is_empty());
+        assert!(is_gpu_available(shared));
+    }
+
+    #[test]
+    fn test_manifest_lists_claims_until_released() {
+        let _lock_dir = TempLockDir::new();
+        let (first, second) = (9_000_231, 9_000_232);
+        let pid = std::process::id();
+
+        claim_gpus(&[first, second], Duration::ZERO, None).unwrap();
+        let listed = read_manifest(pid);
+        release_gpu(first);
+        let after_one = read_manifest(pid);
+        release_gpu(second);
+
+        assert_eq!(listed, vec![lock_path(first), lock_path(second)]);
+        assert_eq!(after_one, vec![lock_path(second)]);
+        assert!(!manifest_path(pid).exists());
+    }
+
+    #[test]
+    fn test_check_lock_dir_writable_leaves_no_probe() {
+        let _lock_dir = TempLockDir::new();
+        check_lock_dir_writable().unwrap();
+        let probe = lock_dir().join(format!(".probe-{}", std::process::id()));
+        assert!(!probe.exists());
+    }
+
+    #[test]
+    fn test_release_all_owned_removes_our_claims() {
+        let _lock_dir = TempLockDir::new();
+        let gpu_index = 9_000_001;
+        claim_gpu_with_hold(gpu_index, Duration::ZERO, Some(2048)).unwrap();
+        assert_eq!(
+            read_gpu_claim(gpu_index),
+            Some(Claim {
+                pid: std::process::id(),
+                reserved_mb: Some(2048)
+            })
+        );
+
+        release_all_owned();
+        assert!(is_gpu_available(gpu_index));
+        assert!(!lock_path(gpu_index).exists());
+    }
+}
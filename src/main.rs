@@ -1,22 +1,68 @@
+mod allocation;
+mod audit;
+mod config;
 #[cfg(not(target_os = "macos"))]
 mod cuda;
-mod lockfile;
+mod interrupt;
+mod model_size;
+mod notify;
 mod nvidia;
-mod selector;
+mod probe;
+mod supervise;
+mod tui;
 
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
 #[cfg(unix)]
 use std::os::unix::process::CommandExt;
 use std::process::Command;
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
 use std::thread;
 use std::time::{Duration, Instant};
 
-use with_gpu::{GpuInfo, GpuSelection};
+use with_gpu::lease::GpuLease;
+use with_gpu::lockfile::ClaimError;
+use with_gpu::selector::{
+    self, CoolestPolicy, FirstFitPolicy, LargestMemoryPolicy, LeastRecentlyUsedPolicy,
+    MostFreeMemoryPolicy, SelectionPolicy, StickyPolicy, DEFAULT_MIN_MEMORY_MB,
+};
+use with_gpu::{
+    format_index_list, lockfile, usage, GpuInfo, GpuMonitor, GpuProcess, GpuSelection, GpuSource,
+    MemorySource, NoiseModel, RestrictedSource, SnapshotSource, StatusSummary,
+};
+
+/// How often `--wait` re-queries the GPUs
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long `--max-runtime` waits after SIGTERM before SIGKILL
+const MAX_RUNTIME_GRACE: Duration = Duration::from_secs(10);
+
+/// Exit code when `--max-runtime` kills the command (as coreutils `timeout`)
+const MAX_RUNTIME_EXIT_CODE: i32 = 124;
+
+/// Exit code when Ctrl-C cancels `--wait` (128 + SIGINT, the shell convention);
+/// SIGTERM gives 128 + SIGTERM instead
+const WAIT_CANCELLED_EXIT_CODE: i32 = 130;
+
+/// Exit codes when the command can't be started, as shells use them: not
+/// found, or found but not executable
+const COMMAND_NOT_FOUND_EXIT_CODE: i32 = 127;
+const COMMAND_NOT_EXECUTABLE_EXIT_CODE: i32 = 126;
+
+/// Pause before selecting again after losing a claim race
+const CLAIM_RETRY_DELAY: Duration = Duration::from_millis(200);
 
 #[derive(Parser, Debug)]
 #[command(
     name = "with-gpu",
+    group(clap::ArgGroup::new("waiting").multiple(true)),
+    group(clap::ArgGroup::new("memory_target")),
     version,
     about = "Intelligent GPU selection wrapper for CUDA commands",
     long_about = "Automatically selects idle GPUs or allows manual GPU selection via CUDA_VISIBLE_DEVICES.\n\n\
@@ -24,361 +70,4049 @@ use with_gpu::{GpuInfo, GpuSelection};
                   with-gpu just train-tc tiny\n  \
                   with-gpu --gpu 1 python train.py\n  \
                   with-gpu --min-gpus 2 --max-gpus 4 torchrun train.py\n  \
-                  with-gpu --wait --timeout 300 python train.py\n  \
-                  with-gpu --status"
+                  with-gpu --wait --timeout 5m python train.py\n  \
+                  with-gpu --status\n  \
+                  with-gpu tui"
 )]
 struct Cli {
-    #[arg(long, help = "Manual GPU selection (e.g., '1' or '0,1,2')")]
+    #[command(subcommand)]
+    subcommand: Option<Commands>,
+
+    #[arg(
+        long,
+        help = "Manual GPU selection by index or UUID (e.g., '1', '0,1,2', or 'GPU-8a1c...');\n\
+                'auto' selects automatically, as if --gpu weren't given"
+    )]
     gpu: Option<String>,
 
-    #[arg(long, default_value = "1", help = "Minimum number of GPUs required")]
-    min_gpus: usize,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Only use GPUs listed in PATH, one index or UUID per line ('#' starts a comment);\n\
+                --gpu and the selection criteria then choose among them"
+    )]
+    allow_file: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Use the criteria in the config file's [profile.NAME] table as defaults\n\
+                (flags still override them)"
+    )]
+    profile: Option<String>,
+
+    #[arg(
+        long = "gpu-group",
+        value_name = "NAME=GPUS",
+        conflicts_with_all = ["gpu", "min_gpus", "max_gpus", "exact_gpus", "memory_target", "best_effort", "after"],
+        help = "Claim a named group of GPUs (by index or UUID) and export it to the command as\n\
+                CUDA_VISIBLE_DEVICES_<NAME>, e.g. 'prefill=0,1' (repeatable; groups can't overlap)"
+    )]
+    gpu_groups: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Set CUDA_VISIBLE_DEVICES to GPU UUIDs instead of indices (stable across reordering)"
+    )]
+    by_uuid: bool,
+
+    #[arg(long, help = "Minimum number of GPUs required [default: 1]")]
+    min_gpus: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Maximum number of GPUs to use [default: 1, or unlimited with --total-memory]"
+    )]
+    max_gpus: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with_all = ["min_gpus", "max_gpus", "best_effort", "memory_target"],
+        help = "Use exactly N GPUs, failing if fewer qualify (same as --min-gpus N --max-gpus N)"
+    )]
+    exact_gpus: Option<usize>,
+
+    #[arg(
+        long,
+        overrides_with = "no_require_idle",
+        help = "Require all selected GPUs to be idle (no processes running)"
+    )]
+    require_idle: bool,
+
+    #[arg(
+        long,
+        overrides_with = "require_idle",
+        help = "Don't require idle GPUs, overriding WITH_GPU_REQUIRE_IDLE or require_idle = true"
+    )]
+    no_require_idle: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "require_idle",
+        overrides_with = "no_include_used",
+        help = "Fall back to GPUs in use when too few idle GPUs qualify (the default unless\n\
+                include_used = false is configured; see README)"
+    )]
+    include_used: bool,
+
+    #[arg(
+        long,
+        overrides_with = "include_used",
+        help = "Don't fall back to GPUs in use, overriding WITH_GPU_INCLUDE_USED or\n\
+                include_used = true"
+    )]
+    no_include_used: bool,
+
+    #[arg(
+        long,
+        help = "If fewer than --min-gpus GPUs qualify, run on as many as are available\n\
+                (at least one) with a warning instead of failing"
+    )]
+    best_effort: bool,
+
+    #[arg(
+        long,
+        help = "Don't exclude GPUs for suspected hidden usage (memory not attributed to\n\
+                any process, e.g. a driver reservation); --status still shows the note"
+    )]
+    allow_hidden_usage: bool,
+
+    #[arg(
+        long,
+        value_name = "MB",
+        help = "A GPU with no processes counts as idle while it uses less than this much memory\n\
+                (default: 500; raise it where idle GPUs hold context memory, e.g. in persistence mode)"
+    )]
+    idle_memory_threshold: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        help = "A GPU with higher SM utilization than this doesn't count as idle, even with\n\
+                no processes and little memory in use (default: 5)"
+    )]
+    idle_max_util: Option<u8>,
+
+    #[arg(
+        long,
+        value_name = "MB",
+        help = "Memory not attributed to any process counts as hidden usage above this much\n\
+                (default: 512; raise it along with --idle-memory-threshold)"
+    )]
+    hidden_usage_threshold: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Minimum free memory required in MB (default: 2048 MB for PyTorch)\n\
+                Use --min-memory 0 to disable and allow any GPU"
+    )]
+    min_memory: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Maximum total GPU memory in MB (skip larger cards, leaving them for big jobs)\n\
+                Example: --max-memory 32768 keeps a small job off 80 GB cards"
+    )]
+    max_memory: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "MB",
+        group = "memory_target",
+        help = "Select the fewest GPUs whose combined free memory is at least this many MB\n\
+                (replaces --min-gpus; --max-gpus still caps the count)"
+    )]
+    total_memory: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "SIZE",
+        group = "memory_target",
+        help = "Select enough GPUs to fit a model: a parameter count like '70B' (at 2 bytes\n\
+                per parameter) or a size in bytes; sets --total-memory from it"
+    )]
+    for_model: Option<model_size::ModelSize>,
+
+    #[arg(
+        long,
+        value_name = "FACTOR",
+        default_value_t = model_size::DEFAULT_MODEL_OVERHEAD,
+        requires = "for_model",
+        help = "With --for-model, multiply the model's size by this for activations, KV cache,\n\
+                and other working memory"
+    )]
+    model_overhead: f64,
+
+    #[arg(
+        long,
+        requires = "memory_target",
+        help = "With --total-memory, only combine GPUs that all have peer-to-peer access to\n\
+                each other (NVLink or PCIe P2P), and fail rather than spread across others"
+    )]
+    require_p2p: bool,
+
+    #[arg(
+        long,
+        help = "Refuse to launch unless at least this percentage of memory is free across\n\
+                all GPUs on the host combined (0-100), even if a single GPU qualifies"
+    )]
+    min_system_free_percent: Option<u8>,
+
+    #[arg(
+        long,
+        help = "Maximum GPU (SM) utilization percentage (0-100)\n\
+                Example: --max-util 70 excludes GPUs with >70% utilization"
+    )]
+    max_util: Option<u8>,
+
+    #[arg(
+        long,
+        help = "Maximum memory-controller (bandwidth) utilization percentage (0-100)"
+    )]
+    max_mem_util: Option<u8>,
+
+    #[arg(
+        long,
+        help = "Skip GPUs whose clocks are slowed down by thermal or power limits\n\
+                (GPUs that can't report throttling count as unthrottled)"
+    )]
+    avoid_throttled: bool,
+
+    #[arg(
+        long,
+        help = "Don't count graphics processes (e.g. a display server) as using a GPU\n\
+                (for headless servers where one is expected and harmless)"
+    )]
+    ignore_graphics: bool,
+
+    #[arg(
+        long,
+        help = "Read each GPU's memory twice, a moment apart, and skip GPUs whose readings\n\
+                disagree (e.g. a card mid-reset or still initializing); slightly slower"
+    )]
+    paranoid: bool,
+
+    #[arg(
+        long,
+        help = "Fail unless every GPU CUDA sees can be matched to its NVML device by PCI bus ID,\n\
+                instead of warning and falling back to NVML's memory numbers"
+    )]
+    strict_identity: bool,
+
+    #[arg(
+        long,
+        help = "Don't open a CUDA context on GPUs in an exclusive or prohibited compute mode;\n\
+                report NVML's memory numbers for them instead"
+    )]
+    cuda_skip_exclusive: bool,
+
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration_secs,
+        help = "Only use GPUs idle for at least this long, e.g. '10m' (tracked across with-gpu runs)"
+    )]
+    min_idle_time: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Among otherwise-equal GPUs, prefer the one with-gpu claimed longest ago"
+    )]
+    lru: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "lru",
+        help = "Prefer the coolest GPUs (lowest temperature first, then most free memory)"
+    )]
+    coolest: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["lru", "coolest"],
+        help = "Pack jobs onto the lowest-index idle GPUs that qualify, instead of the GPUs\n\
+                with the most free memory"
+    )]
+    first_fit: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["lru", "coolest", "first_fit"],
+        help = "Prefer the GPUs with the most total memory (then most free), for big models\n\
+                that expect to have the card to themselves"
+    )]
+    prefer_largest: bool,
+
+    #[arg(
+        long,
+        group = "waiting",
+        help = "Wait for GPUs to become available if not immediately available"
+    )]
+    wait: bool,
+
+    #[arg(
+        long,
+        value_name = "PID",
+        group = "waiting",
+        help = "Wait for process PID to exit, then run on the GPU(s) it was using once they meet\n\
+                the criteria (implies --wait)"
+    )]
+    after: Option<u32>,
+
+    #[arg(
+        long,
+        requires = "after",
+        help = "With --after, proceed even if the process has already exited"
+    )]
+    after_ok_if_gone: bool,
+
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration_secs,
+        help = "Timeout when waiting for GPUs, e.g. '300', '5m', '1h30m' (default: no timeout)",
+        requires = "waiting"
+    )]
+    timeout: Option<u64>,
+
+    #[arg(
+        long,
+        requires = "waiting",
+        help = "While waiting, print one JSON object per poll to stderr instead of progress\n\
+                text, then a final 'available' or 'timeout' event (for dashboards)"
+    )]
+    wait_events_json: bool,
+
+    #[arg(
+        long,
+        requires = "waiting",
+        help = "While waiting, prefer GPUs that already qualified on an earlier poll over\n\
+                newly freed ones, to avoid thrashing when availability flaps"
+    )]
+    sticky: bool,
+
+    #[arg(
+        long,
+        value_name = "TARGET",
+        help = "When --wait finds GPUs, notify 'desktop' or POST JSON to a webhook URL",
+        requires = "waiting"
+    )]
+    notify: Option<notify::NotifyTarget>,
+
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration_secs,
+        help = "Kill the command (SIGTERM, then SIGKILL after 10s) if it runs longer than this,\n\
+                e.g. '12h', and exit with code 124"
+    )]
+    max_runtime: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "CMD",
+        help = "Run the shell command CMD after the command succeeds, with WITH_GPU_EXIT_CODE and\n\
+                WITH_GPU_DEVICES set (the command then runs as a child instead of being exec'd)"
+    )]
+    on_success: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "CMD",
+        help = "Run the shell command CMD after the command fails, with WITH_GPU_EXIT_CODE and\n\
+                WITH_GPU_DEVICES set (the command then runs as a child instead of being exec'd)"
+    )]
+    on_failure: Option<String>,
+
+    #[arg(
+        long,
+        help = "Start the command with an empty environment, apart from CUDA_VISIBLE_DEVICES\n\
+                and the other variables with-gpu sets"
+    )]
+    clear_env: bool,
+
+    #[arg(
+        long,
+        value_name = "VAR",
+        help = "Remove VAR from the command's environment (repeatable); variables with-gpu sets\n\
+                are set afterwards and stay"
+    )]
+    unset_env: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Pass an inherited CUDA_VISIBLE_DEVICES through when no GPUs are selected (e.g.\n\
+                with --no-gpu-ok); by default the command never sees it"
+    )]
+    ignore_visible_devices: bool,
+
+    #[arg(
+        long,
+        value_name = "FRACTION",
+        help = "Memory budget hint for PyTorch jobs sharing a GPU (0.0-1.0, exclusive): sets the\n\
+                allocator's garbage-collection threshold in PYTORCH_CUDA_ALLOC_CONF and exports\n\
+                WITH_GPU_MEMORY_FRACTION. Nothing is capped unless the job applies it (see README)"
+    )]
+    memory_fraction: Option<f64>,
+
+    #[arg(
+        long,
+        help = "Keep selected GPUs in ranked order (best first) in CUDA_VISIBLE_DEVICES, so the\n\
+                best GPU becomes CUDA device 0 (default: ascending index order)"
+    )]
+    selection_order: bool,
+
+    #[arg(
+        long,
+        value_name = "INDEX",
+        help = "List this GPU first in CUDA_VISIBLE_DEVICES, so it becomes CUDA device 0 (where\n\
+                rank 0 usually runs); fails if it isn't among the selected GPUs"
+    )]
+    primary: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration_secs,
+        default_value = "0",
+        help = "Keep the GPU claim for this long even if the command exits first, e.g. '60' or\n\
+                '2m' (for launchers that return before their workload allocates GPU memory)"
+    )]
+    warmup_hold: u64,
+
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "2",
+        help = "If another process claims a selected GPU before we do, select again up to N times"
+    )]
+    claim_retries: u32,
+
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        value_parser = parse_device_format,
+        help = "Also export the selection to the command as WITH_GPU_DEVICES, formatted by TEMPLATE\n\
+                with {devices}, {devices_space}, {uuids}, or {count}, e.g. '--nproc_per_node={count}'"
+    )]
+    device_format: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write the chosen GPUs (and their state) as JSON to PATH before running the command"
+    )]
+    emit_selection: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH|FD",
+        help = "Write the selected GPUs' UUIDs, one per line, to PATH (or to file descriptor FD,\n\
+                if a bare number) before running the command"
+    )]
+    gpu_uuid_output: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "FD",
+        help = "Write the selected GPU indices, then their UUIDs, each comma-separated on one\n\
+                line, to inherited file descriptor FD and close it before running the command"
+    )]
+    report_fd: Option<i32>,
+
+    #[arg(
+        long,
+        help = "Show which GPUs would be selected and why each was included or excluded, then exit"
+    )]
+    explain: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["status", "explain"],
+        help = "Print the number of GPUs that meet the criteria, then exit (ignores the\n\
+                GPU count flags; nothing is claimed or run)"
+    )]
+    count_only: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["status", "explain", "count_only", "gpu_info", "gpu_groups"],
+        help = "Print the selected GPU indices (as CUDA_VISIBLE_DEVICES would get them) and exit,\n\
+                e.g. GPUS=$(with-gpu --select-only); nothing is claimed or run"
+    )]
+    select_only: bool,
+
+    #[arg(long, help = "Show GPU status and exit")]
+    status: bool,
+
+    #[arg(
+        long,
+        requires = "status",
+        help = "With --status, print the GPUs and a summary as JSON (the snapshot format\n\
+                --simulate reads)"
+    )]
+    json: bool,
+
+    #[arg(
+        long,
+        value_name = "INDEX",
+        conflicts_with_all = ["status", "explain", "count_only"],
+        help = "Print one GPU's details as JSON and exit (nothing is selected or claimed)"
+    )]
+    gpu_info: Option<usize>,
+
+    /// Testing aid: run selection against a `--status --json` snapshot
+    /// instead of the real GPUs, print the result, and exit without running
+    /// anything
+    #[arg(long, hide = true, value_name = "FILE")]
+    simulate: Option<std::path::PathBuf>,
+
+    /// Print the JSON Schema of `--status --json` output and of the
+    /// `--emit-selection` record, and exit
+    #[arg(long, hide = true)]
+    json_schema: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "index",
+        help = "Order of the --status listing: index, free (most first), util (least first),\n\
+                or temp (coolest first)"
+    )]
+    sort_by: StatusSort,
+
+    #[arg(
+        long,
+        value_name = "CELLS",
+        default_value_t = DEFAULT_MEMORY_BAR_WIDTH,
+        help = "Width of the memory-usage bar in --status (0 hides it; narrowed to fit\n\
+                the terminal)"
+    )]
+    bar_width: usize,
+
+    #[arg(
+        long,
+        help = "Print to stderr how long NVML initialization, the CUDA memory queries, the\n\
+                NVML device queries, and selection took (as JSON with --json)"
+    )]
+    timing: bool,
+
+    #[arg(
+        long,
+        short = 'q',
+        help = "Don't print the selected GPUs or --timing lines to stderr; warnings still appear"
+    )]
+    quiet: bool,
+
+    #[arg(
+        short,
+        long,
+        help = "With --status, show each GPU's memory breakdown (attributed vs unattributed)"
+    )]
+    verbose: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "auto",
+        help = "Slurm integration: inside a job (auto-detected via SLURM_JOB_ID), only use the\n\
+                job's GPUs (SLURM_JOB_GPUS or CUDA_VISIBLE_DEVICES) and skip lock files"
+    )]
+    slurm: allocation::Detection,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "auto",
+        help = "Kubernetes integration: in a pod (auto-detected via KUBERNETES_SERVICE_HOST),\n\
+                select among visible GPUs and skip lock files"
+    )]
+    k8s: allocation::Detection,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "auto",
+        help = "vGPU (GRID) slices: read memory from NVML only (no CUDA context) and don't\n\
+                exclude GPUs for unattributed memory (auto-detected via NVML)"
+    )]
+    vgpu: allocation::Detection,
+
+    #[arg(
+        long,
+        help = "If no NVIDIA GPUs are found (or NVML fails to initialize), warn and run\n\
+                the command without setting CUDA_VISIBLE_DEVICES instead of failing"
+    )]
+    no_gpu_ok: bool,
+
+    #[arg(
+        long,
+        help = "Don't claim GPUs through lock files or skip GPUs other with-gpu processes\n\
+                have claimed. Concurrent jobs may then pick the same GPU."
+    )]
+    no_lock: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["require_idle", "no_lock", "warmup_hold"],
+        help = "Share the GPU with other --share jobs (for MPS): select by free memory, net of\n\
+                other sharers' --min-memory, among GPUs nobody holds exclusively, and claim it\n\
+                without excluding other sharers"
+    )]
+    share: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "ACTION",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "abort",
+        help = "After claiming, query the GPUs again and check they still have --min-memory\n\
+                free, in case a process outside with-gpu allocated memory meanwhile:\n\
+                release them and fail (abort, the default) or just warn (--verify-after-claim=warn)"
+    )]
+    verify_after_claim: Option<ClaimVerification>,
+
+    #[arg(
+        trailing_var_arg = true,
+        allow_hyphen_values = true,
+        help = "Command to execute with selected GPUs"
+    )]
+    command: Vec<String>,
+}
+
+/// What `--verify-after-claim` does when a claimed GPU no longer has
+/// `--min-memory` free
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ClaimVerification {
+    Abort,
+    Warn,
+}
+
+/// Orderings for the `--status` listing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum StatusSort {
+    Index,
+    Free,
+    Util,
+    Temp,
+}
+
+/// Built-in subcommands. To run a program with one of these names, put `--`
+/// before it (e.g. `with-gpu -- tui`).
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Full-screen, auto-refreshing GPU dashboard (plain --status output when
+    /// stdout isn't a terminal)
+    Tui {
+        #[arg(
+            long,
+            value_name = "DURATION",
+            value_parser = parse_duration_secs,
+            default_value = "2",
+            help = "Refresh interval, e.g. '2' or '1m'"
+        )]
+        interval: u64,
+    },
+    /// Check that with-gpu can reach the NVIDIA driver, CUDA and its lock
+    /// directory, with hints for anything that fails
+    Probe,
+    /// Show the most recent launches recorded in the audit log
+    /// (WITH_GPU_AUDIT_LOG)
+    History {
+        #[arg(
+            short = 'n',
+            long,
+            default_value = "10",
+            help = "Number of entries to show"
+        )]
+        count: usize,
+
+        #[arg(long, help = "Print the entries as JSON")]
+        json: bool,
+    },
+}
+
+fn main() -> Result<()> {
+    match run() {
+        Err(e) => {
+            // Report a failed command's own exit code, as exec does on Unix
+            if let Some(failed) = e.downcast_ref::<ChildFailed>() {
+                std::process::exit(failed.code);
+            }
+            if let Some(cancelled) = e.downcast_ref::<WaitCancelled>() {
+                eprintln!("{}", cancelled);
+                std::process::exit(cancelled.exit_code());
+            }
+            if let Some(not_started) = e.downcast_ref::<CommandNotStarted>() {
+                eprintln!("{}", not_started);
+                std::process::exit(not_started.exit_code());
+            }
+            Err(e)
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+fn run() -> Result<()> {
+    let mut cli = Cli::parse();
+    validate_cli(&cli)?;
+    if let Some(fd) = cli.report_fd {
+        check_report_fd(fd)?;
+    }
+    if let Some(size) = cli.for_model {
+        cli.total_memory = Some(size.required_mb(cli.model_overhead));
+    }
+
+    if let Some(Commands::Probe) = cli.subcommand {
+        return probe::run();
+    }
+
+    if cli.json_schema {
+        println!("{}", serde_json::to_string_pretty(&json_schemas())?);
+        return Ok(());
+    }
+
+    if let Some(Commands::History { count, json }) = cli.subcommand {
+        let Some(path) = audit::log_path(|name| std::env::var(name).ok()) else {
+            anyhow::bail!("WITH_GPU_AUDIT_LOG is not set, so there is no audit log to read");
+        };
+        return audit::print_history(&path, count, json);
+    }
+
+    // Flags override WITH_GPU_* variables, which override the config file
+    let env_var = |name: &str| std::env::var(name).ok();
+    let config_path = config::config_path(env_var);
+    // The read-only views only take noise thresholds from the config file, so
+    // a broken one shouldn't lock the user out of them
+    let read_only = cli.status
+        || cli.gpu_info.is_some()
+        || matches!(cli.subcommand, Some(Commands::Tui { .. }));
+    let config = load_config_for(config_path.as_deref(), read_only)?;
+    let profile_criteria = match cli.profile {
+        Some(ref name) => config.profile(name).with_context(|| match config_path {
+            Some(ref path) => format!("Failed to load --profile from {}", path.display()),
+            None => "Failed to load --profile (no config file; set WITH_GPU_CONFIG)".to_string(),
+        })?,
+        None => Default::default(),
+    };
+    let layered = config::PartialCriteria {
+        min_memory_mb: cli.min_memory,
+        max_utilization: cli.max_util,
+        min_gpus: cli.exact_gpus.or(cli.min_gpus),
+        max_gpus: cli.exact_gpus.or(cli.max_gpus),
+        require_idle: flag_pair(cli.require_idle, cli.no_require_idle),
+        include_used: flag_pair(cli.include_used, cli.no_include_used),
+        idle_memory_threshold_mb: cli.idle_memory_threshold,
+        idle_max_utilization: cli.idle_max_util,
+        hidden_usage_threshold_mb: cli.hidden_usage_threshold,
+    }
+    .or(profile_criteria)
+    .or(config::from_env(env_var)?)
+    .or(config.criteria);
+    let noise = layered.noise();
+    let min_gpus = layered.min_gpus.unwrap_or(1);
+    let max_gpus = layered.max_gpus.unwrap_or(1);
+    let require_idle = layered.require_idle.unwrap_or(false);
+
+    // None for automatic selection, including an explicit --gpu auto
+    let manual_gpu_ids = match cli.gpu {
+        Some(ref manual_selection) => selector::parse_manual_gpu_selection(manual_selection)?,
+        None => None,
+    };
+
+    let allowlist = match cli.allow_file {
+        Some(ref path) => Some(config::load_allowlist(path)?),
+        None => None,
+    };
+
+    let gpu_group_ids = cli
+        .gpu_groups
+        .iter()
+        .map(|group| selector::parse_gpu_group(group))
+        .collect::<Result<Vec<_>>>()?;
+
+    check_layered_gpu_counts(&cli, min_gpus, max_gpus, manual_gpu_ids.is_some())?;
+    if let Some(util) = layered.max_utilization {
+        if util > 100 {
+            anyhow::bail!("max-util must be between 0 and 100, got {}", util);
+        }
+    }
+    if let Some(util) = layered.idle_max_utilization {
+        if util > 100 {
+            anyhow::bail!("idle-max-util must be between 0 and 100, got {}", util);
+        }
+    }
+
+    if let Some(pid) = cli.after {
+        if !lockfile::is_pid_alive(pid) && !cli.after_ok_if_gone {
+            anyhow::bail!(
+                "--after: process {} is not running (use --after-ok-if-gone to continue anyway)",
+                pid
+            );
+        }
+    }
+
+    let max_runtime = cli.max_runtime.map(Duration::from_secs);
+    let child_env = ChildEnv {
+        clear: cli.clear_env,
+        unset: &cli.unset_env,
+        keep_visible_devices: cli.ignore_visible_devices,
+    };
+    let hooks = Hooks {
+        on_success: cli.on_success.as_deref(),
+        on_failure: cli.on_failure.as_deref(),
+    };
+
+    // A --simulate snapshot stands in for this host, so the host's scheduler,
+    // container, and CUDA_VISIBLE_DEVICES settings don't apply to it
+    let snapshot = match cli.simulate {
+        Some(ref path) => Some(load_snapshot(path)?),
+        None => None,
+    };
+    let simulating = snapshot.is_some();
+
+    let external_allocation = if simulating {
+        None
+    } else {
+        match allocation::detect_slurm(cli.slurm, env_var)? {
+            Some(alloc) => Some(alloc),
+            None => allocation::detect_kubernetes(cli.k8s, env_var),
+        }
+    };
+    if let Some(ref alloc) = external_allocation {
+        eprintln!("{}", alloc.describe());
+    }
+    // Lock files are pointless when a scheduler already owns allocation
+    let under_scheduler = external_allocation.is_some();
+
+    // Stays zero for a --simulate snapshot, which isn't queried through NVML
+    let query_timing: Rc<Cell<nvidia::QueryTiming>> = Default::default();
+    let inner: Box<dyn GpuSource> = match snapshot {
+        Some(gpus) => Box::new(SnapshotSource { gpus }),
+        None => {
+            let mut nvml = nvidia::NvmlSource::default();
+            nvml.timing = Rc::clone(&query_timing);
+            nvml.ignore_graphics = cli.ignore_graphics;
+            nvml.vgpu = cli.vgpu;
+            nvml.query_p2p = cli.require_p2p;
+            nvml.paranoid = cli.paranoid;
+            nvml.strict_identity = cli.strict_identity;
+            nvml.cuda_skip_exclusive = cli.cuda_skip_exclusive;
+            // Idle history is shared across runs, so it's always recorded with
+            // the default thresholds; `noise` applies to selection only
+            Box::new(usage::IdleTrackingSource {
+                inner: nvml,
+                noise: NoiseModel::default(),
+            })
+        }
+    };
+    let mut source = RestrictedSource {
+        inner,
+        allowed: external_allocation.and_then(|alloc| alloc.gpu_indices),
+    };
+
+    let visible_devices = if simulating {
+        None
+    } else {
+        allocation::nvidia_visible_devices(env_var)
+    };
+    let no_visible_gpus = visible_devices == Some(allocation::VisibleDevices::NoGpus);
+
+    let query_start = Instant::now();
+    let (gpus, device_count) = match query_visible_gpus(&mut source, visible_devices.as_ref()) {
+        Ok(result) => result,
+        Err(e) if cli.no_gpu_ok => {
+            eprintln!("Warning: {:#}", e);
+            (Vec::new(), 0)
+        }
+        Err(e) => return Err(e),
+    };
+    let mut timing = Timing::new(query_timing.get(), query_start.elapsed());
+
+    if gpus.is_empty() && !cli.no_gpu_ok && !no_visible_gpus {
+        if let Some(ref allowed) = source.allowed {
+            anyhow::bail!(
+                "None of the allocated GPU(s) {} were found on this host (use --slurm off to ignore the allocation)",
+                format_index_list(allowed)
+            );
+        }
+    }
+
+    let gpus = match allowlist {
+        Some(ref ids) if !gpus.is_empty() => {
+            let gpus = apply_allowlist(&mut source, gpus, ids);
+            if gpus.is_empty() {
+                anyhow::bail!("None of this host's GPUs are listed in --allow-file");
+            }
+            gpus
+        }
+        _ => gpus,
+    };
+
+    // Under a scheduler, CUDA_VISIBLE_DEVICES is the scheduler's to set (and
+    // lock files are skipped); otherwise a truncated inherited value hides GPUs
+    // from the CUDA memory query, which CUDA enumerates through it. CUDA indexes
+    // every device, so the range check ignores NVIDIA_VISIBLE_DEVICES and
+    // --allow-file.
+    if !under_scheduler && !simulating && device_count > 0 {
+        if let Ok(value) = std::env::var("CUDA_VISIBLE_DEVICES") {
+            if let Some(warning) =
+                allocation::inherited_cuda_visible_devices_warning(&value, device_count)
+            {
+                eprintln!("Warning: {}", warning);
+            }
+        }
+    }
+
+    if let Some(Commands::Tui { interval }) = cli.subcommand {
+        if !std::io::stdout().is_terminal() {
+            print_status(
+                &sort_for_status(&gpus, cli.sort_by),
+                cli.verbose,
+                &noise,
+                cli.bar_width,
+            );
+            return Ok(());
+        }
+        return tui::run(
+            &GpuMonitor::new(source),
+            Duration::from_secs(interval.max(1)),
+            noise,
+        );
+    }
+
+    if cli.status {
+        if no_visible_gpus {
+            println!("NVIDIA_VISIBLE_DEVICES gives this container no GPUs");
+            return Ok(());
+        }
+        if cli.timing && !cli.quiet {
+            timing.print(cli.json);
+        }
+        let gpus = sort_for_status(&gpus, cli.sort_by);
+        if cli.json {
+            let report = StatusReport {
+                summary: StatusSummary::new(&gpus, &noise, &claimed_indices()),
+                gpus: &gpus,
+            };
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print_status(&gpus, cli.verbose, &noise, cli.bar_width);
+        }
+        return Ok(());
+    }
+
+    if let Some(index) = cli.gpu_info {
+        validate_manual_selection(&gpus, &[selector::GpuId::Index(index)])?;
+        let gpu = gpus
+            .iter()
+            .find(|g| g.index == index)
+            .expect("validated above");
+        println!("{}", serde_json::to_string_pretty(gpu)?);
+        return Ok(());
+    }
+
+    if cli.count_only && gpus.is_empty() {
+        println!("0");
+        return Ok(());
+    }
+
+    // With no GPUs on macOS (always) or with --no-gpu-ok, skip GPU selection
+    // entirely and just execute the command
+    if no_visible_gpus {
+        eprintln!(
+            "Note: NVIDIA_VISIBLE_DEVICES gives this container no GPUs; running command without GPU selection"
+        );
+        eprintln!();
+        return execute_command(
+            &cli.command,
+            None,
+            &memory_fraction_env(cli.memory_fraction),
+            &child_env,
+            &hooks,
+            max_runtime,
+        );
+    }
+    if gpus.is_empty() && !simulating && (cfg!(target_os = "macos") || cli.no_gpu_ok) {
+        if cfg!(target_os = "macos") {
+            // Only warn if user explicitly requested GPU features beyond defaults
+            let has_non_default_flags = manual_gpu_ids.is_some()
+                || !gpu_group_ids.is_empty()
+                || min_gpus != 1
+                || max_gpus != 1
+                || require_idle
+                || cli.wait;
+
+            if has_non_default_flags {
+                eprintln!(
+                    "Warning: GPU selection flags ignored on macOS (no NVIDIA GPUs available)"
+                );
+                eprintln!();
+            }
+        } else {
+            eprintln!("Warning: No NVIDIA GPUs detected; running command without GPU selection");
+            eprintln!();
+        }
+        return execute_command(
+            &cli.command,
+            None,
+            &memory_fraction_env(cli.memory_fraction),
+            &child_env,
+            &hooks,
+            max_runtime,
+        );
+    }
+
+    // Fleet-level headroom guard, independent of whether a single GPU qualifies
+    if let Some(min_percent) = cli.min_system_free_percent {
+        let free_percent = with_gpu::system_free_percent(&gpus);
+        if free_percent < min_percent as f64 {
+            anyhow::bail!(
+                "Only {:.1}% of GPU memory is free across the host ({} GPUs); --min-system-free-percent requires {}%",
+                free_percent,
+                gpus.len(),
+                min_percent
+            );
+        }
+    }
+
+    let use_locks = if under_scheduler || cli.no_lock || simulating {
+        false
+    } else if let Err(e) = lockfile::check_lock_dir_writable() {
+        // Running without coordination beats refusing to run at all
+        eprintln!(
+            "Warning: Can't write to lock directory {} ({}); continuing without GPU claims, so concurrent with-gpu jobs may pick the same GPU (use --no-lock to silence)",
+            lockfile::lock_dir().display(),
+            e
+        );
+        false
+    } else {
+        true
+    };
+
+    let criteria = selector::SelectionCriteria {
+        // --total-memory replaces the GPU count requirement
+        min_gpus: if cli.total_memory.is_some() {
+            1
+        } else {
+            min_gpus
+        },
+        // --total-memory picks as many GPUs as it takes unless capped
+        max_gpus: layered.max_gpus.unwrap_or(if cli.total_memory.is_some() {
+            usize::MAX
+        } else {
+            1
+        }),
+        require_idle,
+        min_memory_mb: effective_min_memory(layered.min_memory_mb),
+        max_memory_mb: cli.max_memory,
+        max_utilization: layered.max_utilization,
+        max_memory_utilization: cli.max_mem_util,
+        avoid_throttled: cli.avoid_throttled,
+        min_idle_secs: cli.min_idle_time,
+        total_memory_mb: cli.total_memory,
+        require_p2p: cli.require_p2p,
+        best_effort: cli.best_effort,
+        allow_hidden_usage: cli.allow_hidden_usage,
+        include_used: layered.include_used.unwrap_or(true),
+        noise,
+        use_locks,
+        share: cli.share,
+    };
+
+    // Catch requests this host can never satisfy before ranking anything.
+    // GPU groups set the counts from the GPUs they name.
+    let criteria = if gpu_group_ids.is_empty() && !cli.count_only && !gpus.is_empty() {
+        let requested_max = layered.max_gpus.filter(|_| cli.total_memory.is_none());
+        if let Some(warning) = check_gpu_count(gpus.len(), &criteria, requested_max)? {
+            eprintln!("{}", warning);
+        }
+        // Already warned about; don't warn again when fewer than that qualify
+        selector::SelectionCriteria {
+            max_gpus: criteria.max_gpus.min(gpus.len()),
+            ..criteria
+        }
+    } else {
+        criteria
+    };
+
+    let policy: Box<dyn SelectionPolicy> = if cli.lru {
+        Box::new(LeastRecentlyUsedPolicy {
+            last_used: usage::last_used(&gpus),
+        })
+    } else if cli.coolest {
+        Box::new(CoolestPolicy)
+    } else if cli.first_fit {
+        Box::new(FirstFitPolicy)
+    } else if cli.prefer_largest {
+        Box::new(LargestMemoryPolicy)
+    } else {
+        Box::new(MostFreeMemoryPolicy)
+    };
+
+    // Resolve manual GPU selection if provided
+    let manual_gpu_indices = match manual_gpu_ids {
+        Some(ref ids) => Some(validate_manual_selection(&gpus, ids)?),
+        None => None,
+    };
+    let criteria = match manual_gpu_indices {
+        Some(ref indices) if cli.total_memory.is_none() => manual_selection_criteria(
+            criteria,
+            indices.len(),
+            cli.exact_gpus.or(cli.min_gpus),
+            cli.exact_gpus.or(cli.max_gpus),
+        )?,
+        _ => criteria,
+    };
+
+    // GPU groups select every GPU they name, which must all qualify
+    let gpu_groups = resolve_gpu_groups(&gpus, &gpu_group_ids)?;
+    let (manual_gpu_indices, criteria) = if gpu_groups.is_empty() {
+        (manual_gpu_indices, criteria)
+    } else {
+        let indices: Vec<usize> = gpu_groups.values().flatten().copied().collect();
+        let criteria = selector::SelectionCriteria {
+            min_gpus: indices.len(),
+            max_gpus: indices.len(),
+            ..criteria
+        };
+        (Some(indices), criteria)
+    };
+
+    // --after queues behind a process on the GPU(s) it's using, when we can
+    // tell which those are
+    let manual_gpu_indices = match (manual_gpu_indices, cli.after) {
+        (None, Some(pid)) => {
+            let indices = gpus_used_by(&gpus, pid);
+            if indices.is_empty() {
+                eprintln!(
+                    "Process {} isn't using any GPU with-gpu can see; waiting for any GPU",
+                    pid
+                );
+                None
+            } else {
+                Some(indices)
+            }
+        }
+        (manual, _) => manual,
+    };
+
+    if cli.count_only {
+        let candidates: Vec<GpuInfo> = match manual_gpu_indices {
+            Some(ref indices) => gpus
+                .into_iter()
+                .filter(|g| indices.contains(&g.index))
+                .collect(),
+            None => gpus,
+        };
+        println!("{}", selector::count_qualifying(&candidates, &criteria));
+        return Ok(());
+    }
+
+    if cli.explain {
+        print_explanation(
+            &gpus,
+            &criteria,
+            policy.as_ref(),
+            manual_gpu_indices.as_deref(),
+            !cli.selection_order,
+        );
+        return Ok(());
+    }
+
+    let selection_start = Instant::now();
+    let (mut selection, display_gpus) = if cli.wait || cli.after.is_some() {
+        let wait_start = Instant::now();
+        let _interrupts = interrupt::catch();
+        let result = wait_for_gpus(
+            &mut source,
+            &criteria,
+            policy.as_ref(),
+            &WaitOptions {
+                timeout_secs: cli.timeout,
+                manual_gpu_indices: manual_gpu_indices.as_deref(),
+                after_pid: cli.after,
+                events_json: cli.wait_events_json,
+                sticky: cli.sticky,
+                ..Default::default()
+            },
+        )?;
+        if let Some(ref target) = cli.notify {
+            notify::notify_gpus_available(
+                target,
+                &result.0.gpu_indices,
+                wait_start.elapsed(),
+                &cli.command,
+            );
+        }
+        result
+    } else {
+        select_candidates(
+            gpus,
+            manual_gpu_indices.as_deref(),
+            &criteria,
+            policy.as_ref(),
+        )?
+    };
+
+    // Ascending order keeps the logical-to-physical mapping stable across runs
+    let finish_selection = |selection: &mut GpuSelection, gpus: &[GpuInfo]| -> Result<()> {
+        if !cli.selection_order {
+            selection.sort_indices();
+        }
+        if let Some(primary) = cli.primary {
+            if !selection.set_primary(primary) {
+                anyhow::bail!(
+                    "--primary {} isn't among the selected GPUs ({})",
+                    primary,
+                    format_index_list(&selection.gpu_indices)
+                );
+            }
+        }
+        if let Some(size) = cli.for_model {
+            eprintln!(
+                "{}",
+                model_size::fit_summary(size, cli.model_overhead, selection, gpus)
+            );
+        }
+        print_selection(gpus, selection, &criteria, cli.quiet);
+        Ok(())
+    };
+    finish_selection(&mut selection, &display_gpus)?;
+    if cli.timing && !cli.quiet {
+        timing.selection = Some(selection_start.elapsed());
+        timing.print(cli.json);
+    }
+
+    if cli.select_only {
+        if use_locks {
+            eprintln!(
+                "Note: --select-only doesn't claim the GPUs, so another job may take them before you use them"
+            );
+        }
+        if cli.by_uuid {
+            println!(
+                "{}",
+                selection.to_cuda_visible_devices_by_uuid(&display_gpus)
+            );
+        } else {
+            println!("{}", selection.to_cuda_visible_devices());
+        }
+        return Ok(());
+    }
+
+    // Claim the selected GPUs before executing the command. The lease releases
+    // them if we return without exec'ing.
+    let hold = Duration::from_secs(cli.warmup_hold);
+    let lease = claim_with_retries(
+        selection,
+        display_gpus,
+        cli.claim_retries,
+        |selection, gpus| claim_lease(selection, gpus, &criteria, hold),
+        || {
+            let (mut selection, gpus) = select_candidates(
+                source.query_gpus()?,
+                manual_gpu_indices.as_deref(),
+                &criteria,
+                policy.as_ref(),
+            )?;
+            finish_selection(&mut selection, &gpus)?;
+            Ok((selection, gpus))
+        },
+    )?;
+    let (selection, display_gpus) = (lease.selection(), lease.gpus());
+    if cli.verify_after_claim.is_some() && criteria.min_memory_mb.is_none() {
+        eprintln!(
+            "Note: --verify-after-claim has no minimum free memory to check, so it is skipped"
+        );
+    }
+    if let (Some(action), Some(min_memory_mb)) = (cli.verify_after_claim, criteria.min_memory_mb) {
+        let gpus = source.query_gpus()?;
+        let short = gpus_below_min_memory(&gpus, &selection.gpu_indices, min_memory_mb);
+        if !short.is_empty() {
+            let details: Vec<String> = short
+                .iter()
+                .map(|g| {
+                    format!(
+                        "GPU {} has {:.2} GB free",
+                        g.index,
+                        g.memory_free_mb() as f64 / 1024.0
+                    )
+                })
+                .collect();
+            let message = format!(
+                "GPU free memory dropped below the {:.2} GB minimum after selection ({}); another process may have allocated it",
+                min_memory_mb as f64 / 1024.0,
+                details.join(", ")
+            );
+            match action {
+                ClaimVerification::Abort => anyhow::bail!(message),
+                ClaimVerification::Warn => eprintln!("Warning: {}", message),
+            }
+        }
+    }
+    if use_locks {
+        usage::record_use(display_gpus, &selection.gpu_indices);
+    }
+
+    let cuda_visible_devices = if cli.by_uuid {
+        selection.to_cuda_visible_devices_by_uuid(display_gpus)
+    } else {
+        selection.to_cuda_visible_devices()
+    };
+
+    let group_env: Vec<(String, String)> = gpu_groups
+        .iter()
+        .map(|(name, indices)| {
+            let group = GpuSelection {
+                gpu_indices: indices.clone(),
+                gpu_uuids: indices
+                    .iter()
+                    .filter_map(|i| display_gpus.iter().find(|g| g.index == *i))
+                    .map(|g| g.uuid.clone())
+                    .collect(),
+                all_idle: selection.all_idle,
+                warning: None,
+            };
+            let devices = if cli.by_uuid {
+                group.to_cuda_visible_devices_by_uuid(display_gpus)
+            } else {
+                group.to_cuda_visible_devices()
+            };
+            (format!("CUDA_VISIBLE_DEVICES_{}", name), devices)
+        })
+        .collect();
+    for (name, devices) in &group_env {
+        eprintln!("{}={}", name, devices);
+    }
+
+    if let Some(ref path) = cli.emit_selection {
+        write_selection(
+            path,
+            selection,
+            display_gpus,
+            &cuda_visible_devices,
+            &gpu_groups,
+        )?;
+    }
+    if let Some(ref target) = cli.gpu_uuid_output {
+        write_gpu_uuids(target, &selection.gpu_uuids)?;
+    }
+    if let Some(fd) = cli.report_fd {
+        write_report_fd(fd, selection)?;
+    }
+
+    let mut extra_env: Vec<(&str, String)> = group_env
+        .iter()
+        .map(|(name, devices)| (name.as_str(), devices.clone()))
+        .collect();
+    if let Some(ref template) = cli.device_format {
+        let formatted = format_devices(template, selection, display_gpus)?;
+        extra_env.push(("WITH_GPU_DEVICES", formatted));
+    }
+    extra_env.extend(memory_fraction_env(cli.memory_fraction));
+
+    if simulating {
+        println!("CUDA_VISIBLE_DEVICES={}", cuda_visible_devices);
+        return Ok(());
+    }
+
+    if let Some(path) = audit::log_path(env_var) {
+        audit::record_launch(&path, &selection.gpu_indices, &cli.command);
+    }
+
+    let result = execute_command(
+        &cli.command,
+        Some(&cuda_visible_devices),
+        &extra_env,
+        &child_env,
+        &hooks,
+        max_runtime,
+    );
+    // On Unix without --max-runtime or hooks we only get here if exec failed
+    // (e.g. a typo'd command); otherwise the command has finished or been
+    // killed.
+    // Either way, our claims are no longer needed.
+    drop(lease);
+    result
+}
+
+/// Check flag values and combinations that clap can't express, before any
+/// GPUs are queried
+fn validate_cli(cli: &Cli) -> Result<()> {
+    if cli.exact_gpus == Some(0) {
+        anyhow::bail!("exact-gpus must be at least 1");
+    }
+
+    if let (Some(min_gpus), Some(max_gpus)) = (cli.min_gpus, cli.max_gpus) {
+        let memory_target = cli.total_memory.is_some() || cli.for_model.is_some();
+        if !memory_target && min_gpus > max_gpus {
+            anyhow::bail!(
+                "min-gpus ({}) cannot be greater than max-gpus ({})",
+                min_gpus,
+                max_gpus
+            );
+        }
+    }
+
+    for (flag, percent) in [
+        ("max-util", cli.max_util),
+        ("max-mem-util", cli.max_mem_util),
+        ("min-system-free-percent", cli.min_system_free_percent),
+    ] {
+        if let Some(percent) = percent.filter(|&p| p > 100) {
+            anyhow::bail!("{} must be between 0 and 100, got {}", flag, percent);
+        }
+    }
+
+    if !cli.model_overhead.is_finite() || cli.model_overhead <= 0.0 {
+        anyhow::bail!(
+            "model-overhead must be greater than 0, got {}",
+            cli.model_overhead
+        );
+    }
+
+    if cli.max_runtime == Some(0) {
+        anyhow::bail!("max-runtime must be greater than 0");
+    }
+
+    if cli.verify_after_claim.is_some() && cli.min_memory == Some(0) {
+        anyhow::bail!(
+            "--verify-after-claim checks free memory against --min-memory, which --min-memory 0 disables"
+        );
+    }
+
+    if let Some(fraction) = cli.memory_fraction {
+        if !(fraction > 0.0 && fraction < 1.0) {
+            anyhow::bail!(
+                "memory-fraction must be between 0.0 and 1.0 (exclusive), got {}",
+                fraction
+            );
+        }
+    }
+
+    if cli.status {
+        if cli.explain {
+            anyhow::bail!("--status and --explain can't be used together");
+        }
+        if !cli.command.is_empty() {
+            anyhow::bail!(
+                "--status doesn't run a command (got '{}'); drop --status to run it",
+                cli.command.join(" ")
+            );
+        }
+    }
+
+    if cli.select_only && !cli.command.is_empty() {
+        anyhow::bail!(
+            "--select-only doesn't run a command (got '{}'); drop --select-only to run it",
+            cli.command.join(" ")
+        );
+    }
+
+    if cli.gpu_info.is_some() && !cli.command.is_empty() {
+        anyhow::bail!(
+            "--gpu-info doesn't run a command (got '{}'); drop --gpu-info to run it",
+            cli.command.join(" ")
+        );
+    }
+
+    // Everything else selects GPUs for a command
+    let reports_only = cli.subcommand.is_some()
+        || cli.status
+        || cli.gpu_info.is_some()
+        || cli.explain
+        || cli.count_only
+        || cli.select_only
+        || cli.simulate.is_some()
+        || cli.json_schema;
+    if cli.command.is_empty() && !reports_only {
+        anyhow::bail!("No command specified (use --help for usage)");
+    }
+
+    Ok(())
+}
+
+/// `--timing` measurements of one run
+#[derive(Debug)]
+struct Timing {
+    query: nvidia::QueryTiming,
+    /// The whole first query, including idle tracking and filtering
+    total_query: Duration,
+    /// Selection, including any `--wait`
+    selection: Option<Duration>,
+}
+
+/// `Timing` in milliseconds, as `--timing --json` prints it
+#[derive(Serialize)]
+struct TimingReport {
+    nvml_init_ms: f64,
+    cuda_memory_ms: f64,
+    nvml_devices_ms: f64,
+    query_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    selection_ms: Option<f64>,
+}
+
+impl Timing {
+    fn new(query: nvidia::QueryTiming, total_query: Duration) -> Self {
+        Self {
+            query,
+            total_query,
+            selection: None,
+        }
+    }
+
+    fn report(&self) -> TimingReport {
+        // Tenths of a millisecond
+        let ms = |d: Duration| (d.as_secs_f64() * 10_000.0).round() / 10.0;
+        TimingReport {
+            nvml_init_ms: ms(self.query.nvml_init),
+            cuda_memory_ms: ms(self.query.cuda_memory),
+            nvml_devices_ms: ms(self.query.nvml_devices),
+            query_ms: ms(self.total_query),
+            selection_ms: self.selection.map(ms),
+        }
+    }
+
+    fn print(&self, json: bool) {
+        let report = self.report();
+        if json {
+            if let Ok(line) = serde_json::to_string(&report) {
+                eprintln!("{}", line);
+            }
+            return;
+        }
+        eprintln!(
+            "Timing: NVML init {:.1} ms, CUDA memory queries {:.1} ms, NVML device queries {:.1} ms ({:.1} ms total)",
+            report.nvml_init_ms, report.cuda_memory_ms, report.nvml_devices_ms, report.query_ms
+        );
+        if let Some(selection_ms) = report.selection_ms {
+            eprintln!("Timing: selection {:.1} ms", selection_ms);
+        }
+    }
+}
+
+/// How `wait_for_gpus` waits
+struct WaitOptions<'a> {
+    timeout_secs: Option<u64>,
+    manual_gpu_indices: Option<&'a [usize]>,
+    /// Wait for this process to exit first (`--after`)
+    after_pid: Option<u32>,
+    poll_interval: Duration,
+    /// Report progress as `WaitEvent` JSON lines instead of prose
+    events_json: bool,
+    /// Prefer GPUs that qualified on the previous poll (`--sticky`)
+    sticky: bool,
+    /// Set when the user interrupts the wait (Ctrl-C)
+    interrupted: &'a AtomicBool,
+}
+
+impl Default for WaitOptions<'_> {
+    fn default() -> Self {
+        Self {
+            timeout_secs: None,
+            manual_gpu_indices: None,
+            after_pid: None,
+            poll_interval: WAIT_POLL_INTERVAL,
+            events_json: false,
+            sticky: false,
+            interrupted: &interrupt::INTERRUPTED,
+        }
+    }
+}
+
+/// A `--wait-events-json` progress event, printed as one line of JSON
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum WaitEvent<'a> {
+    /// `--after`: the process is still running
+    WaitingForProcess {
+        attempt: u32,
+        elapsed_secs: f64,
+        pid: u32,
+    },
+    /// No suitable GPUs yet
+    Poll {
+        attempt: u32,
+        elapsed_secs: f64,
+        idle_count: usize,
+        idle_indices: Vec<usize>,
+        candidate_count: usize,
+    },
+    Available {
+        attempt: u32,
+        elapsed_secs: f64,
+        gpu_indices: &'a [usize],
+    },
+    Timeout {
+        attempt: u32,
+        elapsed_secs: f64,
+        reason: String,
+    },
+    /// Interrupted with Ctrl-C
+    Cancelled { attempt: u32, elapsed_secs: f64 },
+}
+
+fn emit_wait_event(event: &WaitEvent) {
+    if let Ok(line) = serde_json::to_string(event) {
+        eprintln!("{}", line);
+    }
+}
+
+fn wait_for_gpus(
+    source: &mut dyn GpuSource,
+    criteria: &selector::SelectionCriteria,
+    policy: &dyn SelectionPolicy,
+    options: &WaitOptions,
+) -> Result<(GpuSelection, Vec<GpuInfo>)> {
+    let start_time = Instant::now();
+    let mut attempt = 1;
+    let timeout_secs = options.timeout_secs;
+    let manual_gpu_indices = options.manual_gpu_indices;
+    let timed_out = || timeout_secs.is_some_and(|t| start_time.elapsed().as_secs() >= t);
+    // Tenths of a second are plenty for a progress display
+    let elapsed_secs = || (start_time.elapsed().as_secs_f64() * 10.0).round() / 10.0;
+    let human = !options.events_json;
+    // With --sticky, the GPUs that qualified on the previous poll
+    let mut last_qualifying: Vec<usize> = Vec::new();
+    // Sleep between polls; on Ctrl-C, report it and return an error, so
+    // everything held so far is dropped normally
+    let pause = |attempt: u32| -> Result<()> {
+        if !interrupt::sleep(options.poll_interval, options.interrupted) {
+            return Ok(());
+        }
+        if !human {
+            emit_wait_event(&WaitEvent::Cancelled {
+                attempt,
+                elapsed_secs: elapsed_secs(),
+            });
+        }
+        Err(WaitCancelled {
+            waited_secs: start_time.elapsed().as_secs(),
+            signal: interrupt::signal(),
+        }
+        .into())
+    };
+
+    if human {
+        eprintln!("Waiting for GPUs to become available...");
+        if let Some(timeout) = timeout_secs {
+            eprintln!("  Timeout: {} seconds", timeout);
+        }
+        if let Some(indices) = manual_gpu_indices {
+            eprintln!("  Manual selection: {}", format_index_list(indices));
+        }
+        if let Some(pid) = options.after_pid {
+            eprintln!("  After process: {}", pid);
+        }
+        if let Some(target_mb) = criteria.total_memory_mb {
+            eprintln!(
+                "  Requirements: total_memory={} MB, require_idle={}",
+                target_mb, criteria.require_idle
+            );
+        } else {
+            eprintln!(
+                "  Requirements: min={}, max={}, require_idle={}",
+                criteria.min_gpus, criteria.max_gpus, criteria.require_idle
+            );
+        }
+        eprintln!();
+    }
+
+    loop {
+        // --after: nothing to select until the process has exited
+        if let Some(pid) = options.after_pid.filter(|&pid| lockfile::is_pid_alive(pid)) {
+            if timed_out() {
+                let message = format!(
+                    "Timeout after {} seconds waiting for process {} to exit",
+                    start_time.elapsed().as_secs(),
+                    pid
+                );
+                if !human {
+                    emit_wait_event(&WaitEvent::Timeout {
+                        attempt,
+                        elapsed_secs: elapsed_secs(),
+                        reason: message.clone(),
+                    });
+                }
+                anyhow::bail!(message);
+            }
+            if human {
+                eprintln!(
+                    "[Attempt {}] Waiting for process {} to exit (waited {:.0}s)",
+                    attempt,
+                    pid,
+                    start_time.elapsed().as_secs_f64()
+                );
+            } else {
+                emit_wait_event(&WaitEvent::WaitingForProcess {
+                    attempt,
+                    elapsed_secs: elapsed_secs(),
+                    pid,
+                });
+            }
+            pause(attempt)?;
+            attempt += 1;
+            continue;
+        }
+
+        let all_gpus = source.query_gpus()?;
+
+        // Filter to candidate GPUs (manual selection or all)
+        let candidate_gpus: Vec<GpuInfo> = if let Some(indices) = manual_gpu_indices {
+            all_gpus
+                .iter()
+                .filter(|g| indices.contains(&g.index))
+                .cloned()
+                .collect()
+        } else {
+            all_gpus.clone()
+        };
+
+        // The requested GPUs can disappear while we wait (driver reset, device
+        // removed); waiting would then never succeed
+        if let Some(indices) = manual_gpu_indices {
+            if candidate_gpus.is_empty() {
+                anyhow::bail!(
+                    "Requested GPU(s) {} are no longer present (found {} GPU(s); use --status to see GPU state)",
+                    format_index_list(indices),
+                    all_gpus.len()
+                );
+            }
+        }
+
+        // No amount of waiting helps when there aren't enough GPUs at all
+        check_satisfiable(candidate_gpus.len(), criteria, manual_gpu_indices.is_some())?;
+
+        let sticky = StickyPolicy {
+            inner: policy,
+            preferred: last_qualifying.clone(),
+        };
+        let policy: &dyn SelectionPolicy = if options.sticky { &sticky } else { policy };
+        match selector::select_gpus(&candidate_gpus, criteria, policy) {
+            Ok(selection) => {
+                if human {
+                    eprintln!(
+                        "GPUs available after {} attempts ({:.1}s)",
+                        attempt,
+                        start_time.elapsed().as_secs_f64()
+                    );
+                } else {
+                    emit_wait_event(&WaitEvent::Available {
+                        attempt,
+                        elapsed_secs: elapsed_secs(),
+                        gpu_indices: &selection.gpu_indices,
+                    });
+                }
+                return Ok((selection, candidate_gpus));
+            }
+            Err(e) => {
+                if timed_out() {
+                    let error = selector::SelectionError::Timeout {
+                        waited_secs: start_time.elapsed().as_secs(),
+                        reason: Box::new(e),
+                    };
+                    if !human {
+                        emit_wait_event(&WaitEvent::Timeout {
+                            attempt,
+                            elapsed_secs: elapsed_secs(),
+                            reason: error.to_string(),
+                        });
+                    }
+                    return Err(error.into());
+                }
+
+                let idle_indices: Vec<usize> = candidate_gpus
+                    .iter()
+                    .filter(|g| g.is_idle(&criteria.noise))
+                    .map(|g| g.index)
+                    .collect();
+
+                if human {
+                    eprintln!(
+                        "[Attempt {}] No suitable GPUs available (waited {:.0}s)",
+                        attempt,
+                        start_time.elapsed().as_secs_f64()
+                    );
+                    eprintln!(
+                        "  Idle GPUs: {}/{}",
+                        idle_indices.len(),
+                        candidate_gpus.len()
+                    );
+                    if !idle_indices.is_empty() {
+                        eprintln!("  Idle GPU indices: {}", format_index_list(&idle_indices));
+                    }
+                } else {
+                    emit_wait_event(&WaitEvent::Poll {
+                        attempt,
+                        elapsed_secs: elapsed_secs(),
+                        idle_count: idle_indices.len(),
+                        idle_indices,
+                        candidate_count: candidate_gpus.len(),
+                    });
+                }
+
+                if options.sticky {
+                    last_qualifying = selector::qualifying_indices(&candidate_gpus, criteria);
+                }
+                pause(attempt)?;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Query `source`, honoring the container runtime's `NVIDIA_VISIBLE_DEVICES`:
+/// `none`/`void` yields no GPUs without querying, `all` leaves the GPUs
+/// unrestricted, and a list narrows `source.allowed` (so later polls honor it
+/// too). Also returns how many GPUs the source reported before that narrowing.
+fn query_visible_gpus<S: GpuSource>(
+    source: &mut RestrictedSource<S>,
+    visible: Option<&allocation::VisibleDevices>,
+) -> Result<(Vec<GpuInfo>, usize)> {
+    if visible == Some(&allocation::VisibleDevices::NoGpus) {
+        return Ok((Vec::new(), 0));
+    }
+    let mut gpus = source.query_gpus()?;
+    let device_count = gpus.len();
+    if let Some(allowed) = visible.and_then(|visible| visible.allowed_indices(&gpus)) {
+        gpus.retain(|g| allowed.contains(&g.index));
+        source.allowed = Some(allowed);
+    }
+    Ok((gpus, device_count))
+}
+
+/// Keep the GPUs an `--allow-file` names, narrowing `source.allowed` so later
+/// polls honor the allowlist too. Entries for GPUs this host doesn't have are
+/// ignored, so one file can serve many hosts.
+fn apply_allowlist<S: GpuSource>(
+    source: &mut RestrictedSource<S>,
+    mut gpus: Vec<GpuInfo>,
+    allowlist: &[selector::GpuId],
+) -> Vec<GpuInfo> {
+    gpus.retain(|g| {
+        allowlist.iter().any(|id| match id {
+            selector::GpuId::Index(index) => *index == g.index,
+            selector::GpuId::Uuid(uuid) => *uuid == g.uuid,
+        })
+    });
+    source.allowed = Some(gpus.iter().map(|g| g.index).collect());
+    gpus
+}
+
+/// Restrict `gpus` to the manually selected indices, if any, and select among
+/// them. Returns the selection along with the candidate GPUs.
+fn select_candidates(
+    gpus: Vec<GpuInfo>,
+    manual_gpu_indices: Option<&[usize]>,
+    criteria: &selector::SelectionCriteria,
+    policy: &dyn SelectionPolicy,
+) -> Result<(GpuSelection, Vec<GpuInfo>)> {
+    let candidate_gpus: Vec<GpuInfo> = match manual_gpu_indices {
+        Some(indices) => gpus
+            .into_iter()
+            .filter(|g| indices.contains(&g.index))
+            .collect(),
+        None => gpus,
+    };
+    let selection = selector::select_gpus(&candidate_gpus, criteria, policy)?;
+    Ok((selection, candidate_gpus))
+}
+
+/// Claim the selected GPUs as `criteria` asks: shared or exclusive, with its
+/// memory reservation
+fn claim_lease(
+    selection: GpuSelection,
+    gpus: Vec<GpuInfo>,
+    criteria: &selector::SelectionCriteria,
+    hold: Duration,
+) -> Result<GpuLease, ClaimError> {
+    let (use_locks, reserved_mb) = (criteria.use_locks, criteria.reservation_mb());
+    if criteria.share {
+        GpuLease::claim_shared(selection, gpus, use_locks, reserved_mb)
+    } else {
+        GpuLease::claim(selection, gpus, use_locks, hold, reserved_mb)
+    }
+}
+
+/// Claim the selected GPUs with `claim` (normally `claim_lease`). If another
+/// process claims one of them between selection and claiming, call
+/// `reselect` (after a short pause) and try again, up to `retries` times.
+fn claim_with_retries(
+    mut selection: GpuSelection,
+    mut gpus: Vec<GpuInfo>,
+    retries: u32,
+    mut claim: impl FnMut(GpuSelection, Vec<GpuInfo>) -> Result<GpuLease, ClaimError>,
+    mut reselect: impl FnMut() -> Result<(GpuSelection, Vec<GpuInfo>)>,
+) -> Result<GpuLease> {
+    let mut attempt = 0;
+    loop {
+        match claim(selection, gpus) {
+            Ok(lease) => return Ok(lease),
+            Err(ClaimError::AlreadyClaimed { gpu_index, pid }) if attempt < retries => {
+                attempt += 1;
+                eprintln!(
+                    "GPU {} was claimed by process {} after selection; selecting again (retry {}/{})",
+                    gpu_index, pid, attempt, retries
+                );
+                thread::sleep(CLAIM_RETRY_DELAY);
+                (selection, gpus) = reselect()?;
+            }
+            // If we fail to claim, another process grabbed it between selection and claim
+            Err(e) => anyhow::bail!(
+                "Failed to claim GPUs: {} (try again, another process may have claimed it)",
+                e
+            ),
+        }
+    }
+}
+
+/// GPUs a process is using: those NVML lists it on, plus any it holds a
+/// with-gpu claim on
+fn gpus_used_by(gpus: &[GpuInfo], pid: u32) -> Vec<usize> {
+    let claimed = lockfile::get_claimed_gpus();
+    gpus.iter()
+        .filter(|g| {
+            g.pids.contains(&pid)
+                || claimed
+                    .iter()
+                    .any(|(idx, claim)| *idx == g.index && claim.pid == pid)
+        })
+        .map(|g| g.index)
+        .collect()
+}
+
+/// Check the GPU count requirement against the GPUs on the host: an error if
+/// `--min-gpus` exceeds them (unless `--best-effort`), a warning if an
+/// explicit `--max-gpus` does
+fn check_gpu_count(
+    gpu_count: usize,
+    criteria: &selector::SelectionCriteria,
+    requested_max: Option<usize>,
+) -> Result<Option<String>> {
+    if criteria.min_gpus > gpu_count && !criteria.best_effort {
+        anyhow::bail!(
+            "--min-gpus {} exceeds the {} GPU(s) on this host",
+            criteria.min_gpus,
+            gpu_count
+        );
+    }
+    Ok(requested_max.filter(|&max| max > gpu_count).map(|max| {
+        format!(
+            "Warning: --max-gpus {} exceeds the {} GPU(s) on this host; using at most {}",
+            max, gpu_count, gpu_count
+        )
+    }))
+}
+
+/// Check the GPU counts after layering, since the config file and WITH_GPU_*
+/// variables can also set them. When `--gpu` lists GPUs (`manual`), the
+/// maximum defaults to the number listed, which isn't known yet, so the
+/// check waits for `manual_selection_criteria` unless the maximum was given
+/// explicitly.
+fn check_layered_gpu_counts(
+    cli: &Cli,
+    min_gpus: usize,
+    max_gpus: usize,
+    manual: bool,
+) -> Result<()> {
+    let explicit_max = cli.exact_gpus.or(cli.max_gpus).is_some();
+    if cli.total_memory.is_none() && (!manual || explicit_max) && min_gpus > max_gpus {
+        anyhow::bail!(
+            "min-gpus ({}) cannot be greater than max-gpus ({})",
+            min_gpus,
+            max_gpus
+        );
+    }
+    Ok(())
+}
+
+/// The GPU counts for `--gpu` listing `listed` GPUs. Unless the counts were
+/// given explicitly, the command gets every GPU listed; an explicit
+/// `--max-gpus` (or `--exact-gpus`) below the number listed is an error
+/// rather than a silent choice among them.
+fn manual_selection_criteria(
+    criteria: selector::SelectionCriteria,
+    listed: usize,
+    explicit_min: Option<usize>,
+    explicit_max: Option<usize>,
+) -> Result<selector::SelectionCriteria> {
+    match explicit_max {
+        Some(max) if listed > max => anyhow::bail!(
+            "--gpu lists {} GPUs but at most {} were requested; list fewer GPUs or raise --max-gpus",
+            listed,
+            max
+        ),
+        Some(_) => Ok(criteria),
+        None => Ok(selector::SelectionCriteria {
+            min_gpus: explicit_min.unwrap_or(listed),
+            max_gpus: listed,
+            ..criteria
+        }),
+    }
+}
+
+/// Fail fast if the GPU count requirement exceeds the number of candidates,
+/// since `--wait` would otherwise poll until the timeout (or forever)
+fn check_satisfiable(
+    candidate_count: usize,
+    criteria: &selector::SelectionCriteria,
+    manual: bool,
+) -> Result<()> {
+    let needed = if criteria.best_effort {
+        1
+    } else {
+        criteria.min_gpus
+    };
+    if candidate_count < needed {
+        let scope = if manual {
+            "--gpu selects"
+        } else {
+            "host only has"
+        };
+        anyhow::bail!(
+            "Requested {} GPU(s) but {} {}; waiting would never succeed",
+            needed,
+            scope,
+            candidate_count
+        );
+    }
+    Ok(())
+}
+
+/// `gpus` in `--sort-by` order. Free memory and temperature use the selection
+/// policies' rankings, so ties break the same way selection does.
+fn sort_for_status(gpus: &[GpuInfo], key: StatusSort) -> Vec<GpuInfo> {
+    let refs: Vec<&GpuInfo> = gpus.iter().collect();
+    let criteria = selector::SelectionCriteria::default();
+    let ranked = match key {
+        StatusSort::Index => {
+            let mut sorted = gpus.to_vec();
+            sorted.sort_by_key(|g| g.index);
+            return sorted;
+        }
+        StatusSort::Free => MostFreeMemoryPolicy.rank(&refs, &criteria),
+        StatusSort::Temp => CoolestPolicy.rank(&refs, &criteria),
+        StatusSort::Util => {
+            let mut ranked = MostFreeMemoryPolicy.rank(&refs, &criteria);
+            // Stable sort keeps the most-free-memory order among equal utilization
+            ranked.sort_by_key(|&index| {
+                refs.iter()
+                    .find(|g| g.index == index)
+                    .map(|g| g.utilization_percent)
+            });
+            ranked
+        }
+    };
+    ranked
+        .into_iter()
+        .filter_map(|index| gpus.iter().find(|g| g.index == index).cloned())
+        .collect()
+}
+
+fn print_status(gpus: &[GpuInfo], verbose: bool, noise: &NoiseModel, bar_width: usize) {
+    if gpus.is_empty() {
+        #[cfg(target_os = "macos")]
+        {
+            println!("No NVIDIA GPUs available (running on macOS)");
+            println!("Commands will execute without GPU selection.");
+            return;
+        }
+        #[cfg(not(target_os = "macos"))]
+        {
+            println!("No GPUs detected");
+            return;
+        }
+    }
+
+    let claimed_gpus = lockfile::get_claimed_gpus();
+    let shared_gpus = lockfile::get_shared_gpus();
+    let tty = std::io::stdout().is_terminal();
+    let bar_width = terminal_columns().map_or(bar_width, |columns| {
+        bar_width.min(columns / MEMORY_BAR_MAX_SHARE_OF_TERMINAL)
+    });
+
+    println!("Available GPUs:");
+    for gpu in gpus {
+        let claim_info = claimed_gpus
+            .iter()
+            .find(|(idx, _)| *idx == gpu.index)
+            .map(|(_, claim)| {
+                format!(
+                    " [claimed by pid {}{}]",
+                    claim.pid,
+                    reservation_note(claim.reserved_mb)
+                )
+            })
+            .or_else(|| {
+                shared_gpus
+                    .iter()
+                    .find(|(idx, _)| *idx == gpu.index)
+                    .map(|(_, claims)| {
+                        let pids: Vec<String> = claims.iter().map(|c| c.pid.to_string()).collect();
+                        let reserved: u64 = claims.iter().filter_map(|c| c.reserved_mb).sum();
+                        format!(
+                            " [shared by pid(s) {}{}]",
+                            pids.join(", "),
+                            reservation_note((reserved > 0).then_some(reserved))
+                        )
+                    })
+            })
+            .unwrap_or_default();
+        let bar = if bar_width == 0 {
+            String::new()
+        } else {
+            format!("{} ", memory_bar(gpu, bar_width, tty))
+        };
+        println!("  {}{}{}", bar, gpu.status_line(noise), claim_info);
+        if verbose && gpu.error.is_none() {
+            println!("    {}", gpu.memory_breakdown(noise));
+            for process in &gpu.processes {
+                println!("    {}", process);
+            }
+        }
+    }
+
+    println!();
+    println!(
+        "Summary: {}",
+        StatusSummary::new(gpus, noise, &claimed_indices())
+    );
+
+    if !claimed_gpus.is_empty() {
+        println!();
+        println!(
+            "Note: {} GPU(s) claimed by other with-gpu processes",
+            claimed_gpus.len()
+        );
+    }
+    if !shared_gpus.is_empty() {
+        println!(
+            "Note: {} GPU(s) shared by --share jobs (available to other --share jobs)",
+            shared_gpus.len()
+        );
+    }
+}
+
+/// Default `--bar-width`
+const DEFAULT_MEMORY_BAR_WIDTH: usize = 20;
+
+/// The memory bar takes at most 1/N of the terminal's width, leaving the rest
+/// to the status line
+const MEMORY_BAR_MAX_SHARE_OF_TERMINAL: usize = 4;
+
+/// A `[#######---]` bar of `gpu`'s memory usage, `width` cells between the
+/// brackets. On a terminal it uses block characters, colored green, yellow,
+/// or red as memory fills up. Blank when the memory isn't known (an
+/// unavailable GPU, or a total of 0).
+fn memory_bar(gpu: &GpuInfo, width: usize, tty: bool) -> String {
+    if gpu.error.is_some() || gpu.memory_total_mb == 0 {
+        return format!("[{}]", " ".repeat(width));
+    }
+    let percent = gpu.memory_usage_percent().clamp(0.0, 100.0);
+    let filled = ((percent / 100.0) * width as f64).round() as usize;
+    let (full, empty) = if tty { ("█", "░") } else { ("#", "-") };
+    let used = full.repeat(filled);
+    let used = if tty && filled > 0 {
+        let color = match percent {
+            p if p >= 90.0 => "31",
+            p if p >= 50.0 => "33",
+            _ => "32",
+        };
+        format!("\x1b[{}m{}\x1b[0m", color, used)
+    } else {
+        used
+    };
+    format!("[{}{}]", used, empty.repeat(width - filled))
+}
+
+/// Width of the terminal stdout is attached to, falling back to `$COLUMNS`
+/// (`None` when neither is known, e.g. output piped to a file)
+fn terminal_columns() -> Option<usize> {
+    #[cfg(unix)]
+    {
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+        if ok && size.ws_col > 0 {
+            return Some(size.ws_col as usize);
+        }
+    }
+    std::env::var("COLUMNS").ok()?.parse().ok()
+}
+
+/// Print `--explain` output: one line per GPU with its verdict, then the
+/// overall outcome
+fn print_explanation(
+    gpus: &[GpuInfo],
+    criteria: &selector::SelectionCriteria,
+    policy: &dyn SelectionPolicy,
+    manual_gpu_indices: Option<&[usize]>,
+    sort_indices: bool,
+) {
+    let candidates: Vec<GpuInfo> = gpus
+        .iter()
+        .filter(|g| manual_gpu_indices.is_none_or(|indices| indices.contains(&g.index)))
+        .cloned()
+        .collect();
+    let report = selector::explain_selection(&candidates, criteria, policy);
+
+    for gpu in gpus {
+        let line = match report.verdicts.iter().find(|v| v.index == gpu.index) {
+            None => "excluded: not in --gpu selection".to_string(),
+            Some(v) if v.selected => "selected".to_string(),
+            Some(v) if v.exclusions.is_empty() => {
+                "candidate, not selected (ranked lower)".to_string()
+            }
+            Some(v) => format!(
+                "excluded: {}",
+                v.exclusions
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            ),
+        };
+        println!("GPU {}: {}", gpu.index, line);
+    }
+
+    println!();
+    match report.selection {
+        Ok(mut selection) => {
+            if sort_indices {
+                selection.sort_indices();
+            }
+            println!(
+                "Would select: CUDA_VISIBLE_DEVICES={}",
+                selection.to_cuda_visible_devices()
+            );
+            if let Some(warning) = selection.warning {
+                println!("{}", warning);
+            }
+        }
+        Err(e) => println!("Would fail: {}", e),
+    }
+}
+
+/// Check that every manually selected GPU exists, resolving UUIDs to indices.
+/// A GPU named twice (e.g. by index and by UUID) is only listed once.
+fn validate_manual_selection(gpus: &[GpuInfo], ids: &[selector::GpuId]) -> Result<Vec<usize>> {
+    if gpus.is_empty() {
+        anyhow::bail!("No GPUs detected on this system");
+    }
+
+    let mut indices = Vec::new();
+    for id in ids {
+        let index = match id {
+            selector::GpuId::Index(index) => {
+                if !gpus.iter().any(|g| g.index == *index) {
+                    let available: Vec<usize> = gpus.iter().map(|g| g.index).collect();
+                    anyhow::bail!(
+                        "GPU {} not found (available: {})",
+                        index,
+                        format_index_list(&available)
+                    );
+                }
+                *index
+            }
+            selector::GpuId::Uuid(uuid) => match gpus.iter().find(|g| g.uuid == *uuid) {
+                Some(gpu) => gpu.index,
+                None => {
+                    let available = gpus
+                        .iter()
+                        .map(|g| format!("{} (GPU {})", g.uuid, g.index))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    anyhow::bail!("GPU {} not found (available: {})", uuid, available)
+                }
+            },
+        };
+        if !indices.contains(&index) {
+            indices.push(index);
+        }
+    }
+    Ok(indices)
+}
+
+/// Resolve each `--gpu-group` to GPU indices, keyed by group name. Errors on
+/// a repeated name or a GPU named by more than one group.
+fn resolve_gpu_groups(
+    gpus: &[GpuInfo],
+    groups: &[(String, Vec<selector::GpuId>)],
+) -> Result<BTreeMap<String, Vec<usize>>> {
+    let mut resolved: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (name, ids) in groups {
+        let indices = validate_manual_selection(gpus, ids)?;
+        for index in &indices {
+            if let Some((other, _)) = resolved.iter().find(|(_, group)| group.contains(index)) {
+                anyhow::bail!(
+                    "GPU {} is in both --gpu-group {} and --gpu-group {}",
+                    index,
+                    other,
+                    name
+                );
+            }
+        }
+        if resolved.insert(name.clone(), indices).is_some() {
+            anyhow::bail!("--gpu-group {} is given more than once", name);
+        }
+    }
+    Ok(resolved)
+}
+
+/// The launch-time record written by `--emit-selection`
+#[derive(Serialize, JsonSchema)]
+struct LaunchRecord<'a> {
+    #[serde(flatten)]
+    selection: &'a GpuSelection,
+    cuda_visible_devices: &'a str,
+    /// The `--gpu-group` partition of the selection, if any
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    groups: &'a BTreeMap<String, Vec<usize>>,
+    /// State of each selected GPU at selection time
+    gpus: Vec<&'a GpuInfo>,
+}
+
+/// `--json-schema` output: the schemas of the JSON documents with-gpu writes,
+/// keyed by where they come from
+fn json_schemas() -> serde_json::Value {
+    serde_json::json!({
+        "status": schemars::schema_for!(StatusReport<'static>),
+        "selection": schemars::schema_for!(LaunchRecord<'static>),
+    })
+}
+
+/// `--status --json` output
+#[derive(Serialize, JsonSchema)]
+struct StatusReport<'a> {
+    gpus: &'a [GpuInfo],
+    summary: StatusSummary,
+}
+
+/// A `--simulate` snapshot: `--status --json` output, or (as older versions
+/// wrote it) just the array of GPUs
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Snapshot {
+    Report { gpus: Vec<GpuInfo> },
+    Gpus(Vec<GpuInfo>),
+}
+
+/// Read a `--simulate` snapshot, as written by `--status --json`
+fn load_snapshot(path: &std::path::Path) -> Result<Vec<GpuInfo>> {
+    let contents = std::fs::read_to_string(path)
+        .context(format!("Failed to read GPU snapshot {}", path.display()))?;
+    let snapshot = serde_json::from_str(&contents)
+        .context(format!("Failed to parse GPU snapshot {}", path.display()))?;
+    Ok(match snapshot {
+        Snapshot::Report { gpus } | Snapshot::Gpus(gpus) => gpus,
+    })
+}
+
+/// "; N MB reserved" for a claim's status line, if it records a reservation
+fn reservation_note(reserved_mb: Option<u64>) -> String {
+    reserved_mb
+        .map(|mb| format!("; {} MB reserved", mb))
+        .unwrap_or_default()
+}
+
+/// Indices of the GPUs with `with-gpu` claims, exclusive or shared
+fn claimed_indices() -> Vec<usize> {
+    let exclusive = lockfile::get_claimed_gpus().into_iter().map(|(idx, _)| idx);
+    let shared = lockfile::get_shared_gpus().into_iter().map(|(idx, _)| idx);
+    exclusive.chain(shared).collect()
+}
+
+/// Write the selection as JSON so a supervising process can record where a
+/// job landed
+fn write_selection(
+    path: &std::path::Path,
+    selection: &GpuSelection,
+    gpus: &[GpuInfo],
+    cuda_visible_devices: &str,
+    groups: &BTreeMap<String, Vec<usize>>,
+) -> Result<()> {
+    let report = LaunchRecord {
+        selection,
+        cuda_visible_devices,
+        groups,
+        gpus: selection
+            .gpu_indices
+            .iter()
+            .filter_map(|idx| gpus.iter().find(|g| g.index == *idx))
+            .collect(),
+    };
+    let json = serde_json::to_string_pretty(&report)?;
+    std::fs::write(path, json + "\n").context(format!(
+        "Failed to write --emit-selection file {}",
+        path.display()
+    ))
+}
+
+/// Where `--gpu-uuid-output` writes: a bare number names an inherited file
+/// descriptor, anything else a path
+fn uuid_output_path(target: &str) -> std::path::PathBuf {
+    match target.parse::<u32>() {
+        Ok(fd) => std::path::PathBuf::from(format!("/dev/fd/{}", fd)),
+        Err(_) => std::path::PathBuf::from(target),
+    }
+}
+
+/// Write the selected GPUs' UUIDs, one per line, for launchers that record
+/// which physical cards a job used
+fn write_gpu_uuids(target: &str, uuids: &[String]) -> Result<()> {
+    let path = uuid_output_path(target);
+    let contents: String = uuids.iter().map(|uuid| format!("{}\n", uuid)).collect();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, contents.as_bytes()))
+        .context(format!(
+            "Failed to write --gpu-uuid-output {}",
+            path.display()
+        ))
+}
+
+/// The `--report-fd` report: the indices as in `CUDA_VISIBLE_DEVICES`, then
+/// the UUIDs, each list comma-separated on its own line
+fn selection_report(selection: &GpuSelection) -> String {
+    format!(
+        "{}\n{}\n",
+        selection.to_cuda_visible_devices(),
+        selection.gpu_uuids.join(",")
+    )
+}
+
+/// Fail early, before selecting anything, unless `--report-fd` names a file
+/// descriptor open for writing
+#[cfg(unix)]
+fn check_report_fd(fd: i32) -> Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        anyhow::bail!("--report-fd {}: not an open file descriptor", fd);
+    }
+    if flags & libc::O_ACCMODE == libc::O_RDONLY {
+        anyhow::bail!("--report-fd {}: file descriptor is read-only", fd);
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_report_fd(_fd: i32) -> Result<()> {
+    anyhow::bail!("--report-fd is only supported on Unix")
+}
+
+/// Write the selection to `--report-fd`. The descriptor is then closed
+/// (unless it's stdin, stdout or stderr), so the command doesn't inherit it
+/// and a wrapper reading a pipe sees end-of-file.
+#[cfg(unix)]
+fn write_report_fd(fd: i32, selection: &GpuSelection) -> Result<()> {
+    use std::io::Write;
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: check_report_fd verified that fd is open; nothing else in
+    // with-gpu uses it
+    let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+    let written = file
+        .write_all(selection_report(selection).as_bytes())
+        .with_context(|| format!("Failed to write --report-fd {}", fd));
+    if fd <= 2 {
+        std::mem::forget(file);
+    }
+    written
+}
+
+#[cfg(not(unix))]
+fn write_report_fd(_fd: i32, _selection: &GpuSelection) -> Result<()> {
+    anyhow::bail!("--report-fd is only supported on Unix")
+}
+
+/// The variables `--memory-fraction` sets in the command's environment, with
+/// or without GPU selection
+fn memory_fraction_env(fraction: Option<f64>) -> Vec<(&'static str, String)> {
+    let Some(fraction) = fraction else {
+        return Vec::new();
+    };
+    let existing = std::env::var("PYTORCH_CUDA_ALLOC_CONF").ok();
+    vec![
+        (
+            "PYTORCH_CUDA_ALLOC_CONF",
+            pytorch_alloc_conf(existing.as_deref(), fraction),
+        ),
+        ("WITH_GPU_MEMORY_FRACTION", fraction.to_string()),
+    ]
+}
+
+/// `PYTORCH_CUDA_ALLOC_CONF` for `--memory-fraction`: the user's existing
+/// settings plus a garbage-collection threshold at the fraction, so the
+/// caching allocator returns memory to the shared GPU before it hits the cap.
+/// An explicit `garbage_collection_threshold` in the environment wins.
+fn pytorch_alloc_conf(existing: Option<&str>, fraction: f64) -> String {
+    let existing = existing.map(str::trim).filter(|s| !s.is_empty());
+    match existing {
+        Some(conf) if conf.contains("garbage_collection_threshold") => conf.to_string(),
+        Some(conf) => format!("{},garbage_collection_threshold:{}", conf, fraction),
+        None => format!("garbage_collection_threshold:{}", fraction),
+    }
+}
+
+/// Placeholders accepted by `--device-format`
+const DEVICE_FORMAT_PLACEHOLDERS: [&str; 4] = ["devices", "devices_space", "uuids", "count"];
+
+/// Substitute `{name}` placeholders in `template` using `value`. Errors on an
+/// unknown placeholder or an unclosed brace.
+fn expand_template(template: &str, value: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut output = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .with_context(|| format!("Unclosed '{{' in --device-format '{}'", template))?;
+        let name = &rest[start + 1..start + end];
+        let expansion = value(name).with_context(|| {
+            format!(
+                "Unknown placeholder {{{}}} in --device-format (expected one of: {})",
+                name,
+                DEVICE_FORMAT_PLACEHOLDERS
+                    .map(|p| format!("{{{}}}", p))
+                    .join(", ")
+            )
+        })?;
+        output.push_str(&expansion);
+        rest = &rest[start + end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// clap value parser: reject bad templates before any GPUs are claimed
+fn parse_device_format(template: &str) -> std::result::Result<String, String> {
+    expand_template(template, |name| {
+        DEVICE_FORMAT_PLACEHOLDERS.contains(&name).then(String::new)
+    })
+    .map(|_| template.to_string())
+    .map_err(|e| e.to_string())
+}
+
+/// Expand a `--device-format` template for the selected GPUs
+fn format_devices(template: &str, selection: &GpuSelection, gpus: &[GpuInfo]) -> Result<String> {
+    let indices: Vec<String> = selection
+        .gpu_indices
+        .iter()
+        .map(|i| i.to_string())
+        .collect();
+    expand_template(template, |name| match name {
+        "devices" => Some(indices.join(",")),
+        "devices_space" => Some(indices.join(" ")),
+        "uuids" => Some(selection.to_cuda_visible_devices_by_uuid(gpus)),
+        "count" => Some(selection.gpu_indices.len().to_string()),
+        _ => None,
+    })
+}
+
+/// Parse a duration like `90`, `90s`, `5m`, `1h` or `1h30m` into seconds.
+/// A bare number means seconds.
+fn parse_duration_secs(s: &str) -> std::result::Result<u64, String> {
+    let s = s.trim();
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(secs);
+    }
+    let invalid = || {
+        format!(
+            "invalid duration '{}' (expected e.g. '90', '5m', '1h30m')",
+            s
+        )
+    };
+
+    let mut total: u64 = 0;
+    let mut digits = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        let n: u64 = digits.parse().map_err(|_| invalid())?;
+        total = n
+            .checked_mul(unit)
+            .and_then(|v| total.checked_add(v))
+            .ok_or_else(invalid)?;
+        digits.clear();
+    }
+    if !digits.is_empty() || s.is_empty() {
+        return Err(invalid());
+    }
+    Ok(total)
+}
+
+/// The layer an `--X`/`--no-X` flag pair sets: `None` when neither is given,
+/// so the environment and config file decide
+fn flag_pair(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// Read the config file at `path`. For `read_only` views, a file that can't be
+/// read or parsed is reported and skipped instead of failing.
+fn load_config_for(path: Option<&std::path::Path>, read_only: bool) -> Result<config::Config> {
+    let Some(path) = path else {
+        return Ok(Default::default());
+    };
+    match config::load_config(path) {
+        Ok(config) => Ok(config),
+        Err(e) if read_only => {
+            eprintln!("Warning: ignoring the config file: {:#}", e);
+            Ok(Default::default())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// `--min-memory` with the default applied; `--min-memory 0` disables the
+/// filter (and the low-memory warning) entirely
+fn effective_min_memory(min_memory: Option<u64>) -> Option<u64> {
+    match min_memory {
+        Some(0) => None,
+        Some(mb) => Some(mb),
+        None => Some(DEFAULT_MIN_MEMORY_MB),
+    }
+}
+
+/// Warning for a selected GPU (e.g. one chosen with `--gpu`, which bypasses
+/// the memory filter) with less free memory than `--min-memory` asks for.
+/// Suppressed when the memory filter was disabled with `--min-memory 0`.
+fn low_memory_warning(gpu: &GpuInfo, min_memory_mb: Option<u64>) -> Option<String> {
+    let min_memory_mb = min_memory_mb?;
+    if gpu.memory_free_mb() >= min_memory_mb {
+        return None;
+    }
+    Some(format!(
+        "Warning: GPU {} has only {:.2} GB free (< {:.2} GB minimum)",
+        gpu.index,
+        gpu.memory_free_mb() as f64 / 1024.0,
+        min_memory_mb as f64 / 1024.0
+    ))
+}
+
+/// The GPUs among `indices` with less than `min_memory_mb` free, or missing
+/// from `gpus` altogether (for `--verify-after-claim`)
+fn gpus_below_min_memory(gpus: &[GpuInfo], indices: &[usize], min_memory_mb: u64) -> Vec<GpuInfo> {
+    indices
+        .iter()
+        .map(|&index| {
+            gpus.iter()
+                .find(|g| g.index == index)
+                .cloned()
+                .unwrap_or_else(|| GpuInfo::unavailable(index, "no longer reported".to_string()))
+        })
+        .filter(|g| g.memory_free_mb() < min_memory_mb)
+        .collect()
+}
+
+/// Report the selected GPUs on stderr; with `quiet` (`--quiet`), only the
+/// warnings
+fn print_selection(
+    gpus: &[GpuInfo],
+    selection: &GpuSelection,
+    criteria: &selector::SelectionCriteria,
+    quiet: bool,
+) {
+    if !quiet {
+        eprintln!("Selected GPU(s): {}", selection.to_cuda_visible_devices());
+    }
+
+    for &index in &selection.gpu_indices {
+        if let Some(gpu) = gpus.iter().find(|g| g.index == index) {
+            if let Some(warning) = low_memory_warning(gpu, criteria.min_memory_mb) {
+                eprintln!("{}", warning);
+            }
+
+            if !quiet {
+                eprintln!("  {}", gpu.status_line(&criteria.noise));
+            }
+        }
+    }
+
+    if let Some(warning) = &selection.warning {
+        if quiet {
+            eprintln!("{}", warning);
+        } else {
+            eprintln!("\n{}", warning);
+        }
+    }
+
+    if !quiet {
+        eprintln!();
+    }
+}
+
+/// Which inherited variables the command doesn't get (`--clear-env`,
+/// `--unset-env`, and `CUDA_VISIBLE_DEVICES` unless `--ignore-visible-devices`)
+#[derive(Default)]
+struct ChildEnv<'a> {
+    clear: bool,
+    unset: &'a [String],
+    keep_visible_devices: bool,
+}
+
+/// Shell commands to run once the command has finished (`--on-success`,
+/// `--on-failure`)
+#[derive(Default)]
+struct Hooks<'a> {
+    on_success: Option<&'a str>,
+    on_failure: Option<&'a str>,
+}
+
+impl Hooks<'_> {
+    fn is_empty(&self) -> bool {
+        self.on_success.is_none() && self.on_failure.is_none()
+    }
+
+    /// Run the hook for `exit_code`, if there is one, in the command's
+    /// environment plus `WITH_GPU_EXIT_CODE` and `WITH_GPU_DEVICES`. A hook
+    /// that fails is reported, but doesn't change with-gpu's exit code.
+    fn run(
+        &self,
+        exit_code: i32,
+        cuda_visible_devices: Option<&str>,
+        extra_env: &[(&str, String)],
+        child_env: &ChildEnv,
+    ) {
+        let (flag, hook) = match (exit_code, self.on_success, self.on_failure) {
+            (0, Some(hook), _) => ("--on-success", hook),
+            (code, _, Some(hook)) if code != 0 => ("--on-failure", hook),
+            _ => return,
+        };
+        // --device-format's WITH_GPU_DEVICES, if given, wins as it does for the command
+        let mut env = vec![(
+            "WITH_GPU_DEVICES",
+            cuda_visible_devices.unwrap_or_default().to_string(),
+        )];
+        env.extend(extra_env.iter().cloned());
+        env.push(("WITH_GPU_EXIT_CODE", exit_code.to_string()));
+
+        let mut command = shell_command(hook);
+        set_child_env(&mut command, child_env, cuda_visible_devices, &env);
+        match command.status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => eprintln!(
+                "with-gpu: {} hook exited with code {}",
+                flag,
+                child_exit_code(status)
+            ),
+            Err(e) => eprintln!("with-gpu: failed to run {} hook: {}", flag, e),
+        }
+    }
+}
+
+/// A command that runs `script` in the platform's shell
+fn shell_command(script: &str) -> Command {
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C");
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c");
+        command
+    };
+    command.arg(script);
+    command
+}
+
+/// The exit code with-gpu passes on for the command's result (as `main`
+/// does), for `WITH_GPU_EXIT_CODE`. None if the command never started, which
+/// leaves the hooks no outcome to act on.
+fn result_exit_code(result: &Result<()>) -> Option<i32> {
+    let Err(e) = result else { return Some(0) };
+    if let Some(failed) = e.downcast_ref::<ChildFailed>() {
+        Some(failed.code)
+    } else if e.is::<CommandNotStarted>() {
+        None
+    } else {
+        Some(1)
+    }
+}
+
+/// Run the command, setting `CUDA_VISIBLE_DEVICES` if devices were selected.
+/// With `None`, the inherited value (if any) is passed through. With
+/// `max_runtime` or hooks, the command runs as a supervised child (killed if
+/// it runs longer than `max_runtime`), and the hooks run after it.
+fn execute_command(
+    command_parts: &[String],
+    cuda_visible_devices: Option<&str>,
+    extra_env: &[(&str, String)],
+    child_env: &ChildEnv,
+    hooks: &Hooks,
+    max_runtime: Option<Duration>,
+) -> Result<()> {
+    if command_parts.is_empty() {
+        anyhow::bail!("No command specified");
+    }
+
+    let program = &command_parts[0];
+    let args = &command_parts[1..];
+
+    let mut command = Command::new(program);
+    command.args(args);
+    set_child_env(&mut command, child_env, cuda_visible_devices, extra_env);
+
+    if max_runtime.is_some() || !hooks.is_empty() {
+        let result = run_supervised(command, max_runtime);
+        if let Some(exit_code) = result_exit_code(&result) {
+            hooks.run(exit_code, cuda_visible_devices, extra_env, child_env);
+        }
+        return result;
+    }
+
+    #[cfg(unix)]
+    {
+        let error = command.exec();
+
+        Err(CommandNotStarted {
+            program: program.clone(),
+            error,
+        }
+        .into())
+    }
+
+    #[cfg(not(unix))]
+    {
+        let status = command.status().map_err(|error| CommandNotStarted {
+            program: program.clone(),
+            error,
+        })?;
+
+        if !status.success() {
+            return Err(ChildFailed {
+                code: child_exit_code(status),
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Run the command as a child and wait for it, killing it if it runs longer
+/// than `max_runtime`
+fn run_supervised(command: Command, max_runtime: Option<Duration>) -> Result<()> {
+    let Some(max_runtime) = max_runtime else {
+        let status = supervise::run(command)?;
+        if !status.success() {
+            return Err(ChildFailed {
+                code: child_exit_code(status),
+            }
+            .into());
+        }
+        return Ok(());
+    };
+    match supervise::run_with_max_runtime(command, max_runtime, MAX_RUNTIME_GRACE)? {
+        supervise::Outcome::Exited(status) if status.success() => Ok(()),
+        supervise::Outcome::Exited(status) => Err(ChildFailed {
+            code: child_exit_code(status),
+        }
+        .into()),
+        supervise::Outcome::TimedOut => {
+            eprintln!(
+                "with-gpu: command ran longer than --max-runtime ({}s) and was killed",
+                max_runtime.as_secs()
+            );
+            Err(ChildFailed {
+                code: MAX_RUNTIME_EXIT_CODE,
+            }
+            .into())
+        }
+    }
+}
+
+/// Build the command's environment, in order: clear it (`--clear-env`),
+/// remove `--unset-env` variables, then set `CUDA_VISIBLE_DEVICES` (replacing
+/// any inherited value, or removing it when nothing was selected) and
+/// `extra_env`. Later steps win, so what with-gpu sets always reaches the
+/// command.
+fn set_child_env(
+    command: &mut Command,
+    child_env: &ChildEnv,
+    cuda_visible_devices: Option<&str>,
+    extra_env: &[(&str, String)],
+) {
+    if child_env.clear {
+        command.env_clear();
+    }
+    for name in child_env.unset {
+        command.env_remove(name);
+    }
+    if let Some(devices) = cuda_visible_devices {
+        command.env("CUDA_VISIBLE_DEVICES", devices);
+    } else if !child_env.keep_visible_devices {
+        command.env_remove("CUDA_VISIBLE_DEVICES");
+    }
+    command.envs(extra_env.iter().map(|(k, v)| (k, v)));
+}
+
+/// A command run as a child (rather than exec'd) exited unsuccessfully or was
+/// killed by `--max-runtime`. `main` exits with `code` once the GPU claims have
+/// been released.
+#[derive(Debug)]
+struct ChildFailed {
+    code: i32,
+}
+
+impl std::fmt::Display for ChildFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Command exited with code {}", self.code)
+    }
+}
+
+impl std::error::Error for ChildFailed {}
+
+/// The command couldn't be started at all (as opposed to starting and then
+/// failing). `main` reports it and exits with the shell's code for it.
+#[derive(Debug)]
+struct CommandNotStarted {
+    program: String,
+    error: std::io::Error,
+}
+
+impl CommandNotStarted {
+    fn exit_code(&self) -> i32 {
+        match self.error.kind() {
+            std::io::ErrorKind::NotFound => COMMAND_NOT_FOUND_EXIT_CODE,
+            _ => COMMAND_NOT_EXECUTABLE_EXIT_CODE,
+        }
+    }
+}
+
+impl std::fmt::Display for CommandNotStarted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "with-gpu: failed to execute command: {}: {}",
+            self.program, self.error
+        )
+    }
+}
+
+impl std::error::Error for CommandNotStarted {}
+
+/// The user interrupted `--wait` with Ctrl-C, or it got SIGTERM. `main`
+/// reports it and exits with `exit_code`.
+#[derive(Debug)]
+struct WaitCancelled {
+    waited_secs: u64,
+    /// The signal that interrupted the wait, if one did
+    signal: Option<i32>,
+}
+
+impl WaitCancelled {
+    /// 128 + the signal number, as a shell reports a process the signal
+    /// killed
+    fn exit_code(&self) -> i32 {
+        self.signal
+            .map_or(WAIT_CANCELLED_EXIT_CODE, |signal| 128 + signal)
+    }
+}
+
+impl std::fmt::Display for WaitCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "with-gpu: cancelled after {}s waiting for GPUs",
+            self.waited_secs
+        )
+    }
+}
+
+impl std::error::Error for WaitCancelled {}
+
+/// The exit code to pass on for a child's status: its own code, or on Unix
+/// 128 + the signal number if a signal killed it (the shell convention)
+fn child_exit_code(status: std::process::ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use selector::GpuId;
+    use with_gpu::test_support::{make_gpus, MockSource, TempLockDir};
+
+    #[test]
+    fn test_query_visible_gpus() {
+        use allocation::VisibleDevices;
+        let restricted = |allowed: Option<Vec<usize>>| RestrictedSource {
+            inner: MockSource::new(vec![make_gpus(4)]),
+            allowed,
+        };
+        let indices = |gpus: Vec<GpuInfo>| gpus.iter().map(|g| g.index).collect::<Vec<_>>();
+
+        // none/void: a no-op run that doesn't even query
+        let mut source = restricted(None);
+        let (gpus, device_count) =
+            query_visible_gpus(&mut source, Some(&VisibleDevices::NoGpus)).unwrap();
+        assert!(gpus.is_empty());
+        assert_eq!(device_count, 0);
+        assert_eq!(source.inner.queries, 0);
+
+        // all: every physical GPU
+        let mut source = restricted(None);
+        let (gpus, device_count) =
+            query_visible_gpus(&mut source, Some(&VisibleDevices::All)).unwrap();
+        assert_eq!(indices(gpus), vec![0, 1, 2, 3]);
+        assert_eq!(device_count, 4);
+        assert_eq!(source.allowed, None);
+
+        // A list restricts the candidates, on top of a scheduler's allocation
+        let list = VisibleDevices::List(vec![
+            "GPU-0001".into(),
+            "GPU-0002".into(),
+            "GPU-0003".into(),
+        ]);
+        let mut source = restricted(Some(vec![0, 1, 2]));
+        let (gpus, device_count) = query_visible_gpus(&mut source, Some(&list)).unwrap();
+        assert_eq!(indices(gpus), vec![1, 2]);
+        assert_eq!(device_count, 3);
+        assert_eq!(source.allowed, Some(vec![1, 2]));
+        assert_eq!(indices(source.query_gpus().unwrap()), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_apply_allowlist() {
+        let mut source = RestrictedSource {
+            inner: MockSource::new(vec![make_gpus(4)]),
+            allowed: Some(vec![0, 1, 2]),
+        };
+        let gpus = source.query_gpus().unwrap();
+        let allowlist = [
+            GpuId::Index(1),
+            GpuId::Uuid("GPU-0002".to_string()),
+            GpuId::Index(3),
+            GpuId::Uuid("GPU-on-another-host".to_string()),
+        ];
+        let gpus = apply_allowlist(&mut source, gpus, &allowlist);
+        let indices = |gpus: Vec<GpuInfo>| gpus.iter().map(|g| g.index).collect::<Vec<_>>();
+        assert_eq!(indices(gpus), vec![1, 2]);
+        assert_eq!(indices(source.query_gpus().unwrap()), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_timing_report() {
+        let mut timing = Timing::new(
+            nvidia::QueryTiming {
+                nvml_init: Duration::from_micros(12_340),
+                cuda_memory: Duration::from_millis(850),
+                nvml_devices: Duration::ZERO,
+            },
+            Duration::from_millis(900),
+        );
+        let json = serde_json::to_value(timing.report()).unwrap();
+        assert_eq!(json["nvml_init_ms"], 12.3);
+        assert_eq!(json["cuda_memory_ms"], 850.0);
+        assert_eq!(json["query_ms"], 900.0);
+        assert!(json.get("selection_ms").is_none());
+
+        timing.selection = Some(Duration::from_micros(470));
+        assert_eq!(timing.report().selection_ms, Some(0.5));
+    }
+
+    #[test]
+    fn test_wait_event_json() {
+        let poll = WaitEvent::Poll {
+            attempt: 3,
+            elapsed_secs: 10.2,
+            idle_count: 1,
+            idle_indices: vec![2],
+            candidate_count: 4,
+        };
+        assert_eq!(
+            serde_json::to_string(&poll).unwrap(),
+            r#"{"event":"poll","attempt":3,"elapsed_secs":10.2,"idle_count":1,"idle_indices":[2],"candidate_count":4}"#
+        );
+        let available = WaitEvent::Available {
+            attempt: 4,
+            elapsed_secs: 15.0,
+            gpu_indices: &[2],
+        };
+        assert_eq!(
+            serde_json::to_value(&available).unwrap()["event"],
+            "available"
+        );
+    }
+
+    #[test]
+    fn test_wait_for_gpus_bails_when_manual_gpu_disappears() {
+        let _lock_dir = TempLockDir::new();
+        let mut busy = make_gpus(2);
+        busy[0].memory_used_mb = 23_000;
+        let gpu_0_removed = vec![make_gpus(2)[1].clone()];
+        let mut source = MockSource::new(vec![busy, gpu_0_removed]);
+
+        let criteria = selector::SelectionCriteria::default();
+        let err = wait_for_gpus(
+            &mut source,
+            &criteria,
+            &MostFreeMemoryPolicy,
+            &WaitOptions {
+                manual_gpu_indices: Some(&[0]),
+                poll_interval: Duration::ZERO,
+                ..Default::default()
+            },
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("no longer present"), "{}", err);
+        assert_eq!(source.queries, 2);
+    }
+
+    #[test]
+    fn test_wait_for_gpus_sticky_keeps_earlier_candidate() {
+        let mut first = make_gpus(3);
+        first[1].memory_used_mb = 23_000;
+        first[2].memory_used_mb = 23_000;
+        let mut second = make_gpus(3);
+        second[0].memory_used_mb = 1_000;
+        let criteria = selector::SelectionCriteria {
+            min_gpus: 2,
+            max_gpus: 2,
+            use_locks: false,
+            ..Default::default()
+        };
+        let select = |sticky| {
+            let mut source = MockSource::new(vec![first.clone(), second.clone()]);
+            let options = WaitOptions {
+                poll_interval: Duration::ZERO,
+                sticky,
+                ..Default::default()
+            };
+            let (selection, _) =
+                wait_for_gpus(&mut source, &criteria, &MostFreeMemoryPolicy, &options).unwrap();
+            selection.gpu_indices
+        };
+        assert_eq!(select(false), vec![1, 2]);
+        assert_eq!(select(true), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_wait_for_gpus_avoid_throttled_waits_for_slowdown_to_clear() {
+        let mut throttled = make_gpus(1);
+        throttled[0].throttle_reasons = 0x80; // hw power brake
+        let mut source = MockSource::new(vec![throttled, make_gpus(1)]);
+        let criteria = selector::SelectionCriteria {
+            avoid_throttled: true,
+            use_locks: false,
+            ..Default::default()
+        };
+        let (selection, _) = wait_for_gpus(
+            &mut source,
+            &criteria,
+            &MostFreeMemoryPolicy,
+            &WaitOptions {
+                poll_interval: Duration::ZERO,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(selection.gpu_indices, vec![0]);
+        assert_eq!(source.queries, 2);
+    }
+
+    #[test]
+    fn test_wait_for_gpus_cancelled_by_interrupt() {
+        let _lock_dir = TempLockDir::new();
+        let mut busy = make_gpus(1);
+        busy[0].memory_used_mb = 23_000;
+        let mut source = MockSource::new(vec![busy]);
+        let interrupted = AtomicBool::new(true);
+        let err = wait_for_gpus(
+            &mut source,
+            &selector::SelectionCriteria::default(),
+            &MostFreeMemoryPolicy,
+            &WaitOptions {
+                poll_interval: Duration::from_secs(30),
+                interrupted: &interrupted,
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(err.downcast_ref::<WaitCancelled>().is_some(), "{}", err);
+        assert_eq!(source.queries, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_wait_cancelled_exit_code() {
+        let cancelled = |signal| WaitCancelled {
+            waited_secs: 0,
+            signal,
+        };
+        assert_eq!(cancelled(None).exit_code(), 130);
+        assert_eq!(cancelled(Some(libc::SIGINT)).exit_code(), 130);
+        assert_eq!(cancelled(Some(libc::SIGTERM)).exit_code(), 143);
+    }
+
+    #[test]
+    fn test_wait_for_gpus_after_live_process_times_out_without_querying() {
+        let mut source = MockSource::new(vec![make_gpus(1)]);
+        let criteria = selector::SelectionCriteria {
+            use_locks: false,
+            ..Default::default()
+        };
+        let err = wait_for_gpus(
+            &mut source,
+            &criteria,
+            &MostFreeMemoryPolicy,
+            &WaitOptions {
+                timeout_secs: Some(0),
+                after_pid: Some(std::process::id()),
+                poll_interval: Duration::ZERO,
+                ..Default::default()
+            },
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("waiting for process"), "{}", err);
+        assert_eq!(source.queries, 0);
+    }
+
+    #[test]
+    fn test_wait_for_gpus_after_exited_process_selects_its_gpu() {
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+
+        let mut gpus = make_gpus(2);
+        gpus[1].pids = vec![pid];
+        assert_eq!(gpus_used_by(&gpus, pid), vec![1]);
 
-    #[arg(long, default_value = "1", help = "Maximum number of GPUs to use")]
-    max_gpus: usize,
+        let mut source = MockSource::new(vec![gpus]);
+        let criteria = selector::SelectionCriteria {
+            use_locks: false,
+            ..Default::default()
+        };
+        let (selection, _) = wait_for_gpus(
+            &mut source,
+            &criteria,
+            &MostFreeMemoryPolicy,
+            &WaitOptions {
+                manual_gpu_indices: Some(&[1]),
+                after_pid: Some(pid),
+                poll_interval: Duration::ZERO,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(selection.gpu_indices, vec![1]);
+    }
 
-    #[arg(
-        long,
-        help = "Require all selected GPUs to be idle (no processes running)"
-    )]
-    require_idle: bool,
+    #[test]
+    fn test_wait_for_gpus_fails_fast_when_host_has_too_few_gpus() {
+        let _lock_dir = TempLockDir::new();
+        let mut source = MockSource::new(vec![make_gpus(2)]);
+        let criteria = selector::SelectionCriteria {
+            min_gpus: 3,
+            max_gpus: 3,
+            require_idle: true,
+            ..Default::default()
+        };
+        let err = wait_for_gpus(
+            &mut source,
+            &criteria,
+            &MostFreeMemoryPolicy,
+            &WaitOptions {
+                poll_interval: Duration::ZERO,
+                ..Default::default()
+            },
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains("Requested 3 GPU(s) but host only has 2"),
+            "{}",
+            err
+        );
+        assert_eq!(source.queries, 1);
+    }
 
-    #[arg(
-        long,
-        help = "Minimum free memory required in MB (default: 2048 MB for PyTorch)\n\
-                Use --min-memory 0 to disable and allow any GPU"
-    )]
-    min_memory: Option<u64>,
+    #[test]
+    fn test_check_gpu_count() {
+        let criteria = |min_gpus, max_gpus| selector::SelectionCriteria {
+            min_gpus,
+            max_gpus,
+            ..Default::default()
+        };
+        assert_eq!(check_gpu_count(8, &criteria(2, 4), Some(4)).unwrap(), None);
 
-    #[arg(
-        long,
-        help = "Maximum GPU utilization percentage (0-100)\n\
-                Example: --max-util 70 excludes GPUs with >70% utilization"
-    )]
-    max_util: Option<u8>,
+        let warning = check_gpu_count(8, &criteria(1, 16), Some(16))
+            .unwrap()
+            .unwrap();
+        assert!(
+            warning.contains("--max-gpus 16 exceeds the 8 GPU(s)"),
+            "{}",
+            warning
+        );
 
-    #[arg(
-        long,
-        help = "Wait for GPUs to become available if not immediately available"
-    )]
-    wait: bool,
+        let err = check_gpu_count(8, &criteria(16, 16), Some(16))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "--min-gpus 16 exceeds the 8 GPU(s) on this host");
 
-    #[arg(
-        long,
-        help = "Timeout in seconds when waiting for GPUs (default: no timeout)",
-        requires = "wait"
-    )]
-    timeout: Option<u64>,
+        let best_effort = selector::SelectionCriteria {
+            best_effort: true,
+            ..criteria(16, 16)
+        };
+        assert!(check_gpu_count(8, &best_effort, None).unwrap().is_none());
+    }
 
-    #[arg(long, help = "Show GPU status and exit")]
-    status: bool,
+    #[test]
+    fn test_manual_selection_uses_every_listed_gpu() {
+        let gpus = make_gpus(4);
+        let select = |criteria| {
+            let (selection, _) = select_candidates(
+                gpus.clone(),
+                Some(&[0, 1, 2]),
+                &criteria,
+                &MostFreeMemoryPolicy,
+            )
+            .unwrap();
+            selection.gpu_indices.len()
+        };
 
-    #[arg(
-        trailing_var_arg = true,
-        allow_hyphen_values = true,
-        help = "Command to execute with selected GPUs"
-    )]
-    command: Vec<String>,
-}
+        // --gpu 0,1,2
+        let criteria = manual_selection_criteria(Default::default(), 3, None, None).unwrap();
+        assert_eq!((criteria.min_gpus, criteria.max_gpus), (3, 3));
+        assert_eq!(select(criteria), 3);
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+        // --gpu 0,1,2 --min-gpus 2
+        let criteria = manual_selection_criteria(Default::default(), 3, Some(2), None).unwrap();
+        assert_eq!((criteria.min_gpus, criteria.max_gpus), (2, 3));
 
-    if cli.min_gpus > cli.max_gpus {
-        anyhow::bail!(
-            "min-gpus ({}) cannot be greater than max-gpus ({})",
-            cli.min_gpus,
-            cli.max_gpus
+        // --gpu 0,1,2 --max-gpus 4
+        let explicit = selector::SelectionCriteria {
+            max_gpus: 4,
+            ..Default::default()
+        };
+        let criteria = manual_selection_criteria(explicit, 3, None, Some(4)).unwrap();
+        assert_eq!(select(criteria), 3);
+
+        // --gpu 0,1,2 --max-gpus 2
+        let Err(err) = manual_selection_criteria(Default::default(), 3, None, Some(2)) else {
+            panic!("--max-gpus below the GPUs listed should fail");
+        };
+        assert_eq!(
+            err.to_string(),
+            "--gpu lists 3 GPUs but at most 2 were requested; list fewer GPUs or raise --max-gpus"
         );
     }
 
-    if let Some(util) = cli.max_util {
-        if util > 100 {
-            anyhow::bail!("max-util must be between 0 and 100, got {}", util);
-        }
+    #[test]
+    fn test_manual_selection_defers_layered_count_check() {
+        let check = |args: &[&str], min_gpus, max_gpus| {
+            let args = std::iter::once("with-gpu").chain(args.iter().copied());
+            let cli = Cli::try_parse_from(args).unwrap();
+            let manual = cli.gpu.is_some();
+            check_layered_gpu_counts(&cli, min_gpus, max_gpus, manual)
+        };
+        // The layered maximum is the default of 1 until --gpu's count applies
+        assert!(check(&["--gpu", "0,1,2", "--min-gpus", "2", "true"], 2, 1).is_ok());
+        assert!(check(&["--min-gpus", "2", "true"], 2, 1).is_err());
+        assert!(check(
+            &[
+                "--gpu",
+                "0,1,2",
+                "--min-gpus",
+                "2",
+                "--max-gpus",
+                "1",
+                "true"
+            ],
+            2,
+            1
+        )
+        .is_err());
     }
 
-    let gpus = nvidia::query_gpus()?;
+    #[test]
+    fn test_write_selection() {
+        let gpus = make_gpus(3);
+        let selection = GpuSelection {
+            gpu_indices: vec![2, 0],
+            gpu_uuids: vec!["GPU-0002".into(), "GPU-0000".into()],
+            all_idle: true,
+            warning: None,
+        };
+        let path = std::env::temp_dir().join(format!("with-gpu-emit-{}.json", std::process::id()));
+        write_selection(&path, &selection, &gpus, "2,0", &BTreeMap::new()).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
 
-    if cli.status {
-        print_status(&gpus);
-        return Ok(());
+        let json: serde_json::Value = serde_json::from_str(&contents).unwrap();
+        assert_eq!(json["gpu_indices"], serde_json::json!([2, 0]));
+        assert_eq!(json["all_idle"], true);
+        assert_eq!(json["warning"], serde_json::Value::Null);
+        assert_eq!(json["cuda_visible_devices"], "2,0");
+        assert_eq!(json["gpus"][0]["uuid"], "GPU-0002");
+        assert_eq!(json["gpus"][1]["memory_total_mb"], 24000);
     }
 
-    if cli.command.is_empty() {
-        anyhow::bail!("No command specified (use --help for usage)");
+    #[test]
+    fn test_write_gpu_uuids() {
+        assert_eq!(uuid_output_path("3"), std::path::PathBuf::from("/dev/fd/3"));
+        assert_eq!(
+            uuid_output_path("uuids.txt"),
+            std::path::PathBuf::from("uuids.txt")
+        );
+
+        let path = std::env::temp_dir().join(format!("with-gpu-uuids-{}.txt", std::process::id()));
+        let target = path.to_str().unwrap();
+        write_gpu_uuids(target, &["GPU-0002".into(), "GPU-0000".into()]).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(contents, "GPU-0002\nGPU-0000\n");
     }
 
-    // On macOS, skip GPU selection entirely and just execute the command
-    #[cfg(target_os = "macos")]
-    {
-        if gpus.is_empty() {
-            // Only warn if user explicitly requested GPU features beyond defaults
-            let has_non_default_flags = cli.gpu.is_some()
-                || cli.min_gpus != 1
-                || cli.max_gpus != 1
-                || cli.require_idle
-                || cli.wait;
+    #[cfg(unix)]
+    #[test]
+    fn test_write_report_fd() {
+        use std::io::Read;
+        use std::os::fd::FromRawFd;
 
-            if has_non_default_flags {
-                eprintln!(
-                    "Warning: GPU selection flags ignored on macOS (no NVIDIA GPUs available)"
-                );
-                eprintln!();
-            }
-            return execute_command_without_gpus(&cli.command);
-        }
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let [read_fd, write_fd] = fds;
+        assert!(check_report_fd(read_fd).is_err());
+        assert!(check_report_fd(write_fd).is_ok());
+
+        let selection = GpuSelection {
+            gpu_indices: vec![2, 0],
+            gpu_uuids: vec!["GPU-0002".into(), "GPU-0000".into()],
+            all_idle: true,
+            warning: None,
+        };
+        write_report_fd(write_fd, &selection).unwrap();
+
+        // Reads to end-of-file, since the write end was closed
+        let mut report = String::new();
+        unsafe { std::fs::File::from_raw_fd(read_fd) }
+            .read_to_string(&mut report)
+            .unwrap();
+        assert_eq!(report, "2,0\nGPU-0002,GPU-0000\n");
     }
 
-    let criteria = selector::SelectionCriteria {
-        min_gpus: cli.min_gpus,
-        max_gpus: cli.max_gpus,
-        require_idle: cli.require_idle,
-        min_memory_mb: cli.min_memory.or(Some(2048)),
-        max_utilization: cli.max_util,
-    };
+    #[test]
+    fn test_load_snapshot() {
+        let gpus = make_gpus(2);
+        let path = std::env::temp_dir().join(format!("with-gpu-snap-{}.json", std::process::id()));
+        std::fs::write(&path, serde_json::to_string_pretty(&gpus).unwrap()).unwrap();
+        let loaded = load_snapshot(&path).unwrap();
+        let report = StatusReport {
+            summary: StatusSummary::new(&gpus, &NoiseModel::default(), &[]),
+            gpus: &gpus,
+        };
+        std::fs::write(&path, serde_json::to_string_pretty(&report).unwrap()).unwrap();
+        let loaded_report = load_snapshot(&path).unwrap();
+        std::fs::write(&path, "not json").unwrap();
+        let err = load_snapshot(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
 
-    // Parse manual GPU selection if provided
-    let manual_gpu_indices = if let Some(ref manual_selection) = cli.gpu {
-        let indices = selector::parse_manual_gpu_selection(manual_selection)?;
-        validate_manual_selection(&gpus, &indices)?;
-        Some(indices)
-    } else {
-        None
-    };
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[1].uuid, "GPU-0001");
+        assert_eq!(loaded_report.len(), 2);
+        assert!(err.to_string().contains("Failed to parse GPU snapshot"));
+    }
 
-    let (selection, display_gpus) = if cli.wait {
-        wait_for_gpus(&criteria, cli.timeout, manual_gpu_indices.as_deref())?
-    } else {
-        // Filter to candidate GPUs (manual selection or all)
-        let candidate_gpus: Vec<GpuInfo> = if let Some(ref indices) = manual_gpu_indices {
-            gpus.into_iter()
-                .filter(|g| indices.contains(&g.index))
-                .collect()
-        } else {
-            gpus
+    #[test]
+    fn test_pytorch_alloc_conf() {
+        assert_eq!(
+            pytorch_alloc_conf(None, 0.5),
+            "garbage_collection_threshold:0.5"
+        );
+        assert_eq!(
+            pytorch_alloc_conf(Some("expandable_segments:True"), 0.25),
+            "expandable_segments:True,garbage_collection_threshold:0.25"
+        );
+        assert_eq!(
+            pytorch_alloc_conf(Some("garbage_collection_threshold:0.8"), 0.5),
+            "garbage_collection_threshold:0.8"
+        );
+
+        assert!(memory_fraction_env(None).is_empty());
+        let names: Vec<&str> = memory_fraction_env(Some(0.5))
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(
+            names,
+            ["PYTORCH_CUDA_ALLOC_CONF", "WITH_GPU_MEMORY_FRACTION"]
+        );
+    }
+
+    #[test]
+    fn test_format_devices() {
+        let gpus = make_gpus(4);
+        let selection = GpuSelection {
+            gpu_indices: vec![1, 3],
+            gpu_uuids: vec!["GPU-0001".into(), "GPU-0003".into()],
+            all_idle: true,
+            warning: None,
         };
+        let format = |t: &str| format_devices(t, &selection, &gpus).unwrap();
+        assert_eq!(format("--nproc_per_node={count}"), "--nproc_per_node=2");
+        assert_eq!(format("{devices_space}"), "1 3");
+        assert_eq!(format("gpus={devices};n={count}"), "gpus=1,3;n=2");
+        assert_eq!(format("{uuids}"), "GPU-0001,GPU-0003");
+    }
 
-        let sel = selector::select_gpus(&candidate_gpus, &criteria)?;
-        (sel, candidate_gpus)
-    };
+    #[test]
+    fn test_parse_device_format_rejects_bad_templates() {
+        assert!(parse_device_format("{count}").is_ok());
+        let err = parse_device_format("{gpus}").unwrap_err();
+        assert!(err.contains("Unknown placeholder {gpus}"), "{}", err);
+        assert!(parse_device_format("{count").is_err());
+    }
 
-    print_selection(&display_gpus, &selection);
+    #[test]
+    fn test_parse_duration_secs() {
+        assert_eq!(parse_duration_secs("90"), Ok(90));
+        assert_eq!(parse_duration_secs("5m"), Ok(300));
+        assert_eq!(parse_duration_secs("1h"), Ok(3600));
+        assert_eq!(parse_duration_secs("1h30m"), Ok(5400));
+        assert_eq!(parse_duration_secs("2m15s"), Ok(135));
+    }
 
-    // Claim the selected GPUs before executing the command
-    for &gpu_index in &selection.gpu_indices {
-        if let Err(e) = lockfile::claim_gpu(gpu_index) {
-            // If we fail to claim, another process grabbed it between selection and claim
-            anyhow::bail!(
-                "Failed to claim GPU {}: {} (try again, another process may have claimed it)",
-                gpu_index,
-                e
-            );
-        }
+    #[test]
+    fn test_parse_duration_secs_rejects_garbage() {
+        assert!(parse_duration_secs("5 minutes").is_err());
+        assert!(parse_duration_secs("1h30").is_err());
+        assert!(parse_duration_secs("m").is_err());
+        assert!(parse_duration_secs("").is_err());
     }
 
-    execute_command(&cli.command, &selection)
-}
+    #[test]
+    fn test_min_memory_zero_disables_filter_and_warning() {
+        let mut gpu = make_gpus(1).remove(0);
+        gpu.memory_used_mb = 23_500;
 
-fn wait_for_gpus(
-    criteria: &selector::SelectionCriteria,
-    timeout_secs: Option<u64>,
-    manual_gpu_indices: Option<&[usize]>,
-) -> Result<(GpuSelection, Vec<GpuInfo>)> {
-    let start_time = Instant::now();
-    let poll_interval = Duration::from_secs(5);
-    let mut attempt = 1;
+        assert_eq!(effective_min_memory(Some(0)), None);
+        assert!(low_memory_warning(&gpu, effective_min_memory(Some(0))).is_none());
+    }
+
+    #[test]
+    fn test_low_memory_warning_with_default_min_memory() {
+        let mut gpu = make_gpus(1).remove(0);
+        gpu.memory_used_mb = 23_500;
 
-    eprintln!("Waiting for GPUs to become available...");
-    if let Some(timeout) = timeout_secs {
-        eprintln!("  Timeout: {} seconds", timeout);
+        assert_eq!(effective_min_memory(None), Some(DEFAULT_MIN_MEMORY_MB));
+        let warning = low_memory_warning(&gpu, effective_min_memory(None)).unwrap();
+        assert!(
+            warning.contains("GPU 0 has only 0.49 GB free (< 2.00 GB minimum)"),
+            "{}",
+            warning
+        );
     }
-    if let Some(indices) = manual_gpu_indices {
-        eprintln!("  Manual selection: {:?}", indices);
+
+    #[test]
+    fn test_gpus_below_min_memory() {
+        let mut gpus = make_gpus(3);
+        gpus[1].memory_used_mb = gpus[1].memory_total_mb - 1024;
+        let short = gpus_below_min_memory(&gpus, &[0, 1, 5], 8192);
+        let indices: Vec<usize> = short.iter().map(|g| g.index).collect();
+        assert_eq!(indices, vec![1, 5]);
+        assert!(gpus_below_min_memory(&gpus, &[0, 2], 8192).is_empty());
     }
-    eprintln!(
-        "  Requirements: min={}, max={}, require_idle={}",
-        criteria.min_gpus, criteria.max_gpus, criteria.require_idle
-    );
-    eprintln!();
 
-    loop {
-        let all_gpus = nvidia::query_gpus()?;
+    #[test]
+    fn test_low_memory_warning_tracks_min_memory() {
+        let mut gpu = make_gpus(1).remove(0);
+        gpu.memory_used_mb = 20_000; // 4000 MB free
 
-        // Filter to candidate GPUs (manual selection or all)
-        let candidate_gpus: Vec<GpuInfo> = if let Some(indices) = manual_gpu_indices {
-            all_gpus
+        let warning = low_memory_warning(&gpu, effective_min_memory(Some(8192))).unwrap();
+        assert!(warning.contains("(< 8.00 GB minimum)"), "{}", warning);
+        assert!(low_memory_warning(&gpu, effective_min_memory(Some(4000))).is_none());
+        assert!(low_memory_warning(&gpu, effective_min_memory(Some(512))).is_none());
+
+        gpu.memory_used_mb = 23_000; // 1000 MB free
+        assert!(low_memory_warning(&gpu, effective_min_memory(Some(512))).is_none());
+    }
+
+    #[test]
+    fn test_sort_for_status() {
+        let mut gpus = make_gpus(3);
+        gpus[0].memory_used_mb = 20_000;
+        gpus[0].utilization_percent = 90;
+        gpus[0].temperature_c = Some(40);
+        gpus[1].memory_used_mb = 10_000;
+        gpus[1].utilization_percent = 10;
+        gpus[1].temperature_c = Some(70);
+        gpus[2].utilization_percent = 10;
+        let order = |key| {
+            sort_for_status(&gpus, key)
                 .iter()
-                .filter(|g| indices.contains(&g.index))
-                .cloned()
-                .collect()
-        } else {
-            all_gpus.clone()
+                .map(|g| g.index)
+                .collect::<Vec<_>>()
         };
+        assert_eq!(order(StatusSort::Index), vec![0, 1, 2]);
+        assert_eq!(order(StatusSort::Free), vec![2, 1, 0]);
+        // Equal utilization falls back to most free memory
+        assert_eq!(order(StatusSort::Util), vec![2, 1, 0]);
+        // GPUs without a temperature reading go last
+        assert_eq!(order(StatusSort::Temp), vec![0, 1, 2]);
+    }
 
-        match selector::select_gpus(&candidate_gpus, criteria) {
-            Ok(selection) => {
-                eprintln!(
-                    "GPUs available after {} attempts ({:.1}s)",
-                    attempt,
-                    start_time.elapsed().as_secs_f64()
-                );
-                return Ok((selection, candidate_gpus));
-            }
-            Err(e) => {
-                if let Some(timeout) = timeout_secs {
-                    let elapsed = start_time.elapsed().as_secs();
-                    if elapsed >= timeout {
-                        anyhow::bail!("Timeout after {} seconds waiting for GPUs: {}", elapsed, e);
-                    }
-                }
+    #[test]
+    fn test_resolve_gpu_groups() {
+        let gpus = make_gpus(4);
+        let group = |name: &str, ids: Vec<GpuId>| (name.to_string(), ids);
+        let groups = resolve_gpu_groups(
+            &gpus,
+            &[
+                group("PREFILL", vec![GpuId::Index(0), GpuId::Index(1)]),
+                group("DECODE", vec![GpuId::Uuid("GPU-0003".to_string())]),
+            ],
+        )
+        .unwrap();
+        assert_eq!(groups["PREFILL"], vec![0, 1]);
+        assert_eq!(groups["DECODE"], vec![3]);
 
-                eprintln!(
-                    "[Attempt {}] No suitable GPUs available (waited {:.0}s)",
-                    attempt,
-                    start_time.elapsed().as_secs_f64()
-                );
+        let err = resolve_gpu_groups(
+            &gpus,
+            &[
+                group("A", vec![GpuId::Index(1)]),
+                group("B", vec![GpuId::Uuid("GPU-0001".to_string())]),
+            ],
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains("GPU 1 is in both --gpu-group A and --gpu-group B"),
+            "{}",
+            err
+        );
 
-                let idle_count = candidate_gpus.iter().filter(|g| g.is_idle()).count();
-                eprintln!("  Idle GPUs: {}/{}", idle_count, candidate_gpus.len());
+        assert!(resolve_gpu_groups(
+            &gpus,
+            &[
+                group("A", vec![GpuId::Index(0)]),
+                group("A", vec![GpuId::Index(1)]),
+            ],
+        )
+        .is_err());
+    }
 
-                if idle_count > 0 {
-                    eprintln!(
-                        "  Idle GPU indices: {:?}",
-                        candidate_gpus
-                            .iter()
-                            .filter(|g| g.is_idle())
-                            .map(|g| g.index)
-                            .collect::<Vec<_>>()
-                    );
-                }
+    #[test]
+    fn test_validate_manual_selection_resolves_uuids() {
+        let gpus = make_gpus(3);
+        let ids = vec![GpuId::Index(0), GpuId::Uuid("GPU-0002".to_string())];
+        assert_eq!(validate_manual_selection(&gpus, &ids).unwrap(), vec![0, 2]);
+    }
 
-                thread::sleep(poll_interval);
-                attempt += 1;
-            }
-        }
+    #[test]
+    fn test_validate_manual_selection_deduplicates_index_and_uuid() {
+        let gpus = make_gpus(2);
+        let ids = vec![GpuId::Index(1), GpuId::Uuid("GPU-0001".to_string())];
+        assert_eq!(validate_manual_selection(&gpus, &ids).unwrap(), vec![1]);
     }
-}
 
-fn print_status(gpus: &[GpuInfo]) {
-    if gpus.is_empty() {
-        #[cfg(target_os = "macos")]
-        {
-            println!("No NVIDIA GPUs available (running on macOS)");
-            println!("Commands will execute without GPU selection.");
-            return;
+    #[test]
+    fn test_validate_manual_selection_unknown_uuid_lists_available() {
+        let gpus = make_gpus(2);
+        let ids = vec![GpuId::Uuid("GPU-ffff".to_string())];
+        let err = validate_manual_selection(&gpus, &ids)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("GPU-ffff not found"));
+        assert!(err.contains("GPU-0000 (GPU 0), GPU-0001 (GPU 1)"));
+    }
+
+    #[test]
+    fn test_validate_manual_selection_unknown_index_lists_available() {
+        // e.g. a host whose GPUs are restricted by --allow-file
+        let gpus: Vec<GpuInfo> = make_gpus(4).into_iter().filter(|g| g.index != 1).collect();
+        let err = validate_manual_selection(&gpus, &[GpuId::Index(1)])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "GPU 1 not found (available: 0, 2-3)");
+    }
+
+    fn selection_of(gpu_indices: Vec<usize>) -> GpuSelection {
+        GpuSelection {
+            gpu_uuids: gpu_indices
+                .iter()
+                .map(|i| format!("GPU-{:04}", i))
+                .collect(),
+            gpu_indices,
+            all_idle: true,
+            warning: None,
         }
-        #[cfg(not(target_os = "macos"))]
-        {
-            println!("No GPUs detected");
-            return;
+    }
+
+    /// Stands in for `claim_lease` without touching lock files: GPU `taken`
+    /// is held by another process, and any other claim succeeds
+    fn claim_unless(
+        taken: usize,
+    ) -> impl FnMut(GpuSelection, Vec<GpuInfo>) -> Result<GpuLease, ClaimError> {
+        move |selection, gpus| {
+            if selection.gpu_indices.contains(&taken) {
+                return Err(ClaimError::AlreadyClaimed {
+                    gpu_index: taken,
+                    pid: 1,
+                });
+            }
+            GpuLease::claim(selection, gpus, false, Duration::ZERO, None)
         }
     }
 
-    let claimed_gpus = lockfile::get_claimed_gpus();
+    #[test]
+    fn test_claim_with_retries_reselects_after_losing_race() {
+        let (taken, free) = (0, 1);
+        let mut reselections = 0;
+        let result = claim_with_retries(
+            selection_of(vec![taken]),
+            Vec::new(),
+            1,
+            claim_unless(taken),
+            || {
+                reselections += 1;
+                Ok((selection_of(vec![free]), Vec::new()))
+            },
+        );
 
-    println!("Available GPUs:");
-    for gpu in gpus {
-        let claim_info = claimed_gpus
-            .iter()
-            .find(|(idx, _)| *idx == gpu.index)
-            .map(|(_, pid)| format!(" [claimed by pid {}]", pid))
-            .unwrap_or_default();
-        println!("  {}{}", gpu, claim_info);
+        assert_eq!(result.unwrap().indices(), [free]);
+        assert_eq!(reselections, 1);
     }
 
-    if !claimed_gpus.is_empty() {
-        println!();
-        println!(
-            "Note: {} GPU(s) claimed by other with-gpu processes",
-            claimed_gpus.len()
+    #[test]
+    fn test_claim_with_retries_gives_up() {
+        let taken = 0;
+        let result = claim_with_retries(
+            selection_of(vec![taken]),
+            Vec::new(),
+            0,
+            claim_unless(taken),
+            || panic!("should not reselect without retries"),
         );
-    }
-}
 
-fn validate_manual_selection(gpus: &[GpuInfo], indices: &[usize]) -> Result<()> {
-    if gpus.is_empty() {
-        anyhow::bail!("No GPUs detected on this system");
+        let err = result.err().unwrap().to_string();
+        assert!(err.contains("Failed to claim GPUs"), "{}", err);
     }
 
-    for &index in indices {
-        if !gpus.iter().any(|g| g.index == index) {
-            anyhow::bail!("GPU {} not found (available: 0-{})", index, gpus.len() - 1);
-        }
+    #[test]
+    fn test_exact_gpus_conflicts_with_count_flags() {
+        let cli = Cli::try_parse_from(["with-gpu", "--exact-gpus", "2", "true"]).unwrap();
+        assert_eq!(cli.exact_gpus, Some(2));
+        assert!(
+            Cli::try_parse_from(["with-gpu", "--exact-gpus", "2", "--best-effort", "true"])
+                .is_err()
+        );
+        assert!(
+            Cli::try_parse_from(["with-gpu", "--exact-gpus", "2", "--max-gpus", "4", "true"])
+                .is_err()
+        );
     }
-    Ok(())
-}
 
-fn print_selection(gpus: &[GpuInfo], selection: &GpuSelection) {
-    eprintln!("Selected GPU(s): {}", selection.to_cuda_visible_devices());
+    fn validate(args: &[&str]) -> Result<()> {
+        let cli = Cli::try_parse_from(std::iter::once("with-gpu").chain(args.iter().copied()))?;
+        validate_cli(&cli)
+    }
 
-    for &index in &selection.gpu_indices {
-        if let Some(gpu) = gpus.iter().find(|g| g.index == index) {
-            let free_gb = gpu.memory_free_mb() as f64 / 1024.0;
+    #[test]
+    fn test_no_flags_override_lower_layers() {
+        let flags = |args: &[&str]| {
+            let cli = Cli::try_parse_from(std::iter::once("with-gpu").chain(args.iter().copied()))
+                .unwrap();
+            (
+                flag_pair(cli.require_idle, cli.no_require_idle),
+                flag_pair(cli.include_used, cli.no_include_used),
+            )
+        };
+        assert_eq!(flags(&["true"]), (None, None));
+        assert_eq!(flags(&["--no-require-idle", "true"]), (Some(false), None));
+        assert_eq!(
+            flags(&["--no-require-idle", "--require-idle", "true"]),
+            (Some(true), None)
+        );
+        assert_eq!(
+            flags(&["--include-used", "--no-include-used", "true"]),
+            (None, Some(false))
+        );
+    }
 
-            if gpu.memory_free_mb() < 2048 {
-                eprintln!(
-                    "Warning: GPU {} has only {:.2} GB free (< 2 GB recommended for PyTorch)",
-                    index, free_gb
-                );
-            }
+    #[test]
+    fn test_broken_config_only_fails_commands() {
+        let path =
+            std::env::temp_dir().join(format!("with-gpu-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "min_memory_mb = \"lots\"").unwrap();
+        let for_command = load_config_for(Some(&path), false);
+        let for_status = load_config_for(Some(&path), true);
+        std::fs::remove_file(&path).unwrap();
 
-            eprintln!("  {}", gpu);
-        }
+        assert!(for_command.is_err());
+        assert_eq!(for_status.unwrap(), config::Config::default());
     }
 
-    if let Some(warning) = &selection.warning {
-        eprintln!("\n{}", warning);
+    #[test]
+    fn test_validate_cli_accepts_valid_combinations() {
+        assert!(validate(&["python", "train.py"]).is_ok());
+        assert!(validate(&["--status"]).is_ok());
+        assert!(validate(&["--explain", "--min-gpus", "2", "--max-gpus", "2"]).is_ok());
+        assert!(validate(&["--count-only"]).is_ok());
+        assert!(validate(&[
+            "--total-memory",
+            "40000",
+            "--min-gpus",
+            "3",
+            "--max-gpus",
+            "2",
+            "true"
+        ])
+        .is_ok());
+        assert!(validate(&["probe"]).is_ok());
     }
 
-    eprintln!();
-}
+    #[test]
+    fn test_validate_cli_rejects_bad_values() {
+        let err = |args: &[&str]| validate(args).unwrap_err().to_string();
+        assert_eq!(
+            err(&["--min-gpus", "3", "--max-gpus", "2", "true"]),
+            "min-gpus (3) cannot be greater than max-gpus (2)"
+        );
+        assert_eq!(
+            err(&["--max-util", "101", "true"]),
+            "max-util must be between 0 and 100, got 101"
+        );
+        assert!(err(&["--max-mem-util", "150", "true"]).contains("max-mem-util"));
+        assert!(err(&["--exact-gpus", "0", "true"]).contains("at least 1"));
+        assert!(err(&["--memory-fraction", "1.0", "true"]).contains("memory-fraction"));
+        assert!(err(&["--max-runtime", "0", "true"]).contains("max-runtime"));
+        assert!(err(&["--verify-after-claim", "--min-memory", "0", "true"])
+            .contains("--min-memory 0 disables"));
+    }
 
-fn execute_command(command_parts: &[String], selection: &GpuSelection) -> Result<()> {
-    if command_parts.is_empty() {
-        anyhow::bail!("No command specified");
+    #[test]
+    fn test_validate_cli_requires_command_unless_reporting() {
+        let err = validate(&["--gpu", "0"]).unwrap_err().to_string();
+        assert!(err.contains("No command specified"), "{}", err);
+        let err = validate(&["--status", "python", "train.py"])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("--status doesn't run a command"), "{}", err);
+        assert!(validate(&["--status", "--explain"]).is_err());
+        assert!(validate(&["--gpu-info", "0"]).is_ok());
+        let err = validate(&["--gpu-info", "0", "nvidia-smi"])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("--gpu-info doesn't run a command"), "{}", err);
+        assert!(validate(&["--select-only", "--max-gpus", "2"]).is_ok());
+        let err = validate(&["--select-only", "python", "train.py"])
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("--select-only doesn't run a command"),
+            "{}",
+            err
+        );
     }
 
-    let program = &command_parts[0];
-    let args = &command_parts[1..];
+    #[cfg(unix)]
+    #[test]
+    fn test_command_not_started_exit_code() {
+        let exit_code = |program: &str, max_runtime: Option<Duration>| {
+            let err = execute_command(
+                &[program.to_string()],
+                None,
+                &[],
+                &ChildEnv::default(),
+                &Hooks::default(),
+                max_runtime,
+            )
+            .unwrap_err();
+            err.downcast_ref::<CommandNotStarted>()
+                .unwrap_or_else(|| panic!("{:#}", err))
+                .exit_code()
+        };
+        let missing = "/nonexistent/with-gpu-test-command";
+        assert_eq!(exit_code(missing, None), COMMAND_NOT_FOUND_EXIT_CODE);
+        assert_eq!(
+            exit_code(missing, Some(Duration::from_secs(5))),
+            COMMAND_NOT_FOUND_EXIT_CODE
+        );
 
-    let cuda_visible_devices = selection.to_cuda_visible_devices();
+        // Exists, but isn't executable
+        let path = std::env::temp_dir().join(format!("with-gpu-noexec-{}", std::process::id()));
+        std::fs::write(&path, "#!/bin/sh\n").unwrap();
+        let code = exit_code(path.to_str().unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(code, COMMAND_NOT_EXECUTABLE_EXIT_CODE);
+    }
 
     #[cfg(unix)]
-    {
-        let error = Command::new(program)
-            .args(args)
-            .env("CUDA_VISIBLE_DEVICES", cuda_visible_devices)
-            .exec();
+    #[test]
+    fn test_hooks_see_exit_code_and_devices() {
+        let path = std::env::temp_dir().join(format!("with-gpu-hook-{}", std::process::id()));
+        let record = format!(
+            "echo \"$WITH_GPU_EXIT_CODE $WITH_GPU_DEVICES\" > {}",
+            path.display()
+        );
+        let run_program = |command_parts: &[String], hooks: &Hooks| {
+            execute_command(
+                command_parts,
+                Some("1,2"),
+                &[],
+                &ChildEnv::default(),
+                hooks,
+                None,
+            )
+        };
+        let run = |script: &str, hooks: &Hooks| {
+            run_program(
+                &["sh".to_string(), "-c".to_string(), script.to_string()],
+                hooks,
+            )
+        };
 
-        Err(error).context(format!("Failed to execute command: {}", program))
-    }
+        let hooks = Hooks {
+            on_failure: Some(&record),
+            ..Default::default()
+        };
+        let result = run("exit 3", &hooks);
+        assert_eq!(result_exit_code(&result), Some(3));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "3 1,2\n");
+        std::fs::remove_file(&path).unwrap();
 
-    #[cfg(not(unix))]
-    {
-        let status = Command::new(program)
-            .args(args)
-            .env("CUDA_VISIBLE_DEVICES", cuda_visible_devices)
-            .status()
-            .context(format!("Failed to execute command: {}", program))?;
+        // Only the matching hook runs, and a failing hook doesn't change the result
+        assert!(run("exit 0", &hooks).is_ok());
+        assert!(!path.exists());
+        // A command that never started has no outcome for the hooks
+        let missing = ["/nonexistent/with-gpu-test-command".to_string()];
+        let result = run_program(&missing, &hooks);
+        assert_eq!(result_exit_code(&result), None);
+        assert!(!path.exists());
+        let hooks = Hooks {
+            on_success: Some("exit 9"),
+            ..Default::default()
+        };
+        assert!(run("exit 0", &hooks).is_ok());
+    }
 
-        if !status.success() {
-            anyhow::bail!("Command exited with status: {}", status);
+    #[cfg(unix)]
+    #[test]
+    fn test_json_schemas_describe_status_and_selection() {
+        let schemas = json_schemas();
+        let status = &schemas["status"]["properties"];
+        assert_eq!(status["gpus"]["type"], "array");
+        assert!(status.get("summary").is_some());
+        let selection = &schemas["selection"]["properties"];
+        for field in ["gpu_indices", "cuda_visible_devices", "gpus"] {
+            assert!(selection.get(field).is_some(), "missing {}", field);
         }
-        Ok(())
     }
-}
 
-#[cfg(target_os = "macos")]
-fn execute_command_without_gpus(command_parts: &[String]) -> Result<()> {
-    if command_parts.is_empty() {
-        anyhow::bail!("No command specified");
+    #[test]
+    fn test_memory_bar() {
+        let mut gpu = GpuInfo {
+            memory_used_mb: 16800,
+            memory_total_mb: 24000,
+            ..Default::default()
+        };
+        assert_eq!(memory_bar(&gpu, 10, false), "[#######---]");
+
+        let colored = memory_bar(&gpu, 10, true);
+        assert!(
+            colored.starts_with("[\x1b[33m███████\x1b[0m"),
+            "{}",
+            colored
+        );
+        assert!(colored.ends_with("░░░]"), "{}", colored);
+
+        gpu.memory_total_mb = 0;
+        assert_eq!(memory_bar(&gpu, 4, false), "[    ]");
     }
 
-    let program = &command_parts[0];
-    let args = &command_parts[1..];
+    #[test]
+    fn test_set_child_env() {
+        let child_env_of = |child_env: &ChildEnv, devices: Option<&str>| -> Vec<String> {
+            let mut command = Command::new("/usr/bin/env");
+            // As if inherited
+            command.env("CUDA_VISIBLE_DEVICES", "7");
+            let extra_env = [("WITH_GPU_MEMORY_FRACTION", "0.5".to_string())];
+            set_child_env(&mut command, child_env, devices, &extra_env);
+            let output = command.output().unwrap();
+            String::from_utf8(output.stdout)
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect()
+        };
+
+        let cleared = child_env_of(
+            &ChildEnv {
+                clear: true,
+                unset: &["CUDA_VISIBLE_DEVICES".to_string()],
+                keep_visible_devices: false,
+            },
+            Some("1"),
+        );
+        assert_eq!(
+            cleared,
+            vec!["CUDA_VISIBLE_DEVICES=1", "WITH_GPU_MEMORY_FRACTION=0.5"]
+        );
 
-    let error = Command::new(program).args(args).exec();
+        let unset = ["PATH".to_string()];
+        let inherited = child_env_of(
+            &ChildEnv {
+                clear: false,
+                unset: &unset,
+                keep_visible_devices: false,
+            },
+            None,
+        );
+        assert!(!inherited.iter().any(|v| v.starts_with("PATH=")));
+        assert!(!inherited
+            .iter()
+            .any(|v| v.starts_with("CUDA_VISIBLE_DEVICES=")));
+        assert!(inherited.contains(&"WITH_GPU_MEMORY_FRACTION=0.5".to_string()));
+
+        let kept = child_env_of(
+            &ChildEnv {
+                keep_visible_devices: true,
+                ..Default::default()
+            },
+            None,
+        );
+        assert!(kept.contains(&"CUDA_VISIBLE_DEVICES=7".to_string()));
+    }
 
-    Err(error).context(format!("Failed to execute command: {}", program))
+    #[cfg(unix)]
+    #[test]
+    fn test_child_exit_code() {
+        let status = |script: &str| Command::new("sh").args(["-c", script]).status().unwrap();
+        assert_eq!(child_exit_code(status("exit 3")), 3);
+        assert_eq!(
+            child_exit_code(status("kill -TERM $$")),
+            128 + libc::SIGTERM
+        );
+    }
 }
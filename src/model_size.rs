@@ -0,0 +1,146 @@
+//! `--for-model`: size a `--total-memory` request from a model's size.
+//!
+//! The estimate is advisory. Weights are assumed to take 2 bytes per
+//! parameter (fp16/bf16), and `--model-overhead` scales that up for
+//! activations, KV cache, and the like.
+
+use std::fmt;
+use std::str::FromStr;
+
+use with_gpu::{format_index_list, GpuInfo, GpuSelection};
+
+/// Default `--model-overhead`
+pub const DEFAULT_MODEL_OVERHEAD: f64 = 1.2;
+
+/// Bytes per parameter for fp16/bf16 weights
+const BYTES_PER_PARAMETER: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelSize {
+    /// A parameter count, e.g. `70B`
+    Parameters(u64),
+    /// A size in bytes
+    Bytes(u64),
+}
+
+impl FromStr for ModelSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(bytes) = s.parse::<u64>() {
+            return Ok(ModelSize::Bytes(bytes));
+        }
+        let billions = s
+            .strip_suffix(['B', 'b'])
+            .and_then(|n| n.parse::<f64>().ok())
+            .filter(|n| n.is_finite() && *n > 0.0);
+        match billions {
+            Some(n) => Ok(ModelSize::Parameters((n * 1e9).round() as u64)),
+            None => Err(format!(
+                "invalid model size '{}' (expected a parameter count like '70B' or '7.5B', or a size in bytes)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ModelSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ModelSize::Parameters(n) => write!(f, "{}B-parameter model", n as f64 / 1e9),
+            ModelSize::Bytes(bytes) => write!(f, "{} GB model", gigabytes(bytes / (1024 * 1024))),
+        }
+    }
+}
+
+impl ModelSize {
+    fn bytes(self) -> u64 {
+        match self {
+            ModelSize::Parameters(n) => n.saturating_mul(BYTES_PER_PARAMETER),
+            ModelSize::Bytes(bytes) => bytes,
+        }
+    }
+
+    /// Free memory to ask for, in MB, with `overhead` applied
+    pub fn required_mb(self, overhead: f64) -> u64 {
+        (self.bytes() as f64 * overhead / (1024.0 * 1024.0)).ceil() as u64
+    }
+}
+
+/// The reasoning behind a `--for-model` selection, e.g. "70B-parameter
+/// model: need ~157 GB (with 1.2x overhead); selecting 2x 80 GB (GPUs 0-1)"
+pub fn fit_summary(
+    size: ModelSize,
+    overhead: f64,
+    selection: &GpuSelection,
+    gpus: &[GpuInfo],
+) -> String {
+    let totals: Vec<u64> = selection
+        .gpu_indices
+        .iter()
+        .filter_map(|i| gpus.iter().find(|g| g.index == *i))
+        .map(|g| g.memory_total_mb)
+        .collect();
+    let cards = match totals.first() {
+        Some(&first) if totals.iter().all(|&mb| mb == first) => {
+            format!("{}x {} GB", totals.len(), gigabytes(first))
+        }
+        _ => totals
+            .iter()
+            .map(|&mb| format!("{} GB", gigabytes(mb)))
+            .collect::<Vec<_>>()
+            .join(" + "),
+    };
+    format!(
+        "{}: need ~{} GB (with {}x overhead); selecting {} (GPUs {})",
+        size,
+        gigabytes(size.required_mb(overhead)),
+        overhead,
+        cards,
+        format_index_list(&selection.gpu_indices)
+    )
+}
+
+fn gigabytes(mb: u64) -> u64 {
+    (mb as f64 / 1024.0).round() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_model_size() {
+        assert_eq!("70B".parse(), Ok(ModelSize::Parameters(70_000_000_000)));
+        assert_eq!("7.5b".parse(), Ok(ModelSize::Parameters(7_500_000_000)));
+        assert_eq!("1000000".parse(), Ok(ModelSize::Bytes(1_000_000)));
+        assert!("70GB".parse::<ModelSize>().is_err());
+        assert!("B".parse::<ModelSize>().is_err());
+        assert!("-7B".parse::<ModelSize>().is_err());
+    }
+
+    #[test]
+    fn test_fit_summary() {
+        let size = ModelSize::Parameters(70_000_000_000);
+        assert_eq!(size.required_mb(1.0), 133_515);
+
+        let gpus: Vec<GpuInfo> = (0..4)
+            .map(|index| GpuInfo {
+                index,
+                memory_total_mb: 81920,
+                ..Default::default()
+            })
+            .collect();
+        let selection = GpuSelection {
+            gpu_indices: vec![0, 1],
+            gpu_uuids: Vec::new(),
+            all_idle: true,
+            warning: None,
+        };
+        assert_eq!(
+            fit_summary(size, 1.2, &selection, &gpus),
+            "70B-parameter model: need ~156 GB (with 1.2x overhead); selecting 2x 80 GB (GPUs 0-1)"
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! Notifications for `--notify`, sent once `--wait` finds GPUs.
+//!
+//! Notifications are best-effort: a failure prints a warning but never stops
+//! the command from launching.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use with_gpu::format_index_list;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotifyTarget {
+    /// Desktop notification (libnotify on Linux, Notification Center on macOS)
+    Desktop,
+    /// POST a JSON payload to this URL
+    Webhook(String),
+}
+
+impl FromStr for NotifyTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "desktop" {
+            Ok(NotifyTarget::Desktop)
+        } else if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(NotifyTarget::Webhook(s.to_string()))
+        } else {
+            Err(format!(
+                "expected 'desktop' or an http(s):// webhook URL, got '{}'",
+                s
+            ))
+        }
+    }
+}
+
+/// Announce that GPUs became available. Failures are reported as warnings.
+pub fn notify_gpus_available(
+    target: &NotifyTarget,
+    gpu_indices: &[usize],
+    waited: Duration,
+    command: &[String],
+) {
+    let result = match target {
+        NotifyTarget::Desktop => send_desktop(gpu_indices, waited, command),
+        NotifyTarget::Webhook(url) => send_webhook(url, gpu_indices, waited, command),
+    };
+    if let Err(e) = result {
+        eprintln!("Warning: Failed to send notification: {:#}", e);
+    }
+}
+
+fn message(gpu_indices: &[usize], waited: Duration, command: &[String]) -> String {
+    format!(
+        "GPU(s) {} available after {:.0}s; starting: {}",
+        format_index_list(gpu_indices),
+        waited.as_secs_f64(),
+        command.join(" ")
+    )
+}
+
+fn payload(gpu_indices: &[usize], waited: Duration, command: &[String]) -> serde_json::Value {
+    serde_json::json!({
+        "event": "gpus_available",
+        "gpu_indices": gpu_indices,
+        "waited_secs": waited.as_secs_f64(),
+        "command": command,
+    })
+}
+
+fn send_desktop(gpu_indices: &[usize], waited: Duration, command: &[String]) -> Result<()> {
+    notify_rust::Notification::new()
+        .summary("with-gpu: GPUs available")
+        .body(&message(gpu_indices, waited, command))
+        .show()
+        .context("desktop notification failed")?;
+    Ok(())
+}
+
+fn send_webhook(
+    url: &str,
+    gpu_indices: &[usize],
+    waited: Duration,
+    command: &[String],
+) -> Result<()> {
+    ureq::post(url)
+        .timeout(Duration::from_secs(10))
+        .set("Content-Type", "application/json")
+        .send_string(&payload(gpu_indices, waited, command).to_string())
+        .context(format!("webhook POST to {} failed", url))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_notify_target() {
+        assert_eq!("desktop".parse(), Ok(NotifyTarget::Desktop));
+        assert_eq!(
+            "https://example.com/hook".parse(),
+            Ok(NotifyTarget::Webhook(
+                "https://example.com/hook".to_string()
+            ))
+        );
+        assert!("email".parse::<NotifyTarget>().is_err());
+    }
+
+    #[test]
+    fn test_webhook_payload() {
+        let command = vec!["python".to_string(), "train.py".to_string()];
+        let payload = payload(&[1, 3], Duration::from_secs(90), &command);
+        assert_eq!(payload["gpu_indices"], serde_json::json!([1, 3]));
+        assert_eq!(payload["waited_secs"], 90.0);
+        assert_eq!(
+            payload["command"],
+            serde_json::json!(["python", "train.py"])
+        );
+    }
+}
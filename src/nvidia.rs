@@ -1,6 +1,16 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Duration;
+
 use anyhow::Result;
 
-use crate::GpuInfo;
+use crate::allocation::Detection;
+use crate::{GpuInfo, GpuProcess, GpuSource};
+
+#[cfg(not(target_os = "macos"))]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(not(target_os = "macos"))]
+use std::time::Instant;
 
 #[cfg(not(target_os = "macos"))]
 use anyhow::Context;
@@ -8,88 +18,789 @@ use anyhow::Context;
 #[cfg(not(target_os = "macos"))]
 use nvml_wrapper::Nvml;
 
+#[cfg(not(target_os = "macos"))]
+use nvml_wrapper_sys::bindings::NvmlLib;
+
 #[cfg(not(target_os = "macos"))]
 use crate::cuda;
+#[cfg(not(target_os = "macos"))]
+use crate::MemorySource;
 
-pub fn query_gpus() -> Result<Vec<GpuInfo>> {
-    #[cfg(target_os = "macos")]
-    {
-        // On macOS, there are no NVIDIA GPUs - return empty list
-        // This allows the tool to work as a no-op (just execute the command)
-        Ok(vec![])
-    }
+/// How long the parts of the most recent query took, for `--timing`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueryTiming {
+    /// Zero once the NVML handle is being reused
+    pub nvml_init: Duration,
+    /// CUDA memory queries, across all devices
+    pub cuda_memory: Duration,
+    /// NVML per-device queries
+    pub nvml_devices: Duration,
+}
 
+/// Queries the GPUs on this machine through NVML and CUDA. The NVML handle is
+/// initialized on the first query and reused, so polling (`--wait`, `tui`)
+/// doesn't re-initialize NVML every time.
+pub struct NvmlSource {
+    #[cfg(not(target_os = "macos"))]
+    nvml: Option<Nvml>,
+    /// A second handle on the NVML library, for the virtualization mode and
+    /// P2P status queries nvml-wrapper doesn't wrap (`None` if it couldn't be
+    /// loaded)
     #[cfg(not(target_os = "macos"))]
+    raw_nvml: Option<Option<NvmlLib>>,
+    /// Updated by every query; clone the handle before boxing the source
+    pub timing: Rc<Cell<QueryTiming>>,
+    /// Leave graphics processes (e.g. a display server) out of process
+    /// counts, so they don't keep a GPU from looking idle
+    pub ignore_graphics: bool,
+    /// Whether to treat the GPUs as vGPU slices (`--vgpu`)
+    pub vgpu: Detection,
+    /// Fill in `GpuInfo::p2p_peers` (`--require-p2p`)
+    pub query_p2p: bool,
+    /// Mark GPUs whose memory readings aren't stable as unavailable
+    /// (`--paranoid`)
+    pub paranoid: bool,
+    /// Fail the query unless every CUDA device matches an NVML device by PCI
+    /// bus ID (`--strict-identity`)
+    pub strict_identity: bool,
+    /// Never open a CUDA context on a GPU in a compute mode other than
+    /// Default (`--cuda-skip-exclusive`)
+    pub cuda_skip_exclusive: bool,
+}
+
+impl Default for NvmlSource {
+    fn default() -> Self {
+        NvmlSource {
+            #[cfg(not(target_os = "macos"))]
+            nvml: None,
+            #[cfg(not(target_os = "macos"))]
+            raw_nvml: None,
+            timing: Default::default(),
+            ignore_graphics: false,
+            vgpu: Detection::Auto,
+            query_p2p: false,
+            paranoid: false,
+            strict_identity: false,
+            cuda_skip_exclusive: false,
+        }
+    }
+}
+
+impl GpuSource for NvmlSource {
+    fn query_gpus(&mut self) -> Result<Vec<GpuInfo>> {
+        #[cfg(target_os = "macos")]
+        {
+            // On macOS, there are no NVIDIA GPUs - return empty list
+            // This allows the tool to work as a no-op (just execute the command)
+            Ok(vec![])
+        }
+
+        #[cfg(not(target_os = "macos"))]
+        {
+            let mut timing = QueryTiming::default();
+            if self.nvml.is_none() {
+                let start = Instant::now();
+                let nvml = Nvml::init()
+                    .context("Failed to initialize NVML (is the NVIDIA driver installed?)")?;
+                self.nvml = Some(nvml);
+                timing.nvml_init = start.elapsed();
+            }
+            let nvml = self.nvml.as_ref().expect("NVML initialized above");
+            let raw_nvml = if self.vgpu == Detection::Auto || self.query_p2p {
+                self.raw_nvml.get_or_insert_with(load_raw_nvml).as_ref()
+            } else {
+                None
+            };
+            let vgpu = resolve_vgpu(self.vgpu, || {
+                raw_nvml.is_some_and(|lib| detect_vgpu(nvml, lib))
+            });
+            let mut gpus = query_gpus(
+                nvml,
+                self.ignore_graphics,
+                vgpu,
+                self.strict_identity,
+                self.cuda_skip_exclusive,
+                &mut timing,
+            );
+            if self.paranoid {
+                if let Ok(gpus) = &mut gpus {
+                    double_check_memory(nvml, gpus);
+                }
+            }
+            if self.query_p2p {
+                if let (Ok(gpus), Some(lib)) = (&mut gpus, raw_nvml) {
+                    fill_p2p_peers(nvml, lib, gpus);
+                }
+            }
+            self.timing.set(timing);
+            gpus
+        }
+    }
+}
+
+/// The NVIDIA driver version, as NVML reports it (for `with-gpu probe`)
+#[cfg(not(target_os = "macos"))]
+pub fn driver_version() -> Result<String> {
+    let nvml = Nvml::init().context("Failed to initialize NVML")?;
+    nvml.sys_driver_version()
+        .context("Failed to get driver version")
+}
+
+/// The number of GPUs NVML sees (for `with-gpu probe`)
+#[cfg(not(target_os = "macos"))]
+pub fn device_count() -> Result<u32> {
+    let nvml = Nvml::init().context("Failed to initialize NVML")?;
+    nvml.device_count().context("Failed to get GPU count")
+}
+
+#[cfg(not(target_os = "macos"))]
+fn query_gpus(
+    nvml: &Nvml,
+    ignore_graphics: bool,
+    vgpu: bool,
+    strict_identity: bool,
+    cuda_skip_exclusive: bool,
+    timing: &mut QueryTiming,
+) -> Result<Vec<GpuInfo>> {
+    let device_count = nvml.device_count().context("Failed to get GPU count")?;
+
+    // Query CUDA memory for all devices upfront
+    // This gives us accurate memory usage that NVML may miss. On a vGPU,
+    // creating a CUDA context behaves differently and NVML already reports
+    // the slice's memory, so use NVML alone.
+    let start = Instant::now();
+    let cuda::CudaMemory {
+        device_count: cuda_device_count,
+        devices: cuda_memory,
+    } = if vgpu {
+        Default::default()
+    } else {
+        let skip = if cuda_skip_exclusive {
+            exclusive_addresses(nvml, device_count)
+        } else {
+            Vec::new()
+        };
+        cuda::query_all_device_memory(&skip).unwrap_or_default()
+    };
+    timing.cuda_memory = start.elapsed();
+
+    if strict_identity && !cuda_memory.is_empty() {
+        let nvml_addresses: Vec<Option<cuda::PciAddress>> = (0..device_count)
+            .map(|i| {
+                let pci = nvml.device_by_index(i).and_then(|device| device.pci_info());
+                pci.ok().map(|pci| (pci.domain, pci.bus, pci.device))
+            })
+            .collect();
+        check_device_identity(&cuda_memory, &nvml_addresses)?;
+    }
+    if cuda_device_count > 0 && cuda_device_count != device_count as usize {
+        warn_device_count_mismatch(cuda_device_count, device_count as usize);
+    }
+
+    let start = Instant::now();
+    let mut gpus = Vec::new();
+    for i in 0..device_count {
+        // A device that fell off the bus (e.g. Xid 79) may still enumerate but
+        // fail every query; list it as unavailable instead of failing outright
+        match query_device(
+            nvml,
+            i,
+            device_count,
+            &cuda_memory,
+            cuda_device_count,
+            ignore_graphics,
+        ) {
+            Ok(gpu) => gpus.push(GpuInfo { vgpu, ..gpu }),
+            Err(e) => gpus.push(GpuInfo::unavailable(i as usize, format!("{:#}", e))),
+        }
+    }
+    timing.nvml_devices = start.elapsed();
+
+    if !gpus.is_empty() && gpus.iter().all(|g| g.error.is_some()) {
+        let errors: Vec<String> = gpus.iter().map(|g| g.to_string()).collect();
+        anyhow::bail!("Failed to query every GPU: {}", errors.join("; "));
+    }
+
+    Ok(gpus)
+}
+
+/// PCI locations of the GPUs whose compute mode isn't Default (exclusive or
+/// prohibited), for `--cuda-skip-exclusive`. GPUs that don't report their
+/// mode or location aren't included.
+#[cfg(not(target_os = "macos"))]
+fn exclusive_addresses(nvml: &Nvml, device_count: u32) -> Vec<cuda::PciAddress> {
+    use nvml_wrapper::enum_wrappers::device::ComputeMode;
+
+    (0..device_count)
+        .filter_map(|i| {
+            let device = nvml.device_by_index(i).ok()?;
+            if device.compute_mode().ok()? == ComputeMode::Default {
+                return None;
+            }
+            let pci = device.pci_info().ok()?;
+            Some((pci.domain, pci.bus, pci.device))
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "macos"))]
+fn query_device(
+    nvml: &Nvml,
+    i: u32,
+    device_count: u32,
+    cuda_memory: &[cuda::CudaMemoryInfo],
+    cuda_device_count: usize,
+    ignore_graphics: bool,
+) -> Result<GpuInfo> {
+    let device = nvml
+        .device_by_index(i)
+        .context(format!("Failed to get GPU {}", i))?;
+
+    // Get NVML memory info as fallback
+    let nvml_memory_info = device
+        .memory_info()
+        .context(format!("Failed to get memory info for GPU {}", i))?;
+
+    // Prefer CUDA memory info if available (more accurate)
+    let pci_address = device
+        .pci_info()
+        .ok()
+        .map(|pci| (pci.domain, pci.bus, pci.device));
+    let cuda_info = match_cuda_device(
+        cuda_memory,
+        i as usize,
+        pci_address,
+        cuda_device_count,
+        device_count as usize,
+    );
+    let (memory_used_mb, memory_total_mb, memory_source) = if let Some(cuda_info) = cuda_info {
+        (
+            cuda_info.used_mb(),
+            cuda_info.total_mb(),
+            MemorySource::Cuda,
+        )
+    } else {
+        // Fallback to NVML if CUDA query failed for this device
+        (
+            nvml_memory_info.used / (1024 * 1024),
+            nvml_memory_info.total / (1024 * 1024),
+            MemorySource::Nvml,
+        )
+    };
+
+    let uuid = device
+        .uuid()
+        .context(format!("Failed to get UUID for GPU {}", i))?;
+
+    let utilization = device
+        .utilization_rates()
+        .context(format!("Failed to get utilization for GPU {}", i))?;
+
+    // Not every board reports temperature; treat that as unknown
+    let temperature_c = device
+        .temperature(nvml_wrapper::enum_wrappers::device::TemperatureSensor::Gpu)
+        .ok();
+
+    // Boards that don't support the query count as not throttled
+    let throttle_reasons = device
+        .current_throttle_reasons()
+        .map(|reasons| reasons.bits())
+        .unwrap_or(0);
+
+    let compute_infos = device
+        .running_compute_processes()
+        .context(format!("Failed to get process info for GPU {}", i))?;
+    // Not every board (or driver) reports graphics processes; treat that as none
+    let graphics_infos = device.running_graphics_processes().unwrap_or_default();
+    // Whatever kept the device from giving CUDA a context has exited
+    if compute_infos.is_empty() {
+        cuda::retry_unavailable_device(i as usize, pci_address);
+    }
+
+    let index = i as usize;
+    let utilization_percent = clamp_percent(utilization.gpu);
+    let memory_utilization_percent = clamp_percent(utilization.memory);
+    let to_process =
+        |p: &nvml_wrapper::struct_wrappers::device::ProcessInfo, graphics: bool| GpuProcess {
+            pid: p.pid,
+            used_memory_mb: match p.used_gpu_memory {
+                nvml_wrapper::enums::device::UsedGpuMemory::Used(bytes) => {
+                    Some(bytes / (1024 * 1024))
+                }
+                nvml_wrapper::enums::device::UsedGpuMemory::Unavailable => None,
+            },
+            name: nvml.sys_process_name(p.pid, PROCESS_NAME_LENGTH).ok(),
+            user: process_user(p.pid),
+            graphics,
+        };
+    let mut processes = merge_processes(
+        compute_infos.iter().map(|p| to_process(p, false)).collect(),
+        graphics_infos.iter().map(|p| to_process(p, true)).collect(),
+    );
+
+    // Sum memory attributed to visible processes (from NVML), ignored graphics
+    // processes included, so their memory doesn't show up as hidden usage
+    let attributed_memory_mb: u64 = processes.iter().filter_map(|p| p.used_memory_mb).sum();
+
+    if ignore_graphics {
+        processes.retain(|p| !p.graphics);
+    }
+    let process_count = processes.len();
+    let pids = processes.iter().map(|p| p.pid).collect();
+
+    // Hidden usage is total used minus attributed (clamp negative/rounding noise to zero)
+    // Now uses CUDA memory which is more accurate than NVML
+    let hidden_usage_mb = memory_used_mb.saturating_sub(attributed_memory_mb);
+
+    Ok(GpuInfo {
+        index,
+        uuid,
+        memory_used_mb,
+        memory_total_mb,
+        utilization_percent,
+        memory_utilization_percent,
+        temperature_c,
+        process_count,
+        pids,
+        processes,
+        // Filled in by usage::IdleTrackingSource
+        idle_secs: None,
+        attributed_memory_mb,
+        hidden_usage_mb,
+        memory_source,
+        throttle_reasons,
+        // Set by query_gpus
+        vgpu: false,
+        // Set by fill_p2p_peers
+        p2p_peers: Vec::new(),
+        error: None,
+    })
+}
+
+/// How long `--paranoid` waits between its two memory readings
+#[cfg(not(target_os = "macos"))]
+const DOUBLE_CHECK_GAP: Duration = Duration::from_millis(50);
+
+/// How far apart, as a percentage of total memory, `--paranoid`'s two used
+/// memory readings may be. Real workloads rarely move this much in 50 ms; a
+/// card mid-reset reports garbage.
+#[cfg(not(target_os = "macos"))]
+const DOUBLE_CHECK_TOLERANCE_PERCENT: u64 = 10;
+
+/// Read each GPU's memory from NVML twice, `DOUBLE_CHECK_GAP` apart, and mark
+/// GPUs whose readings fail or disagree as unavailable. A device being reset
+/// or initialized can answer one query with a plausible-looking but wrong
+/// amount of free memory, so a single successful query isn't enough.
+#[cfg(not(target_os = "macos"))]
+fn double_check_memory(nvml: &Nvml, gpus: &mut [GpuInfo]) {
+    let read = |index: usize| {
+        nvml.device_by_index(index as u32)
+            .and_then(|device| device.memory_info())
+            .map(|info| (info.used / (1024 * 1024), info.total / (1024 * 1024)))
+            .map_err(|e| format!("memory re-read failed: {}", e))
+    };
+    let first: Vec<_> = gpus
+        .iter()
+        .map(|gpu| gpu.error.is_none().then(|| read(gpu.index)))
+        .collect();
+    if first.iter().all(Option::is_none) {
+        return;
+    }
+    std::thread::sleep(DOUBLE_CHECK_GAP);
+    for (gpu, first) in gpus.iter_mut().zip(first) {
+        let Some(first) = first else { continue };
+        let checked = first.and_then(|first| {
+            let second = read(gpu.index)?;
+            check_memory_readings(first, second)
+        });
+        if let Err(e) = checked {
+            *gpu = GpuInfo::unavailable(gpu.index, format!("{} (the GPU may be resetting)", e));
+        }
+    }
+}
+
+/// Whether two `(used, total)` memory readings, in MB, could both be right
+#[cfg(not(target_os = "macos"))]
+fn check_memory_readings(first: (u64, u64), second: (u64, u64)) -> Result<(), String> {
+    let describe = |(used, total): (u64, u64)| format!("{}/{} MB", used, total);
+    let plausible = |(used, total): (u64, u64)| total > 0 && used <= total;
+    let tolerance_mb = first.1 * DOUBLE_CHECK_TOLERANCE_PERCENT / 100;
+    if !plausible(first)
+        || !plausible(second)
+        || first.1 != second.1
+        || first.0.abs_diff(second.0) > tolerance_mb
     {
-        let nvml =
-            Nvml::init().context("Failed to initialize NVML (is the NVIDIA driver installed?)")?;
-
-        let device_count = nvml.device_count().context("Failed to get GPU count")?;
-
-        // Query CUDA memory for all devices upfront
-        // This gives us accurate memory usage that NVML may miss
-        let cuda_memory = cuda::query_all_device_memory().unwrap_or_default();
-
-        let mut gpus = Vec::new();
-        for i in 0..device_count {
-            let device = nvml
-                .device_by_index(i)
-                .context(format!("Failed to get GPU {}", i))?;
-
-            // Get NVML memory info as fallback
-            let nvml_memory_info = device
-                .memory_info()
-                .context(format!("Failed to get memory info for GPU {}", i))?;
-
-            // Prefer CUDA memory info if available (more accurate)
-            let (memory_used_mb, memory_total_mb) =
-                if let Some(cuda_info) = cuda_memory.iter().find(|m| m.device_index == i as usize) {
-                    (cuda_info.used_mb(), cuda_info.total_mb())
-                } else {
-                    // Fallback to NVML if CUDA query failed for this device
-                    (
-                        nvml_memory_info.used / (1024 * 1024),
-                        nvml_memory_info.total / (1024 * 1024),
-                    )
-                };
+        return Err(format!(
+            "inconsistent memory readings: {} then {}",
+            describe(first),
+            describe(second)
+        ));
+    }
+    Ok(())
+}
+
+/// The NVML library names to try, in order. Driver packages always install
+/// the versioned `libnvidia-ml.so.1`; the unversioned name usually comes only
+/// with development packages.
+#[cfg(all(not(target_os = "macos"), not(windows)))]
+const NVML_LIB_NAMES: [&str; 2] = ["libnvidia-ml.so.1", "libnvidia-ml.so"];
+#[cfg(windows)]
+const NVML_LIB_NAMES: [&str; 1] = ["nvml.dll"];
+
+/// Load the NVML library for the queries nvml-wrapper doesn't expose, or
+/// `None` if no name in `NVML_LIB_NAMES` loads
+#[cfg(not(target_os = "macos"))]
+fn load_raw_nvml() -> Option<NvmlLib> {
+    NVML_LIB_NAMES
+        .iter()
+        .find_map(|name| unsafe { NvmlLib::new(*name) }.ok())
+}
+
+/// Whether to treat the GPUs as vGPU slices under `--vgpu mode`. `detect`
+/// only runs for `auto`.
+#[cfg(not(target_os = "macos"))]
+fn resolve_vgpu(mode: Detection, detect: impl FnOnce() -> bool) -> bool {
+    match mode {
+        Detection::On => true,
+        Detection::Off => false,
+        Detection::Auto => detect(),
+    }
+}
+
+/// Whether NVML reports any device in vGPU (guest) virtualization mode.
+/// Drivers without the query count as not virtualized.
+#[cfg(not(target_os = "macos"))]
+fn detect_vgpu(nvml: &Nvml, lib: &NvmlLib) -> bool {
+    use nvml_wrapper_sys::bindings::{
+        nvmlGpuVirtualizationMode_NVML_GPU_VIRTUALIZATION_MODE_VGPU as MODE_VGPU,
+        nvmlGpuVirtualizationMode_t, nvmlReturn_enum_NVML_SUCCESS as SUCCESS,
+    };
+
+    if lib.nvmlDeviceGetVirtualizationMode.is_err() {
+        return false;
+    }
+    let device_count = nvml.device_count().unwrap_or(0);
+    (0..device_count).any(|i| {
+        let Ok(device) = nvml.device_by_index(i) else {
+            return false;
+        };
+        let mut mode: nvmlGpuVirtualizationMode_t = 0;
+        let status = unsafe { lib.nvmlDeviceGetVirtualizationMode(device.handle(), &mut mode) };
+        status == SUCCESS && mode == MODE_VGPU
+    })
+}
 
-            let utilization = device
-                .utilization_rates()
-                .context(format!("Failed to get utilization for GPU {}", i))?;
+/// Record in each GPU's `p2p_peers` the other GPUs it reports peer read
+/// access to. Pairs the driver can't answer for count as not connected.
+#[cfg(not(target_os = "macos"))]
+fn fill_p2p_peers(nvml: &Nvml, lib: &NvmlLib, gpus: &mut [GpuInfo]) {
+    use nvml_wrapper_sys::bindings::{
+        nvmlGpuP2PCapsIndex_enum_NVML_P2P_CAPS_INDEX_READ as CAPS_READ,
+        nvmlGpuP2PStatus_enum_NVML_P2P_STATUS_OK as STATUS_OK, nvmlGpuP2PStatus_t,
+        nvmlReturn_enum_NVML_SUCCESS as SUCCESS,
+    };
 
-            let process_infos = device
-                .running_compute_processes()
-                .context(format!("Failed to get process info for GPU {}", i))?;
+    if lib.nvmlDeviceGetP2PStatus.is_err() {
+        return;
+    }
+    let handles: Vec<_> = gpus
+        .iter()
+        .map(|gpu| {
+            (gpu.error.is_none())
+                .then(|| nvml.device_by_index(gpu.index as u32).ok())
+                .flatten()
+                .map(|device| unsafe { device.handle() })
+        })
+        .collect();
+    for i in 0..gpus.len() {
+        let Some(handle) = handles[i] else { continue };
+        let peers = (0..gpus.len())
+            .filter(|&j| j != i)
+            .filter(|&j| {
+                let Some(other) = handles[j] else {
+                    return false;
+                };
+                let mut status: nvmlGpuP2PStatus_t = 0;
+                let result =
+                    unsafe { lib.nvmlDeviceGetP2PStatus(handle, other, CAPS_READ, &mut status) };
+                result == SUCCESS && status == STATUS_OK
+            })
+            .map(|j| gpus[j].index)
+            .collect();
+        gpus[i].p2p_peers = peers;
+    }
+}
 
-            let index = i as usize;
-            let utilization_percent = utilization.gpu as u8;
-            let process_count = process_infos.len();
+/// The CUDA memory entry for NVML device `index`. CUDA numbers only the
+/// devices `CUDA_VISIBLE_DEVICES` exposes, in its own order, while NVML numbers
+/// them all, so match on PCI location. Falling back to the index is only safe
+/// when the two see the same number of devices. `cuda_device_count` counts
+/// every CUDA device, including any `cuda_memory` has no entry for (e.g. one
+/// that refused a context), so such a gap doesn't rule out the fallback.
+#[cfg(not(target_os = "macos"))]
+fn match_cuda_device(
+    cuda_memory: &[cuda::CudaMemoryInfo],
+    index: usize,
+    pci_address: Option<cuda::PciAddress>,
+    cuda_device_count: usize,
+    nvml_device_count: usize,
+) -> Option<&cuda::CudaMemoryInfo> {
+    let all_located = cuda_memory.iter().all(|m| m.pci_address.is_some());
+    match pci_address {
+        Some(address) if all_located => cuda_memory.iter().find(|m| m.pci_address == Some(address)),
+        _ if cuda_device_count == nvml_device_count => {
+            cuda_memory.iter().find(|m| m.device_index == index)
+        }
+        _ => None,
+    }
+}
 
-            // Sum memory attributed to visible processes (from NVML)
-            let attributed_memory_mb: u64 = process_infos
+/// For `--strict-identity`: fail unless every CUDA device and every NVML
+/// device reports its PCI location and each CUDA device's location matches
+/// an NVML device's. NVML devices CUDA can't see (e.g. outside
+/// `CUDA_VISIBLE_DEVICES`) are fine; they report NVML's memory numbers.
+#[cfg(not(target_os = "macos"))]
+fn check_device_identity(
+    cuda_memory: &[cuda::CudaMemoryInfo],
+    nvml_addresses: &[Option<cuda::PciAddress>],
+) -> Result<()> {
+    let unlocated: Vec<String> = cuda_memory
+        .iter()
+        .filter(|m| m.pci_address.is_none())
+        .map(|m| format!("CUDA device {}", m.device_index))
+        .chain(
+            nvml_addresses
                 .iter()
-                .filter_map(|p| match p.used_gpu_memory {
-                    nvml_wrapper::enums::device::UsedGpuMemory::Used(bytes) => {
-                        Some(bytes / (1024 * 1024))
-                    }
-                    nvml_wrapper::enums::device::UsedGpuMemory::Unavailable => None,
-                })
-                .sum();
-
-            // Hidden usage is total used minus attributed (clamp negative/rounding noise to zero)
-            // Now uses CUDA memory which is more accurate than NVML
-            let hidden_usage_mb = memory_used_mb.saturating_sub(attributed_memory_mb);
-
-            gpus.push(GpuInfo {
-                index,
-                memory_used_mb,
-                memory_total_mb,
-                utilization_percent,
-                process_count,
-                hidden_usage_mb,
-            });
+                .enumerate()
+                .filter(|(_, address)| address.is_none())
+                .map(|(index, _)| format!("GPU {}", index)),
+        )
+        .collect();
+    if !unlocated.is_empty() {
+        anyhow::bail!(
+            "--strict-identity: can't match CUDA and NVML devices, since {} didn't report a PCI bus ID",
+            unlocated.join(", ")
+        );
+    }
+    let unmatched: Vec<String> = cuda_memory
+        .iter()
+        .filter(|m| !nvml_addresses.contains(&m.pci_address))
+        .map(|m| {
+            let (domain, bus, device) = m.pci_address.expect("checked above");
+            format!(
+                "CUDA device {} ({:04x}:{:02x}:{:02x})",
+                m.device_index, domain, bus, device
+            )
+        })
+        .collect();
+    if !unmatched.is_empty() {
+        anyhow::bail!(
+            "--strict-identity: no NVML device has the PCI bus ID of {}",
+            unmatched.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "macos"))]
+static WARNED_DEVICE_COUNT_MISMATCH: AtomicBool = AtomicBool::new(false);
+
+/// Print (once per process) that CUDA and NVML disagree on the GPU count
+#[cfg(not(target_os = "macos"))]
+fn warn_device_count_mismatch(cuda_count: usize, nvml_count: usize) {
+    if WARNED_DEVICE_COUNT_MISMATCH.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let visible = std::env::var("CUDA_VISIBLE_DEVICES")
+        .map(|value| format!(" (CUDA_VISIBLE_DEVICES={})", value))
+        .unwrap_or_default();
+    eprintln!(
+        "Warning: CUDA sees {} GPU(s) but NVML sees {}{}; GPUs CUDA can't see, or can't be matched to by PCI bus ID, report NVML's memory numbers",
+        cuda_count, nvml_count, visible
+    );
+}
+
+/// Combine a device's compute and graphics processes. A process with both
+/// kinds of context appears in both lists; it's kept once, as compute.
+#[cfg(not(target_os = "macos"))]
+fn merge_processes(mut compute: Vec<GpuProcess>, graphics: Vec<GpuProcess>) -> Vec<GpuProcess> {
+    for process in graphics {
+        if !compute.iter().any(|p| p.pid == process.pid) {
+            compute.push(process);
         }
+    }
+    compute
+}
+
+/// Longest process name to ask NVML for
+#[cfg(not(target_os = "macos"))]
+const PROCESS_NAME_LENGTH: usize = 64;
+
+/// The user owning process `pid`, by name if it has one. `None` when the
+/// process isn't visible here, e.g. from inside a container.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn process_user(pid: u32) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+    let uid = std::fs::metadata(format!("/proc/{}", pid)).ok()?.uid();
+    Some(user_name(uid).unwrap_or_else(|| format!("uid {}", uid)))
+}
+
+#[cfg(not(unix))]
+fn process_user(_pid: u32) -> Option<String> {
+    None
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn user_name(uid: u32) -> Option<String> {
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let status =
+        unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// Convert an NVML utilization reading to a percentage. NVML should report
+/// 0-100, but buggy drivers and some virtual GPUs report more, which a plain
+/// `as u8` cast would wrap around (300 becomes 44).
+#[cfg(not(target_os = "macos"))]
+fn clamp_percent(value: u32) -> u8 {
+    value.min(100) as u8
+}
+
+#[cfg(all(test, not(target_os = "macos")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_vgpu() {
+        let never = || panic!("detection should be skipped");
+        assert!(resolve_vgpu(Detection::On, never));
+        assert!(!resolve_vgpu(Detection::Off, never));
+        assert!(resolve_vgpu(Detection::Auto, || true));
+        assert!(!resolve_vgpu(Detection::Auto, || false));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_process_user() {
+        let uid = unsafe { libc::getuid() };
+        let expected = user_name(uid).unwrap_or_else(|| format!("uid {}", uid));
+        assert_eq!(process_user(std::process::id()), Some(expected));
+        assert_eq!(process_user(u32::MAX), None);
+    }
+
+    #[test]
+    fn test_match_cuda_device_by_pci_address() {
+        let cuda_device = |device_index, bus| cuda::CudaMemoryInfo {
+            device_index,
+            pci_address: Some((0, bus, 0)),
+            free_bytes: 0,
+            total_bytes: 0,
+        };
+        // NVML sees four GPUs on buses 0x10-0x13; CUDA_VISIBLE_DEVICES=3,1
+        // leaves CUDA two, renumbered from 0
+        let cuda_memory = vec![cuda_device(0, 0x13), cuda_device(1, 0x11)];
+        let matched = |index, bus| {
+            match_cuda_device(&cuda_memory, index, Some((0, bus, 0)), 2, 4).map(|m| m.device_index)
+        };
+        assert_eq!(matched(0, 0x10), None);
+        assert_eq!(matched(1, 0x11), Some(1));
+        assert_eq!(matched(2, 0x12), None);
+        assert_eq!(matched(3, 0x13), Some(0));
+
+        // Without PCI locations, the index is only trusted when the counts agree
+        assert_eq!(
+            match_cuda_device(&cuda_memory, 1, None, 2, 4).map(|m| m.device_index),
+            None
+        );
+        assert_eq!(
+            match_cuda_device(&cuda_memory, 1, None, 2, 2).map(|m| m.device_index),
+            Some(1)
+        );
+        // A device CUDA skipped (e.g. in EXCLUSIVE_PROCESS mode) still counts
+        let skipped_first = vec![cuda::CudaMemoryInfo {
+            pci_address: None,
+            ..cuda_device(1, 0x11)
+        }];
+        let by_index =
+            |index| match_cuda_device(&skipped_first, index, None, 2, 2).map(|m| m.device_index);
+        assert_eq!(by_index(0), None);
+        assert_eq!(by_index(1), Some(1));
+    }
+
+    #[test]
+    fn test_check_device_identity() {
+        let cuda_device = |device_index, bus: Option<u32>| cuda::CudaMemoryInfo {
+            device_index,
+            pci_address: bus.map(|bus| (0, bus, 0)),
+            free_bytes: 0,
+            total_bytes: 0,
+        };
+        let nvml = [Some((0, 0x10, 0)), Some((0, 0x11, 0)), Some((0, 0x12, 0))];
+        // CUDA_VISIBLE_DEVICES=2,0
+        let remapped = [cuda_device(0, Some(0x12)), cuda_device(1, Some(0x10))];
+        assert!(check_device_identity(&remapped, &nvml).is_ok());
+
+        let stray = [cuda_device(0, Some(0x12)), cuda_device(1, Some(0x20))];
+        let err = check_device_identity(&stray, &nvml).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--strict-identity: no NVML device has the PCI bus ID of CUDA device 1 (0000:20:00)"
+        );
+
+        let unlocated = [cuda_device(0, None)];
+        let err = check_device_identity(&unlocated, &nvml).unwrap_err();
+        assert!(
+            err.to_string().contains("CUDA device 0 didn't report"),
+            "{}",
+            err
+        );
+        let err = check_device_identity(&remapped, &[Some((0, 0x10, 0)), None]).unwrap_err();
+        assert!(err.to_string().contains("GPU 1 didn't report"), "{}", err);
+    }
+
+    #[test]
+    fn test_check_memory_readings() {
+        assert!(check_memory_readings((1000, 81920), (1000, 81920)).is_ok());
+        assert!(check_memory_readings((1000, 81920), (5000, 81920)).is_ok());
+        assert_eq!(
+            check_memory_readings((1000, 81920), (70000, 81920)),
+            Err("inconsistent memory readings: 1000/81920 MB then 70000/81920 MB".to_string())
+        );
+        assert!(check_memory_readings((1000, 81920), (1000, 40960)).is_err());
+        assert!(check_memory_readings((0, 0), (0, 0)).is_err());
+        assert!(check_memory_readings((90000, 81920), (90000, 81920)).is_err());
+    }
+
+    #[test]
+    fn test_merge_processes() {
+        let process = |pid, graphics| GpuProcess {
+            pid,
+            used_memory_mb: Some(100),
+            graphics,
+            ..Default::default()
+        };
+        let merged = merge_processes(
+            vec![process(10, false), process(20, false)],
+            vec![process(20, true), process(30, true)],
+        );
+        assert_eq!(
+            merged,
+            vec![process(10, false), process(20, false), process(30, true)]
+        );
+    }
 
-        Ok(gpus)
+    #[test]
+    fn test_clamp_percent() {
+        assert_eq!(clamp_percent(0), 0);
+        assert_eq!(clamp_percent(73), 73);
+        assert_eq!(clamp_percent(100), 100);
+        assert_eq!(clamp_percent(300), 100);
+        assert_eq!(clamp_percent(u32::MAX), 100);
     }
 }
@@ -0,0 +1,181 @@
+//! `with-gpu probe`: checks that `with-gpu` can reach everything it depends on
+//! (NVML, the CUDA driver, the lock directory) and prints a checklist with
+//! hints for whatever fails.
+
+use std::io::IsTerminal;
+
+use anyhow::Result;
+
+use with_gpu::lockfile;
+
+#[cfg(not(target_os = "macos"))]
+use crate::{cuda, nvidia};
+
+enum Outcome {
+    Pass(String),
+    Fail(String),
+    Skipped(&'static str),
+}
+
+struct Check {
+    name: &'static str,
+    outcome: Outcome,
+    /// What to try when the check fails
+    hint: &'static str,
+    /// Whether `with-gpu` can't select GPUs at all without this
+    critical: bool,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<String>, hint: &'static str) -> Self {
+        let outcome = match result {
+            Ok(detail) => Outcome::Pass(detail),
+            Err(e) => Outcome::Fail(format!("{:#}", e)),
+        };
+        Self {
+            name,
+            outcome,
+            hint,
+            critical: false,
+        }
+    }
+
+    fn critical(self) -> Self {
+        Self {
+            critical: true,
+            ..self
+        }
+    }
+
+    fn format(&self, color: bool) -> String {
+        let paint = |code: &str, text: &str| {
+            if color {
+                format!("\x1b[{}m{}\x1b[0m", code, text)
+            } else {
+                text.to_string()
+            }
+        };
+        match &self.outcome {
+            Outcome::Pass(detail) => format!("{} {}: {}", paint("32", "✓"), self.name, detail),
+            Outcome::Skipped(reason) => format!("- {}: skipped ({})", self.name, reason),
+            Outcome::Fail(error) => {
+                let mark = if self.critical {
+                    paint("31", "✗")
+                } else {
+                    paint("33", "!")
+                };
+                format!("{} {}: {}\n    {}", mark, self.name, error, self.hint)
+            }
+        }
+    }
+}
+
+fn run_checks() -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    #[cfg(target_os = "macos")]
+    {
+        checks.push(Check {
+            name: "NVIDIA driver",
+            outcome: Outcome::Skipped("macOS has no NVIDIA GPUs; commands run without selection"),
+            hint: "",
+            critical: false,
+        });
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        let nvml = Check::new(
+            "NVML",
+            nvidia::driver_version().map(|version| format!("driver {}", version)),
+            "Is the NVIDIA driver installed? Check that `nvidia-smi` works.",
+        )
+        .critical();
+        let nvml_ok = matches!(nvml.outcome, Outcome::Pass(_));
+        checks.push(nvml);
+
+        if nvml_ok {
+            checks.push(
+                Check::new(
+                    "GPUs",
+                    nvidia::device_count().and_then(|count| match count {
+                        0 => anyhow::bail!("NVML found no GPUs"),
+                        n => Ok(format!("{} found", n)),
+                    }),
+                    "Check that the GPUs are visible to this machine or container.",
+                )
+                .critical(),
+            );
+        } else {
+            checks.push(Check {
+                name: "GPUs",
+                outcome: Outcome::Skipped("NVML unavailable"),
+                hint: "",
+                critical: true,
+            });
+        }
+
+        checks.push(Check::new(
+            "CUDA driver",
+            cuda::device_count().map(|count| format!("{} device(s)", count)),
+            "libcuda couldn't be loaded; memory figures fall back to NVML, which can be stale.",
+        ));
+    }
+
+    checks.push(Check::new(
+        "Lock directory",
+        lockfile::check_lock_dir_writable()
+            .map(|()| format!("{} is writable", lockfile::lock_dir().display()))
+            .map_err(anyhow::Error::from),
+        "GPUs can't be claimed, so concurrent jobs may collide. Fix the directory's \
+         permissions, or pass --no-lock to run without claims.",
+    ));
+
+    checks
+}
+
+/// Whether any critical check failed
+fn critical_failure(checks: &[Check]) -> bool {
+    checks
+        .iter()
+        .any(|c| c.critical && !matches!(c.outcome, Outcome::Pass(_)))
+}
+
+/// Run the checks and print the checklist. Fails if a critical check failed.
+pub fn run() -> Result<()> {
+    let checks = run_checks();
+    let color = std::io::stdout().is_terminal();
+    for check in &checks {
+        println!("{}", check.format(color));
+    }
+    if critical_failure(&checks) {
+        anyhow::bail!("with-gpu can't select GPUs on this machine (see above)");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_and_critical_failure() {
+        let lock = Check::new(
+            "Lock directory",
+            Err(anyhow::anyhow!("Permission denied")),
+            "pass --no-lock",
+        );
+        assert_eq!(
+            lock.format(false),
+            "! Lock directory: Permission denied\n    pass --no-lock"
+        );
+        let nvml = Check::new("NVML", Ok("driver 550.54".to_string()), "").critical();
+        assert_eq!(nvml.format(false), "✓ NVML: driver 550.54");
+
+        // A non-critical failure doesn't fail the probe
+        let mut checks = vec![nvml, lock];
+        assert!(!critical_failure(&checks));
+        checks.push(Check::new("GPUs", Err(anyhow::anyhow!("none")), "").critical());
+        assert!(critical_failure(&checks));
+    }
+}
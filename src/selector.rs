@@ -1,14 +1,57 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
 use anyhow::{Context, Result};
+use serde::Serialize;
 
 use crate::lockfile;
-use with_gpu::{GpuInfo, GpuSelection, HIDDEN_USAGE_THRESHOLD_MB};
+use crate::{GpuInfo, GpuSelection, NoiseModel};
+
+/// Default minimum free memory: enough for PyTorch initialization plus a
+/// typical model (see DESIGN.md)
+pub const DEFAULT_MIN_MEMORY_MB: u64 = 2048;
 
 pub struct SelectionCriteria {
     pub min_gpus: usize,
     pub max_gpus: usize,
     pub require_idle: bool,
     pub min_memory_mb: Option<u64>,
+    /// Upper bound on *total* (not free) memory, to keep small jobs off big cards
+    pub max_memory_mb: Option<u64>,
     pub max_utilization: Option<u8>,
+    /// Upper bound on memory-controller (bandwidth) utilization
+    pub max_memory_utilization: Option<u8>,
+    /// Skip GPUs whose clocks are in a slowdown state (`GpuInfo::throttle_summary`)
+    pub avoid_throttled: bool,
+    /// Only consider GPUs that have been idle at least this long
+    /// (`GpuInfo::idle_secs`)
+    pub min_idle_secs: Option<u64>,
+    /// Select the fewest GPUs whose combined free memory reaches this target,
+    /// instead of selecting by count (`max_gpus` still caps the count)
+    pub total_memory_mb: Option<u64>,
+    /// With `total_memory_mb`, only combine GPUs that all have peer-to-peer
+    /// access to each other (`GpuInfo::p2p_peers`)
+    pub require_p2p: bool,
+    /// Run on fewer than `min_gpus` GPUs (but at least one) with a warning,
+    /// instead of failing
+    pub best_effort: bool,
+    /// Don't exclude GPUs for suspected hidden usage, and don't let
+    /// unattributed memory make a GPU count as busy
+    pub allow_hidden_usage: bool,
+    /// Fall back to GPUs in use when too few idle GPUs qualify. When false,
+    /// selection acts as if `require_idle` were set (the error then suggests
+    /// `--include-used`).
+    pub include_used: bool,
+    /// The idle-memory and hidden-usage thresholds
+    pub noise: NoiseModel,
+    /// Skip GPUs claimed through lock files. Disabled when an external
+    /// scheduler (e.g. Slurm) owns allocation.
+    pub use_locks: bool,
+    /// Share GPUs with other `share` claimers (MPS): a GPU another process
+    /// shares stays a candidate, and only exclusive claims rule one out
+    pub share: bool,
 }
 
 impl Default for SelectionCriteria {
@@ -17,123 +60,409 @@ impl Default for SelectionCriteria {
             min_gpus: 1,
             max_gpus: 1,
             require_idle: false,
-            min_memory_mb: Some(2048),
+            min_memory_mb: Some(DEFAULT_MIN_MEMORY_MB),
+            max_memory_mb: None,
             max_utilization: None,
+            max_memory_utilization: None,
+            avoid_throttled: false,
+            min_idle_secs: None,
+            total_memory_mb: None,
+            require_p2p: false,
+            best_effort: false,
+            allow_hidden_usage: false,
+            include_used: true,
+            noise: NoiseModel::default(),
+            use_locks: true,
+            share: false,
         }
     }
 }
 
-pub fn select_gpus(gpus: &[GpuInfo], criteria: &SelectionCriteria) -> Result<GpuSelection> {
-    if gpus.is_empty() {
-        anyhow::bail!("No GPUs detected");
+impl SelectionCriteria {
+    /// The memory to record in our claims for other selections to discount:
+    /// the `min_memory_mb` we expect to need on each selected GPU
+    pub fn reservation_mb(&self) -> Option<u64> {
+        self.min_memory_mb.filter(|&mb| mb > 0)
     }
+}
 
-    // Apply threshold filters and exclude claimed GPUs
-    let filtered_gpus: Vec<&GpuInfo> = gpus
-        .iter()
-        .filter(|gpu| {
-            // Filter out GPUs claimed by other processes
-            if !lockfile::is_gpu_available(gpu.index) {
-                return false;
-            }
-            // Filter out GPUs with hidden memory usage (stale NVML data)
-            if gpu.has_hidden_usage(HIDDEN_USAGE_THRESHOLD_MB) {
-                return false;
+/// Orders the GPUs that passed filtering. Implement this to plug a custom
+/// strategy (e.g. least-recently-used) into `select_gpus`.
+pub trait SelectionPolicy {
+    /// Return the indices (`GpuInfo::index`) of `gpus`, most preferred first.
+    /// Indices that are omitted are never selected.
+    fn rank(&self, gpus: &[&GpuInfo], criteria: &SelectionCriteria) -> Vec<usize>;
+}
+
+/// The default policy: most free memory, then fewest processes, then lowest index.
+pub struct MostFreeMemoryPolicy;
+
+impl SelectionPolicy for MostFreeMemoryPolicy {
+    fn rank(&self, gpus: &[&GpuInfo], _criteria: &SelectionCriteria) -> Vec<usize> {
+        sort_by_most_free_refs(gpus)
+            .iter()
+            .map(|g| g.index)
+            .collect()
+    }
+}
+
+/// Like `MostFreeMemoryPolicy`, but among GPUs with equal free memory and
+/// process counts, prefers the one claimed longest ago (never-claimed first).
+/// This spreads work across identical idle cards instead of always using GPU 0.
+pub struct LeastRecentlyUsedPolicy {
+    /// Last-claimed timestamps by GPU index, e.g. from `usage::last_used`
+    pub last_used: HashMap<usize, u64>,
+}
+
+impl SelectionPolicy for LeastRecentlyUsedPolicy {
+    fn rank(&self, gpus: &[&GpuInfo], _criteria: &SelectionCriteria) -> Vec<usize> {
+        let last_used = |g: &GpuInfo| self.last_used.get(&g.index).copied().unwrap_or(0);
+        sort_by_most_free_then(gpus, |a, b| last_used(a).cmp(&last_used(b)))
+            .iter()
+            .map(|g| g.index)
+            .collect()
+    }
+}
+
+/// Prefers the coolest GPUs, to keep cards within thermal headroom. Ties (and
+/// GPUs that don't report temperature, which rank last) fall back to
+/// `MostFreeMemoryPolicy` ordering.
+pub struct CoolestPolicy;
+
+impl SelectionPolicy for CoolestPolicy {
+    fn rank(&self, gpus: &[&GpuInfo], _criteria: &SelectionCriteria) -> Vec<usize> {
+        let mut sorted = sort_by_most_free_refs(gpus);
+        // Stable sort keeps the most-free-memory order among equal temperatures
+        sorted.sort_by_key(|g| g.temperature_c.unwrap_or(u32::MAX));
+        sorted.iter().map(|g| g.index).collect()
+    }
+}
+
+/// Prefers the physically largest GPUs, for big-model jobs that expect to
+/// have them to themselves: most total memory, then most free memory, then
+/// lowest index
+pub struct LargestMemoryPolicy;
+
+impl SelectionPolicy for LargestMemoryPolicy {
+    fn rank(&self, gpus: &[&GpuInfo], _criteria: &SelectionCriteria) -> Vec<usize> {
+        let mut sorted = gpus.to_vec();
+        sorted.sort_by(|a, b| {
+            b.memory_total_mb
+                .cmp(&a.memory_total_mb)
+                .then_with(|| b.memory_free_mb().cmp(&a.memory_free_mb()))
+                .then_with(|| a.index.cmp(&b.index))
+        });
+        sorted.iter().map(|g| g.index).collect()
+    }
+}
+
+/// Packs jobs instead of spreading them: idle GPUs first, each group in
+/// ascending index order, so the GPUs with the most free memory stay free for
+/// larger jobs
+pub struct FirstFitPolicy;
+
+impl SelectionPolicy for FirstFitPolicy {
+    fn rank(&self, gpus: &[&GpuInfo], criteria: &SelectionCriteria) -> Vec<usize> {
+        let mut sorted = gpus.to_vec();
+        sorted.sort_by_key(|g| (!is_idle(g, criteria), g.index));
+        sorted.iter().map(|g| g.index).collect()
+    }
+}
+
+/// Wraps another policy, ranking the `preferred` GPUs (e.g. those that
+/// qualified on an earlier poll) ahead of the rest. Within each group the
+/// inner policy's order is kept.
+pub struct StickyPolicy<'a> {
+    pub inner: &'a dyn SelectionPolicy,
+    pub preferred: Vec<usize>,
+}
+
+impl SelectionPolicy for StickyPolicy<'_> {
+    fn rank(&self, gpus: &[&GpuInfo], criteria: &SelectionCriteria) -> Vec<usize> {
+        let mut ranked = self.inner.rank(gpus, criteria);
+        // Stable sort keeps the inner order within each group
+        ranked.sort_by_key(|idx| !self.preferred.contains(idx));
+        ranked
+    }
+}
+
+/// Why `select_gpus` couldn't make a selection, for callers that want to
+/// react to the cause rather than display it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelectionError {
+    NoGpusDetected,
+    /// Every matching GPU is claimed by another with-gpu process, given as
+    /// (GPU index, holder PID)
+    AllClaimed {
+        holders: Vec<(usize, u32)>,
+    },
+    NoGpusMatchCriteria {
+        reasons: Vec<String>,
+    },
+    /// `require_idle` and too few idle GPUs are unclaimed and meet the filters
+    InsufficientIdle {
+        required: usize,
+        available: usize,
+        /// Idle GPUs before claims and filters
+        idle: usize,
+        gpu_count: usize,
+        /// The active filters, as `filter_descriptions` gives them
+        filters: Vec<String>,
+    },
+    /// `include_used` is off and too few idle GPUs qualify
+    NoIdleGpus {
+        required: usize,
+        available: usize,
+    },
+    InsufficientGpus {
+        required: usize,
+        available: usize,
+    },
+    /// `total_memory_mb` can't be reached; `max_gpus` is set when that limit,
+    /// rather than the GPUs available, is what fell short
+    InsufficientMemory {
+        required_mb: u64,
+        available_mb: u64,
+        gpu_count: usize,
+        max_gpus: Option<usize>,
+    },
+    /// `total_memory_mb` with `require_p2p`: no set of mutually peer-connected
+    /// GPUs reaches the target. `available_mb` is the best such set's total.
+    InsufficientP2pMemory {
+        required_mb: u64,
+        available_mb: u64,
+        gpu_count: usize,
+    },
+    /// Waiting (e.g. `with-gpu --wait`) gave up; `reason` is the last attempt's error
+    Timeout {
+        waited_secs: u64,
+        reason: Box<SelectionError>,
+    },
+}
+
+impl std::fmt::Display for SelectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SelectionError::NoGpusDetected => write!(f, "No GPUs detected"),
+            SelectionError::AllClaimed { holders } => {
+                let list = holders
+                    .iter()
+                    .map(|(idx, pid)| format!("GPU {} by pid {}", idx, pid))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(
+                    f,
+                    "All {} matching GPU(s) are claimed by other with-gpu processes ({}); use --wait to wait for them to finish",
+                    holders.len(),
+                    list
+                )
             }
-            // Filter by minimum free memory
-            if let Some(min_mem) = criteria.min_memory_mb {
-                if gpu.memory_free_mb() < min_mem {
-                    return false;
-                }
+            SelectionError::NoGpusMatchCriteria { reasons } => write!(
+                f,
+                "No GPUs found matching criteria: {} (use --status to see GPU state)",
+                reasons.join(", ")
+            ),
+            SelectionError::InsufficientIdle {
+                required,
+                available,
+                idle,
+                gpu_count,
+                filters,
+            } => {
+                let filter_note = if filters.is_empty() {
+                    String::new()
+                } else {
+                    format!(" ({})", filters.join(", "))
+                };
+                write!(
+                    f,
+                    "Require {} idle GPUs but only {} available: {} of {} GPU(s) idle, {} of those unclaimed and meeting filters{} (use --status to see GPU state)",
+                    required, available, idle, gpu_count, available, filter_note
+                )
             }
-            // Filter by maximum utilization
-            if let Some(max_util) = criteria.max_utilization {
-                if gpu.utilization_percent > max_util {
-                    return false;
-                }
+            SelectionError::NoIdleGpus {
+                required,
+                available,
+            } => write!(
+                f,
+                "Need {} idle GPU(s) but only {} available; pass --include-used to fall back to GPUs in use (use --status to see GPU state)",
+                required, available
+            ),
+            SelectionError::InsufficientGpus {
+                required,
+                available,
+            } => write!(
+                f,
+                "Need {} GPUs but only {} available (use --status to see GPU state)",
+                required, available
+            ),
+            SelectionError::InsufficientMemory {
+                required_mb,
+                available_mb,
+                gpu_count,
+                max_gpus,
+            } => {
+                let limit_note = match max_gpus {
+                    Some(max_gpus) => format!(" (limited by --max-gpus {})", max_gpus),
+                    None => String::new(),
+                };
+                write!(
+                    f,
+                    "Need {} MB free memory in total but {} GPU(s) only have {} MB free{} (use --status to see GPU state)",
+                    required_mb, gpu_count, available_mb, limit_note
+                )
             }
-            true
-        })
-        .collect();
+            SelectionError::InsufficientP2pMemory {
+                required_mb,
+                available_mb,
+                gpu_count,
+            } => write!(
+                f,
+                "Need {} MB free memory in total across GPUs with peer-to-peer access, but the best such set ({} GPU(s)) only has {} MB free (drop --require-p2p to combine GPUs without it)",
+                required_mb, gpu_count, available_mb
+            ),
+            SelectionError::Timeout {
+                waited_secs,
+                reason,
+            } => write!(
+                f,
+                "Timeout after {} seconds waiting for GPUs: {}",
+                waited_secs, reason
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SelectionError {}
+
+pub fn select_gpus(
+    gpus: &[GpuInfo],
+    criteria: &SelectionCriteria,
+    policy: &dyn SelectionPolicy,
+) -> std::result::Result<GpuSelection, SelectionError> {
+    if gpus.is_empty() {
+        return Err(SelectionError::NoGpusDetected);
+    }
+
+    let gpus = &*with_reservations(gpus, criteria);
+
+    // Apply threshold filters and exclude claimed GPUs
+    let filtered_gpus = unclaimed_matching(gpus, criteria);
 
     // Check if filtering left us with no GPUs
     if filtered_gpus.is_empty() {
+        let claimed = blocking_claims(criteria);
+        // GPUs that would qualify if another with-gpu process weren't holding them
+        let claimed_suitable: Vec<(usize, u32)> = gpus
+            .iter()
+            .filter(|g| meets_filters(g, criteria))
+            .filter_map(|g| claimed.iter().find(|(idx, _)| *idx == g.index).copied())
+            .collect();
+
+        // Sibling with-gpu jobs may finish soon, unlike GPUs busy with real work
+        if claimed_suitable.len() == gpus.len() {
+            return Err(SelectionError::AllClaimed {
+                holders: claimed_suitable,
+            });
+        }
+
         let mut reasons = Vec::new();
-        let claimed = lockfile::get_claimed_gpus();
-        if !claimed.is_empty() {
+        if !claimed_suitable.is_empty() {
             reasons.push(format!(
-                "{} GPU(s) claimed by other processes",
-                claimed.len()
+                "{} otherwise-suitable GPU(s) claimed by other with-gpu processes",
+                claimed_suitable.len()
             ));
         }
+        let busy_count = gpus.iter().filter(|g| !meets_filters(g, criteria)).count();
+        reasons.push(format!(
+            "{} GPU(s) busy or not meeting requirements",
+            busy_count
+        ));
         let hidden_count = gpus
             .iter()
-            .filter(|g| g.has_hidden_usage(HIDDEN_USAGE_THRESHOLD_MB))
+            .filter(|g| g.has_hidden_usage(&criteria.noise))
             .count();
-        if hidden_count > 0 {
+        if hidden_count > 0 && !criteria.allow_hidden_usage {
             reasons.push(format!(
                 "{} GPU(s) have suspected hidden memory usage",
                 hidden_count
             ));
         }
-        if let Some(min_mem) = criteria.min_memory_mb {
-            reasons.push(format!("{}+ MB free memory required", min_mem));
-        }
-        if let Some(max_util) = criteria.max_utilization {
-            reasons.push(format!("≤{}% utilization required", max_util));
-        }
-        anyhow::bail!(
-            "No GPUs found matching criteria: {} (use --status to see GPU state)",
-            reasons.join(", ")
-        );
+        reasons.extend(filter_descriptions(criteria));
+        return Err(SelectionError::NoGpusMatchCriteria { reasons });
     }
 
-    let (idle_gpus, _used_gpus) = partition_gpus_refs(&filtered_gpus);
+    let (idle_gpus, _used_gpus) = partition_gpus_refs(&filtered_gpus, criteria);
 
-    // If --require-idle is set, only consider idle GPUs
-    if criteria.require_idle {
-        if idle_gpus.len() < criteria.min_gpus {
-            anyhow::bail!(
-                "Require {} idle GPUs but only {} available (use --status to see GPU state)",
-                criteria.min_gpus,
-                idle_gpus.len()
-            );
+    if let Some(target_mb) = criteria.total_memory_mb {
+        let pool = if idle_only(criteria) {
+            &idle_gpus
+        } else {
+            &filtered_gpus
+        };
+        return select_by_total_memory(pool, target_mb, criteria, policy);
+    }
+
+    // If --require-idle is set (or used GPUs aren't included), only consider
+    // idle GPUs
+    if idle_only(criteria) {
+        // With --best-effort, any idle GPU will do
+        let enough_idle =
+            idle_gpus.len() >= criteria.min_gpus || (criteria.best_effort && !idle_gpus.is_empty());
+        if !enough_idle && !criteria.require_idle {
+            return Err(SelectionError::NoIdleGpus {
+                required: criteria.min_gpus,
+                available: idle_gpus.len(),
+            });
         }
-        // Sort idle GPUs by available memory (most free first)
-        let sorted_idle = sort_by_most_free_refs(&idle_gpus);
+        if !enough_idle {
+            // Break down where the idle GPUs went, so the user can tell whether
+            // to wait or to relax a filter
+            return Err(SelectionError::InsufficientIdle {
+                required: criteria.min_gpus,
+                available: idle_gpus.len(),
+                idle: gpus.iter().filter(|g| is_idle(g, criteria)).count(),
+                gpu_count: gpus.len(),
+                filters: filter_descriptions(criteria),
+            });
+        }
+        let sorted_idle = rank_refs(policy, &idle_gpus, criteria);
         let count = criteria.max_gpus.min(sorted_idle.len());
-        let selected: Vec<usize> = sorted_idle.iter().take(count).map(|g| g.index).collect();
+        let selected: Vec<&GpuInfo> = sorted_idle.iter().take(count).copied().collect();
 
         return Ok(GpuSelection {
-            gpu_indices: selected,
+            warning: count_warning(criteria, selected.len()),
+            gpu_indices: selected.iter().map(|g| g.index).collect(),
+            gpu_uuids: selected.iter().map(|g| g.uuid.clone()).collect(),
             all_idle: true,
-            warning: None,
         });
     }
 
-    // Sort filtered GPUs by available memory (most free first)
-    // This prioritizes available memory over idle status
-    let all_gpus_sorted = sort_by_most_free_refs(&filtered_gpus);
+    // Rank filtered GPUs by the policy (by default, most free memory first,
+    // which prioritizes available memory over idle status)
+    let all_gpus_sorted = rank_refs(policy, &filtered_gpus, criteria);
 
     // Select the requested number of GPUs
     let count = criteria.max_gpus.min(all_gpus_sorted.len());
     let selected_gpus: Vec<&GpuInfo> = all_gpus_sorted.iter().take(count).copied().collect();
 
-    // Check if we have enough GPUs
-    if selected_gpus.len() < criteria.min_gpus {
-        anyhow::bail!(
-            "Need {} GPUs but only {} available (use --status to see GPU state)",
-            criteria.min_gpus,
-            selected_gpus.len()
-        );
+    // Check if we have enough GPUs (with --best-effort, any GPU will do)
+    if selected_gpus.len() < criteria.min_gpus && !criteria.best_effort {
+        return Err(SelectionError::InsufficientGpus {
+            required: criteria.min_gpus,
+            available: selected_gpus.len(),
+        });
     }
 
     // Check if all selected GPUs are idle
-    let all_idle = selected_gpus.iter().all(|g| g.is_idle());
+    let all_idle = selected_gpus.iter().all(|g| is_idle(g, criteria));
 
     // Generate warning if we're using non-idle GPUs
-    let warning = if !all_idle {
-        let non_idle_count = selected_gpus.iter().filter(|g| !g.is_idle()).count();
+    let non_idle_warning = if !all_idle {
+        let non_idle_count = selected_gpus
+            .iter()
+            .filter(|g| !is_idle(g, criteria))
+            .count();
         let idle_count = idle_gpus.len();
         Some(format!(
             "Warning: Using {} non-idle GPU(s) with most available memory (only {} idle GPU(s) available)",
@@ -143,22 +472,536 @@ pub fn select_gpus(gpus: &[GpuInfo], criteria: &SelectionCriteria) -> Result<Gpu
     } else {
         None
     };
-
-    let gpu_indices: Vec<usize> = selected_gpus.iter().map(|g| g.index).collect();
+    let warning = join_warnings(
+        count_warning(criteria, selected_gpus.len()),
+        non_idle_warning,
+    );
 
     Ok(GpuSelection {
-        gpu_indices,
+        gpu_indices: selected_gpus.iter().map(|g| g.index).collect(),
+        gpu_uuids: selected_gpus.iter().map(|g| g.uuid.clone()).collect(),
         all_idle,
         warning,
     })
 }
 
-fn partition_gpus_refs<'a>(gpus: &[&'a GpuInfo]) -> (Vec<&'a GpuInfo>, Vec<&'a GpuInfo>) {
+/// The GPUs that pass the filters and aren't claimed by another process
+fn unclaimed_matching<'a>(gpus: &'a [GpuInfo], criteria: &SelectionCriteria) -> Vec<&'a GpuInfo> {
+    gpus.iter()
+        .filter(|gpu| {
+            // Filter out GPUs claimed by other processes
+            let available = if criteria.share {
+                lockfile::is_gpu_available_shared(gpu.index)
+            } else {
+                lockfile::is_gpu_available(gpu.index)
+            };
+            if criteria.use_locks && !available {
+                return false;
+            }
+            meets_filters(gpu, criteria)
+        })
+        .collect()
+}
+
+/// The claims that rule GPUs out under `criteria`, as (GPU, holder PID). A
+/// shared GPU is listed under its first sharer, unless we're sharing too.
+fn blocking_claims(criteria: &SelectionCriteria) -> Vec<(usize, u32)> {
+    if !criteria.use_locks {
+        return Vec::new();
+    }
+    let mut claims: Vec<(usize, u32)> = lockfile::get_claimed_gpus()
+        .into_iter()
+        .map(|(idx, claim)| (idx, claim.pid))
+        .collect();
+    if !criteria.share {
+        claims.extend(
+            lockfile::get_shared_gpus()
+                .into_iter()
+                .map(|(idx, claims)| (idx, claims[0].pid)),
+        );
+    }
+    claims
+}
+
+/// Memory reserved on each GPU by with-gpu claims (the claimants'
+/// `--min-memory`), which the claimants may not have allocated yet. Only
+/// claims whose lock files carry the reservation field count.
+fn reserved_by_claims_mb(criteria: &SelectionCriteria) -> HashMap<usize, u64> {
+    let mut reserved = HashMap::new();
+    if !criteria.use_locks {
+        return reserved;
+    }
+    let exclusive = lockfile::get_claimed_gpus()
+        .into_iter()
+        .map(|(idx, claim)| (idx, vec![claim]));
+    for (idx, claims) in exclusive.chain(lockfile::get_shared_gpus()) {
+        let mb: u64 = claims.iter().filter_map(|claim| claim.reserved_mb).sum();
+        if mb > 0 {
+            *reserved.entry(idx).or_default() += mb;
+        }
+    }
+    reserved
+}
+
+/// `gpus` with the memory reserved by claims counted as used for as long as
+/// the claims exist, since a claimant that hasn't allocated yet leaves the
+/// GPU's free memory overstated. GPUs the claims rule out keep their
+/// readings, so that errors still count them as otherwise suitable.
+fn with_reservations<'a>(gpus: &'a [GpuInfo], criteria: &SelectionCriteria) -> Cow<'a, [GpuInfo]> {
+    let reserved = reserved_by_claims_mb(criteria);
+    if reserved.is_empty() {
+        return Cow::Borrowed(gpus);
+    }
+    let blocked = blocking_claims(criteria);
+    let gpus = gpus
+        .iter()
+        .map(|gpu| {
+            let mut gpu = gpu.clone();
+            let mb = reserved.get(&gpu.index).copied().unwrap_or(0);
+            if mb > 0 && !blocked.iter().any(|(idx, _)| *idx == gpu.index) {
+                gpu.memory_used_mb = (gpu.memory_used_mb + mb).min(gpu.memory_total_mb);
+            }
+            gpu
+        })
+        .collect();
+    Cow::Owned(gpus)
+}
+
+/// How many GPUs `select_gpus` could choose from: unclaimed, passing the
+/// filters, and idle under `require_idle`. The GPU count limits don't apply.
+pub fn count_qualifying(gpus: &[GpuInfo], criteria: &SelectionCriteria) -> usize {
+    qualifying_indices(gpus, criteria).len()
+}
+
+/// The indices of the GPUs `count_qualifying` counts
+pub fn qualifying_indices(gpus: &[GpuInfo], criteria: &SelectionCriteria) -> Vec<usize> {
+    unclaimed_matching(&with_reservations(gpus, criteria), criteria)
+        .into_iter()
+        .filter(|gpu| !idle_only(criteria) || is_idle(gpu, criteria))
+        .map(|gpu| gpu.index)
+        .collect()
+}
+
+/// Greedily take GPUs in `policy` order until their combined free memory
+/// reaches `target_mb`. With the default policy (most free memory first) this
+/// yields the fewest GPUs that can; other policies may take more.
+fn select_by_total_memory(
+    gpus: &[&GpuInfo],
+    target_mb: u64,
+    criteria: &SelectionCriteria,
+    policy: &dyn SelectionPolicy,
+) -> std::result::Result<GpuSelection, SelectionError> {
+    let max_gpus = criteria.max_gpus;
+    let sorted = rank_refs(policy, gpus, criteria);
+    if criteria.require_p2p {
+        return select_p2p_by_total_memory(&sorted, target_mb, criteria);
+    }
+    let mut selected: Vec<&GpuInfo> = Vec::new();
+    let mut free_mb = 0;
+    for gpu in sorted {
+        if free_mb >= target_mb || selected.len() >= max_gpus {
+            break;
+        }
+        free_mb += gpu.memory_free_mb();
+        selected.push(gpu);
+    }
+
+    if free_mb < target_mb {
+        return Err(SelectionError::InsufficientMemory {
+            required_mb: target_mb,
+            available_mb: free_mb,
+            gpu_count: selected.len(),
+            max_gpus: (selected.len() >= max_gpus && gpus.len() > max_gpus).then_some(max_gpus),
+        });
+    }
+
+    Ok(total_memory_selection(&selected, target_mb, criteria))
+}
+
+/// Like the greedy accumulation in `select_by_total_memory`, but every GPU
+/// added must have mutual peer-to-peer access with those already taken.
+/// Starts from each GPU in turn (in policy order) and keeps the set that
+/// reaches the target with the fewest GPUs, the earliest such start on ties.
+fn select_p2p_by_total_memory(
+    sorted: &[&GpuInfo],
+    target_mb: u64,
+    criteria: &SelectionCriteria,
+) -> std::result::Result<GpuSelection, SelectionError> {
+    let mut best: Option<(Vec<&GpuInfo>, u64)> = None;
+    for (start, first) in sorted.iter().enumerate() {
+        let mut group = vec![*first];
+        let mut free_mb = first.memory_free_mb();
+        for gpu in &sorted[start + 1..] {
+            if free_mb >= target_mb || group.len() >= criteria.max_gpus {
+                break;
+            }
+            if group.iter().all(|member| are_peers(member, gpu)) {
+                free_mb += gpu.memory_free_mb();
+                group.push(gpu);
+            }
+        }
+        let better = match &best {
+            None => true,
+            Some((best_group, best_mb)) => match (free_mb >= target_mb, *best_mb >= target_mb) {
+                (true, false) => true,
+                (true, true) => group.len() < best_group.len(),
+                (false, false) => free_mb > *best_mb,
+                (false, true) => false,
+            },
+        };
+        if better {
+            best = Some((group, free_mb));
+        }
+    }
+
+    match best {
+        Some((group, free_mb)) if free_mb >= target_mb => {
+            Ok(total_memory_selection(&group, target_mb, criteria))
+        }
+        best => Err(SelectionError::InsufficientP2pMemory {
+            required_mb: target_mb,
+            available_mb: best.as_ref().map_or(0, |(_, mb)| *mb),
+            gpu_count: best.as_ref().map_or(0, |(group, _)| group.len()),
+        }),
+    }
+}
+
+/// Whether two GPUs report peer-to-peer access to each other
+fn are_peers(a: &GpuInfo, b: &GpuInfo) -> bool {
+    a.p2p_peers.contains(&b.index) && b.p2p_peers.contains(&a.index)
+}
+
+fn total_memory_selection(
+    selected: &[&GpuInfo],
+    target_mb: u64,
+    criteria: &SelectionCriteria,
+) -> GpuSelection {
+    let all_idle = selected.iter().all(|g| is_idle(g, criteria));
+    let warning = if all_idle {
+        None
+    } else {
+        Some(format!(
+            "Warning: Using {} non-idle GPU(s) to reach {} MB free memory",
+            selected.iter().filter(|g| !is_idle(g, criteria)).count(),
+            target_mb
+        ))
+    };
+
+    GpuSelection {
+        gpu_indices: selected.iter().map(|g| g.index).collect(),
+        gpu_uuids: selected.iter().map(|g| g.uuid.clone()).collect(),
+        all_idle,
+        warning,
+    }
+}
+
+/// Whether a GPU passes the hidden-usage and threshold filters (claims aside)
+fn meets_filters(gpu: &GpuInfo, criteria: &SelectionCriteria) -> bool {
+    filter_exclusions(gpu, criteria).is_empty()
+}
+
+/// The hidden-usage and threshold filters a GPU fails (claims aside)
+fn filter_exclusions(gpu: &GpuInfo, criteria: &SelectionCriteria) -> Vec<Exclusion> {
+    // Nothing else is known about a device that failed to answer queries
+    if let Some(error) = &gpu.error {
+        return vec![Exclusion::Unavailable {
+            error: error.clone(),
+        }];
+    }
+    let mut exclusions = Vec::new();
+    // A device reporting no memory at all is mid-reset or broken
+    if !gpu.reports_memory() {
+        exclusions.push(Exclusion::NoMemoryReported);
+    }
+    // Filter out GPUs with hidden memory usage (stale NVML data)
+    if !criteria.allow_hidden_usage && gpu.has_hidden_usage(&criteria.noise) {
+        exclusions.push(Exclusion::HiddenUsage {
+            hidden_mb: gpu.hidden_usage_mb,
+        });
+    }
+    // Filter by minimum free memory
+    if let Some(min_mb) = criteria.min_memory_mb {
+        if gpu.memory_free_mb() < min_mb {
+            exclusions.push(Exclusion::BelowMinMemory {
+                free_mb: gpu.memory_free_mb(),
+                min_mb,
+            });
+        }
+    }
+    // Filter by maximum total memory (right-sizing small jobs)
+    if let Some(max_mb) = criteria.max_memory_mb {
+        if gpu.memory_total_mb > max_mb {
+            exclusions.push(Exclusion::AboveMaxMemory {
+                total_mb: gpu.memory_total_mb,
+                max_mb,
+            });
+        }
+    }
+    // Filter by maximum utilization
+    if let Some(max_percent) = criteria.max_utilization {
+        if gpu.utilization_percent > max_percent {
+            exclusions.push(Exclusion::AboveMaxUtilization {
+                percent: gpu.utilization_percent,
+                max_percent,
+            });
+        }
+    }
+    // Filter by maximum memory-controller utilization
+    if let Some(max_percent) = criteria.max_memory_utilization {
+        if gpu.memory_utilization_percent > max_percent {
+            exclusions.push(Exclusion::AboveMaxMemoryUtilization {
+                percent: gpu.memory_utilization_percent,
+                max_percent,
+            });
+        }
+    }
+    // Filter out GPUs slowed down by thermal or power limits
+    if criteria.avoid_throttled {
+        if let Some(reasons) = gpu.throttle_summary() {
+            exclusions.push(Exclusion::Throttled { reasons });
+        }
+    }
+    // Filter by how long the GPU has been idle
+    if let Some(min_secs) = criteria.min_idle_secs {
+        let idle_secs = gpu
+            .idle_secs
+            .filter(|_| is_idle(gpu, criteria))
+            .unwrap_or(0);
+        if idle_secs < min_secs {
+            exclusions.push(Exclusion::IdleTooShort {
+                idle_secs,
+                min_secs,
+            });
+        }
+    }
+    exclusions
+}
+
+/// Why a GPU was not a candidate for selection
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum Exclusion {
+    /// Claimed through a lock file by another `with-gpu` process
+    Claimed {
+        pid: u32,
+    },
+    /// Failed to answer queries (`GpuInfo::error`)
+    Unavailable {
+        error: String,
+    },
+    /// Reports 0 MB total memory, e.g. during a driver reset
+    NoMemoryReported,
+    HiddenUsage {
+        hidden_mb: u64,
+    },
+    BelowMinMemory {
+        free_mb: u64,
+        min_mb: u64,
+    },
+    AboveMaxMemory {
+        total_mb: u64,
+        max_mb: u64,
+    },
+    AboveMaxUtilization {
+        percent: u8,
+        max_percent: u8,
+    },
+    AboveMaxMemoryUtilization {
+        percent: u8,
+        max_percent: u8,
+    },
+    /// In a slowdown state, under `avoid_throttled`
+    Throttled {
+        reasons: String,
+    },
+    /// Busy, or idle for less than `min_idle_secs`
+    IdleTooShort {
+        idle_secs: u64,
+        min_secs: u64,
+    },
+    /// Only excluded under `--require-idle`
+    NotIdle,
+}
+
+impl fmt::Display for Exclusion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exclusion::Claimed { pid } => write!(f, "claimed by pid {}", pid),
+            Exclusion::Unavailable { error } => write!(f, "unavailable ({})", error),
+            Exclusion::NoMemoryReported => write!(f, "reports 0 MB total memory"),
+            Exclusion::HiddenUsage { hidden_mb } => {
+                write!(f, "suspected hidden usage ({} MB unattributed)", hidden_mb)
+            }
+            Exclusion::BelowMinMemory { free_mb, min_mb } => {
+                write!(f, "{} MB free < {} MB (--min-memory)", free_mb, min_mb)
+            }
+            Exclusion::AboveMaxMemory { total_mb, max_mb } => {
+                write!(f, "{} MB total > {} MB (--max-memory)", total_mb, max_mb)
+            }
+            Exclusion::AboveMaxUtilization {
+                percent,
+                max_percent,
+            } => write!(
+                f,
+                "{}% utilization > {}% (--max-util)",
+                percent, max_percent
+            ),
+            Exclusion::AboveMaxMemoryUtilization {
+                percent,
+                max_percent,
+            } => write!(
+                f,
+                "{}% memory utilization > {}% (--max-mem-util)",
+                percent, max_percent
+            ),
+            Exclusion::Throttled { reasons } => {
+                write!(f, "throttled: {} (--avoid-throttled)", reasons)
+            }
+            Exclusion::IdleTooShort {
+                idle_secs,
+                min_secs,
+            } => write!(
+                f,
+                "idle for {}s < {}s (--min-idle-time)",
+                idle_secs, min_secs
+            ),
+            Exclusion::NotIdle => write!(f, "not idle (--require-idle)"),
+        }
+    }
+}
+
+/// Per-GPU outcome of a selection, for `--explain`
+#[derive(Debug, Clone, Serialize)]
+pub struct GpuVerdict {
+    pub index: usize,
+    pub selected: bool,
+    /// Empty for candidates; a candidate that wasn't selected was ranked out
+    /// (e.g. beyond `max_gpus`)
+    pub exclusions: Vec<Exclusion>,
+}
+
+/// Structured explanation of a selection, alongside the `GpuSelection` itself
+#[derive(Debug, Serialize)]
+pub struct SelectionReport {
+    pub verdicts: Vec<GpuVerdict>,
+    /// The selection, or the error `select_gpus` would have reported
+    pub selection: std::result::Result<GpuSelection, String>,
+}
+
+/// Run `select_gpus` and record, for every GPU, whether it was a candidate
+/// and why it was included or excluded
+pub fn explain_selection(
+    gpus: &[GpuInfo],
+    criteria: &SelectionCriteria,
+    policy: &dyn SelectionPolicy,
+) -> SelectionReport {
+    let claimed = blocking_claims(criteria);
+    let selection = select_gpus(gpus, criteria, policy).map_err(|e| e.to_string());
+    // Report free memory as selection saw it
+    let gpus = &*with_reservations(gpus, criteria);
+    let selected: &[usize] = match &selection {
+        Ok(sel) => &sel.gpu_indices,
+        Err(_) => &[],
+    };
+
+    let verdicts = gpus
+        .iter()
+        .map(|gpu| {
+            let mut exclusions = Vec::new();
+            if let Some((_, pid)) = claimed.iter().find(|(idx, _)| *idx == gpu.index) {
+                exclusions.push(Exclusion::Claimed { pid: *pid });
+            }
+            exclusions.extend(filter_exclusions(gpu, criteria));
+            if idle_only(criteria) && !is_idle(gpu, criteria) {
+                exclusions.push(Exclusion::NotIdle);
+            }
+            GpuVerdict {
+                index: gpu.index,
+                selected: selected.contains(&gpu.index),
+                exclusions,
+            }
+        })
+        .collect();
+
+    SelectionReport {
+        verdicts,
+        selection,
+    }
+}
+
+/// Human-readable descriptions of the active threshold filters
+fn filter_descriptions(criteria: &SelectionCriteria) -> Vec<String> {
+    let mut descriptions = Vec::new();
+    if let Some(min_mem) = criteria.min_memory_mb {
+        descriptions.push(format!("{}+ MB free memory required", min_mem));
+    }
+    if let Some(max_mem) = criteria.max_memory_mb {
+        descriptions.push(format!("≤{} MB total memory required", max_mem));
+    }
+    if let Some(max_util) = criteria.max_utilization {
+        descriptions.push(format!("≤{}% utilization required", max_util));
+    }
+    if let Some(min_idle) = criteria.min_idle_secs {
+        descriptions.push(format!("idle for {}s+ required", min_idle));
+    }
+    if let Some(max_mem_util) = criteria.max_memory_utilization {
+        descriptions.push(format!("≤{}% memory utilization required", max_mem_util));
+    }
+    if criteria.avoid_throttled {
+        descriptions.push("unthrottled clocks required".to_string());
+    }
+    descriptions
+}
+
+/// Warning for a selection with fewer GPUs than requested: below `min_gpus`
+/// (only possible with `--best-effort`), or below an explicit `max_gpus`,
+/// which a multi-GPU job may be counting on
+fn count_warning(criteria: &SelectionCriteria, selected_count: usize) -> Option<String> {
+    if selected_count < criteria.min_gpus {
+        Some(format!(
+            "WARNING: Requested at least {} GPUs but only {} available; continuing with {} (--best-effort)",
+            criteria.min_gpus, selected_count, selected_count
+        ))
+    } else if selected_count < criteria.max_gpus && criteria.max_gpus != usize::MAX {
+        Some(format!(
+            "Warning: Requested up to {} GPUs but only {} qualified; continuing with {}",
+            criteria.max_gpus, selected_count, selected_count
+        ))
+    } else {
+        None
+    }
+}
+
+fn join_warnings(first: Option<String>, second: Option<String>) -> Option<String> {
+    match (first, second) {
+        (Some(a), Some(b)) => Some(format!("{}\n{}", a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Whether only idle GPUs may be selected
+fn idle_only(criteria: &SelectionCriteria) -> bool {
+    criteria.require_idle || !criteria.include_used
+}
+
+/// Whether a GPU counts as idle, honoring `allow_hidden_usage`
+fn is_idle(gpu: &GpuInfo, criteria: &SelectionCriteria) -> bool {
+    if criteria.allow_hidden_usage {
+        gpu.is_idle_ignoring_hidden_usage(&criteria.noise)
+    } else {
+        gpu.is_idle(&criteria.noise)
+    }
+}
+
+fn partition_gpus_refs<'a>(
+    gpus: &[&'a GpuInfo],
+    criteria: &SelectionCriteria,
+) -> (Vec<&'a GpuInfo>, Vec<&'a GpuInfo>) {
     let mut idle = Vec::new();
     let mut used = Vec::new();
 
     for &gpu in gpus {
-        if gpu.is_idle() {
+        if is_idle(gpu, criteria) {
             idle.push(gpu);
         } else {
             used.push(gpu);
@@ -168,7 +1011,29 @@ fn partition_gpus_refs<'a>(gpus: &[&'a GpuInfo]) -> (Vec<&'a GpuInfo>, Vec<&'a G
     (idle, used)
 }
 
+/// Apply `policy` to `gpus`, mapping the ranked indices back to the GPUs
+fn rank_refs<'a>(
+    policy: &dyn SelectionPolicy,
+    gpus: &[&'a GpuInfo],
+    criteria: &SelectionCriteria,
+) -> Vec<&'a GpuInfo> {
+    policy
+        .rank(gpus, criteria)
+        .into_iter()
+        .filter_map(|index| gpus.iter().find(|g| g.index == index).copied())
+        .collect()
+}
+
 fn sort_by_most_free_refs<'a>(gpus: &[&'a GpuInfo]) -> Vec<&'a GpuInfo> {
+    sort_by_most_free_then(gpus, |_, _| Ordering::Equal)
+}
+
+/// Sort by most free memory, then fewest processes, then `tie_break`, then
+/// lowest index
+fn sort_by_most_free_then<'a>(
+    gpus: &[&'a GpuInfo],
+    tie_break: impl Fn(&GpuInfo, &GpuInfo) -> Ordering,
+) -> Vec<&'a GpuInfo> {
     let mut sorted = gpus.to_vec();
     sorted.sort_by(|a, b| {
         // Primary: Most free memory (descending)
@@ -176,19 +1041,1062 @@ fn sort_by_most_free_refs<'a>(gpus: &[&'a GpuInfo]) -> Vec<&'a GpuInfo> {
             .cmp(&a.memory_free_mb())
             // Secondary: Fewest processes (ascending)
             .then_with(|| a.process_count.cmp(&b.process_count))
-            // Tertiary: Lowest index (ascending)
+            // Policy-specific tie-break
+            .then_with(|| tie_break(a, b))
+            // Finally: Lowest index (ascending)
             .then_with(|| a.index.cmp(&b.index))
     });
     sorted
 }
 
-pub fn parse_manual_gpu_selection(input: &str) -> Result<Vec<usize>> {
-    input
-        .split(',')
-        .map(|s| {
-            s.trim()
-                .parse::<usize>()
-                .context(format!("Invalid GPU ID: '{}'", s))
-        })
-        .collect()
+/// A GPU named in a manual `--gpu` selection, either by index or by UUID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GpuId {
+    Index(usize),
+    Uuid(String),
+}
+
+/// Parse a comma-separated `--gpu` value. Entries starting with `GPU-` are
+/// treated as UUIDs; everything else must be an integer index. Duplicates are
+/// dropped, keeping the first occurrence. `auto` requests automatic selection
+/// and yields `None`, so scripts can always pass `--gpu`.
+pub fn parse_manual_gpu_selection(input: &str) -> Result<Option<Vec<GpuId>>> {
+    parse_gpu_list(input, "--gpu", "")
+}
+
+/// `parse_manual_gpu_selection` for the GPU list in `flag`'s value, which is
+/// `prefix` followed by `input`, so that errors quote what the user typed
+fn parse_gpu_list(input: &str, flag: &str, prefix: &str) -> Result<Option<Vec<GpuId>>> {
+    if input.trim().eq_ignore_ascii_case("auto") {
+        return Ok(None);
+    }
+    let value = format!("{}{}", prefix, input);
+    if input.split(',').all(|s| s.trim().is_empty()) {
+        anyhow::bail!("No GPUs specified in {} '{}'", flag, value);
+    }
+
+    let mut ids = Vec::new();
+    for s in input.split(',') {
+        let s = s.trim();
+        let id = if s.is_empty() {
+            anyhow::bail!("Empty GPU ID in {} '{}'", flag, value);
+        } else if s.starts_with("GPU-") && !s.contains(char::is_whitespace) {
+            GpuId::Uuid(s.to_string())
+        } else if let Ok(index) = s.parse::<usize>() {
+            GpuId::Index(index)
+        } else if s.split_whitespace().all(is_gpu_id) {
+            let suggestion: Vec<&str> = input
+                .split([',', ' ', '\t'])
+                .filter(|s| !s.is_empty())
+                .collect();
+            anyhow::bail!(
+                "GPU IDs in {} '{}' are separated by spaces; separate them with commas instead ({} {}{})",
+                flag,
+                value,
+                flag,
+                prefix,
+                suggestion.join(",")
+            );
+        } else {
+            anyhow::bail!(
+                "Invalid GPU ID '{}' in {} '{}' (expected an index or a UUID starting with GPU-)",
+                s,
+                flag,
+                value
+            );
+        };
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    Ok(Some(ids))
+}
+
+/// Whether `s` looks like a single GPU index or UUID
+fn is_gpu_id(s: &str) -> bool {
+    s.starts_with("GPU-") || s.parse::<usize>().is_ok()
+}
+
+/// Parse a `--gpu-group NAME=IDS` value into the group's name (uppercased, as
+/// it appears in `CUDA_VISIBLE_DEVICES_<NAME>`) and its GPUs, which use the
+/// `--gpu` syntax except that `auto` isn't allowed.
+pub fn parse_gpu_group(input: &str) -> Result<(String, Vec<GpuId>)> {
+    let (name, ids) = input
+        .split_once('=')
+        .with_context(|| format!("Expected NAME=GPUS in --gpu-group '{}'", input))?;
+    let name = name.trim();
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        anyhow::bail!(
+            "Invalid group name '{}' in --gpu-group (use letters, digits, and underscores)",
+            name
+        );
+    }
+    match parse_gpu_list(ids, "--gpu-group", &format!("{}=", name))? {
+        Some(ids) => Ok((name.to_ascii_uppercase(), ids)),
+        None => anyhow::bail!(
+            "--gpu-group '{}' must list its GPUs ('auto' isn't supported)",
+            input
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempLockDir;
+
+    fn gpu_with_memory(index: usize, memory_total_mb: u64, memory_used_mb: u64) -> GpuInfo {
+        GpuInfo {
+            memory_total_mb,
+            memory_used_mb,
+            ..crate::test_support::make_gpu(index)
+        }
+    }
+
+    #[test]
+    fn test_max_memory_prefers_smaller_card() {
+        let gpus = vec![gpu_with_memory(0, 81920, 0), gpu_with_memory(1, 24576, 0)];
+
+        let uncapped = select_gpus(
+            &gpus,
+            &SelectionCriteria {
+                use_locks: false,
+                ..Default::default()
+            },
+            &MostFreeMemoryPolicy,
+        )
+        .unwrap();
+        assert_eq!(uncapped.gpu_indices, vec![0]);
+
+        let criteria = SelectionCriteria {
+            max_memory_mb: Some(32768),
+            use_locks: false,
+            ..Default::default()
+        };
+        let capped = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).unwrap();
+        assert_eq!(capped.gpu_indices, vec![1]);
+    }
+
+    struct LowestIndexPolicy;
+
+    impl SelectionPolicy for LowestIndexPolicy {
+        fn rank(&self, gpus: &[&GpuInfo], _criteria: &SelectionCriteria) -> Vec<usize> {
+            let mut indices: Vec<usize> = gpus.iter().map(|g| g.index).collect();
+            indices.sort();
+            indices
+        }
+    }
+
+    #[test]
+    fn test_custom_policy_overrides_ranking() {
+        let gpus = vec![
+            gpu_with_memory(0, 24576, 12000),
+            gpu_with_memory(1, 24576, 0),
+        ];
+        let criteria = SelectionCriteria {
+            use_locks: false,
+            ..Default::default()
+        };
+
+        let default = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).unwrap();
+        assert_eq!(default.gpu_indices, vec![1]);
+
+        let custom = select_gpus(&gpus, &criteria, &LowestIndexPolicy).unwrap();
+        assert_eq!(custom.gpu_indices, vec![0]);
+    }
+
+    #[test]
+    fn test_lru_policy_prefers_least_recently_used_among_equals() {
+        let gpus = vec![
+            gpu_with_memory(0, 24576, 0),
+            gpu_with_memory(1, 24576, 0),
+            gpu_with_memory(2, 24576, 0),
+        ];
+        let policy = LeastRecentlyUsedPolicy {
+            // GPU 1 has never been used; GPU 0 was used most recently
+            last_used: HashMap::from([(0, 300), (2, 100)]),
+        };
+        let criteria = SelectionCriteria {
+            max_gpus: 3,
+            use_locks: false,
+            ..Default::default()
+        };
+        let selection = select_gpus(&gpus, &criteria, &policy).unwrap();
+        assert_eq!(selection.gpu_indices, vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn test_lru_policy_does_not_override_free_memory() {
+        let gpus = vec![
+            gpu_with_memory(0, 24576, 0),
+            gpu_with_memory(1, 24576, 8000),
+        ];
+        let policy = LeastRecentlyUsedPolicy {
+            last_used: HashMap::from([(0, 300)]),
+        };
+        let selection = select_gpus(
+            &gpus,
+            &SelectionCriteria {
+                use_locks: false,
+                ..Default::default()
+            },
+            &policy,
+        )
+        .unwrap();
+        assert_eq!(selection.gpu_indices, vec![0]);
+    }
+
+    #[test]
+    fn test_coolest_policy_prefers_low_temperature_then_free_memory() {
+        let with_temp = |index, used, temp| GpuInfo {
+            temperature_c: temp,
+            ..gpu_with_memory(index, 24576, used)
+        };
+        let gpus = vec![
+            with_temp(0, 0, Some(80)),
+            with_temp(1, 8000, Some(45)),
+            with_temp(2, 0, Some(45)),
+            with_temp(3, 0, None),
+        ];
+        let criteria = SelectionCriteria {
+            max_gpus: 4,
+            use_locks: false,
+            ..Default::default()
+        };
+        let selection = select_gpus(&gpus, &criteria, &CoolestPolicy).unwrap();
+        assert_eq!(selection.gpu_indices, vec![2, 1, 0, 3]);
+    }
+
+    #[test]
+    fn test_largest_memory_outranks_slightly_more_free() {
+        // Card size comes first: the partly used 80 GB card (GPU 1) outranks
+        // the 24 GB cards even though they have more free memory
+        let gpus = vec![
+            gpu_with_memory(0, 24576, 400),
+            gpu_with_memory(1, 81920, 60000),
+            gpu_with_memory(2, 24576, 200),
+            gpu_with_memory(3, 81920, 300),
+        ];
+        let criteria = SelectionCriteria {
+            max_gpus: 4,
+            use_locks: false,
+            ..Default::default()
+        };
+        let largest = select_gpus(&gpus, &criteria, &LargestMemoryPolicy).unwrap();
+        assert_eq!(largest.gpu_indices, vec![3, 1, 2, 0]);
+
+        let most_free = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).unwrap();
+        assert_eq!(most_free.gpu_indices, vec![3, 2, 0, 1]);
+    }
+
+    #[test]
+    fn test_first_fit_packs_where_best_fit_spreads() {
+        let gpus = vec![
+            gpu_with_memory(0, 24576, 12000),
+            gpu_with_memory(1, 24576, 8000),
+            gpu_with_memory(2, 24576, 0),
+            gpu_with_memory(3, 81920, 0),
+        ];
+        let criteria = SelectionCriteria {
+            min_memory_mb: Some(10000),
+            use_locks: false,
+            ..Default::default()
+        };
+        let best_fit = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).unwrap();
+        let first_fit = select_gpus(&gpus, &criteria, &FirstFitPolicy).unwrap();
+        assert_eq!(best_fit.gpu_indices, vec![3]);
+        assert_eq!(first_fit.gpu_indices, vec![2]);
+
+        // Used GPUs come after every idle one, each in index order
+        let criteria = SelectionCriteria {
+            max_gpus: 4,
+            ..criteria
+        };
+        let first_fit = select_gpus(&gpus, &criteria, &FirstFitPolicy).unwrap();
+        assert_eq!(first_fit.gpu_indices, vec![2, 3, 0, 1]);
+    }
+
+    #[test]
+    fn test_sticky_policy_prefers_earlier_candidates() {
+        let gpus = vec![
+            gpu_with_memory(0, 24576, 4000),
+            gpu_with_memory(1, 24576, 0),
+            gpu_with_memory(2, 24576, 2000),
+        ];
+        let criteria = SelectionCriteria {
+            max_gpus: 2,
+            use_locks: false,
+            ..Default::default()
+        };
+        let sticky = StickyPolicy {
+            inner: &MostFreeMemoryPolicy,
+            preferred: vec![0, 2],
+        };
+        let selection = select_gpus(&gpus, &criteria, &sticky).unwrap();
+        assert_eq!(selection.gpu_indices, vec![2, 0]);
+    }
+
+    #[test]
+    fn test_min_gpus_strict_by_default() {
+        let gpus = vec![gpu_with_memory(0, 24576, 0)];
+        let criteria = SelectionCriteria {
+            min_gpus: 2,
+            max_gpus: 2,
+            use_locks: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).unwrap_err(),
+            SelectionError::InsufficientGpus {
+                required: 2,
+                available: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_selection_errors_can_be_matched() {
+        let criteria = SelectionCriteria {
+            use_locks: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            select_gpus(&[], &criteria, &MostFreeMemoryPolicy).unwrap_err(),
+            SelectionError::NoGpusDetected
+        );
+
+        let busy = vec![gpu_with_memory(0, 24576, 24000)];
+        let err = select_gpus(&busy, &criteria, &MostFreeMemoryPolicy).unwrap_err();
+        assert!(
+            matches!(err, SelectionError::NoGpusMatchCriteria { ref reasons } if !reasons.is_empty()),
+            "{:?}",
+            err
+        );
+
+        let timeout = SelectionError::Timeout {
+            waited_secs: 60,
+            reason: Box::new(err),
+        };
+        assert!(timeout.to_string().starts_with(
+            "Timeout after 60 seconds waiting for GPUs: No GPUs found matching criteria"
+        ));
+    }
+
+    #[test]
+    fn test_idle_memory_threshold() {
+        // No processes, but 700 MB of context memory (e.g. persistence mode)
+        let gpus = vec![gpu_with_memory(0, 24576, 700)];
+        let criteria = SelectionCriteria {
+            require_idle: true,
+            use_locks: false,
+            ..Default::default()
+        };
+        assert_eq!(criteria.noise.idle_memory_mb, 500);
+        assert!(select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).is_err());
+
+        let criteria = SelectionCriteria {
+            noise: NoiseModel {
+                idle_memory_mb: 1024,
+                ..Default::default()
+            },
+            ..criteria
+        };
+        let selection = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).unwrap();
+        assert_eq!(selection.gpu_indices, vec![0]);
+        assert!(selection.all_idle);
+    }
+
+    #[test]
+    fn test_without_include_used_prefers_idle_and_points_to_flag() {
+        let gpus = vec![
+            gpu_with_memory(0, 24576, 8000),
+            gpu_with_memory(1, 24576, 0),
+        ];
+        let criteria = SelectionCriteria {
+            include_used: false,
+            use_locks: false,
+            ..Default::default()
+        };
+        // GPU 0 has more free memory, but it's in use
+        let selection = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).unwrap();
+        assert_eq!(selection.gpu_indices, vec![1]);
+
+        let busy = vec![gpu_with_memory(0, 24576, 8000)];
+        let err = select_gpus(&busy, &criteria, &MostFreeMemoryPolicy).unwrap_err();
+        assert_eq!(
+            err,
+            SelectionError::NoIdleGpus {
+                required: 1,
+                available: 0
+            }
+        );
+        assert!(err.to_string().contains("--include-used"));
+
+        let include_used = SelectionCriteria {
+            include_used: true,
+            ..criteria
+        };
+        let selection = select_gpus(&busy, &include_used, &MostFreeMemoryPolicy).unwrap();
+        assert_eq!(selection.gpu_indices, vec![0]);
+    }
+
+    #[test]
+    fn test_best_effort_proceeds_with_fewer_gpus() {
+        let gpus = vec![gpu_with_memory(0, 24576, 0)];
+        let criteria = SelectionCriteria {
+            min_gpus: 2,
+            max_gpus: 2,
+            best_effort: true,
+            use_locks: false,
+            ..Default::default()
+        };
+        let selection = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).unwrap();
+        assert_eq!(selection.gpu_indices, vec![0]);
+        assert!(selection.warning.unwrap().contains("only 1 available"));
+
+        let idle_criteria = SelectionCriteria {
+            require_idle: true,
+            ..criteria
+        };
+        let selection = select_gpus(&gpus, &idle_criteria, &MostFreeMemoryPolicy).unwrap();
+        assert_eq!(selection.gpu_indices, vec![0]);
+        assert!(selection.warning.is_some());
+    }
+
+    #[test]
+    fn test_require_idle_error_breaks_down_idle_and_filtered() {
+        // GPU 0 is busy; GPUs 1 and 2 are idle but GPU 2 is too big for the cap
+        let gpus = vec![
+            gpu_with_memory(0, 24576, 12000),
+            gpu_with_memory(1, 24576, 0),
+            gpu_with_memory(2, 81920, 0),
+        ];
+        let criteria = SelectionCriteria {
+            min_gpus: 2,
+            max_gpus: 2,
+            require_idle: true,
+            max_memory_mb: Some(32768),
+            use_locks: false,
+            ..Default::default()
+        };
+        let err = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("Require 2 idle GPUs but only 1 available"),
+            "{}",
+            err
+        );
+        assert!(err.contains("2 of 3 GPU(s) idle"), "{}", err);
+        assert!(
+            err.contains("1 of those unclaimed and meeting filters"),
+            "{}",
+            err
+        );
+        assert!(err.contains("≤32768 MB total memory required"), "{}", err);
+    }
+
+    #[test]
+    fn test_all_claimed_error_suggests_wait_and_lists_pids() {
+        let _lock_dir = TempLockDir::new();
+        let gpu_index = 9_000_101;
+        lockfile::claim_gpu(gpu_index).unwrap();
+
+        let gpus = vec![gpu_with_memory(gpu_index, 24576, 0)];
+        let result = select_gpus(&gpus, &SelectionCriteria::default(), &MostFreeMemoryPolicy);
+        lockfile::release_gpu(gpu_index);
+
+        let err = result.unwrap_err().to_string();
+        assert!(
+            err.contains("All 1 matching GPU(s) are claimed by other with-gpu processes"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains(&format!("GPU {} by pid {}", gpu_index, std::process::id())),
+            "{}",
+            err
+        );
+        assert!(err.contains("--wait"), "{}", err);
+    }
+
+    #[test]
+    fn test_busy_error_distinguishes_claimed_from_busy() {
+        let _lock_dir = TempLockDir::new();
+        let gpu_index = 9_000_102;
+        lockfile::claim_gpu(gpu_index).unwrap();
+
+        let gpus = vec![
+            gpu_with_memory(gpu_index, 24576, 0),
+            gpu_with_memory(gpu_index + 1, 24576, 24000),
+        ];
+        let result = select_gpus(&gpus, &SelectionCriteria::default(), &MostFreeMemoryPolicy);
+        lockfile::release_gpu(gpu_index);
+
+        let err = result.unwrap_err().to_string();
+        assert!(
+            err.contains("1 otherwise-suitable GPU(s) claimed"),
+            "{}",
+            err
+        );
+        assert!(err.contains("1 GPU(s) busy"), "{}", err);
+        assert!(!err.contains("--wait"), "{}", err);
+    }
+
+    #[test]
+    fn test_share_selects_gpus_other_sharers_hold() {
+        let _lock_dir = TempLockDir::new();
+        let gpu_index = 9_000_103;
+        lockfile::claim_gpu_shared(gpu_index, None).unwrap();
+
+        let gpus = vec![gpu_with_memory(gpu_index, 24576, 8000)];
+        let select = |share| {
+            let criteria = SelectionCriteria {
+                share,
+                ..Default::default()
+            };
+            select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy)
+        };
+        let exclusive = select(false);
+        let shared = select(true);
+        lockfile::release_gpu(gpu_index);
+
+        assert!(matches!(exclusive, Err(SelectionError::AllClaimed { .. })));
+        assert_eq!(shared.unwrap().gpu_indices, vec![gpu_index]);
+    }
+
+    #[test]
+    fn test_share_discounts_sharers_reservations() {
+        let _lock_dir = TempLockDir::new();
+        let (reserved, unreserved) = (9_000_104, 9_000_105);
+        lockfile::claim_gpu_shared(reserved, Some(16000)).unwrap();
+
+        // The reserved GPU has more free memory, until its sharer allocates
+        let gpus = vec![
+            gpu_with_memory(reserved, 24576, 0),
+            gpu_with_memory(unreserved, 24576, 4000),
+        ];
+        let criteria = SelectionCriteria {
+            share: true,
+            ..Default::default()
+        };
+        let selection = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy);
+        let tight = select_gpus(
+            &gpus[..1],
+            &SelectionCriteria {
+                min_memory_mb: Some(10000),
+                ..criteria
+            },
+            &MostFreeMemoryPolicy,
+        );
+        lockfile::release_gpu(reserved);
+
+        assert_eq!(selection.unwrap().gpu_indices, vec![unreserved]);
+        assert!(matches!(
+            tight,
+            Err(SelectionError::NoGpusMatchCriteria { .. })
+        ));
+    }
+
+    #[test]
+    fn test_reservations_apply_to_qualifying_count_and_explain() {
+        let _lock_dir = TempLockDir::new();
+        let gpu_index = 9_000_106;
+        lockfile::claim_gpu_shared(gpu_index, Some(23000)).unwrap();
+
+        let gpus = vec![gpu_with_memory(gpu_index, 24576, 0)];
+        let criteria = SelectionCriteria {
+            share: true,
+            ..Default::default()
+        };
+        let qualifying = count_qualifying(&gpus, &criteria);
+        let report = explain_selection(&gpus, &criteria, &MostFreeMemoryPolicy);
+        lockfile::release_gpu(gpu_index);
+
+        assert_eq!(qualifying, 0);
+        assert_eq!(
+            report.verdicts[0].exclusions,
+            vec![Exclusion::BelowMinMemory {
+                free_mb: 1576,
+                min_mb: DEFAULT_MIN_MEMORY_MB
+            }]
+        );
+    }
+
+    #[test]
+    fn test_total_memory_selects_fewest_gpus() {
+        let gpus = vec![
+            gpu_with_memory(0, 24576, 14576), // 10000 free
+            gpu_with_memory(1, 24576, 4576),  // 20000 free
+            gpu_with_memory(2, 24576, 9576),  // 15000 free
+        ];
+        let criteria = SelectionCriteria {
+            total_memory_mb: Some(30000),
+            max_gpus: usize::MAX,
+            use_locks: false,
+            ..Default::default()
+        };
+        let selection = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).unwrap();
+        assert_eq!(selection.gpu_indices, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_total_memory_follows_policy_order() {
+        let gpus = vec![
+            gpu_with_memory(0, 24576, 4576), // 20000 free
+            gpu_with_memory(1, 24576, 4576), // 20000 free
+            gpu_with_memory(2, 49152, 9152), // 40000 free
+        ];
+        let criteria = SelectionCriteria {
+            total_memory_mb: Some(30000),
+            max_gpus: usize::MAX,
+            use_locks: false,
+            ..Default::default()
+        };
+        let selection = select_gpus(&gpus, &criteria, &FirstFitPolicy).unwrap();
+        assert_eq!(selection.gpu_indices, vec![0, 1]);
+
+        let last_used = HashMap::from([(0, 20), (1, 10), (2, 30)]);
+        let criteria = SelectionCriteria {
+            require_p2p: true,
+            ..criteria
+        };
+        let peers = |gpu: GpuInfo, p2p_peers: Vec<usize>| GpuInfo { p2p_peers, ..gpu };
+        let gpus = vec![
+            peers(gpus[0].clone(), vec![1]),
+            peers(gpus[1].clone(), vec![0]),
+            gpu_with_memory(2, 24576, 4576),
+        ];
+        let selection =
+            select_gpus(&gpus, &criteria, &LeastRecentlyUsedPolicy { last_used }).unwrap();
+        assert_eq!(selection.gpu_indices, vec![1, 0]);
+    }
+
+    #[test]
+    fn test_total_memory_errors_when_host_cannot_satisfy() {
+        let gpus = vec![
+            gpu_with_memory(0, 24576, 4576),
+            gpu_with_memory(1, 24576, 4576),
+        ];
+        let criteria = SelectionCriteria {
+            total_memory_mb: Some(50000),
+            max_gpus: usize::MAX,
+            use_locks: false,
+            ..Default::default()
+        };
+        let err = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("Need 50000 MB free memory in total"),
+            "{}",
+            err
+        );
+        assert!(err.contains("2 GPU(s) only have 40000 MB free"), "{}", err);
+    }
+
+    #[test]
+    fn test_total_memory_respects_max_gpus() {
+        let gpus = vec![
+            gpu_with_memory(0, 24576, 4576),
+            gpu_with_memory(1, 24576, 4576),
+            gpu_with_memory(2, 24576, 4576),
+        ];
+        let criteria = SelectionCriteria {
+            total_memory_mb: Some(50000),
+            max_gpus: 2,
+            use_locks: false,
+            ..Default::default()
+        };
+        let err = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("limited by --max-gpus 2"), "{}", err);
+    }
+
+    #[test]
+    fn test_total_memory_require_p2p_combines_only_peers() {
+        // Two NVLink pairs: {0, 1} and {2, 3}
+        let peers = [vec![1], vec![0], vec![3], vec![2]];
+        let gpus: Vec<GpuInfo> = [4576, 16576, 6576, 9576]
+            .into_iter()
+            .zip(peers)
+            .enumerate()
+            .map(|(i, (used, p2p_peers))| GpuInfo {
+                p2p_peers,
+                ..gpu_with_memory(i, 24576, used)
+            })
+            .collect();
+        let select = |target_mb| {
+            let criteria = SelectionCriteria {
+                total_memory_mb: Some(target_mb),
+                max_gpus: usize::MAX,
+                require_p2p: true,
+                use_locks: false,
+                ..Default::default()
+            };
+            select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy)
+        };
+
+        // 0 and 2 have the most free memory but can't reach each other
+        assert_eq!(select(30000).unwrap().gpu_indices, vec![2, 3]);
+
+        let err = select(40000).unwrap_err().to_string();
+        assert!(
+            err.contains("best such set (2 GPU(s)) only has 33000 MB free"),
+            "{}",
+            err
+        );
+        assert!(err.contains("--require-p2p"), "{}", err);
+    }
+
+    #[test]
+    fn test_avoid_throttled_skips_slowed_down_gpu() {
+        let mut hot = gpu_with_memory(0, 24576, 0);
+        hot.throttle_reasons = 0x40; // hw thermal slowdown
+        let gpus = vec![hot, gpu_with_memory(1, 24576, 1000)];
+        let select = |avoid_throttled| {
+            let criteria = SelectionCriteria {
+                avoid_throttled,
+                use_locks: false,
+                ..Default::default()
+            };
+            select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy)
+                .unwrap()
+                .gpu_indices
+        };
+        assert_eq!(select(false), vec![0]);
+        assert_eq!(select(true), vec![1]);
+    }
+
+    #[test]
+    fn test_max_memory_utilization_skips_bandwidth_bound_gpu() {
+        let mut busy_bus = gpu_with_memory(0, 24576, 0);
+        busy_bus.memory_utilization_percent = 90;
+        let gpus = vec![busy_bus, gpu_with_memory(1, 24576, 1000)];
+        let criteria = SelectionCriteria {
+            max_memory_utilization: Some(50),
+            use_locks: false,
+            ..Default::default()
+        };
+        let selection = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).unwrap();
+        assert_eq!(selection.gpu_indices, vec![1]);
+    }
+
+    #[test]
+    fn test_exact_gpus_fails_when_one_short() {
+        // --exact-gpus 3 with only two qualifying GPUs
+        let gpus = vec![
+            gpu_with_memory(0, 24576, 0),
+            gpu_with_memory(1, 24576, 0),
+            gpu_with_memory(2, 24576, 24000),
+        ];
+        let criteria = SelectionCriteria {
+            min_gpus: 3,
+            max_gpus: 3,
+            use_locks: false,
+            ..Default::default()
+        };
+        let err = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Need 3 GPUs but only 2 available"), "{}", err);
+    }
+
+    #[test]
+    fn test_count_qualifying_ignores_count_limits() {
+        let mut busy = gpu_with_memory(2, 24576, 8000);
+        busy.process_count = 1;
+        let gpus = vec![
+            gpu_with_memory(0, 24576, 0),
+            gpu_with_memory(1, 24576, 0),
+            busy,
+            gpu_with_memory(3, 24576, 24000),
+        ];
+        let criteria = SelectionCriteria {
+            max_gpus: 1,
+            use_locks: false,
+            ..Default::default()
+        };
+        assert_eq!(count_qualifying(&gpus, &criteria), 3);
+
+        let criteria = SelectionCriteria {
+            require_idle: true,
+            ..criteria
+        };
+        assert_eq!(count_qualifying(&gpus, &criteria), 2);
+    }
+
+    #[test]
+    fn test_fewer_than_max_gpus_warns() {
+        let gpus = vec![
+            gpu_with_memory(0, 24576, 0),
+            gpu_with_memory(1, 24576, 0),
+            gpu_with_memory(2, 24576, 24000),
+        ];
+        let criteria = SelectionCriteria {
+            max_gpus: 4,
+            use_locks: false,
+            ..Default::default()
+        };
+        let selection = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).unwrap();
+        assert_eq!(selection.gpu_indices, vec![0, 1]);
+        let warning = selection.warning.unwrap();
+        assert!(warning.contains("up to 4 GPUs but only 2"), "{}", warning);
+
+        let criteria = SelectionCriteria {
+            max_gpus: 2,
+            ..criteria
+        };
+        let selection = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).unwrap();
+        assert_eq!(selection.warning, None);
+    }
+
+    #[test]
+    fn test_unavailable_gpu_is_never_selected() {
+        let gpus = vec![
+            GpuInfo::unavailable(0, "GPU is lost".to_string()),
+            gpu_with_memory(1, 24576, 20000),
+        ];
+        let criteria = SelectionCriteria {
+            min_memory_mb: None,
+            use_locks: false,
+            ..Default::default()
+        };
+        let report = explain_selection(&gpus, &criteria, &MostFreeMemoryPolicy);
+        assert_eq!(report.selection.unwrap().gpu_indices, vec![1]);
+        assert_eq!(
+            report.verdicts[0].exclusions,
+            vec![Exclusion::Unavailable {
+                error: "GPU is lost".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_zero_total_memory_is_never_selected() {
+        let resetting = gpu_with_memory(0, 0, 0);
+        assert!(!resetting.is_idle(&NoiseModel::default()));
+
+        let gpus = vec![resetting.clone()];
+        let criteria = SelectionCriteria {
+            min_memory_mb: None,
+            use_locks: false,
+            ..Default::default()
+        };
+        assert!(select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).is_err());
+
+        let gpus = vec![resetting, gpu_with_memory(1, 24576, 20000)];
+        let selection = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).unwrap();
+        assert_eq!(selection.gpu_indices, vec![1]);
+        let report = explain_selection(&gpus, &criteria, &MostFreeMemoryPolicy);
+        assert_eq!(
+            report.verdicts[0].exclusions,
+            vec![Exclusion::NoMemoryReported]
+        );
+    }
+
+    #[test]
+    fn test_allow_hidden_usage_makes_gpu_selectable() {
+        // A driver reservation NVML doesn't attribute to any process
+        let mut reserved = gpu_with_memory(0, 24576, 4000);
+        reserved.hidden_usage_mb = 4000;
+        let gpus = vec![reserved];
+        let criteria = SelectionCriteria {
+            require_idle: true,
+            use_locks: false,
+            ..Default::default()
+        };
+        assert!(select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).is_err());
+
+        let criteria = SelectionCriteria {
+            allow_hidden_usage: true,
+            ..criteria
+        };
+        let selection = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).unwrap();
+        assert_eq!(selection.gpu_indices, vec![0]);
+        assert!(selection.all_idle);
+    }
+
+    #[test]
+    fn test_explain_selection_reports_reasons() {
+        let mut hot = gpu_with_memory(2, 24576, 0);
+        hot.utilization_percent = 95;
+        let gpus = vec![
+            gpu_with_memory(0, 24576, 0),
+            gpu_with_memory(1, 24576, 23576),
+            hot,
+            gpu_with_memory(3, 24576, 1000),
+        ];
+        let criteria = SelectionCriteria {
+            max_utilization: Some(50),
+            use_locks: false,
+            ..Default::default()
+        };
+        let report = explain_selection(&gpus, &criteria, &MostFreeMemoryPolicy);
+
+        assert_eq!(report.selection.unwrap().gpu_indices, vec![0]);
+        let verdicts = &report.verdicts;
+        assert!(verdicts[0].selected && verdicts[0].exclusions.is_empty());
+        assert_eq!(
+            verdicts[1].exclusions,
+            vec![Exclusion::BelowMinMemory {
+                free_mb: 1000,
+                min_mb: DEFAULT_MIN_MEMORY_MB
+            }]
+        );
+        assert_eq!(
+            verdicts[2].exclusions,
+            vec![Exclusion::AboveMaxUtilization {
+                percent: 95,
+                max_percent: 50
+            }]
+        );
+        // A candidate that lost on ranking (max_gpus = 1)
+        assert!(!verdicts[3].selected && verdicts[3].exclusions.is_empty());
+    }
+
+    #[test]
+    fn test_explain_selection_records_error() {
+        let gpus = vec![gpu_with_memory(0, 24576, 24000)];
+        let criteria = SelectionCriteria {
+            require_idle: true,
+            use_locks: false,
+            ..Default::default()
+        };
+        let report = explain_selection(&gpus, &criteria, &MostFreeMemoryPolicy);
+        assert!(report.selection.is_err());
+        assert!(report.verdicts[0].exclusions.contains(&Exclusion::NotIdle));
+    }
+
+    #[test]
+    fn test_min_idle_time_skips_recently_busy_gpus() {
+        let idle_for = |index, secs| GpuInfo {
+            idle_secs: Some(secs),
+            ..gpu_with_memory(index, 24576, 0)
+        };
+        let gpus = vec![
+            idle_for(0, 30),
+            idle_for(1, 900),
+            gpu_with_memory(2, 24576, 0),
+        ];
+        let criteria = SelectionCriteria {
+            min_idle_secs: Some(600),
+            max_gpus: 3,
+            use_locks: false,
+            ..Default::default()
+        };
+        let selection = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy).unwrap();
+        assert_eq!(selection.gpu_indices, vec![1]);
+    }
+
+    #[test]
+    fn test_max_memory_errors_when_all_cards_too_large() {
+        let gpus = vec![gpu_with_memory(0, 81920, 0)];
+        let criteria = SelectionCriteria {
+            max_memory_mb: Some(32768),
+            use_locks: false,
+            ..Default::default()
+        };
+        let err = select_gpus(&gpus, &criteria, &MostFreeMemoryPolicy)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("≤32768 MB total memory required"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_manual_gpu_selection_indices() {
+        assert_eq!(
+            parse_manual_gpu_selection("0, 2").unwrap(),
+            Some(vec![GpuId::Index(0), GpuId::Index(2)])
+        );
+    }
+
+    #[test]
+    fn test_parse_manual_gpu_selection_mixed_uuid_and_index() {
+        assert_eq!(
+            parse_manual_gpu_selection("0,GPU-abcd-1234").unwrap(),
+            Some(vec![
+                GpuId::Index(0),
+                GpuId::Uuid("GPU-abcd-1234".to_string())
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_manual_gpu_selection_deduplicates() {
+        assert_eq!(
+            parse_manual_gpu_selection("0,0,1").unwrap(),
+            Some(vec![GpuId::Index(0), GpuId::Index(1)])
+        );
+    }
+
+    #[test]
+    fn test_parse_manual_gpu_selection_auto() {
+        assert_eq!(parse_manual_gpu_selection("auto").unwrap(), None);
+        assert_eq!(parse_manual_gpu_selection(" AUTO ").unwrap(), None);
+        assert!(parse_manual_gpu_selection("auto,1").is_err());
+    }
+
+    #[test]
+    fn test_parse_manual_gpu_selection_rejects_empty() {
+        assert!(parse_manual_gpu_selection("").is_err());
+        assert!(parse_manual_gpu_selection(",").is_err());
+        assert!(parse_manual_gpu_selection(" ").is_err());
+    }
+
+    #[test]
+    fn test_parse_manual_gpu_selection_rejects_trailing_comma() {
+        let err = parse_manual_gpu_selection("0,").unwrap_err().to_string();
+        assert!(err.contains("Empty GPU ID"));
+    }
+
+    #[test]
+    fn test_parse_manual_gpu_selection_invalid() {
+        let err = parse_manual_gpu_selection("0,x,2").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Invalid GPU ID 'x' in --gpu '0,x,2' (expected an index or a UUID starting with GPU-)"
+        );
+        let err = parse_manual_gpu_selection("0,1 x").unwrap_err().to_string();
+        assert!(err.contains("Invalid GPU ID '1 x'"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_manual_gpu_selection_suggests_commas() {
+        let err = parse_manual_gpu_selection("0 1 2").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "GPU IDs in --gpu '0 1 2' are separated by spaces; separate them with commas instead (--gpu 0,1,2)"
+        );
+        let err = parse_manual_gpu_selection("0, 1 GPU-abc")
+            .unwrap_err()
+            .to_string();
+        assert!(err.ends_with("(--gpu 0,1,GPU-abc)"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_gpu_group() {
+        assert_eq!(
+            parse_gpu_group("prefill=0,GPU-abc").unwrap(),
+            (
+                "PREFILL".to_string(),
+                vec![GpuId::Index(0), GpuId::Uuid("GPU-abc".to_string())]
+            )
+        );
+        assert!(parse_gpu_group("0,1").is_err());
+        assert!(parse_gpu_group("=0").is_err());
+        assert!(parse_gpu_group("my-group=0").is_err());
+        assert!(parse_gpu_group("decode=auto").is_err());
+        assert!(parse_gpu_group("decode=").is_err());
+
+        // Errors name --gpu-group, not --gpu
+        let err = parse_gpu_group("a=0 1").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "GPU IDs in --gpu-group 'a=0 1' are separated by spaces; separate them with commas instead (--gpu-group a=0,1)"
+        );
+        let err = parse_gpu_group("a=0,x").unwrap_err().to_string();
+        assert!(err.contains("in --gpu-group 'a=0,x'"), "{}", err);
+    }
 }
@@ -0,0 +1,335 @@
+//! `--max-runtime`: run the command as a child instead of exec'ing it, and
+//! kill it if it runs too long. `--on-success` and `--on-failure` also run it
+//! as a child, without a limit, so there's something left to run the hook.
+//!
+//! Without a limit, the child stays in with-gpu's process group and keeps the
+//! terminal. The terminal's SIGINT and SIGQUIT reach both processes, so
+//! with-gpu ignores them and outlives the child; SIGTERM and SIGHUP sent to
+//! with-gpu are forwarded to the child.
+//!
+//! With a limit, on Unix the child gets its own process group, so the whole
+//! tree it starts (e.g. `torchrun` workers) is signaled together: SIGTERM
+//! first, then SIGKILL if the group is still around after a grace period. If
+//! with-gpu is in the foreground on a terminal, the child's group takes the
+//! terminal over while it runs (a background group reading it would be
+//! stopped by SIGTTIN), so Ctrl-C reaches the child directly. SIGINT,
+//! SIGTERM, and SIGHUP sent to with-gpu itself are forwarded to the group.
+//!
+//! Either way, the previous signal handlers are restored once the child exits.
+
+use std::process::{Child, Command, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::CommandNotStarted;
+
+/// How often to check whether the child has exited
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How the supervised command ended
+#[derive(Debug)]
+pub enum Outcome {
+    Exited(ExitStatus),
+    /// It ran past the limit and was killed
+    TimedOut,
+}
+
+/// Spawn `command` and wait for it to exit
+pub fn run(command: Command) -> Result<ExitStatus> {
+    let (mut child, supervision) = spawn(command)?;
+    let status = child.wait();
+    drop(supervision);
+    Ok(status?)
+}
+
+/// Spawn `command` in its own process group and wait for it, for at most
+/// `max_runtime`. A command that outlives it is sent SIGTERM, then SIGKILL
+/// once `grace` has passed.
+pub fn run_with_max_runtime(
+    command: Command,
+    max_runtime: Duration,
+    grace: Duration,
+) -> Result<Outcome> {
+    let (mut child, supervision) = spawn_group(command)?;
+
+    let deadline = Instant::now() + max_runtime;
+    let outcome = loop {
+        if let Some(status) = child.try_wait()? {
+            break Outcome::Exited(status);
+        }
+        if Instant::now() >= deadline {
+            terminate(&mut child, grace);
+            break Outcome::TimedOut;
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    drop(supervision);
+    Ok(outcome)
+}
+
+/// Signal forwarding and terminal ownership while a child runs, undone when
+/// dropped
+struct Supervision {
+    #[cfg(unix)]
+    _handlers: signals::Handlers,
+    /// The terminal the child's group took over, to take back
+    #[cfg(unix)]
+    terminal: Option<libc::c_int>,
+}
+
+impl Drop for Supervision {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(fd) = self.terminal {
+            terminal::take(fd);
+        }
+    }
+}
+
+/// Start `command` in our process group, forwarding the signals the terminal
+/// doesn't already deliver to it
+fn spawn(mut command: Command) -> Result<(Child, Supervision)> {
+    let child = spawn_child(&mut command)?;
+    let supervision = Supervision {
+        #[cfg(unix)]
+        _handlers: signals::Handlers::forward(
+            child.id() as libc::pid_t,
+            &[libc::SIGTERM, libc::SIGHUP],
+            &[libc::SIGINT, libc::SIGQUIT],
+        ),
+        #[cfg(unix)]
+        terminal: None,
+    };
+    Ok((child, supervision))
+}
+
+/// Start `command` in its own process group, forwarding signals to it and
+/// handing it the terminal if we have it
+fn spawn_group(mut command: Command) -> Result<(Child, Supervision)> {
+    #[cfg(unix)]
+    let terminal = terminal::foreground_fd();
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+        if let Some(fd) = terminal {
+            // In the child, so the group has the terminal before the command
+            // can touch it
+            unsafe {
+                command.pre_exec(move || {
+                    terminal::take(fd);
+                    Ok(())
+                });
+            }
+        }
+    }
+
+    let child = spawn_child(&mut command)?;
+    let supervision = Supervision {
+        #[cfg(unix)]
+        _handlers: signals::Handlers::forward(
+            -(child.id() as libc::pid_t),
+            &[libc::SIGINT, libc::SIGTERM, libc::SIGHUP],
+            &[],
+        ),
+        #[cfg(unix)]
+        terminal,
+    };
+    Ok((child, supervision))
+}
+
+fn spawn_child(command: &mut Command) -> Result<Child> {
+    let child = command.spawn().map_err(|error| CommandNotStarted {
+        program: command.get_program().to_string_lossy().into_owned(),
+        error,
+    })?;
+    Ok(child)
+}
+
+/// SIGTERM the child's process group, then SIGKILL whatever is left of it
+/// after `grace`
+#[cfg(unix)]
+fn terminate(child: &mut Child, grace: Duration) {
+    let group = -(child.id() as libc::pid_t);
+    unsafe { libc::kill(group, libc::SIGTERM) };
+    let deadline = Instant::now() + grace;
+    while Instant::now() < deadline {
+        if let Ok(Some(_)) = child.try_wait() {
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    // Processes the child started may outlive it
+    unsafe { libc::kill(group, libc::SIGKILL) };
+    let _ = child.wait();
+}
+
+#[cfg(not(unix))]
+fn terminate(child: &mut Child, _grace: Duration) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[cfg(unix)]
+mod terminal {
+    /// A terminal among stdin, stdout, and stderr on which with-gpu's process
+    /// group is in the foreground
+    pub fn foreground_fd() -> Option<libc::c_int> {
+        [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO]
+            .into_iter()
+            .find(|&fd| unsafe { libc::isatty(fd) == 1 && libc::tcgetpgrp(fd) == libc::getpgrp() })
+    }
+
+    /// Make the calling process's group the foreground group on `fd`. A
+    /// background group may only do this with SIGTTOU ignored. Only makes
+    /// async-signal-safe calls, so it can run between fork and exec.
+    pub fn take(fd: libc::c_int) {
+        unsafe {
+            let previous = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+            libc::tcsetpgrp(fd, libc::getpgrp());
+            libc::signal(libc::SIGTTOU, previous);
+        }
+    }
+}
+
+#[cfg(unix)]
+mod signals {
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    /// Where to forward signals, as a `kill` target (a PID, or a negated
+    /// process group); 0 when nothing is running
+    static TARGET: AtomicI32 = AtomicI32::new(0);
+
+    extern "C" fn forward(signal: libc::c_int) {
+        let target = TARGET.load(Ordering::SeqCst);
+        if target != 0 {
+            unsafe { libc::kill(target, signal) };
+        }
+    }
+
+    /// Signal handlers we installed, and the ones they replaced, which are
+    /// restored when this is dropped
+    pub struct Handlers {
+        previous: Vec<(libc::c_int, libc::sigaction)>,
+    }
+
+    impl Handlers {
+        /// Forward `forwarded` signals to `target` (as for `kill`), and
+        /// ignore `ignored` ones
+        pub fn forward(
+            target: libc::pid_t,
+            forwarded: &[libc::c_int],
+            ignored: &[libc::c_int],
+        ) -> Self {
+            TARGET.store(target, Ordering::SeqCst);
+            let handler = forward as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            let handlers = forwarded
+                .iter()
+                .map(|&signal| (signal, handler))
+                .chain(ignored.iter().map(|&signal| (signal, libc::SIG_IGN)));
+            let previous = handlers
+                .map(|(signal, handler)| (signal, set_handler(signal, handler)))
+                .collect();
+            Self { previous }
+        }
+    }
+
+    impl Drop for Handlers {
+        fn drop(&mut self) {
+            for (signal, action) in self.previous.iter().rev() {
+                unsafe { libc::sigaction(*signal, action, std::ptr::null_mut()) };
+            }
+            TARGET.store(0, Ordering::SeqCst);
+        }
+    }
+
+    /// Set `signal`'s handler, returning the action it replaced
+    fn set_handler(signal: libc::c_int, handler: libc::sighandler_t) -> libc::sigaction {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handler;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut previous: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(signal, &action, &mut previous);
+            previous
+        }
+    }
+
+    #[cfg(test)]
+    pub fn current_handler(signal: libc::c_int) -> libc::sighandler_t {
+        unsafe {
+            let mut current: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(signal, std::ptr::null(), &mut current);
+            current.sa_sigaction
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.args(["-c", script]);
+        command
+    }
+
+    #[test]
+    fn test_command_that_finishes_in_time() {
+        let outcome =
+            run_with_max_runtime(sh("exit 3"), Duration::from_secs(10), Duration::ZERO).unwrap();
+        match outcome {
+            Outcome::Exited(status) => assert_eq!(status.code(), Some(3)),
+            Outcome::TimedOut => panic!("timed out"),
+        }
+    }
+
+    #[test]
+    fn test_forwarding_restores_previous_handlers() {
+        // No other test touches SIGUSR2
+        unsafe { libc::signal(libc::SIGUSR2, libc::SIG_IGN) };
+        let handlers = signals::Handlers::forward(std::process::id() as i32, &[libc::SIGUSR2], &[]);
+        let forwarding = signals::current_handler(libc::SIGUSR2);
+        drop(handlers);
+
+        assert_ne!(forwarding, libc::SIG_IGN);
+        assert_eq!(signals::current_handler(libc::SIGUSR2), libc::SIG_IGN);
+        unsafe { libc::signal(libc::SIGUSR2, libc::SIG_DFL) };
+    }
+
+    #[test]
+    fn test_run_waits_for_exit() {
+        assert_eq!(run(sh("sleep 0.1; exit 4")).unwrap().code(), Some(4));
+    }
+
+    #[test]
+    fn test_runaway_command_is_killed() {
+        let start = Instant::now();
+        let outcome = run_with_max_runtime(
+            sh("sleep 30"),
+            Duration::from_millis(200),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert!(matches!(outcome, Outcome::TimedOut));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_command_ignoring_sigterm_is_killed_after_grace() {
+        let start = Instant::now();
+        let outcome = run_with_max_runtime(
+            sh("trap '' TERM; sleep 30"),
+            Duration::from_millis(200),
+            Duration::from_millis(300),
+        )
+        .unwrap();
+        assert!(matches!(outcome, Outcome::TimedOut));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,111 @@
+//! Fixtures shared by the library's and the binary's tests. Only compiled
+//! for tests: the `test-support` feature, which the crate enables on itself
+//! as a dev-dependency, lets the binary's tests reach it.
+
+use std::cell::RefCell;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Result;
+
+use crate::{GpuInfo, GpuSource};
+
+/// An idle 24000 MB GPU with UUID `GPU-NNNN`. Tests set other fields with
+/// struct update syntax.
+pub fn make_gpu(index: usize) -> GpuInfo {
+    GpuInfo {
+        index,
+        uuid: format!("GPU-{:04}", index),
+        memory_total_mb: 24000,
+        ..Default::default()
+    }
+}
+
+/// `make_gpu` for indices `0..count`
+pub fn make_gpus(count: usize) -> Vec<GpuInfo> {
+    (0..count).map(make_gpu).collect()
+}
+
+thread_local! {
+    static LOCK_DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+/// The current test's lock directory, if it made one with `TempLockDir`
+pub(crate) fn lock_dir_override() -> Option<PathBuf> {
+    LOCK_DIR.with(|dir| dir.borrow().clone())
+}
+
+/// A private lock directory for the current test, which `lockfile::lock_dir`
+/// (and so every claim and `usage` record) uses in place of
+/// `$WITH_GPU_LOCK_DIR` until the guard drops. Tests run on their own
+/// threads, so each sees only its own claims. Dropping deletes the directory.
+pub struct TempLockDir {
+    path: PathBuf,
+}
+
+impl TempLockDir {
+    pub fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "with-gpu-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        LOCK_DIR.with(|dir| *dir.borrow_mut() = Some(path.clone()));
+        TempLockDir { path }
+    }
+}
+
+impl Default for TempLockDir {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TempLockDir {
+    fn drop(&mut self) {
+        LOCK_DIR.with(|dir| *dir.borrow_mut() = None);
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Returns canned snapshots in order, repeating the last one. With no
+/// snapshots, every query fails.
+pub struct MockSource {
+    pub snapshots: Vec<Vec<GpuInfo>>,
+    /// How many times `query_gpus` has been called
+    pub queries: usize,
+}
+
+impl MockSource {
+    pub fn new(snapshots: Vec<Vec<GpuInfo>>) -> Self {
+        Self {
+            snapshots,
+            queries: 0,
+        }
+    }
+}
+
+impl GpuSource for MockSource {
+    fn query_gpus(&mut self) -> Result<Vec<GpuInfo>> {
+        let i = self.queries;
+        self.queries += 1;
+        let Some(last) = self.snapshots.len().checked_sub(1) else {
+            anyhow::bail!("MockSource has no snapshots");
+        };
+        Ok(self.snapshots[i.min(last)].clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_source_without_snapshots_errors() {
+        let mut source = MockSource::new(Vec::new());
+        assert!(source.query_gpus().is_err());
+        assert_eq!(source.queries, 1);
+    }
+}
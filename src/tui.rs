@@ -0,0 +1,332 @@
+//! Full-screen status dashboard for `with-gpu tui`.
+//!
+//! Polls a `GpuSource` on an interval and redraws one panel per GPU: a memory
+//! gauge, a utilization sparkline, and the process count and claim holder.
+//! When stdout isn't a terminal, the caller falls back to `--status` output.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Gauge, Paragraph, Sparkline};
+use ratatui::{DefaultTerminal, Frame};
+
+use with_gpu::{lockfile, GpuInfo, GpuMonitor, GpuSource, NoiseModel};
+
+/// Utilization samples kept per GPU for the sparkline
+const HISTORY_LEN: usize = 120;
+
+/// Height of one GPU panel, including borders
+const PANEL_HEIGHT: u16 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Index,
+    FreeMemory,
+    Utilization,
+}
+
+impl SortKey {
+    fn next(self) -> Self {
+        match self {
+            SortKey::Index => SortKey::FreeMemory,
+            SortKey::FreeMemory => SortKey::Utilization,
+            SortKey::Utilization => SortKey::Index,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            SortKey::Index => "index",
+            SortKey::FreeMemory => "free memory",
+            SortKey::Utilization => "utilization",
+        }
+    }
+}
+
+struct App {
+    gpus: Vec<GpuInfo>,
+    claims: Vec<(usize, u32)>,
+    /// Recent utilization samples, keyed by GPU index
+    history: HashMap<usize, VecDeque<u64>>,
+    sort: SortKey,
+    idle_only: bool,
+    /// What counts as idle
+    noise: NoiseModel,
+    error: Option<String>,
+}
+
+impl App {
+    fn new(noise: NoiseModel) -> Self {
+        Self {
+            gpus: Vec::new(),
+            claims: Vec::new(),
+            history: HashMap::new(),
+            sort: SortKey::Index,
+            idle_only: false,
+            noise,
+            error: None,
+        }
+    }
+
+    /// Record a fresh snapshot. A failed query keeps the last snapshot on
+    /// screen and shows the error instead of exiting.
+    fn update(&mut self, result: Result<Vec<GpuInfo>>) {
+        match result {
+            Ok(gpus) => {
+                for gpu in &gpus {
+                    let samples = self.history.entry(gpu.index).or_default();
+                    if samples.len() == HISTORY_LEN {
+                        samples.pop_front();
+                    }
+                    samples.push_back(gpu.utilization_percent as u64);
+                }
+                self.gpus = gpus;
+                self.error = None;
+            }
+            Err(e) => self.error = Some(format!("{:#}", e)),
+        }
+        self.claims = lockfile::get_claimed_gpus()
+            .into_iter()
+            .map(|(idx, claim)| (idx, claim.pid))
+            .collect();
+    }
+
+    /// The GPUs to draw, after the idle-only filter and the current sort
+    fn visible_gpus(&self) -> Vec<&GpuInfo> {
+        let mut gpus: Vec<&GpuInfo> = self
+            .gpus
+            .iter()
+            .filter(|g| !self.idle_only || g.is_idle(&self.noise))
+            .collect();
+        match self.sort {
+            SortKey::Index => gpus.sort_by_key(|g| g.index),
+            SortKey::FreeMemory => gpus.sort_by_key(|g| std::cmp::Reverse(g.memory_free_mb())),
+            SortKey::Utilization => gpus.sort_by_key(|g| g.utilization_percent),
+        }
+        gpus
+    }
+
+    fn claim_holder(&self, gpu_index: usize) -> Option<u32> {
+        self.claims
+            .iter()
+            .find(|(idx, _)| *idx == gpu_index)
+            .map(|(_, pid)| *pid)
+    }
+}
+
+/// Run the dashboard until the user quits
+pub fn run<S: GpuSource>(
+    monitor: &GpuMonitor<S>,
+    refresh_interval: Duration,
+    noise: NoiseModel,
+) -> Result<()> {
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, monitor, refresh_interval, noise);
+    ratatui::restore();
+    result
+}
+
+fn event_loop<S: GpuSource>(
+    terminal: &mut DefaultTerminal,
+    monitor: &GpuMonitor<S>,
+    refresh_interval: Duration,
+    noise: NoiseModel,
+) -> Result<()> {
+    let mut app = App::new(noise);
+    app.update(monitor.refresh());
+    let mut last_refresh = Instant::now();
+
+    loop {
+        terminal.draw(|frame| draw(frame, &app, refresh_interval))?;
+
+        let timeout = refresh_interval.saturating_sub(last_refresh.elapsed());
+        if event::poll(timeout)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    KeyCode::Char('s') => app.sort = app.sort.next(),
+                    KeyCode::Char('i') => app.idle_only = !app.idle_only,
+                    KeyCode::Char('r') => {
+                        app.update(monitor.refresh());
+                        last_refresh = Instant::now();
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if last_refresh.elapsed() >= refresh_interval {
+            app.update(monitor.refresh());
+            last_refresh = Instant::now();
+        }
+    }
+}
+
+fn draw(frame: &mut Frame, app: &App, refresh_interval: Duration) {
+    let [header_area, body_area, footer_area] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Min(0),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let idle_count = app.gpus.iter().filter(|g| g.is_idle(&app.noise)).count();
+    let header = format!(
+        "with-gpu: {} GPU(s), {} idle, {} claimed | sort: {}{} | refresh {}s",
+        app.gpus.len(),
+        idle_count,
+        app.claims.len(),
+        app.sort.label(),
+        if app.idle_only { " | idle only" } else { "" },
+        refresh_interval.as_secs_f64()
+    );
+    frame.render_widget(Paragraph::new(header).bold(), header_area);
+
+    let footer = match &app.error {
+        Some(error) => Line::from(format!("Error: {}", error)).fg(Color::Red),
+        None => Line::from("q quit  s sort  i idle only  r refresh").dim(),
+    };
+    frame.render_widget(Paragraph::new(footer), footer_area);
+
+    let gpus = app.visible_gpus();
+    if gpus.is_empty() {
+        let message = if app.gpus.is_empty() {
+            "No GPUs detected"
+        } else {
+            "No idle GPUs (press i to show all)"
+        };
+        frame.render_widget(Paragraph::new(message), body_area);
+        return;
+    }
+
+    let panels =
+        Layout::vertical(vec![Constraint::Length(PANEL_HEIGHT); gpus.len()]).split(body_area);
+    for (gpu, area) in gpus.iter().zip(panels.iter()) {
+        draw_gpu(frame, app, gpu, *area);
+    }
+}
+
+fn draw_gpu(frame: &mut Frame, app: &App, gpu: &GpuInfo, area: Rect) {
+    let status = if gpu.error.is_some() {
+        "ERROR (unavailable)"
+    } else if gpu.is_idle(&app.noise) {
+        "IDLE"
+    } else {
+        "USED"
+    };
+    let claim = match app.claim_holder(gpu.index) {
+        Some(pid) => format!(", claimed by pid {}", pid),
+        None => String::new(),
+    };
+    let title = format!(
+        " GPU {}: {} - {} processes{} ",
+        gpu.index, status, gpu.process_count, claim
+    );
+    let color = if gpu.error.is_some() {
+        Color::Red
+    } else if gpu.is_idle(&app.noise) {
+        Color::Green
+    } else {
+        Color::Yellow
+    };
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(title)
+        .border_style(Style::default().fg(color));
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let [memory_area, util_area] =
+        Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(inner);
+
+    let memory = Gauge::default()
+        .gauge_style(Style::default().fg(color))
+        .ratio((gpu.memory_usage_percent() / 100.0).clamp(0.0, 1.0))
+        .label(format!("{}/{} MB", gpu.memory_used_mb, gpu.memory_total_mb));
+    frame.render_widget(memory, memory_area);
+
+    let samples: Vec<u64> = app
+        .history
+        .get(&gpu.index)
+        .map(|h| h.iter().copied().collect())
+        .unwrap_or_default();
+    let [label_area, spark_area] =
+        Layout::horizontal([Constraint::Length(10), Constraint::Min(0)]).areas(util_area);
+    // Show the most recent samples that fit beside the label
+    let start = samples.len().saturating_sub(spark_area.width as usize);
+    frame.render_widget(
+        Paragraph::new(format!(" {:>3}% util", gpu.utilization_percent)),
+        label_area,
+    );
+    frame.render_widget(
+        Sparkline::default()
+            .data(&samples[start..])
+            .max(100)
+            .style(Style::default().fg(Color::Cyan)),
+        spark_area,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use with_gpu::test_support::make_gpu;
+
+    fn gpu_with_load(index: usize, memory_used_mb: u64, utilization_percent: u8) -> GpuInfo {
+        GpuInfo {
+            memory_used_mb,
+            utilization_percent,
+            ..make_gpu(index)
+        }
+    }
+
+    fn app_with(gpus: Vec<GpuInfo>) -> App {
+        let mut app = App::new(NoiseModel::default());
+        app.gpus = gpus;
+        app
+    }
+
+    fn indices(gpus: &[&GpuInfo]) -> Vec<usize> {
+        gpus.iter().map(|g| g.index).collect()
+    }
+
+    #[test]
+    fn test_sort_keys() {
+        let mut app = app_with(vec![
+            gpu_with_load(0, 20000, 10),
+            gpu_with_load(1, 0, 0),
+            gpu_with_load(2, 10000, 90),
+        ]);
+        assert_eq!(indices(&app.visible_gpus()), vec![0, 1, 2]);
+
+        app.sort = app.sort.next();
+        assert_eq!(indices(&app.visible_gpus()), vec![1, 2, 0]);
+
+        app.sort = app.sort.next();
+        assert_eq!(indices(&app.visible_gpus()), vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn test_idle_only_filter() {
+        let mut app = app_with(vec![gpu_with_load(0, 20000, 10), gpu_with_load(1, 0, 0)]);
+        app.idle_only = true;
+        assert_eq!(indices(&app.visible_gpus()), vec![1]);
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let mut app = App::new(NoiseModel::default());
+        for _ in 0..HISTORY_LEN + 10 {
+            app.update(Ok(vec![gpu_with_load(0, 0, 50)]));
+        }
+        assert_eq!(app.history[&0].len(), HISTORY_LEN);
+    }
+}
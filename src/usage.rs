@@ -0,0 +1,201 @@
+//! Per-GPU timestamps persisted across runs, under the lock directory:
+//!
+//! - `last-used`: when `with-gpu` last claimed each GPU, for `--lru`
+//! - `last-busy`: when each GPU was last seen busy, for idle durations and
+//!   `--min-idle-time`
+//!
+//! Entries are keyed by UUID when known (indices can shift), falling back to
+//! the index. Entries for GPUs that no longer exist are ignored when reading.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+
+use crate::lockfile;
+use crate::{GpuInfo, GpuSource, NoiseModel};
+
+fn usage_path() -> PathBuf {
+    lockfile::lock_dir().join("last-used")
+}
+
+fn busy_path() -> PathBuf {
+    lockfile::lock_dir().join("last-busy")
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn key(gpu: &GpuInfo) -> String {
+    if gpu.uuid.is_empty() {
+        gpu.index.to_string()
+    } else {
+        gpu.uuid.clone()
+    }
+}
+
+/// Parse `KEY TIMESTAMP` lines, skipping malformed ones
+fn parse_entries(contents: &str) -> HashMap<String, u64> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (key, timestamp) = line.trim().split_once(' ')?;
+            Some((key.to_string(), timestamp.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+fn format_entries(entries: &HashMap<String, u64>) -> String {
+    let mut lines: Vec<String> = entries
+        .iter()
+        .map(|(key, timestamp)| format!("{} {}\n", key, timestamp))
+        .collect();
+    lines.sort();
+    lines.concat()
+}
+
+fn read_entries(path: &PathBuf) -> HashMap<String, u64> {
+    parse_entries(&fs::read_to_string(path).unwrap_or_default())
+}
+
+/// Write to a temporary file and rename, so concurrent readers never see a
+/// partially written file
+fn write_entries(path: &PathBuf, entries: &HashMap<String, u64>) {
+    let tmp_path = path.with_extension(format!("tmp-{}", std::process::id()));
+    if fs::write(&tmp_path, format_entries(entries)).is_ok() {
+        let _ = fs::rename(&tmp_path, path);
+    }
+}
+
+/// Map each GPU's index to when it was last claimed (seconds since the Unix
+/// epoch). GPUs that have never been claimed are absent.
+pub fn last_used(gpus: &[GpuInfo]) -> HashMap<usize, u64> {
+    let entries = read_entries(&usage_path());
+    gpus.iter()
+        .filter_map(|gpu| entries.get(&key(gpu)).map(|&t| (gpu.index, t)))
+        .collect()
+}
+
+/// Record that the given GPUs were just claimed. Best-effort: failures are
+/// ignored, since this only affects `--lru` tie-breaking.
+pub fn record_use(gpus: &[GpuInfo], gpu_indices: &[usize]) {
+    if lockfile::ensure_lock_dir().is_err() {
+        return;
+    }
+    let path = usage_path();
+    let mut entries = read_entries(&path);
+    let now = now_secs();
+    for gpu in gpus.iter().filter(|g| gpu_indices.contains(&g.index)) {
+        entries.insert(key(gpu), now);
+    }
+    write_entries(&path, &entries);
+}
+
+/// Fold a fresh snapshot into the last-busy entries and set `idle_secs` on
+/// each idle GPU. A busy GPU's entry moves to `now`. An idle GPU seen for the
+/// first time also starts at `now`, since we can't know how long it was idle
+/// before that.
+///
+/// Only queries are observed, so a GPU that was busy between two of them
+/// without either query seeing it looks idle for the whole gap: idle times can
+/// be overestimated, never underestimated.
+fn track_idle(
+    entries: &mut HashMap<String, u64>,
+    gpus: &mut [GpuInfo],
+    noise: &NoiseModel,
+    now: u64,
+) {
+    for gpu in gpus.iter_mut() {
+        let last_busy = entries.entry(key(gpu)).or_insert(now);
+        if gpu.is_idle(noise) {
+            gpu.idle_secs = Some(now.saturating_sub(*last_busy));
+        } else {
+            *last_busy = now;
+            gpu.idle_secs = None;
+        }
+    }
+}
+
+/// Wraps a `GpuSource` to fill in `GpuInfo::idle_secs` from the persisted
+/// last-busy timestamps, updating them on every query. Persistence is
+/// best-effort; without it, idle durations start from this query.
+pub struct IdleTrackingSource<S> {
+    pub inner: S,
+    /// What counts as idle
+    pub noise: NoiseModel,
+}
+
+impl<S: GpuSource> GpuSource for IdleTrackingSource<S> {
+    fn query_gpus(&mut self) -> Result<Vec<GpuInfo>> {
+        let mut gpus = self.inner.query_gpus()?;
+        let path = busy_path();
+        let mut entries = read_entries(&path);
+        track_idle(&mut entries, &mut gpus, &self.noise, now_secs());
+        if !gpus.is_empty() && lockfile::ensure_lock_dir().is_ok() {
+            write_entries(&path, &entries);
+        }
+        Ok(gpus)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_round_trip() {
+        let mut entries = HashMap::new();
+        entries.insert("GPU-aaaa".to_string(), 100);
+        entries.insert("3".to_string(), 200);
+        let text = format_entries(&entries);
+        assert_eq!(text, "3 200\nGPU-aaaa 100\n");
+        assert_eq!(parse_entries(&text), entries);
+    }
+
+    #[test]
+    fn test_track_idle() {
+        let busy = GpuInfo {
+            index: 0,
+            uuid: "GPU-aaaa".to_string(),
+            process_count: 1,
+            memory_used_mb: 8000,
+            ..Default::default()
+        };
+        let idle = GpuInfo {
+            index: 1,
+            uuid: "GPU-bbbb".to_string(),
+            memory_total_mb: 24000,
+            ..Default::default()
+        };
+        let new_idle = GpuInfo {
+            index: 2,
+            uuid: "GPU-cccc".to_string(),
+            memory_total_mb: 24000,
+            ..Default::default()
+        };
+        let mut entries =
+            HashMap::from([("GPU-aaaa".to_string(), 100), ("GPU-bbbb".to_string(), 400)]);
+        let mut gpus = vec![busy, idle, new_idle];
+        track_idle(&mut entries, &mut gpus, &NoiseModel::default(), 1000);
+
+        assert_eq!(gpus[0].idle_secs, None);
+        assert_eq!(entries["GPU-aaaa"], 1000);
+        assert_eq!(gpus[1].idle_secs, Some(600));
+        assert_eq!(entries["GPU-bbbb"], 400);
+        assert_eq!(gpus[2].idle_secs, Some(0));
+        assert_eq!(entries["GPU-cccc"], 1000);
+    }
+
+    #[test]
+    fn test_parse_entries_skips_malformed_lines() {
+        let entries = parse_entries("GPU-aaaa 100\ngarbage\nGPU-bbbb notanumber\n");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries["GPU-aaaa"], 100);
+    }
+}